@@ -0,0 +1,79 @@
+use std::hint::black_box;
+use criterion::{criterion_group, criterion_main, Criterion};
+use stealthcat_backend::models::Rule;
+use stealthcat_backend::rules::{compile_rules, find_linear, RuleIndex};
+
+const RULE_COUNT: usize = 100_000;
+const QUERY_PORT: u16 = 443;
+
+fn rule(i: usize, rule_type: &str, pattern: String) -> Rule {
+    Rule {
+        id: i.to_string(),
+        name: format!("rule-{}", i),
+        rule_type: rule_type.to_string(),
+        pattern,
+        action: "proxy".to_string(),
+        // Первое правило проверяется первым
+        priority: (RULE_COUNT - i) as i32,
+        enabled: true,
+    }
+}
+
+// Набор, похожий на импорт adblock/geosite списков: в основном домены,
+// немного ключевых слов, сетей и правил, которые не индексируются
+fn generate_rules() -> Vec<Rule> {
+    (0..RULE_COUNT)
+        .map(|i| match i % 10 {
+            0..=3 => rule(i, "domain-suffix", format!("site{}.example{}.com", i, i % 97)),
+            4..=6 => rule(i, "domain", format!("host{}.cdn{}.net", i, i % 89)),
+            7 => rule(i, "domain-keyword", format!("kw{}x", i)),
+            8 => rule(i, "ip-cidr", format!("{}.0/24", ipv4_prefix(i))),
+            _ if i % 1000 == 9 => rule(i, "domain-wildcard", format!("*.wild{}.org", i)),
+            _ if i % 1000 == 19 => rule(i, "domain-regex", format!(r"^api\d+\.re{}\.io$", i)),
+            _ => rule(i, "ip-cidr6", format!("2001:db8:{:x}:{:x}::/64", i >> 16, i & 0xffff)),
+        })
+        .collect()
+}
+
+fn ipv4_prefix(i: usize) -> String {
+    format!("{}.{}.{}", 10 + (i >> 16), (i >> 8) & 0xff, i & 0xff)
+}
+
+fn queries() -> Vec<(&'static str, String)> {
+    vec![
+        ("suffix-late", "www.site99990.example80.com".to_string()),
+        ("exact-mid", "host50004.cdn75.net".to_string()),
+        ("keyword-late", "static-kw99997x-edge.example.org".to_string()),
+        ("ipv4-late", format!("{}.7", ipv4_prefix(99998))),
+        ("ipv6-late", "2001:db8:1:869f::1".to_string()),
+        ("wildcard-late", "cdn.wild99009.org".to_string()),
+        ("miss", "nothing.matches.here.org".to_string()),
+    ]
+}
+
+fn bench_rule_matching(c: &mut Criterion) {
+    let compiled = compile_rules(&generate_rules());
+    let index = RuleIndex::new(compiled.clone());
+    let queries = queries();
+
+    // Индекс обязан выбирать то же правило, что и линейный перебор
+    for (name, host) in &queries {
+        let linear = find_linear(&compiled, host, QUERY_PORT, &[]).map(|r| r.rule.id.clone());
+        let indexed = index.find(host, QUERY_PORT, &[]).map(|r| r.rule.id.clone());
+        assert_eq!(linear, indexed, "matchers disagree for {} ({})", name, host);
+    }
+
+    let mut group = c.benchmark_group("rule_matching_100k");
+    for (name, host) in &queries {
+        group.bench_function(format!("linear/{}", name), |b| {
+            b.iter(|| find_linear(black_box(&compiled), black_box(host), QUERY_PORT, &[]))
+        });
+        group.bench_function(format!("indexed/{}", name), |b| {
+            b.iter(|| index.find(black_box(host), QUERY_PORT, &[]))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_rule_matching);
+criterion_main!(benches);
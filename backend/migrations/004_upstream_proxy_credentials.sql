@@ -0,0 +1,4 @@
+-- Http, Https и Socks5 стали структурами с учётными данными
+UPDATE servers_v2 SET config = '{"Http":{}}' WHERE config = '"Http"';
+UPDATE servers_v2 SET config = '{"Https":{}}' WHERE config = '"Https"';
+UPDATE servers_v2 SET config = '{"Socks5":{}}' WHERE config = '"Socks5"';
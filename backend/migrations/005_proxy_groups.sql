@@ -0,0 +1,11 @@
+-- Группы прокси (proxy-groups)
+CREATE TABLE IF NOT EXISTS proxy_groups (
+    id TEXT PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    group_type TEXT NOT NULL,
+    proxies TEXT NOT NULL, -- JSON массив имён или ID серверов
+    selected TEXT,
+    tolerance INTEGER NOT NULL DEFAULT 0,
+    strategy TEXT NOT NULL DEFAULT 'consistent-hashing',
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,3 @@
+-- Результаты фоновой проверки серверов
+ALTER TABLE servers_v2 ADD COLUMN failure_streak INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE servers_v2 ADD COLUMN up BOOLEAN; -- NULL: сервер ещё не проверялся
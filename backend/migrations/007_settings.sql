@@ -0,0 +1,5 @@
+-- Настройки, которые меняются во время работы
+CREATE TABLE IF NOT EXISTS settings (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);
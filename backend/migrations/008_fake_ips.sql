@@ -0,0 +1,7 @@
+-- Соответствие fake-IP и доменов, чтобы оно пережило перезапуск
+CREATE TABLE IF NOT EXISTS fake_ips (
+    ip TEXT PRIMARY KEY,
+    domain TEXT NOT NULL,
+    -- Логические часы LRU
+    last_used INTEGER NOT NULL
+);
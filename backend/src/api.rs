@@ -1,13 +1,12 @@
-use actix_web::{web, HttpResponse, Result};
+use actix_web::{http::header, web, HttpRequest, HttpResponse, Result};
 use serde_json::json;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use crate::models::*;
 use crate::subscription::SubscriptionParser;
-use crate::models::Subscription;
 use crate::database::Database;
-use crate::models::{ProxyServerV2, ProxyProtocol, ProxyConfig};
-use uuid;
+use crate::proxy::{chain, group, speed_test, ProxyEngine};
+use crate::{pac, rules};
 
 pub async fn get_status(
     data: web::Data<Arc<RwLock<AppState>>>,
@@ -59,6 +58,7 @@ pub async fn get_stats(
 
 pub async fn select_server(
     data: web::Data<Arc<RwLock<AppState>>>,
+    engine: web::Data<Arc<ProxyEngine>>,
     payload: web::Json<serde_json::Value>,
 ) -> Result<HttpResponse> {
     let server_id = payload.get("server_id")
@@ -67,6 +67,7 @@ pub async fn select_server(
     
     let mut state = data.write().await;
     state.current_server = Some(server_id.to_string());
+    engine.select_server(Some(server_id.to_string()));
     
     let response = ApiResponse {
         success: true,
@@ -76,6 +77,52 @@ pub async fn select_server(
     Ok(HttpResponse::Ok().json(response))
 }
 
+pub async fn set_mode(
+    data: web::Data<Arc<RwLock<AppState>>>,
+    db: web::Data<Arc<Database>>,
+    engine: web::Data<Arc<ProxyEngine>>,
+    payload: web::Json<serde_json::Value>,
+) -> Result<HttpResponse> {
+    let mode = payload.get("mode")
+        .cloned()
+        .ok_or_else(|| actix_web::error::ErrorBadRequest("Missing mode"))?;
+    let mode: ProxyMode = match serde_json::from_value(mode) {
+        Ok(mode) => mode,
+        Err(_) => {
+            return Ok(HttpResponse::BadRequest().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                error: Some(ApiError {
+                    code: 400,
+                    message: "Unknown mode, expected Global, PAC or Bypass".to_string(),
+                }),
+            }));
+        }
+    };
+
+    if let Err(e) = db.set_proxy_mode(mode).await {
+        return Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            error: Some(ApiError {
+                code: 500,
+                message: format!("Failed to save mode: {}", e),
+            }),
+        }));
+    }
+
+    let mut state = data.write().await;
+    state.proxy_status.mode = mode;
+    engine.set_mode(mode);
+
+    let response = ApiResponse {
+        success: true,
+        data: Some(json!({"mode": mode})),
+        error: None,
+    };
+    Ok(HttpResponse::Ok().json(response))
+}
+
 pub async fn get_config(
     data: web::Data<Arc<RwLock<AppState>>>,
 ) -> Result<HttpResponse> {
@@ -195,9 +242,10 @@ pub async fn get_rules(
 pub async fn create_rule(
     payload: web::Json<serde_json::Value>,
     db: web::Data<Arc<Database>>,
+    engine: web::Data<Arc<ProxyEngine>>,
 ) -> Result<HttpResponse> {
     let rule = Rule {
-        id: "0".to_string(), // Будет установлен автоматически
+        id: uuid::Uuid::new_v4().to_string(),
         name: payload.get("name")
             .and_then(|v| v.as_str())
             .unwrap_or("Unnamed Rule")
@@ -222,8 +270,13 @@ pub async fn create_rule(
             .unwrap_or(true),
     };
     
+    if let Err(e) = rules::validate_rule(&rule) {
+        return Ok(invalid_rule_response(e));
+    }
+    
     match db.insert_rule(&rule).await {
         Ok(_) => {
+            reload_routing(&engine, &db).await;
             let response = ApiResponse {
                 success: true,
                 data: Some(json!({"message": "Rule created successfully"})),
@@ -246,6 +299,7 @@ pub async fn create_rule(
 
 pub async fn update_rule(
     db: web::Data<Arc<Database>>,
+    engine: web::Data<Arc<ProxyEngine>>,
     path: web::Path<String>, // Изменяем с u32 на String
     rule_data: web::Json<Rule>,
 ) -> Result<HttpResponse> {
@@ -253,8 +307,13 @@ pub async fn update_rule(
     let mut rule = rule_data.into_inner();
     rule.id = rule_id;
     
+    if let Err(e) = rules::validate_rule(&rule) {
+        return Ok(invalid_rule_response(e));
+    }
+    
     match db.update_rule(&rule).await {
         Ok(_) => {
+            reload_routing(&engine, &db).await;
             let response = ApiResponse {
                 success: true,
                 data: Some(json!({"message": "Rule updated successfully"})),
@@ -277,12 +336,14 @@ pub async fn update_rule(
 
 pub async fn delete_rule(
     db: web::Data<Arc<Database>>, // Убираем подчеркивание
+    engine: web::Data<Arc<ProxyEngine>>,
     path: web::Path<String>,
 ) -> Result<HttpResponse> {
     let rule_id = path.into_inner();
     
     match db.delete_rule(&rule_id).await { // Передаем ссылку на String
         Ok(_) => {
+            reload_routing(&engine, &db).await;
             let response = ApiResponse {
                 success: true,
                 data: Some(json!({"message": "Rule deleted successfully"})),
@@ -303,6 +364,293 @@ pub async fn delete_rule(
     }
 }
 
+fn invalid_rule_response(e: anyhow::Error) -> HttpResponse {
+    HttpResponse::BadRequest().json(ApiResponse::<()> {
+        success: false,
+        data: None,
+        error: Some(ApiError {
+            code: 400,
+            message: format!("Invalid rule: {}", e),
+        }),
+    })
+}
+
+/// Проверяет цепочку до записи в БД: все hop'ы существуют и нет циклов.
+/// Новая ссылка может замкнуть цикл только через сам сервер, поэтому
+/// достаточно раскрыть его с учётом изменений.
+async fn validate_chain(db: &Database, server: &ProxyServerV2) -> Result<(), HttpResponse> {
+    if !matches!(server.config, ProxyConfig::Chain { .. }) {
+        return Ok(());
+    }
+    let mut servers = db.get_servers_v2().await.map_err(|e| {
+        HttpResponse::InternalServerError().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            error: Some(ApiError {
+                code: 500,
+                message: format!("Failed to load servers: {}", e),
+            }),
+        })
+    })?;
+    servers.retain(|s| s.id != server.id);
+    servers.push(server.clone());
+
+    chain::expand(server, &servers).map(|_| ()).map_err(|e| {
+        HttpResponse::BadRequest().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            error: Some(ApiError {
+                code: 400,
+                message: format!("Invalid chain: {}", e),
+            }),
+        })
+    })
+}
+
+/// Проверяет группу до записи в БД, см. `group::validate`.
+async fn validate_group(db: &Database, group: &ProxyGroup) -> Result<(), HttpResponse> {
+    let loaded = tokio::try_join!(db.get_groups(), db.get_servers_v2());
+    let (groups, servers) = loaded.map_err(|e| {
+        HttpResponse::InternalServerError().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            error: Some(ApiError {
+                code: 500,
+                message: format!("Failed to load groups: {}", e),
+            }),
+        })
+    })?;
+
+    group::validate(group, &groups, &servers).map_err(|e| {
+        HttpResponse::BadRequest().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            error: Some(ApiError {
+                code: 400,
+                message: format!("Invalid group: {}", e),
+            }),
+        })
+    })
+}
+
+fn group_not_found_response() -> HttpResponse {
+    HttpResponse::NotFound().json(ApiResponse::<()> {
+        success: false,
+        data: None,
+        error: Some(ApiError {
+            code: 404,
+            message: "Group not found".to_string(),
+        }),
+    })
+}
+
+pub async fn get_groups(
+    db: web::Data<Arc<Database>>,
+) -> Result<HttpResponse> {
+    match db.get_groups().await {
+        Ok(groups) => Ok(HttpResponse::Ok().json(ApiResponse {
+            success: true,
+            data: Some(groups),
+            error: None,
+        })),
+        Err(e) => Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            error: Some(ApiError {
+                code: 500,
+                message: format!("Failed to get groups: {}", e),
+            }),
+        })),
+    }
+}
+
+pub async fn create_group(
+    payload: web::Json<UpdateProxyGroup>,
+    db: web::Data<Arc<Database>>,
+    engine: web::Data<Arc<ProxyEngine>>,
+) -> Result<HttpResponse> {
+    let data = payload.into_inner();
+    let group = ProxyGroup {
+        id: uuid::Uuid::new_v4().to_string(),
+        name: data.name.trim().to_string(),
+        group_type: data.group_type,
+        proxies: data.proxies,
+        selected: data.selected,
+        tolerance: data.tolerance,
+        strategy: data.strategy,
+    };
+
+    if let Err(response) = validate_group(&db, &group).await {
+        return Ok(response);
+    }
+
+    match db.insert_group(&group).await {
+        Ok(_) => {
+            reload_routing(&engine, &db).await;
+            Ok(HttpResponse::Created().json(ApiResponse {
+                success: true,
+                data: Some(group),
+                error: None,
+            }))
+        },
+        Err(e) => Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            error: Some(ApiError {
+                code: 500,
+                message: format!("Failed to create group: {}", e),
+            }),
+        })),
+    }
+}
+
+pub async fn update_group(
+    db: web::Data<Arc<Database>>,
+    engine: web::Data<Arc<ProxyEngine>>,
+    path: web::Path<String>,
+    payload: web::Json<UpdateProxyGroup>,
+) -> Result<HttpResponse> {
+    let group_id = path.into_inner();
+    let data = payload.into_inner();
+
+    match db.get_group(&group_id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return Ok(group_not_found_response()),
+        Err(e) => {
+            return Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                error: Some(ApiError {
+                    code: 500,
+                    message: format!("Failed to get group: {}", e),
+                }),
+            }));
+        }
+    }
+
+    let group = ProxyGroup {
+        id: group_id,
+        name: data.name.trim().to_string(),
+        group_type: data.group_type,
+        proxies: data.proxies,
+        selected: data.selected,
+        tolerance: data.tolerance,
+        strategy: data.strategy,
+    };
+
+    if let Err(response) = validate_group(&db, &group).await {
+        return Ok(response);
+    }
+
+    match db.update_group(&group).await {
+        Ok(_) => {
+            reload_routing(&engine, &db).await;
+            Ok(HttpResponse::Ok().json(ApiResponse {
+                success: true,
+                data: Some(group),
+                error: None,
+            }))
+        },
+        Err(e) => Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            error: Some(ApiError {
+                code: 500,
+                message: format!("Failed to update group: {}", e),
+            }),
+        })),
+    }
+}
+
+pub async fn delete_group(
+    db: web::Data<Arc<Database>>,
+    engine: web::Data<Arc<ProxyEngine>>,
+    path: web::Path<String>,
+) -> Result<HttpResponse> {
+    let group_id = path.into_inner();
+
+    match db.delete_group(&group_id).await {
+        Ok(_) => {
+            reload_routing(&engine, &db).await;
+            Ok(HttpResponse::Ok().json(ApiResponse {
+                success: true,
+                data: Some("Group deleted successfully"),
+                error: None,
+            }))
+        },
+        Err(e) => Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            error: Some(ApiError {
+                code: 500,
+                message: format!("Failed to delete group: {}", e),
+            }),
+        })),
+    }
+}
+
+// Ручной выбор участника select-группы
+pub async fn select_group_proxy(
+    db: web::Data<Arc<Database>>,
+    engine: web::Data<Arc<ProxyEngine>>,
+    path: web::Path<String>,
+    payload: web::Json<serde_json::Value>,
+) -> Result<HttpResponse> {
+    let group_id = path.into_inner();
+    let proxy = payload.get("proxy")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| actix_web::error::ErrorBadRequest("Missing proxy"))?;
+
+    let mut group = match db.get_group(&group_id).await {
+        Ok(Some(group)) => group,
+        Ok(None) => return Ok(group_not_found_response()),
+        Err(e) => {
+            return Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                error: Some(ApiError {
+                    code: 500,
+                    message: format!("Failed to get group: {}", e),
+                }),
+            }));
+        }
+    };
+    if group.group_type != ProxyGroupType::Select {
+        return Ok(HttpResponse::BadRequest().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            error: Some(ApiError {
+                code: 400,
+                message: format!("Group \"{}\" is {}, only select groups can be switched manually", group.name, group.group_type.as_str()),
+            }),
+        }));
+    }
+
+    group.selected = Some(proxy.to_string());
+    if let Err(response) = validate_group(&db, &group).await {
+        return Ok(response);
+    }
+
+    match db.update_group(&group).await {
+        Ok(_) => {
+            reload_routing(&engine, &db).await;
+            Ok(HttpResponse::Ok().json(ApiResponse {
+                success: true,
+                data: Some(group),
+                error: None,
+            }))
+        },
+        Err(e) => Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            error: Some(ApiError {
+                code: 500,
+                message: format!("Failed to update group: {}", e),
+            }),
+        })),
+    }
+}
+
 // Получение всех подписок
 pub async fn get_subscriptions(
     db: web::Data<Arc<Database>>,
@@ -382,6 +730,7 @@ pub async fn get_subscription(
 pub async fn update_subscription_servers(
     path: web::Path<String>,
     db: web::Data<Arc<Database>>,
+    engine: web::Data<Arc<ProxyEngine>>,
 ) -> Result<HttpResponse, actix_web::Error> {
     let subscription_id = path.into_inner();
     
@@ -394,14 +743,17 @@ pub async fn update_subscription_servers(
                 Ok(servers) => {
                     // Обновляем серверы в базе данных
                     match db.update_subscription_servers(&subscription_id, &servers).await {
-                        Ok(_) => Ok(HttpResponse::Ok().json(ApiResponse {
-                            success: true,
-                            data: Some(serde_json::json!({
-                                "updated_servers": servers.len(),
-                                "subscription_id": subscription_id
-                            })),
-                            error: None,
-                        })),
+                        Ok(_) => {
+                            reload_routing(&engine, &db).await;
+                            Ok(HttpResponse::Ok().json(ApiResponse {
+                                success: true,
+                                data: Some(serde_json::json!({
+                                    "updated_servers": servers.len(),
+                                    "subscription_id": subscription_id
+                                })),
+                                error: None,
+                            }))
+                        },
                         Err(e) => Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
                             success: false,
                             data: None,
@@ -466,15 +818,19 @@ pub async fn get_servers_v2(
 pub async fn delete_subscription(
     path: web::Path<String>,
     db: web::Data<Arc<Database>>,
+    engine: web::Data<Arc<ProxyEngine>>,
 ) -> Result<HttpResponse, actix_web::Error> {
     let subscription_id = path.into_inner();
     
     match db.delete_subscription(&subscription_id).await {
-        Ok(_) => Ok(HttpResponse::Ok().json(ApiResponse {
-            success: true,
-            data: Some("Subscription deleted successfully"),
-            error: None,
-        })),
+        Ok(_) => {
+            reload_routing(&engine, &db).await;
+            Ok(HttpResponse::Ok().json(ApiResponse {
+                success: true,
+                data: Some("Subscription deleted successfully"),
+                error: None,
+            }))
+        },
         Err(e) => Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
             success: false,
             data: None,
@@ -489,22 +845,75 @@ pub async fn delete_subscription(
 // Тестирование скорости сервера
 pub async fn test_server_speed(
     path: web::Path<String>,
-    _db: web::Data<Arc<Database>>, // Добавляем подчеркивание перед именем
+    db: web::Data<Arc<Database>>,
+    engine: web::Data<Arc<ProxyEngine>>,
+    payload: Option<web::Json<SpeedTestRequest>>,
 ) -> Result<HttpResponse, actix_web::Error> {
     let server_id = path.into_inner();
-    
-    // Здесь будет логика тестирования скорости
-    // Пока что возвращаем заглушку
-    Ok(HttpResponse::Ok().json(ApiResponse {
-        success: true,
-        data: Some(serde_json::json!({
-            "server_id": server_id,
-            "latency_ms": 42,
-            "download_speed": 1024000, // 1 MB/s
-            "upload_speed": 512000     // 512 KB/s
+    let overrides = payload.map(|p| p.into_inner()).unwrap_or_default();
+
+    let servers = match db.get_servers_v2().await {
+        Ok(servers) => servers,
+        Err(e) => {
+            return Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                success: false,
+                data: None,
+                error: Some(ApiError {
+                    code: 500,
+                    message: format!("Failed to get servers: {}", e),
+                }),
+            }));
+        }
+    };
+    let Some(server) = servers.iter().find(|s| s.id == server_id) else {
+        return Ok(HttpResponse::NotFound().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            error: Some(ApiError {
+                code: 404,
+                message: "Server not found".to_string(),
+            }),
+        }));
+    };
+
+    let defaults = &engine.settings.speed_test;
+    let settings = crate::config::SpeedTestSettings {
+        download_url: overrides.download_url.unwrap_or_else(|| defaults.download_url.clone()),
+        upload_url: overrides.upload_url.unwrap_or_else(|| defaults.upload_url.clone()),
+        download_bytes: overrides.download_bytes.unwrap_or(defaults.download_bytes),
+        upload_bytes: overrides.upload_bytes.unwrap_or(defaults.upload_bytes),
+        timeout: overrides.timeout.unwrap_or(defaults.timeout),
+    };
+
+    match speed_test::run(server, &servers, &settings).await {
+        Ok(result) => {
+            if let Err(e) = db.update_server_speed(&server_id, result.latency_ms, result.download_speed, result.upload_speed).await {
+                log::error!("Failed to save speed test of server {}: {}", server_id, e);
+            }
+            reload_routing(&engine, &db).await;
+            Ok(HttpResponse::Ok().json(ApiResponse {
+                success: true,
+                data: Some(json!({
+                    "server_id": server_id,
+                    "latency_ms": result.latency_ms,
+                    "ttfb_ms": result.ttfb_ms,
+                    "download_speed": result.download_speed,
+                    "upload_speed": result.upload_speed,
+                    "downloaded_bytes": result.downloaded_bytes,
+                    "uploaded_bytes": result.uploaded_bytes
+                })),
+                error: None,
+            }))
+        },
+        Err(e) => Ok(HttpResponse::BadGateway().json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            error: Some(ApiError {
+                code: 502,
+                message: format!("Speed test of \"{}\" failed: {:#}", server.name, e),
+            }),
         })),
-        error: None,
-    }))
+    }
 }
 
 // Обновление подписки
@@ -547,27 +956,32 @@ pub async fn update_subscription(
 pub async fn create_server(
     payload: web::Json<UpdateProxyServer>, // Изменяем на UpdateProxyServer
     db: web::Data<Arc<Database>>,
+    engine: web::Data<Arc<ProxyEngine>>,
 ) -> Result<HttpResponse> {
     let update_data = payload.into_inner();
     
     // Создаем ProxyServer с автогенерированным ID
     // Вместо создания ProxyServer создаем ProxyServerV2
+    let protocol = match update_data.protocol.as_str() {
+        "HTTP" => ProxyProtocol::HTTP,
+        "HTTPS" => ProxyProtocol::HTTPS,
+        "SOCKS5" => ProxyProtocol::SOCKS5,
+        "VLESS" => ProxyProtocol::VLESS,
+        "VMess" => ProxyProtocol::VMess,
+        "Trojan" => ProxyProtocol::Trojan,
+        "Shadowsocks" => ProxyProtocol::Shadowsocks,
+        "Chain" => ProxyProtocol::Chain,
+        _ => ProxyProtocol::HTTP,
+    };
+    let config = update_data.config.unwrap_or_else(|| ProxyConfig::default_for(&protocol));
+
     let server = ProxyServerV2 {
         id: uuid::Uuid::new_v4().to_string(),
         name: update_data.name,
         hostname: update_data.hostname,  // Исправлено: hostname вместо host
         port: update_data.port,
-        protocol: match update_data.protocol.as_str() {
-            "HTTP" => ProxyProtocol::HTTP,
-            "HTTPS" => ProxyProtocol::HTTPS,
-            "SOCKS5" => ProxyProtocol::SOCKS5,
-            "VLESS" => ProxyProtocol::VLESS,
-            "VMess" => ProxyProtocol::VMess,
-            "Trojan" => ProxyProtocol::Trojan,
-            "Shadowsocks" => ProxyProtocol::Shadowsocks,
-            _ => ProxyProtocol::HTTP,
-        },
-        config: ProxyConfig::Http, // Временная заглушка, будет заменена в insert_server_v2
+        protocol,
+        config,
         latency_ms: None,
         last_ping: None,
         active: update_data.active,
@@ -576,14 +990,23 @@ pub async fn create_server(
         upload_speed: None,
         download_speed: None,
         subscription_id: None,
+        failure_streak: 0,
+        up: None,
     };
 
+    if let Err(response) = validate_chain(&db, &server).await {
+        return Ok(response);
+    }
+
     match db.insert_server_v2(&server).await { // Используем insert_server_v2
-        Ok(_) => Ok(HttpResponse::Ok().json(ApiResponse {
-            success: true,
-            data: Some("Server created successfully"),
-            error: None,
-        })),
+        Ok(_) => {
+            reload_routing(&engine, &db).await;
+            Ok(HttpResponse::Ok().json(ApiResponse {
+                success: true,
+                data: Some("Server created successfully"),
+                error: None,
+            }))
+        },
         Err(e) => Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
             success: false,
             data: None,
@@ -597,6 +1020,7 @@ pub async fn create_server(
 
 pub async fn update_server(
     db: web::Data<Arc<Database>>,
+    engine: web::Data<Arc<ProxyEngine>>,
     path: web::Path<String>,
     server_data: web::Json<UpdateProxyServerV2>,  // ✅ Изменено на V2
 ) -> Result<HttpResponse> {
@@ -619,14 +1043,23 @@ pub async fn update_server(
         upload_speed: update_data.upload_speed,
         download_speed: update_data.download_speed,
         subscription_id: update_data.subscription_id,
+        failure_streak: 0,
+        up: None,
     };
 
+    if let Err(response) = validate_chain(&db, &server).await {
+        return Ok(response);
+    }
+
     match db.update_server(&server).await {
-        Ok(_) => Ok(HttpResponse::Ok().json(ApiResponse {
-            success: true,
-            data: Some("Server updated successfully"),
-            error: None,
-        })),
+        Ok(_) => {
+            reload_routing(&engine, &db).await;
+            Ok(HttpResponse::Ok().json(ApiResponse {
+                success: true,
+                data: Some("Server updated successfully"),
+                error: None,
+            }))
+        },
         Err(e) => Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
             success: false,
             data: None,
@@ -640,16 +1073,20 @@ pub async fn update_server(
 
 pub async fn delete_server(
     db: web::Data<Arc<Database>>,
+    engine: web::Data<Arc<ProxyEngine>>,
     path: web::Path<String>,
 ) -> Result<HttpResponse> {
     let server_id = path.into_inner();
 
     match db.delete_server(&server_id).await {
-        Ok(_) => Ok(HttpResponse::Ok().json(ApiResponse {
-            success: true,
-            data: Some("Server deleted successfully"),
-            error: None,
-        })),
+        Ok(_) => {
+            reload_routing(&engine, &db).await;
+            Ok(HttpResponse::Ok().json(ApiResponse {
+                success: true,
+                data: Some("Server deleted successfully"),
+                error: None,
+            }))
+        },
         Err(e) => Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
             success: false,
             data: None,
@@ -665,6 +1102,7 @@ pub async fn delete_server(
 pub async fn import_subscription(
     import_data: web::Json<ImportSubscriptionRequest>,
     db: web::Data<Arc<Database>>,
+    engine: web::Data<Arc<ProxyEngine>>,
 ) -> Result<HttpResponse, actix_web::Error> {
     let parser = SubscriptionParser::new();
     
@@ -699,15 +1137,18 @@ pub async fn import_subscription(
                 Ok(_) => {
                     // Сохраняем серверы с привязкой к подписке
                     match db.update_subscription_servers(&subscription.id, &servers).await {
-                        Ok(_) => Ok(HttpResponse::Ok().json(ApiResponse {
-                            success: true,
-                            data: Some(serde_json::json!({
-                                "subscription_id": subscription.id,
-                                "imported_servers": servers.len(),
-                                "subscription_name": subscription.name
-                            })),
-                            error: None,
-                        })),
+                        Ok(_) => {
+                            reload_routing(&engine, &db).await;
+                            Ok(HttpResponse::Ok().json(ApiResponse {
+                                success: true,
+                                data: Some(serde_json::json!({
+                                    "subscription_id": subscription.id,
+                                    "imported_servers": servers.len(),
+                                    "subscription_name": subscription.name
+                                })),
+                                error: None,
+                            }))
+                        },
                         Err(e) => Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
                             success: false,
                             data: None,
@@ -737,4 +1178,59 @@ pub async fn import_subscription(
             }),
         })),
     }
-}
\ No newline at end of file
+}
+
+/// PAC файл по текущим правилам. Правила меняются через API вместе со
+/// снимком маршрутизации, поэтому файл собирается заново на каждый запрос,
+/// а клиенты с актуальным ETag получают 304.
+pub async fn get_pac(
+    req: HttpRequest,
+    engine: web::Data<Arc<ProxyEngine>>,
+) -> HttpResponse {
+    let settings = &engine.settings;
+    // Клиентам из сети отдаём адрес, по которому они пришли за PAC файлом
+    let host = if settings.allow_lan {
+        let connection = req.connection_info();
+        split_host(connection.host()).to_string()
+    } else {
+        "127.0.0.1".to_string()
+    };
+
+    let router = engine.router();
+    let proxy = pac::proxy_directive(settings, &host);
+    let body = pac::generate(router.rules(), router.mode(), &proxy, settings.resolve_ip_rules);
+    let etag = pac::etag(&body);
+
+    let cached = req.headers().get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.split(',').any(|tag| tag.trim() == etag || tag.trim() == "*"));
+    if cached {
+        return HttpResponse::NotModified()
+            .insert_header((header::ETAG, etag))
+            .finish();
+    }
+
+    HttpResponse::Ok()
+        .content_type("application/x-ns-proxy-autoconfig")
+        .insert_header((header::ETAG, etag))
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .body(body)
+}
+
+// Host без порта; IPv6 адрес остаётся без скобок
+fn split_host(authority: &str) -> &str {
+    if let Some(rest) = authority.strip_prefix('[') {
+        return rest.split(']').next().unwrap_or(rest);
+    }
+    match authority.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') && port.parse::<u16>().is_ok() => host,
+        _ => authority,
+    }
+}
+
+// Применяет изменения правил и серверов к работающему прокси
+async fn reload_routing(engine: &ProxyEngine, db: &Database) {
+    if let Err(e) = engine.reload(db).await {
+        log::error!("Failed to reload routing: {:#}", e);
+    }
+}
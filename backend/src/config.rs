@@ -1,10 +1,234 @@
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
 use serde_yaml;
 use serde_json;
 use std::fs;
 use crate::models::*;
 
+pub const DEFAULT_CONFIG_PATH: &str = "data/config.yaml";
+// Порт, на котором запускается mixed listener, если в конфиге нет ни одного порта
+pub const DEFAULT_MIXED_PORT: u16 = 8081;
+
 pub struct ConfigManager;
 
+// Настройки движка, которые StealthCat читает из конфигурации в формате mihomo.
+// Остальные ключи конфига на работу прокси пока не влияют.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct ProxySettings {
+    // Режим при первом запуске; выбранный через API режим хранится в базе
+    pub mode: ProxyMode,
+    // Разрешать доменные цели в IP перед проверкой ip-cidr правил
+    pub resolve_ip_rules: bool,
+    pub port: Option<u16>,
+    pub socks_port: Option<u16>,
+    pub mixed_port: Option<u16>,
+    // Слушать на всех интерфейсах, а не только на 127.0.0.1
+    pub allow_lan: bool,
+    // Учётные записи "user:pass" для входящих HTTP и SOCKS5 соединений
+    pub authentication: Vec<String>,
+    // Таймаут TCP подключения к прокси-серверу и к цели напрямую, миллисекунды
+    pub connect_timeout: u64,
+    // UDP сессия без трафика закрывается через столько миллисекунд
+    pub udp_timeout: u64,
+    // Наибольшее число UDP сессий (целей) одной ассоциации SOCKS5
+    pub udp_max_sessions: usize,
+    pub health_check: HealthCheckSettings,
+    pub speed_test: SpeedTestSettings,
+    pub failover: FailoverSettings,
+    pub dns: DnsSettings,
+}
+
+impl Default for ProxySettings {
+    fn default() -> Self {
+        Self {
+            mode: ProxyMode::default(),
+            resolve_ip_rules: false,
+            port: None,
+            socks_port: None,
+            mixed_port: None,
+            allow_lan: false,
+            authentication: Vec::new(),
+            connect_timeout: 5000,
+            udp_timeout: 60000,
+            udp_max_sessions: 1024,
+            health_check: HealthCheckSettings::default(),
+            speed_test: SpeedTestSettings::default(),
+            failover: FailoverSettings::default(),
+            dns: DnsSettings::default(),
+        }
+    }
+}
+
+// Фоновая проверка серверов запросом к url через сам сервер
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct HealthCheckSettings {
+    pub enable: bool,
+    pub url: String,
+    // Интервал между проверками, секунды
+    pub interval: u64,
+    // Таймаут одной проверки, миллисекунды
+    pub timeout: u64,
+    // После стольких неудач подряд сервер считается недоступным
+    pub max_failures: u32,
+}
+
+impl Default for HealthCheckSettings {
+    fn default() -> Self {
+        Self {
+            enable: true,
+            url: "http://www.gstatic.com/generate_204".to_string(),
+            interval: 300,
+            timeout: 5000,
+            max_failures: 3,
+        }
+    }
+}
+
+// Замер скорости через сервер по запросу из API
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct SpeedTestSettings {
+    // GET: отдаёт не меньше download-bytes байт
+    pub download_url: String,
+    // POST: принимает тело размером upload-bytes
+    pub upload_url: String,
+    pub download_bytes: u64,
+    pub upload_bytes: u64,
+    // Таймаут каждого этапа, миллисекунды
+    pub timeout: u64,
+}
+
+impl Default for SpeedTestSettings {
+    fn default() -> Self {
+        Self {
+            download_url: "https://speed.cloudflare.com/__down?bytes=10000000".to_string(),
+            upload_url: "https://speed.cloudflare.com/__up".to_string(),
+            download_bytes: 10_000_000,
+            upload_bytes: 2_000_000,
+            timeout: 30_000,
+        }
+    }
+}
+
+// Автоматическая замена выбранного сервера, когда он перестаёт отвечать
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct FailoverSettings {
+    pub enable: bool,
+    // Имена или ID серверов в порядке предпочтения; пустой список - активные серверы
+    pub priority: Vec<String>,
+    // После стольких неудачных подключений подряд сервер заменяется
+    pub max_dial_failures: u32,
+    // Вернуться на выбранный сервер, когда проверка покажет, что он снова работает.
+    // Работает только с включённым health-check
+    pub restore: bool,
+}
+
+impl Default for FailoverSettings {
+    fn default() -> Self {
+        Self {
+            enable: true,
+            priority: Vec::new(),
+            max_dial_failures: 3,
+            restore: true,
+        }
+    }
+}
+
+// Встроенный DNS для целей соединений. Выключен - используется системный резолвер
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct DnsSettings {
+    pub enable: bool,
+    // Запрашивать AAAA записи
+    pub ipv6: bool,
+    // Серверы в формате 8.8.8.8, tcp://8.8.8.8, tls://1.1.1.1:853, https://1.1.1.1/dns-query;
+    // опрашиваются по порядку, пока один не ответит
+    pub nameserver: Vec<String>,
+    // Обычные DNS серверы по IP для имён DoT и DoH серверов
+    pub default_nameserver: Vec<String>,
+    // Домен -> серверы: "example.com", "+.example.com" (с поддоменами),
+    // "*.example.com" (один уровень), ".example.com" (только поддомены)
+    pub nameserver_policy: BTreeMap<String, Nameservers>,
+    // Сколько ответов хранится в кэше
+    pub cache_size: usize,
+    // Таймаут запроса к одному серверу, миллисекунды
+    pub timeout: u64,
+    // Адрес DNS сервера для клиентов, например 127.0.0.1:1053
+    pub listen: Option<String>,
+    pub enhanced_mode: EnhancedMode,
+    // Пул адресов, которые DNS сервер выдаёт в режиме fake-ip
+    pub fake_ip_range: String,
+    // Домены, которые и в режиме fake-ip получают настоящие адреса
+    pub fake_ip_filter: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EnhancedMode {
+    #[default]
+    Normal,
+    // Клиенты получают адреса из fake-ip-range, прокси восстанавливает по ним домен
+    FakeIp,
+}
+
+impl Default for DnsSettings {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            ipv6: false,
+            nameserver: vec!["1.1.1.1".to_string(), "8.8.8.8".to_string()],
+            default_nameserver: Vec::new(),
+            nameserver_policy: BTreeMap::new(),
+            cache_size: 4096,
+            timeout: 5000,
+            listen: None,
+            enhanced_mode: EnhancedMode::Normal,
+            fake_ip_range: "198.18.0.0/15".to_string(),
+            fake_ip_filter: Vec::new(),
+        }
+    }
+}
+
+/// Один сервер или список, как в nameserver-policy mihomo.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Nameservers {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Nameservers {
+    pub fn as_slice(&self) -> &[String] {
+        match self {
+            Nameservers::One(server) => std::slice::from_ref(server),
+            Nameservers::Many(servers) => servers,
+        }
+    }
+}
+
+impl ProxySettings {
+    pub fn listen_addr(&self, port: u16) -> std::net::SocketAddr {
+        let ip = if self.allow_lan {
+            std::net::Ipv4Addr::UNSPECIFIED
+        } else {
+            std::net::Ipv4Addr::LOCALHOST
+        };
+        (ip, port).into()
+    }
+
+    /// Порт mixed listener'а; если в конфиге нет ни одного порта - порт по умолчанию.
+    pub fn effective_mixed_port(&self) -> Option<u16> {
+        if self.port.is_none() && self.socks_port.is_none() && self.mixed_port.is_none() {
+            Some(DEFAULT_MIXED_PORT)
+        } else {
+            self.mixed_port
+        }
+    }
+}
+
 impl ConfigManager {
     pub fn load_from_file(path: &str) -> Result<MihomoConfig, Box<dyn std::error::Error>> {
         let content = fs::read_to_string(path)?;
@@ -31,6 +255,22 @@ impl ConfigManager {
         })
     }
 
+    pub fn parse_settings(config: &MihomoConfig) -> Result<ProxySettings, Box<dyn std::error::Error>> {
+        let settings = match config.format {
+            ConfigFormat::YAML => {
+                // Пустой YAML-документ разбирается как null
+                let value: serde_yaml::Value = serde_yaml::from_str(&config.raw_config)?;
+                if value.is_null() {
+                    ProxySettings::default()
+                } else {
+                    serde_yaml::from_value(value)?
+                }
+            }
+            ConfigFormat::JSON => serde_json::from_str(&config.raw_config)?,
+        };
+        Ok(settings)
+    }
+
     pub fn save_to_file(config: &MihomoConfig, path: &str) -> Result<(), Box<dyn std::error::Error>> {
         fs::write(path, &config.raw_config)?;
         Ok(())
@@ -38,11 +278,45 @@ impl ConfigManager {
 
     pub fn get_default_config() -> MihomoConfig {
         let default_yaml = r#"
-port: 7890
-socks-port: 7891
+mixed-port: 8081
 allow-lan: false
 mode: Rule
 log-level: info
+resolve-ip-rules: false
+authentication: []
+connect-timeout: 5000
+udp-timeout: 60000
+udp-max-sessions: 1024
+health-check:
+  enable: true
+  url: http://www.gstatic.com/generate_204
+  interval: 300
+  timeout: 5000
+  max-failures: 3
+speed-test:
+  download-url: https://speed.cloudflare.com/__down?bytes=10000000
+  upload-url: https://speed.cloudflare.com/__up
+  download-bytes: 10000000
+  upload-bytes: 2000000
+  timeout: 30000
+failover:
+  enable: true
+  priority: []
+  max-dial-failures: 3
+  restore: true
+dns:
+  enable: false
+  ipv6: false
+  nameserver:
+    - 1.1.1.1
+    - 8.8.8.8
+  default-nameserver: []
+  nameserver-policy: {}
+  cache-size: 4096
+  timeout: 5000
+  enhanced-mode: normal
+  fake-ip-range: 198.18.0.0/15
+  fake-ip-filter: []
 external-controller: 127.0.0.1:9090
 
 proxies:
@@ -6,6 +6,8 @@ use anyhow::Result;
 // use chrono::NaiveDateTime;
 use crate::models::{LogEntry, ProxyServer, Rule, LogLevel};
 use crate::models::{Subscription, ProxyServerV2};
+use crate::models::{LoadBalanceStrategy, ProxyGroup, ProxyGroupType, ProxyMode};
+use crate::models::{FakeIpMapping, ServerHealth};
 
 pub struct Database {
     pool: Pool<Sqlite>,
@@ -97,7 +99,7 @@ impl Database {
         .bind(&server.protocol) // используем protocol как server_type
         .bind(if server.active { "active" } else { "inactive" }) // статус на основе active
         .bind(server.latency_ms.map(|l| l as i32))
-        .bind(&server.last_ping)
+        .bind(server.last_ping)
         .execute(&self.pool)
         .await?;
         
@@ -130,15 +132,16 @@ impl Database {
     pub async fn insert_rule(&self, rule: &Rule) -> Result<()> {
         sqlx::query(
             r#"
-            INSERT INTO rules (id, rule_type, pattern, action, priority, enabled)
-            VALUES (?, ?, ?, ?, ?, ?)
+            INSERT INTO rules (id, name, rule_type, pattern, action, priority, enabled)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
             "#,
         )
-        .bind(rule.id.to_string()) // конвертируем u32 в String
+        .bind(&rule.id)
+        .bind(&rule.name)
         .bind(&rule.rule_type)
         .bind(&rule.pattern)
         .bind(&rule.action)
-        .bind(0) // приоритет по умолчанию
+        .bind(rule.priority)
         .bind(rule.enabled)
         .execute(&self.pool)
         .await?;
@@ -185,7 +188,7 @@ impl Database {
         .bind(&subscription.name)
         .bind(&subscription.url)
         .bind(subscription.update_interval as i32)
-        .bind(&subscription.last_update)
+        .bind(subscription.last_update)
         .bind(subscription.servers_count as i32)
         .bind(subscription.active)
         .bind(&subscription.user_agent)
@@ -250,7 +253,7 @@ impl Database {
         .bind(&subscription.name)
         .bind(&subscription.url)
         .bind(subscription.update_interval as i32)
-        .bind(&subscription.last_update)
+        .bind(subscription.last_update)
         .bind(subscription.servers_count as i32)
         .bind(subscription.active)
         .bind(&subscription.user_agent)
@@ -272,46 +275,7 @@ impl Database {
     
     // Методы для работы с ProxyServerV2
     pub async fn insert_server_v2(&self, server: &ProxyServerV2) -> Result<()> {
-        // Создаем базовую конфигурацию на основе протокола
-        // В функции update_server (строки 464-468) уже исправлены
-        
-        // Нужно исправить в другом месте (строки 277-300):
-        let config = match server.protocol {
-            crate::models::ProxyProtocol::HTTP => crate::models::ProxyConfig::Http,
-            crate::models::ProxyProtocol::HTTPS => crate::models::ProxyConfig::Https,
-            crate::models::ProxyProtocol::SOCKS5 => crate::models::ProxyConfig::Socks5,
-            crate::models::ProxyProtocol::Shadowsocks => crate::models::ProxyConfig::Shadowsocks {
-                method: "aes-256-gcm".to_string(),
-                password: "default".to_string(),
-            },
-            crate::models::ProxyProtocol::Trojan => crate::models::ProxyConfig::Trojan {
-                password: "default".to_string(),
-            },
-            crate::models::ProxyProtocol::VLESS => crate::models::ProxyConfig::Vless(crate::models::VlessConfig {
-                uuid: "default-uuid".to_string(),
-                flow: None,
-                encryption: "none".to_string(),
-                network: "tcp".to_string(),
-                security: "tls".to_string(),
-                sni: None,
-                alpn: None,
-                fp: None,
-                pbk: None,
-                sid: None,
-                spx: None,
-            }),
-            crate::models::ProxyProtocol::VMess => crate::models::ProxyConfig::Vmess(crate::models::VmessConfig {
-                uuid: "default-uuid".to_string(),
-                alter_id: 0,
-                security: "auto".to_string(),
-                network: "tcp".to_string(),
-                tls: false,
-                sni: None,
-                alpn: None,
-            }),
-        };
-        
-        let config_json = serde_json::to_string(&config)?;
+        let config_json = serde_json::to_string(&server.config)?;
         
         sqlx::query(
             r#"
@@ -347,7 +311,7 @@ impl Database {
         .bind(&subscription.name)
         .bind(&subscription.url)
         .bind(subscription.update_interval as i32)
-        .bind(&subscription.last_update)
+        .bind(subscription.last_update)
         .bind(subscription.servers_count as i32)
         .bind(subscription.active)
         .bind(&subscription.user_agent)
@@ -404,6 +368,7 @@ impl Database {
                 "VMess" => crate::models::ProxyProtocol::VMess,
                 "Trojan" => crate::models::ProxyProtocol::Trojan,
                 "Shadowsocks" => crate::models::ProxyProtocol::Shadowsocks,
+                "Chain" => crate::models::ProxyProtocol::Chain,
                 _ => continue, // Пропускаем неизвестные протоколы
             };
             
@@ -425,6 +390,8 @@ impl Database {
                 upload_speed: row.get::<Option<i64>, _>("upload_speed").map(|s| s as u64),
                 download_speed: row.get::<Option<i64>, _>("download_speed").map(|s| s as u64),
                 subscription_id: row.get("subscription_id"),
+                failure_streak: row.get::<i64, _>("failure_streak") as u32,
+                up: row.get("up"),
             };
             
             servers.push(server);
@@ -471,6 +438,7 @@ impl Database {
             crate::models::ProxyProtocol::VMess => "VMess",      // ✅ Исправлено: Vmess -> VMess
             crate::models::ProxyProtocol::Trojan => "Trojan",
             crate::models::ProxyProtocol::Shadowsocks => "Shadowsocks",
+            crate::models::ProxyProtocol::Chain => "Chain",
         };
         
         sqlx::query(
@@ -502,4 +470,180 @@ impl Database {
         
         Ok(())
     }
-}
\ No newline at end of file
+
+    // Методы для работы с группами прокси
+    pub async fn insert_group(&self, group: &ProxyGroup) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO proxy_groups (id, name, group_type, proxies, selected, tolerance, strategy, created_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, CURRENT_TIMESTAMP)
+            "#,
+        )
+        .bind(&group.id)
+        .bind(&group.name)
+        .bind(group.group_type.as_str())
+        .bind(serde_json::to_string(&group.proxies)?)
+        .bind(&group.selected)
+        .bind(group.tolerance as i64)
+        .bind(group.strategy.as_str())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_groups(&self) -> Result<Vec<ProxyGroup>> {
+        let rows = sqlx::query("SELECT * FROM proxy_groups ORDER BY created_at, name")
+            .fetch_all(&self.pool)
+            .await?;
+
+        let mut groups = Vec::new();
+        for row in rows {
+            let group_type = match row.get::<String, _>("group_type").as_str() {
+                "select" => ProxyGroupType::Select,
+                "url-test" => ProxyGroupType::UrlTest,
+                "fallback" => ProxyGroupType::Fallback,
+                "load-balance" => ProxyGroupType::LoadBalance,
+                _ => continue, // Пропускаем неизвестные типы
+            };
+            let strategy = match row.get::<String, _>("strategy").as_str() {
+                "round-robin" => LoadBalanceStrategy::RoundRobin,
+                _ => LoadBalanceStrategy::ConsistentHashing,
+            };
+            let proxies_json: String = row.get("proxies");
+
+            groups.push(ProxyGroup {
+                id: row.get("id"),
+                name: row.get("name"),
+                group_type,
+                proxies: serde_json::from_str(&proxies_json)?,
+                selected: row.get("selected"),
+                tolerance: row.get::<i64, _>("tolerance") as u32,
+                strategy,
+            });
+        }
+
+        Ok(groups)
+    }
+
+    pub async fn get_group(&self, id: &str) -> Result<Option<ProxyGroup>> {
+        Ok(self.get_groups().await?.into_iter().find(|g| g.id == id))
+    }
+
+    pub async fn update_group(&self, group: &ProxyGroup) -> Result<()> {
+        sqlx::query(
+            "UPDATE proxy_groups SET name = ?, group_type = ?, proxies = ?, selected = ?, tolerance = ?, strategy = ? WHERE id = ?"
+        )
+        .bind(&group.name)
+        .bind(group.group_type.as_str())
+        .bind(serde_json::to_string(&group.proxies)?)
+        .bind(&group.selected)
+        .bind(group.tolerance as i64)
+        .bind(group.strategy.as_str())
+        .bind(&group.id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn delete_group(&self, group_id: &str) -> Result<()> {
+        sqlx::query("DELETE FROM proxy_groups WHERE id = ?")
+            .bind(group_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    // Результат проверки сервера; latency NULL, если проверка не прошла
+    pub async fn update_server_health(&self, server_id: &str, health: &ServerHealth) -> Result<()> {
+        sqlx::query("UPDATE servers_v2 SET latency = ?, last_check = ?, failure_streak = ?, up = ? WHERE id = ?")
+            .bind(health.latency_ms.map(|l| l as i32))
+            .bind(health.last_check)
+            .bind(health.failure_streak as i64)
+            .bind(health.up)
+            .bind(server_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_proxy_mode(&self) -> Result<Option<ProxyMode>> {
+        let row = sqlx::query("SELECT value FROM settings WHERE key = 'mode'")
+            .fetch_optional(&self.pool)
+            .await?;
+
+        let Some(row) = row else { return Ok(None) };
+        let value: String = row.get("value");
+        match serde_json::from_value(serde_json::Value::String(value.clone())) {
+            Ok(mode) => Ok(Some(mode)),
+            Err(_) => {
+                log::warn!("Ignoring unknown proxy mode {} in settings", value);
+                Ok(None)
+            }
+        }
+    }
+
+    pub async fn set_proxy_mode(&self, mode: ProxyMode) -> Result<()> {
+        sqlx::query("INSERT INTO settings (key, value) VALUES ('mode', ?) ON CONFLICT(key) DO UPDATE SET value = excluded.value")
+            .bind(mode.as_str())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_fake_ips(&self) -> Result<Vec<FakeIpMapping>> {
+        let rows = sqlx::query("SELECT ip, domain, last_used FROM fake_ips")
+            .fetch_all(&self.pool)
+            .await?;
+
+        let mut mappings = Vec::new();
+        for row in rows {
+            let ip: String = row.get("ip");
+            let Ok(ip) = ip.parse() else {
+                log::warn!("Ignoring invalid fake IP {} in database", ip);
+                continue;
+            };
+            let last_used: i64 = row.get("last_used");
+            mappings.push(FakeIpMapping { ip, domain: row.get("domain"), last_used: last_used as u64 });
+        }
+        Ok(mappings)
+    }
+
+    // Адрес, перешедший другому домену, перезаписывает прежнюю строку
+    pub async fn save_fake_ips(&self, mappings: &[FakeIpMapping]) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        for mapping in mappings {
+            sqlx::query(
+                r#"
+                INSERT INTO fake_ips (ip, domain, last_used) VALUES (?, ?, ?)
+                ON CONFLICT(ip) DO UPDATE SET domain = excluded.domain, last_used = excluded.last_used
+                "#
+            )
+            .bind(mapping.ip.to_string())
+            .bind(&mapping.domain)
+            .bind(mapping.last_used as i64)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    pub async fn update_server_speed(&self, server_id: &str, latency_ms: u32, download_speed: u64, upload_speed: u64) -> Result<()> {
+        sqlx::query("UPDATE servers_v2 SET latency = ?, download_speed = ?, upload_speed = ?, last_check = ? WHERE id = ?")
+            .bind(latency_ms as i32)
+            .bind(download_speed as i64)
+            .bind(upload_speed as i64)
+            .bind(chrono::Utc::now())
+            .bind(server_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,9 @@
+pub mod api;
+pub mod config;
+pub mod models;
+pub mod pac;
+pub mod proxy;
+pub mod rules;
+pub mod websocket;
+pub mod database;
+pub mod subscription;
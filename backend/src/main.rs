@@ -1,17 +1,11 @@
-mod api;
-mod config;
-mod models;
-mod proxy;
-mod websocket;
-mod database;
-mod subscription; // Добавить эту строку
-
+use stealthcat_backend::{api, proxy, websocket};
 use actix_web::{web, App, HttpServer, middleware::Logger};
 use actix_cors::Cors;
 use std::sync::Arc;
 use tokio::sync::RwLock; // Изменено с Mutex на RwLock
-use crate::models::AppState;
-use crate::database::Database;
+use stealthcat_backend::config::{ConfigManager, DEFAULT_CONFIG_PATH};
+use stealthcat_backend::models::AppState;
+use stealthcat_backend::database::Database;
 use anyhow::Result;
 
 #[actix_web::main]
@@ -22,21 +16,91 @@ async fn main() -> Result<()> {
     let database = Database::new("sqlite:data/stealthcat.db").await?;
     let db = Arc::new(database);
     
+    // Загрузка конфигурации, при отсутствии файла используется конфиг по умолчанию
+    let config = ConfigManager::load_from_file(DEFAULT_CONFIG_PATH).unwrap_or_else(|e| {
+        log::info!("Using default config ({}: {})", DEFAULT_CONFIG_PATH, e);
+        ConfigManager::get_default_config()
+    });
+
     // Создание состояния приложения
-    let app_state = Arc::new(RwLock::new(AppState::new())); // Изменено с Mutex на RwLock
+    let mut state = AppState::new();
+    state.config = config.clone();
+    let app_state = Arc::new(RwLock::new(state)); // Изменено с Mutex на RwLock
     
     log::info!("🐱 Starting StealthCat backend server...");
     
     // Запуск прокси-сервера в отдельной задаче
-    let proxy_engine = proxy::ProxyEngine::new();
-    let proxy_addr = "127.0.0.1:8081".parse().unwrap();
-    
-    tokio::spawn(async move {
-        if let Err(e) = proxy_engine.start_proxy_server(proxy_addr).await {
-            log::error!("Proxy server error: {}", e);
+    let rules = db.get_rules().await?;
+    let servers = db.get_servers_v2().await?;
+    let groups = db.get_groups().await?;
+    log::info!(
+        "Loaded {} rules, {} servers and {} groups for routing",
+        rules.len(), servers.len(), groups.len()
+    );
+    let proxy_engine = Arc::new(proxy::ProxyEngine::new(config, rules, servers, groups));
+    // Режим, выбранный через API, важнее режима из конфига
+    if let Some(mode) = db.get_proxy_mode().await? {
+        proxy_engine.set_mode(mode);
+    }
+    app_state.write().await.proxy_status.mode = proxy_engine.mode();
+
+    let settings = proxy_engine.settings.clone();
+    if let Some(port) = settings.port {
+        let addr = settings.listen_addr(port);
+        let engine = proxy_engine.clone();
+        tokio::spawn(async move {
+            if let Err(e) = engine.start_proxy_server(addr).await {
+                log::error!("Proxy server error: {}", e);
+            }
+        });
+    }
+
+    if let Some(socks_port) = settings.socks_port {
+        let addr = settings.listen_addr(socks_port);
+        let engine = proxy_engine.clone();
+        tokio::spawn(async move {
+            if let Err(e) = engine.start_socks_server(addr).await {
+                log::error!("SOCKS server error: {}", e);
+            }
+        });
+    }
+
+    if let Some(mixed_port) = settings.effective_mixed_port() {
+        let addr = settings.listen_addr(mixed_port);
+        let engine = proxy_engine.clone();
+        tokio::spawn(async move {
+            if let Err(e) = engine.start_mixed_server(addr).await {
+                log::error!("Mixed proxy server error: {}", e);
+            }
+        });
+    }
+
+    if settings.health_check.enable {
+        let checker = proxy::health::HealthChecker::new(proxy_engine.clone(), db.clone());
+        tokio::spawn(checker.run());
+    }
+
+    if let Some(pool) = proxy_engine.router().dns().fake_ips() {
+        let restored = pool.restore(db.get_fake_ips().await?);
+        log::info!("Restored {} fake IP mappings", restored);
+    }
+    if let Some(listen) = &settings.dns.listen {
+        match listen.parse::<std::net::SocketAddr>() {
+            Ok(addr) => {
+                let engine = proxy_engine.clone();
+                let db = db.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = engine.start_dns_server(addr, db).await {
+                        log::error!("DNS server error: {}", e);
+                    }
+                });
+            }
+            Err(_) => log::error!("Invalid dns.listen address {}", listen),
         }
-    });
-    
+    }
+
+    tokio::spawn(proxy::failover::record_events(proxy_engine.failover_events(), db.clone()));
+
     // Запуск HTTP API сервера
     HttpServer::new(move || {
         let cors = Cors::default()
@@ -47,6 +111,7 @@ async fn main() -> Result<()> {
         App::new()
             .app_data(web::Data::new(app_state.clone()))
             .app_data(web::Data::new(db.clone()))
+            .app_data(web::Data::new(proxy_engine.clone()))
             .wrap(cors)
             .wrap(Logger::default())
             .service(
@@ -80,8 +145,16 @@ async fn main() -> Result<()> {
                     .route("/servers-v2/{id}", web::delete().to(api::delete_server)) // ← ДОБАВИТЬ
                     .route("/servers-v2/{id}/test", web::post().to(api::test_server_speed))
                     .route("/select-server", web::post().to(api::select_server))
+                    .route("/mode", web::post().to(api::set_mode))
+                    // Группы прокси
+                    .route("/groups", web::get().to(api::get_groups))
+                    .route("/groups", web::post().to(api::create_group))
+                    .route("/groups/{id}", web::put().to(api::update_group))
+                    .route("/groups/{id}", web::delete().to(api::delete_group))
+                    .route("/groups/{id}/select", web::post().to(api::select_group_proxy))
             )
             .route("/ws", web::get().to(websocket::websocket_handler))
+            .route("/proxy.pac", web::get().to(api::get_pac))
     })
     .bind("127.0.0.1:8080")?
     .run()
@@ -1,3 +1,4 @@
+use std::net::IpAddr;
 use serde::{Deserialize, Serialize};
 use chrono::{DateTime, Utc};
 
@@ -59,7 +60,7 @@ impl AppState {
                 Rule {
                     id: "1".to_string(),  // Изменяем на String
                     name: "Блокировка рекламы".to_string(),
-                    rule_type: "domain".to_string(),
+                    rule_type: "domain-wildcard".to_string(),
                     pattern: "*.ads.google.com".to_string(),
                     action: "block".to_string(),
                     priority: 1,  // Добавляем priority
@@ -68,7 +69,7 @@ impl AppState {
                 Rule {
                     id: "2".to_string(),  // Изменяем на String
                     name: "Прямое соединение для локальных сайтов".to_string(),
-                    rule_type: "domain".to_string(),
+                    rule_type: "domain-wildcard".to_string(),
                     pattern: "*.local".to_string(),
                     action: "direct".to_string(),
                     priority: 2,  // Добавляем priority
@@ -87,6 +88,12 @@ impl AppState {
     }
 }
 
+impl Default for AppState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ProxyStatus {
     pub connected: bool,
@@ -95,13 +102,32 @@ pub struct ProxyStatus {
     pub last_error: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+// Режим маршрутизации; в конфиге mihomo те же режимы называются global, rule и direct
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum ProxyMode {
+    // Всё через выбранный сервер
+    #[serde(alias = "global")]
     Global,
+    // Маршрут по правилам
+    #[default]
+    #[serde(alias = "Rule", alias = "rule", alias = "pac")]
     PAC,
+    // Всё напрямую
+    #[serde(alias = "Direct", alias = "direct", alias = "bypass")]
     Bypass,
 }
 
+impl ProxyMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProxyMode::Global => "Global",
+            ProxyMode::PAC => "PAC",
+            ProxyMode::Bypass => "Bypass",
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ProxyServer {
     pub id: String,
@@ -121,6 +147,9 @@ pub struct UpdateProxyServer {
     pub hostname: String,
     pub port: u16,
     pub protocol: String,
+    // Параметры протокола; без них сервер создаётся с конфигурацией по умолчанию
+    #[serde(default)]
+    pub config: Option<ProxyConfig>,
     pub latency_ms: Option<u32>,
     pub last_ping: Option<DateTime<Utc>>,
     pub active: bool,
@@ -143,6 +172,7 @@ pub struct LogEntry {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum LogLevel {
     INFO,
     WARN,
@@ -158,6 +188,7 @@ pub struct MihomoConfig {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum ConfigFormat {
     YAML,
     JSON,
@@ -183,12 +214,13 @@ pub struct Rule {
     pub rule_type: String,
     pub pattern: String,
     pub action: String,
-    pub priority: i32,
+    pub priority: i32,     // большее значение проверяется раньше
     pub enabled: bool,
 }
 
 // Новые типы протоколов
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum ProxyProtocol {
     HTTP,
     HTTPS,
@@ -197,6 +229,8 @@ pub enum ProxyProtocol {
     VMess,
     Trojan,
     Shadowsocks,
+    // Цепочка из других серверов servers_v2
+    Chain,
 }
 
 // Конфигурация для VLESS
@@ -213,6 +247,10 @@ pub struct VlessConfig {
     pub pbk: Option<String>,  // public key for reality
     pub sid: Option<String>,  // short id for reality
     pub spx: Option<String>,  // spider x for reality
+    #[serde(default)]
+    pub ws: Option<WsConfig>, // network: ws
+    #[serde(default)]
+    pub grpc: Option<GrpcConfig>, // network: grpc
 }
 
 // Конфигурация для VMess
@@ -225,6 +263,38 @@ pub struct VmessConfig {
     pub tls: bool,
     pub sni: Option<String>,
     pub alpn: Option<Vec<String>>,
+    #[serde(default)]
+    pub skip_cert_verify: bool,
+    #[serde(default)]
+    pub ws: Option<WsConfig>, // network: ws
+    #[serde(default)]
+    pub grpc: Option<GrpcConfig>, // network: grpc
+}
+
+// Параметры WebSocket транспорта
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WsConfig {
+    #[serde(default)]
+    pub path: String,
+    // Заголовок Host; по умолчанию - адрес сервера
+    #[serde(default)]
+    pub host: Option<String>,
+    // ed: сколько первых байт отправить в заголовке рукопожатия
+    #[serde(default)]
+    pub max_early_data: usize,
+    // Заголовок для early data; по умолчанию Sec-WebSocket-Protocol
+    #[serde(default)]
+    pub early_data_header: Option<String>,
+}
+
+// Параметры gRPC (gun) транспорта
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GrpcConfig {
+    #[serde(default)]
+    pub service_name: String,
+    // gun (по умолчанию) или multi
+    #[serde(default)]
+    pub mode: String,
 }
 
 // Расширенная структура прокси-сервера
@@ -244,17 +314,152 @@ pub struct ProxyServerV2 {
     pub upload_speed: Option<u64>,
     pub download_speed: Option<u64>,
     pub subscription_id: Option<String>,
+    // Неудачные проверки подряд и состояние по последней проверке
+    #[serde(default)]
+    pub failure_streak: u32,
+    #[serde(default)]
+    pub up: Option<bool>,
+}
+
+/// Состояние сервера по результатам проверок.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ServerHealth {
+    pub latency_ms: Option<u32>,
+    pub last_check: Option<DateTime<Utc>>,
+    pub failure_streak: u32,
+    // None - сервер ещё не проверялся
+    pub up: Option<bool>,
+}
+
+impl ServerHealth {
+    pub fn from_server(server: &ProxyServerV2) -> Self {
+        Self {
+            latency_ms: server.latency_ms,
+            last_check: server.last_ping,
+            failure_streak: server.failure_streak,
+            up: server.up,
+        }
+    }
+
+    /// Непроверенный сервер считается доступным.
+    pub fn is_alive(&self) -> bool {
+        self.up.unwrap_or(true)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ProxyConfig {
-    Http,
-    Https,
-    Socks5,
+    // Вышестоящий HTTP прокси (CONNECT), логин и пароль для Basic auth
+    Http {
+        #[serde(default)]
+        username: Option<String>,
+        #[serde(default)]
+        password: Option<String>,
+    },
+    // То же, но соединение с прокси идёт по TLS
+    Https {
+        #[serde(default)]
+        username: Option<String>,
+        #[serde(default)]
+        password: Option<String>,
+        #[serde(default)]
+        sni: Option<String>,
+        #[serde(default)]
+        skip_cert_verify: bool,
+        #[serde(default)]
+        ca: Option<String>,
+    },
+    Socks5 {
+        #[serde(default)]
+        username: Option<String>,
+        #[serde(default)]
+        password: Option<String>,
+    },
     Vless(VlessConfig),
     Vmess(VmessConfig),
-    Trojan { password: String },
+    Trojan {
+        password: String,
+        #[serde(default)]
+        sni: Option<String>,
+        #[serde(default)]
+        alpn: Option<Vec<String>>,
+        // Не проверять сертификат сервера (самоподписанные узлы)
+        #[serde(default)]
+        skip_cert_verify: bool,
+        // Путь к PEM файлу дополнительного корневого сертификата
+        #[serde(default)]
+        ca: Option<String>,
+        // tcp, ws или grpc; TLS используется всегда
+        #[serde(default)]
+        network: Option<String>,
+        #[serde(default)]
+        ws: Option<WsConfig>,
+        #[serde(default)]
+        grpc: Option<GrpcConfig>,
+    },
     Shadowsocks { method: String, password: String },
+    // ID серверов в порядке прохождения: первый подключается напрямую,
+    // каждый следующий - через предыдущий
+    Chain { hops: Vec<String> },
+}
+
+impl ProxyConfig {
+    /// Конфигурация-заглушка для сервера, созданного без параметров протокола.
+    pub fn default_for(protocol: &ProxyProtocol) -> Self {
+        match protocol {
+            ProxyProtocol::HTTP => ProxyConfig::Http { username: None, password: None },
+            ProxyProtocol::HTTPS => ProxyConfig::Https {
+                username: None,
+                password: None,
+                sni: None,
+                skip_cert_verify: false,
+                ca: None,
+            },
+            ProxyProtocol::SOCKS5 => ProxyConfig::Socks5 { username: None, password: None },
+            ProxyProtocol::Chain => ProxyConfig::Chain { hops: Vec::new() },
+            ProxyProtocol::Shadowsocks => ProxyConfig::Shadowsocks {
+                method: "aes-256-gcm".to_string(),
+                password: "default".to_string(),
+            },
+            ProxyProtocol::Trojan => ProxyConfig::Trojan {
+                password: "default".to_string(),
+                sni: None,
+                alpn: None,
+                skip_cert_verify: false,
+                ca: None,
+                network: None,
+                ws: None,
+                grpc: None,
+            },
+            ProxyProtocol::VLESS => ProxyConfig::Vless(VlessConfig {
+                uuid: "default-uuid".to_string(),
+                flow: None,
+                encryption: "none".to_string(),
+                network: "tcp".to_string(),
+                security: "tls".to_string(),
+                sni: None,
+                alpn: None,
+                fp: None,
+                pbk: None,
+                sid: None,
+                spx: None,
+                ws: None,
+                grpc: None,
+            }),
+            ProxyProtocol::VMess => ProxyConfig::Vmess(VmessConfig {
+                uuid: "default-uuid".to_string(),
+                alter_id: 0,
+                security: "auto".to_string(),
+                network: "tcp".to_string(),
+                tls: false,
+                sni: None,
+                alpn: None,
+                skip_cert_verify: false,
+                ws: None,
+                grpc: None,
+            }),
+        }
+    }
 }
 
 // Структура подписки
@@ -287,9 +492,97 @@ pub struct UpdateProxyServerV2 {
     pub subscription_id: Option<String>,
 }
 
+// Параметры замера скорости; не заданные берутся из speed-test в конфиге
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SpeedTestRequest {
+    pub download_url: Option<String>,
+    pub upload_url: Option<String>,
+    pub download_bytes: Option<u64>,
+    pub upload_bytes: Option<u64>,
+    pub timeout: Option<u64>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ImportSubscriptionRequest {
     pub url: String,
     pub name: Option<String>,
     pub update_interval: Option<u32>, // в часах
-}
\ No newline at end of file
+}
+
+// Тип группы прокси, как в proxy-groups у mihomo
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProxyGroupType {
+    // Участник выбирается вручную
+    Select,
+    // Наименьшая задержка с учётом tolerance
+    UrlTest,
+    // Первый работающий участник
+    Fallback,
+    LoadBalance,
+}
+
+impl ProxyGroupType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProxyGroupType::Select => "select",
+            ProxyGroupType::UrlTest => "url-test",
+            ProxyGroupType::Fallback => "fallback",
+            ProxyGroupType::LoadBalance => "load-balance",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LoadBalanceStrategy {
+    // Одно и то же назначение всегда идёт через одного участника
+    #[default]
+    ConsistentHashing,
+    RoundRobin,
+}
+
+impl LoadBalanceStrategy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LoadBalanceStrategy::ConsistentHashing => "consistent-hashing",
+            LoadBalanceStrategy::RoundRobin => "round-robin",
+        }
+    }
+}
+
+// Группа прокси; правила ссылаются на неё по имени
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProxyGroup {
+    pub id: String,
+    pub name: String,
+    pub group_type: ProxyGroupType,
+    // Имена или ID серверов из servers_v2, а также DIRECT и REJECT
+    pub proxies: Vec<String>,
+    // select: выбранный участник; по умолчанию первый
+    pub selected: Option<String>,
+    // url-test: на сколько миллисекунд новый участник должен быть быстрее текущего
+    pub tolerance: u32,
+    pub strategy: LoadBalanceStrategy,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateProxyGroup {
+    pub name: String,
+    pub group_type: ProxyGroupType,
+    pub proxies: Vec<String>,
+    #[serde(default)]
+    pub selected: Option<String>,
+    #[serde(default)]
+    pub tolerance: u32,
+    #[serde(default)]
+    pub strategy: LoadBalanceStrategy,
+}
+
+/// Выданный домену fake-IP. `last_used` - логические часы LRU.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FakeIpMapping {
+    pub ip: IpAddr,
+    pub domain: String,
+    pub last_used: u64,
+}
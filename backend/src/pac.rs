@@ -0,0 +1,115 @@
+use std::fmt::Write;
+use ipnet::IpNet;
+use sha2::{Digest, Sha256};
+use crate::config::ProxySettings;
+use crate::models::ProxyMode;
+use crate::rules::{CompiledRule, RuleMatcher};
+
+/// Генерирует PAC файл по включённым правилам. Трафик, который правила не
+/// отправляют напрямую, идёт на `proxy` - там его маршрутизирует сам движок,
+/// поэтому действия block, proxy и группы в PAC одинаковы.
+/// На первом правиле, которое в PAC не выразить (regex, dst-port, IPv6 сети),
+/// файл заканчивается: правило могло совпасть, поэтому всё остальное
+/// отправляется на `proxy`, и порядок правил решает движок.
+pub fn generate(rules: &[CompiledRule], mode: ProxyMode, proxy: &str, resolve_ip_rules: bool) -> String {
+    let proxy = js_string(proxy);
+    let mut pac = String::from("function FindProxyForURL(url, host) {\n");
+    match mode {
+        ProxyMode::Global => {
+            let _ = writeln!(pac, "  return {};", proxy);
+        }
+        ProxyMode::Bypass => pac.push_str("  return \"DIRECT\";\n"),
+        ProxyMode::PAC => {
+            pac.push_str("  host = host.toLowerCase();\n");
+            pac.push_str("  var ip;\n");
+            pac.push_str("  function hostIp() {\n");
+            pac.push_str("    if (ip === undefined) {\n");
+            if resolve_ip_rules {
+                pac.push_str("      ip = isPlainIp(host) ? host : dnsResolve(host);\n");
+            } else {
+                pac.push_str("      ip = isPlainIp(host) ? host : null;\n");
+            }
+            pac.push_str("    }\n");
+            pac.push_str("    return ip;\n");
+            pac.push_str("  }\n");
+            pac.push_str("  function isPlainIp(value) {\n");
+            pac.push_str("    return /^\\d+\\.\\d+\\.\\d+\\.\\d+$/.test(value);\n");
+            pac.push_str("  }\n");
+
+            let mut fallback = "\"DIRECT\"".to_string();
+            for compiled in rules.iter().filter(|c| c.rule.enabled) {
+                let name = compiled.rule.name.replace(['\r', '\n'], " ");
+                let Some(condition) = condition(compiled) else {
+                    let _ = writeln!(pac, "  // {} (not expressible in PAC)", name);
+                    fallback = proxy.clone();
+                    break;
+                };
+                let target = if compiled.rule.action.eq_ignore_ascii_case("direct") {
+                    "\"DIRECT\"".to_string()
+                } else {
+                    proxy.clone()
+                };
+                let _ = writeln!(pac, "  // {}", name);
+                let _ = writeln!(pac, "  if ({}) return {};", condition, target);
+            }
+            let _ = writeln!(pac, "  return {};", fallback);
+        }
+    }
+    pac.push_str("}\n");
+    pac
+}
+
+/// Адреса запущенных listener'ов в формате PAC. `host` - адрес, по которому
+/// клиент обращается к StealthCat.
+pub fn proxy_directive(settings: &ProxySettings, host: &str) -> String {
+    // IPv6 адрес в PAC пишется в скобках, иначе порт сливается с адресом
+    let host = match host.trim_start_matches('[').trim_end_matches(']') {
+        ip if ip.contains(':') => format!("[{}]", ip),
+        name => name.to_string(),
+    };
+    let mut directives = Vec::new();
+    if let Some(port) = settings.effective_mixed_port() {
+        directives.push(format!("PROXY {}:{}", host, port));
+    }
+    if let Some(port) = settings.port {
+        directives.push(format!("PROXY {}:{}", host, port));
+    }
+    if let Some(port) = settings.socks_port.or(settings.effective_mixed_port()) {
+        directives.push(format!("SOCKS5 {}:{}", host, port));
+    }
+    directives.join("; ")
+}
+
+/// ETag содержимого PAC файла.
+pub fn etag(pac: &str) -> String {
+    let digest = Sha256::digest(pac.as_bytes());
+    let hex: String = digest[..16].iter().map(|b| format!("{:02x}", b)).collect();
+    format!("\"{}\"", hex)
+}
+
+fn condition(compiled: &CompiledRule) -> Option<String> {
+    match &compiled.matcher {
+        RuleMatcher::Domain(domain) => Some(format!("host == {}", js_string(domain))),
+        RuleMatcher::DomainSuffix(suffix) => Some(format!(
+            "host == {} || dnsDomainIs(host, {})",
+            js_string(suffix), js_string(&format!(".{}", suffix))
+        )),
+        RuleMatcher::DomainKeyword(keyword) => Some(format!("host.indexOf({}) >= 0", js_string(keyword))),
+        // Glob из правила совпадает с синтаксисом shExpMatch
+        RuleMatcher::DomainWildcard(_) => Some(format!(
+            "shExpMatch(host, {})",
+            js_string(&crate::rules::normalize_domain(compiled.rule.pattern.trim()))
+        )),
+        // isInNet работает только с IPv4
+        RuleMatcher::IpCidr(IpNet::V4(net)) => Some(format!(
+            "hostIp() && isInNet(hostIp(), {}, {})",
+            js_string(&net.network().to_string()), js_string(&net.netmask().to_string())
+        )),
+        RuleMatcher::IpCidr(IpNet::V6(_)) | RuleMatcher::DomainRegex(_) | RuleMatcher::DstPort(_) => None,
+    }
+}
+
+// Строка JSON - корректный строковый литерал JavaScript
+fn js_string(value: &str) -> String {
+    serde_json::to_string(value).expect("string serialization cannot fail")
+}
@@ -1,39 +1,147 @@
-use std::net::SocketAddr;
+mod auth;
+pub mod chain;
+pub mod dns;
+pub mod failover;
+pub mod group;
+pub mod health;
+pub mod speed_test;
+mod http;
+mod outbound;
+mod socks;
+mod udp;
+
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use arc_swap::ArcSwap;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncWriteExt, BufReader};
 use crate::models::*;
+use crate::config::{ConfigManager, ProxySettings};
+use crate::database::Database;
+use crate::rules::{self, RuleIndex};
 use anyhow::Result;
 
+pub use outbound::ProxyStream;
+
 pub struct ProxyEngine {
     pub config: MihomoConfig,
-    pub servers: Vec<ProxyServer>,
-    pub rules: Vec<Rule>,
+    pub settings: ProxySettings,
+    // Снимок правил и серверов; API подменяет его целиком, открытые
+    // соединения продолжают работать со своим снимком
+    router: ArcSwap<Router>,
 }
 
 impl ProxyEngine {
-    pub fn new() -> Self {
+    pub fn new(config: MihomoConfig, rules: Vec<Rule>, servers: Vec<ProxyServerV2>, groups: Vec<ProxyGroup>) -> Self {
+        let settings = ConfigManager::parse_settings(&config).unwrap_or_else(|e| {
+            log::warn!("Invalid proxy settings in config, using defaults: {}", e);
+            ProxySettings::default()
+        });
+        if settings.failover.enable && settings.failover.restore && !settings.health_check.enable {
+            log::warn!("failover.restore needs health-check.enable; a replaced server will stay replaced until another server is selected");
+        }
+        if let Err(e) = outbound::preload_tls(&servers) {
+            log::warn!("{:#}", e);
+        }
+        let router = Router::new(rules, servers, groups, &settings);
+
         Self {
-            config: MihomoConfig {
-                raw_config: String::new(),
-                format: ConfigFormat::YAML,
-                last_modified: chrono::Utc::now(),
-            },
-            servers: vec![],
-            rules: vec![],
+            config,
+            settings,
+            router: ArcSwap::from_pointee(router),
         }
     }
 
+    /// Перечитывает правила, серверы и группы из базы для новых соединений.
+    pub async fn reload(&self, db: &Database) -> Result<()> {
+        let rules = db.get_rules().await?;
+        let servers = db.get_servers_v2().await?;
+        let groups = db.get_groups().await?;
+        // Ошибка TLS настроек оставляет прежний снимок
+        outbound::preload_tls(&servers)?;
+        log::info!(
+            "Reloaded {} rules, {} servers and {} groups for routing",
+            rules.len(), servers.len(), groups.len()
+        );
+
+        self.router.rcu(|current| {
+            let mut router = Router::new(rules.clone(), servers.clone(), groups.clone(), &self.settings);
+            router.selected_server = current.selected_server.clone();
+            router.group_state = current.group_state.clone();
+            router.health = current.health.clone();
+            router.failover = current.failover.clone();
+            router.dns = current.dns.clone();
+            router.mode = current.mode;
+            router
+        });
+        Ok(())
+    }
+
+    pub fn select_server(&self, server_id: Option<String>) {
+        self.router.load().failover.reset();
+        self.router.rcu(|current| {
+            let mut router = Router::clone(current);
+            router.selected_server = server_id.clone();
+            router
+        });
+    }
+
+    /// Переключает режим маршрутизации для новых соединений.
+    pub fn set_mode(&self, mode: ProxyMode) {
+        self.router.rcu(|current| {
+            let mut router = Router::clone(current);
+            router.mode = mode;
+            router
+        });
+        log::info!("Proxy mode switched to {}", mode.as_str());
+    }
+
+    pub fn mode(&self) -> ProxyMode {
+        self.router.load().mode()
+    }
+
+    /// События failover для журнала и WebSocket клиентов.
+    pub fn failover_events(&self) -> tokio::sync::broadcast::Receiver<failover::FailoverEvent> {
+        self.router.load().failover.subscribe()
+    }
+
+    /// DNS сервер для клиентов; соответствия fake-IP сохраняются в `db`.
+    pub async fn start_dns_server(&self, addr: SocketAddr, db: Arc<crate::database::Database>) -> Result<()> {
+        dns::server::serve(self.router.load().dns.clone(), db, addr).await
+    }
+
+    pub fn router(&self) -> Arc<Router> {
+        self.router.load_full()
+    }
+
     pub async fn start_proxy_server(&self, addr: SocketAddr) -> Result<()> {
+        self.serve(addr, Inbound::Http).await
+    }
+
+    /// SOCKS4/4a и SOCKS5 сервер; маршрутизация та же, что и у HTTP прокси.
+    pub async fn start_socks_server(&self, addr: SocketAddr) -> Result<()> {
+        self.serve(addr, Inbound::Socks).await
+    }
+
+    /// Общий порт для HTTP и SOCKS: протокол определяется по первому байту.
+    pub async fn start_mixed_server(&self, addr: SocketAddr) -> Result<()> {
+        self.serve(addr, Inbound::Mixed).await
+    }
+
+    async fn serve(&self, addr: SocketAddr, inbound: Inbound) -> Result<()> {
         let listener = TcpListener::bind(addr).await?;
-        log::info!("🔗 Proxy server listening on {}", addr);
+        let credentials = Arc::new(auth::Credentials::parse(&self.settings.authentication));
+        log::info!("🔗 {} listening on {}", inbound, addr);
 
         loop {
             let (stream, client_addr) = listener.accept().await?;
-            let servers = self.servers.clone();
-            let rules = self.rules.clone();
-            
+            let router = self.router();
+            let credentials = credentials.clone();
+
             tokio::spawn(async move {
-                if let Err(e) = handle_connection(stream, client_addr, servers, rules).await {
+                if let Err(e) = dispatch(stream, client_addr, inbound, router, credentials).await {
                     log::error!("Error handling connection from {}: {}", client_addr, e);
                 }
             });
@@ -53,148 +161,549 @@ impl ProxyEngine {
         }
     }
 
-    pub fn find_matching_rule(&self, host: &str, port: u16) -> Option<&Rule> {
-        for rule in &self.rules {
-            if self.rule_matches(rule, host, port) {
-                return Some(rule);
-            }
+    pub fn find_matching_rule(&self, host: &str, port: u16) -> Option<Rule> {
+        self.router.load().find_matching_rule(host, port, &[]).cloned()
+    }
+}
+
+/// Правила, серверы и группы, по которым выбирается маршрут соединения.
+#[derive(Clone)]
+pub struct Router {
+    rules: RuleIndex,
+    servers: Vec<ProxyServerV2>,
+    groups: Vec<ProxyGroup>,
+    group_state: Arc<group::GroupState>,
+    health: Arc<health::HealthTable>,
+    failover: Arc<failover::Failover>,
+    dns: Arc<dns::Resolver>,
+    selected_server: Option<String>,
+    mode: ProxyMode,
+    resolve_ip_rules: bool,
+    connect_timeout: Duration,
+    udp_timeout: Duration,
+    udp_max_sessions: usize,
+}
+
+#[derive(Debug, Clone)]
+pub enum RouteAction {
+    Direct,
+    Block,
+    Proxy(Box<ProxyServerV2>),
+}
+
+#[derive(Debug, Clone)]
+pub struct RouteDecision {
+    pub action: RouteAction,
+    pub rule_id: Option<String>,
+    // Группа, через которую выбран сервер
+    pub group: Option<String>,
+}
+
+impl fmt::Display for RouteAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RouteAction::Direct => write!(f, "DIRECT"),
+            RouteAction::Block => write!(f, "BLOCK"),
+            RouteAction::Proxy(server) => write!(f, "{:?} \"{}\"", server.protocol, server.name),
         }
-        None
     }
+}
 
-    fn rule_matches(&self, rule: &Rule, host: &str, port: u16) -> bool {
-        if !rule.enabled {
-            return false;
+impl Router {
+    pub fn new(rules: Vec<Rule>, servers: Vec<ProxyServerV2>, groups: Vec<ProxyGroup>, settings: &ProxySettings) -> Self {
+        Self {
+            rules: RuleIndex::new(rules::compile_rules(&rules)),
+            servers,
+            groups,
+            group_state: Arc::default(),
+            health: Arc::default(),
+            failover: Arc::new(failover::Failover::new(settings.failover.clone())),
+            dns: Arc::new(dns::Resolver::new(&settings.dns)),
+            selected_server: None,
+            mode: settings.mode,
+            resolve_ip_rules: settings.resolve_ip_rules,
+            connect_timeout: Duration::from_millis(settings.connect_timeout.max(1)),
+            udp_timeout: Duration::from_millis(settings.udp_timeout.max(1)),
+            udp_max_sessions: settings.udp_max_sessions,
         }
-        
-        match rule.rule_type.as_str() {
-            "domain" => rule.pattern == host,
-            "domain-suffix" => host.ends_with(&rule.pattern),
-            "domain-keyword" => host.contains(&rule.pattern),
-            "ip-cidr" => {
-                // Простая проверка IP (можно улучшить)
-                host.parse::<std::net::IpAddr>().is_ok() && host.starts_with(&rule.pattern.split('/').next().unwrap_or(""))
-            },
-            "dst-port" => {
-                rule.pattern.parse::<u16>().map_or(false, |p| p == port)
-            },
-            _ => false,
+    }
+
+    /// Скомпилированные правила в порядке проверки.
+    pub fn rules(&self) -> &[rules::CompiledRule] {
+        self.rules.rules()
+    }
+
+    pub fn mode(&self) -> ProxyMode {
+        self.mode
+    }
+
+    pub fn find_matching_rule(&self, host: &str, port: u16, resolved: &[IpAddr]) -> Option<&Rule> {
+        let host = rules::normalize_domain(host);
+        self.rules.find(&host, port, resolved).map(|compiled| &compiled.rule)
+    }
+
+    /// Нужно ли разрешить доменную цель в IP до проверки правил. Со
+    /// встроенным DNS цели разрешаются для ip-cidr правил всегда.
+    pub fn needs_resolution(&self, host: &str) -> bool {
+        self.mode == ProxyMode::PAC
+            && (self.resolve_ip_rules || self.dns.is_enabled())
+            && host.parse::<IpAddr>().is_err()
+            && self.rules.has_ip_rules()
+    }
+
+    /// Выбирает маршрут для соединения. В режиме PAC действует первое
+    /// подходящее правило, а если ни одно не подошло, соединение идёт напрямую.
+    pub fn route(&self, host: &str, port: u16, resolved: &[IpAddr]) -> Result<RouteDecision> {
+        match self.mode {
+            ProxyMode::PAC => {}
+            ProxyMode::Bypass => {
+                return Ok(RouteDecision { action: RouteAction::Direct, rule_id: None, group: None });
+            }
+            ProxyMode::Global => {
+                let server = self.current_server()
+                    .ok_or_else(|| anyhow::anyhow!("Global mode requires a proxy, but no server is selected"))?;
+                return Ok(RouteDecision { action: RouteAction::Proxy(Box::new(server.clone())), rule_id: None, group: None });
+            }
+        }
+
+        let rule = match self.find_matching_rule(host, port, resolved) {
+            Some(rule) => rule,
+            None => {
+                return Ok(RouteDecision { action: RouteAction::Direct, rule_id: None, group: None });
+            }
+        };
+
+        // Группа с именем "Proxy" важнее встроенного действия proxy
+        if let Some(group) = self.groups.iter().find(|g| g.name == rule.action) {
+            let action = self.pick_from_group(group, host)?;
+            return Ok(RouteDecision { action, rule_id: Some(rule.id.clone()), group: Some(group.name.clone()) });
         }
+
+        let action = match rule.action.to_ascii_lowercase().as_str() {
+            "direct" => RouteAction::Direct,
+            "block" | "reject" => RouteAction::Block,
+            "proxy" => RouteAction::Proxy(Box::new(self.current_server()
+                .ok_or_else(|| anyhow::anyhow!("Rule {} requires a proxy, but no server is selected", rule.id))?
+                .clone())),
+            _ => RouteAction::Proxy(Box::new(self.find_server(&rule.action)
+                .ok_or_else(|| anyhow::anyhow!("Rule {} targets unknown server \"{}\"", rule.id, rule.action))?
+                .clone())),
+        };
+
+        Ok(RouteDecision { action, rule_id: Some(rule.id.clone()), group: None })
+    }
+
+    fn pick_from_group(&self, group: &ProxyGroup, host: &str) -> Result<RouteAction> {
+        let candidates: Vec<group::Candidate> = group.proxies.iter()
+            .filter_map(|name| {
+                let member = group::resolve_member(name, &self.servers);
+                if member.is_none() {
+                    log::warn!("Group \"{}\" references unknown server \"{}\"", group.name, name);
+                }
+                member.map(|member| {
+                    let health = match member {
+                        group::Member::Server(server) => self.health.health_of(server),
+                        _ => ServerHealth::default(),
+                    };
+                    group::Candidate { name, member, latency_ms: health.latency_ms, alive: health.is_alive() }
+                })
+            })
+            .collect();
+
+        let picked = self.group_state.pick(group, &candidates, &rules::normalize_domain(host))?;
+        Ok(match picked.member {
+            group::Member::Direct => RouteAction::Direct,
+            group::Member::Block => RouteAction::Block,
+            group::Member::Server(server) => RouteAction::Proxy(Box::new(server.clone())),
+        })
+    }
+
+    pub fn dns(&self) -> &dns::Resolver {
+        &self.dns
+    }
+
+    pub fn health(&self) -> &health::HealthTable {
+        &self.health
+    }
+
+    pub fn failover(&self) -> &failover::Failover {
+        &self.failover
+    }
+
+    /// Серверы для фоновой проверки: активные, участники групп, выбранный
+    /// и серверы из списка failover.
+    pub fn checked_servers(&self) -> Vec<ProxyServerV2> {
+        let primary = self.primary_server().map(|s| s.id.as_str());
+        let priority: Vec<&str> = self.failover.priority_servers(self).iter().map(|s| s.id.as_str()).collect();
+        self.servers.iter()
+            .filter(|server| {
+                server.active
+                    || Some(server.id.as_str()) == primary
+                    || priority.contains(&server.id.as_str())
+                    || self.groups.iter().any(|group| {
+                        group.proxies.iter().any(|p| *p == server.name || *p == server.id)
+                    })
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Сервер для действия proxy: замена от failover, иначе выбранный.
+    pub fn current_server(&self) -> Option<&ProxyServerV2> {
+        failover::current_server(self, self.failover.replacement().as_deref())
+    }
+
+    /// Выбранный сервер, иначе первый активный из servers_v2.
+    fn primary_server(&self) -> Option<&ProxyServerV2> {
+        self.selected_server.as_deref()
+            .and_then(|id| self.servers.iter().find(|s| s.id == id))
+            .or_else(|| self.servers.iter().find(|s| s.active))
+    }
+
+    fn find_server(&self, name_or_id: &str) -> Option<&ProxyServerV2> {
+        self.servers.iter().find(|s| s.name == name_or_id || s.id == name_or_id)
     }
 }
 
-async fn handle_connection(
-    mut stream: TcpStream,
+/// Тип входящего listener'а.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Inbound {
+    Http,
+    Socks,
+    Mixed,
+}
+
+impl fmt::Display for Inbound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Inbound::Http => write!(f, "HTTP proxy"),
+            Inbound::Socks => write!(f, "SOCKS proxy"),
+            Inbound::Mixed => write!(f, "Mixed proxy"),
+        }
+    }
+}
+
+/// Передаёт соединение обработчику протокола. Первый байт читается через
+/// `peek`, поэтому обработчик получает поток целиком.
+async fn dispatch(
+    stream: TcpStream,
     client_addr: SocketAddr,
-    _servers: Vec<ProxyServer>,
-    _rules: Vec<Rule>,
+    inbound: Inbound,
+    router: Arc<Router>,
+    credentials: Arc<auth::Credentials>,
 ) -> Result<()> {
-    let mut buffer = [0; 4096];
-    let n = stream.read(&mut buffer).await?;
-    
-    let request = String::from_utf8_lossy(&buffer[..n]);
-    let lines: Vec<&str> = request.lines().collect();
-    
-    if lines.is_empty() {
-        return Err(anyhow::anyhow!("Empty request"));
-    }
-
-    let first_line = lines[0];
-    log::info!("Request from {}: {}", client_addr, first_line);
-
-    // Парсинг HTTP запроса
-    if first_line.starts_with("CONNECT") {
-        handle_connect_request(&mut stream, first_line).await
-    } else if first_line.starts_with("GET") || first_line.starts_with("POST") {
-        handle_http_request(&mut stream, &request).await
-    } else {
-        // Неподдерживаемый метод
-        let response = "HTTP/1.1 405 Method Not Allowed\r\n\r\n";
-        stream.write_all(response.as_bytes()).await?;
-        Ok(())
+    if inbound == Inbound::Http {
+        return handle_connection(stream, client_addr, router, credentials).await;
+    }
+
+    let mut first = [0u8; 1];
+    if stream.peek(&mut first).await? == 0 {
+        return Ok(());
+    }
+
+    match first[0] {
+        socks::SOCKS5_VERSION => socks::handle_socks5(stream, client_addr, router, credentials).await,
+        socks::SOCKS4_VERSION => socks::handle_socks4(stream, client_addr, router, credentials).await,
+        _ if inbound == Inbound::Mixed => handle_connection(stream, client_addr, router, credentials).await,
+        version => Err(anyhow::anyhow!("Unsupported SOCKS version: {}", version)),
     }
 }
 
-async fn handle_connect_request(
-    stream: &mut TcpStream,
-    connect_line: &str,
+async fn handle_connection(
+    stream: TcpStream,
+    client_addr: SocketAddr,
+    router: Arc<Router>,
+    credentials: Arc<auth::Credentials>,
 ) -> Result<()> {
-    // Парсинг CONNECT запроса
-    let parts: Vec<&str> = connect_line.split_whitespace().collect();
-    if parts.len() < 2 {
-        return Err(anyhow::anyhow!("Invalid CONNECT request"));
+    let mut client = BufReader::new(stream);
+    let mut upstream: Option<Upstream> = None;
+
+    // Keep-alive: обрабатываем запросы, пока клиент держит соединение
+    loop {
+        let request = match http::read_request_head(&mut client).await {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                let _ = http::write_simple_response(&mut client, 400, "Bad Request").await;
+                return Err(e);
+            }
+        };
+
+        log::info!("Request from {}: {} {} {}", client_addr, request.method, request.target, request.version);
+
+        if credentials.required() && !credentials.verify_basic(request.header("proxy-authorization")) {
+            http::write_auth_required(&mut client).await?;
+            return Err(anyhow::anyhow!("HTTP proxy authentication failed for {}", client_addr));
+        }
+
+        if request.method == "CONNECT" {
+            return handle_connect_request(&mut client, &request.target, &router).await;
+        }
+
+        if !handle_http_request(&mut client, request, &mut upstream, &router).await? {
+            return Ok(());
+        }
     }
+}
 
-    let target = parts[1];
-    let (host, port) = parse_host_port(target)?;
+async fn handle_connect_request(
+    client: &mut BufReader<TcpStream>,
+    target: &str,
+    router: &Router,
+) -> Result<()> {
+    let (host, port) = match http::split_authority(target, 443) {
+        Ok(host_port) => host_port,
+        Err(e) => {
+            http::write_simple_response(client, 400, "Bad Request").await?;
+            return Err(anyhow::anyhow!("Invalid CONNECT request: {}", e));
+        }
+    };
 
     log::info!("CONNECT request to {}:{}", host, port);
 
-    // Прямое подключение к целевому серверу
-    let target_addr = format!("{}:{}", host, port);
-
-    // Подключение к целевому серверу
-    match TcpStream::connect(&target_addr).await {
-        Ok(mut target_stream) => {
+    match open_route(router, &host, port).await {
+        Ok(Some(mut target_stream)) => {
             // Отправляем успешный ответ клиенту
             let response = "HTTP/1.1 200 Connection Established\r\n\r\n";
-            stream.write_all(response.as_bytes()).await?;
-
-            // Начинаем туннелирование
-            let (mut client_read, mut client_write) = stream.split();
-            let (mut target_read, mut target_write) = target_stream.split();
-
-            let client_to_target = tokio::io::copy(&mut client_read, &mut target_write);
-            let target_to_client = tokio::io::copy(&mut target_read, &mut client_write);
-
-            // Ждем завершения любого из направлений
-            tokio::select! {
-                result = client_to_target => {
-                    if let Err(e) = result {
-                        log::warn!("Client to target copy error: {}", e);
-                    }
-                },
-                result = target_to_client => {
-                    if let Err(e) = result {
-                        log::warn!("Target to client copy error: {}", e);
-                    }
-                }
+            client.write_all(response.as_bytes()).await?;
+
+            // Начинаем туннелирование; данные, уже прочитанные в буфер, уйдут первыми
+            if let Err(e) = tokio::io::copy_bidirectional(client, &mut target_stream).await {
+                log::warn!("CONNECT tunnel error for {}:{}: {}", host, port, e);
             }
 
             log::info!("CONNECT tunnel closed for {}:{}", host, port);
             Ok(())
         }
+        Ok(None) => {
+            http::write_simple_response(client, 403, "Forbidden").await?;
+            Ok(())
+        }
         Err(e) => {
-            log::error!("Failed to connect to {}: {}", target_addr, e);
-            let response = "HTTP/1.1 502 Bad Gateway\r\n\r\n";
-            stream.write_all(response.as_bytes()).await?;
+            log::error!("Failed to connect to {}:{}: {}", host, port, e);
+            http::write_simple_response(client, 502, "Bad Gateway").await?;
             Err(anyhow::anyhow!("Connection failed: {}", e))
         }
     }
 }
 
+// Соединение с origin-сервером, переиспользуемое между запросами клиента
+struct Upstream {
+    host: String,
+    port: u16,
+    stream: BufReader<ProxyStream>,
+}
+
+/// Пересылает один HTTP запрос на origin-сервер и возвращает ответ клиенту.
+/// Возвращает `true`, если клиентское соединение можно использовать дальше.
 async fn handle_http_request(
-    stream: &mut TcpStream,
-    request: &str,
-) -> Result<()> {
-    // Простая HTTP прокси логика
-    log::info!("HTTP request: {}", request.lines().next().unwrap_or(""));
-    
-    // Пока что возвращаем простой ответ
-    let response = "HTTP/1.1 200 OK\r\nContent-Length: 13\r\n\r\nProxy working";
-    stream.write_all(response.as_bytes()).await?;
-    
-    Ok(())
-}
-
-fn parse_host_port(target: &str) -> Result<(String, u16)> {
-    if let Some(colon_pos) = target.rfind(':') {
-        let host = target[..colon_pos].to_string();
-        let port = target[colon_pos + 1..].parse::<u16>()
-            .map_err(|_| anyhow::anyhow!("Invalid port number"))?;
-        Ok((host, port))
+    client: &mut BufReader<TcpStream>,
+    request: http::RequestHead,
+    upstream: &mut Option<Upstream>,
+    router: &Router,
+) -> Result<bool> {
+    // absolute-form от прокси-клиента или origin-form с заголовком Host
+    let target = if request.target.starts_with('/') || request.target == "*" {
+        request.header("host")
+            .ok_or_else(|| anyhow::anyhow!("Missing Host header"))
+            .and_then(|host| http::split_authority(host, 80))
+            .map(|(host, port)| (host, port, request.target.clone()))
     } else {
-        // Если порт не указан, используем 80 для HTTP
-        Ok((target.to_string(), 80))
+        http::split_absolute_uri(&request.target)
+    };
+    let parsed = target.and_then(|target| Ok((target, request.body_kind()?)));
+    let ((host, port, path), body_kind) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            http::write_simple_response(client, 400, "Bad Request").await?;
+            return Err(e);
+        }
+    };
+
+    log::info!("HTTP {} request to {}:{}{}", request.method, host, port, path);
+
+    let mut headers = http::strip_hop_by_hop(&request.headers);
+    headers.retain(|(name, _)| !name.eq_ignore_ascii_case("host"));
+    headers.insert(0, ("Host".to_string(), format_authority(&host, port, 80)));
+
+    let upgrade = request.is_upgrade();
+    if upgrade {
+        headers.push(("Connection".to_string(), "Upgrade".to_string()));
+        headers.push(("Upgrade".to_string(), request.header("upgrade").unwrap_or_default().to_string()));
+    }
+
+    // Expect: 100-continue подтверждаем сами, чтобы клиент не ждал тело
+    let expects_continue = request.header("expect")
+        .is_some_and(|v| v.eq_ignore_ascii_case("100-continue"));
+    if expects_continue {
+        headers.retain(|(name, _)| !name.eq_ignore_ascii_case("expect"));
+        if body_kind != http::BodyKind::None {
+            client.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+        }
+    }
+
+    // Переиспользуем соединение, только если запрос идёт на тот же хост
+    let reusable = matches!(upstream, Some(u) if u.host == host && u.port == port);
+    if !reusable {
+        *upstream = None;
+        match open_route(router, &host, port).await {
+            Ok(Some(stream)) => {
+                *upstream = Some(Upstream { host: host.clone(), port, stream: BufReader::new(stream) });
+            }
+            Ok(None) => {
+                http::write_simple_response(client, 403, "Forbidden").await?;
+                return Ok(false);
+            }
+            Err(e) => {
+                log::error!("Failed to connect to {}:{}: {}", host, port, e);
+                http::write_simple_response(client, 502, "Bad Gateway").await?;
+                return Ok(false);
+            }
+        }
     }
-}
\ No newline at end of file
+    let server = &mut upstream.as_mut().expect("upstream connection is set").stream;
+
+    http::write_request_head(server, &request.method, &path, &request.version, &headers).await?;
+    http::copy_body(client, server, body_kind).await?;
+
+    let response = loop {
+        let response = http::read_response_head(server).await?;
+        // Промежуточные 1xx ответы пересылаем и ждём финальный
+        if (100..200).contains(&response.status) && response.status != 101 {
+            let interim_headers = http::strip_hop_by_hop(&response.headers);
+            http::write_response_head(client, &response, &interim_headers).await?;
+            continue;
+        }
+        break response;
+    };
+
+    let mut response_headers = http::strip_hop_by_hop(&response.headers);
+
+    if response.status == 101 && upgrade {
+        response_headers.push(("Connection".to_string(), "Upgrade".to_string()));
+        if let Some(protocol) = http::find_header(&response.headers, "upgrade") {
+            response_headers.push(("Upgrade".to_string(), protocol.to_string()));
+        }
+        http::write_response_head(client, &response, &response_headers).await?;
+
+        // После смены протокола соединение превращается в туннель
+        let mut server = upstream.take().expect("upstream connection is set").stream;
+        if let Err(e) = tokio::io::copy_bidirectional(client, &mut server).await {
+            log::warn!("Upgraded connection error for {}:{}: {}", host, port, e);
+        }
+        return Ok(false);
+    }
+
+    let response_kind = response.body_kind(&request.method)?;
+    let keep_alive = request.wants_keep_alive() && response_kind != http::BodyKind::UntilClose;
+    if !keep_alive {
+        response_headers.push(("Connection".to_string(), "close".to_string()));
+    } else if request.version == "HTTP/1.0" {
+        response_headers.push(("Connection".to_string(), "keep-alive".to_string()));
+    }
+
+    http::write_response_head(client, &response, &response_headers).await?;
+    http::copy_body(server, client, response_kind).await?;
+
+    if !response.wants_keep_alive() || response_kind == http::BodyKind::UntilClose {
+        *upstream = None;
+    }
+
+    Ok(keep_alive)
+}
+
+/// Подключается к цели по маршруту из правил. `None` означает, что
+/// соединение заблокировано правилом.
+async fn open_route(router: &Router, host: &str, port: u16) -> Result<Option<ProxyStream>> {
+    // Соединение на fake-IP маршрутизируется и устанавливается по домену
+    let host = router.dns.restore_domain(host);
+    let host = host.as_ref();
+    let (decision, resolved) = resolve_route(router, host, port).await?;
+
+    match decision.action {
+        RouteAction::Direct => {
+            // Уже разрешённые адреса используем, чтобы не делать второй запрос DNS
+            let resolved = if resolved.is_empty() { router.dns.lookup(host, port).await? } else { resolved };
+            let stream = outbound::connect_tcp(&resolved[..], router.connect_timeout).await?;
+            Ok(Some(Box::new(stream)))
+        }
+        RouteAction::Block => Ok(None),
+        RouteAction::Proxy(server) => {
+            let hops = chain::expand(&server, &router.servers)?;
+            let stream = outbound::connect_chain(&hops, host, port, router.connect_timeout).await;
+            router.failover.report_dial(router, &server, stream.as_ref().map(|_| ()));
+            stream.map(Some)
+        }
+    }
+}
+
+/// UDP вариант `open_route`: сессия до одной цели.
+async fn open_udp_route(router: &Router, host: &str, port: u16) -> Result<Option<Box<dyn outbound::UdpSession>>> {
+    let host = router.dns.restore_domain(host);
+    let host = host.as_ref();
+    let (decision, mut resolved) = resolve_route(router, host, port).await?;
+
+    match decision.action {
+        RouteAction::Direct => {
+            if resolved.is_empty() {
+                resolved = router.dns.lookup(host, port).await?;
+            }
+            let target = *resolved.first()
+                .ok_or_else(|| anyhow::anyhow!("No addresses found for {}", host))?;
+            let bind: SocketAddr = if target.is_ipv4() {
+                (std::net::Ipv4Addr::UNSPECIFIED, 0).into()
+            } else {
+                (std::net::Ipv6Addr::UNSPECIFIED, 0).into()
+            };
+            let socket = tokio::net::UdpSocket::bind(bind).await?;
+            socket.connect(target).await?;
+            Ok(Some(Box::new(socket)))
+        }
+        RouteAction::Block => Ok(None),
+        RouteAction::Proxy(server) if outbound::supports_udp(&server) => {
+            outbound::connect_udp(&server, host, port, router.connect_timeout).await.map(Some)
+        }
+        RouteAction::Proxy(server) => Err(anyhow::anyhow!(
+            "{:?} server \"{}\" does not support UDP", server.protocol, server.name
+        )),
+    }
+}
+
+/// Выбирает маршрут и возвращает адреса цели, если их пришлось разрешить
+/// для ip-cidr правил.
+async fn resolve_route(router: &Router, host: &str, port: u16) -> Result<(RouteDecision, Vec<SocketAddr>)> {
+    let resolved: Vec<SocketAddr> = if router.needs_resolution(host) {
+        match router.dns.lookup(host, port).await {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                log::warn!("Failed to resolve {} for IP rules: {}", host, e);
+                Vec::new()
+            }
+        }
+    } else {
+        Vec::new()
+    };
+    let resolved_ips: Vec<IpAddr> = resolved.iter().map(|addr| addr.ip()).collect();
+
+    let decision = router.route(host, port, &resolved_ips)?;
+    match (&decision.rule_id, &decision.group) {
+        (Some(rule_id), Some(group)) => {
+            log::info!("Route {}:{} -> {} via group \"{}\" (rule {})", host, port, decision.action, group, rule_id)
+        }
+        (Some(rule_id), None) => log::info!("Route {}:{} -> {} (rule {})", host, port, decision.action, rule_id),
+        (None, _) if router.mode != ProxyMode::PAC => {
+            log::info!("Route {}:{} -> {} ({} mode)", host, port, decision.action, router.mode.as_str())
+        }
+        (None, _) => log::info!("Route {}:{} -> {} (no rule matched)", host, port, decision.action),
+    }
+    Ok((decision, resolved))
+}
+
+fn format_authority(host: &str, port: u16, default_port: u16) -> String {
+    let host = if host.contains(':') { format!("[{}]", host) } else { host.to_string() };
+    if port == default_port {
+        host
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
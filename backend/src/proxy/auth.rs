@@ -0,0 +1,48 @@
+use base64::{Engine as _, engine::general_purpose};
+
+/// Учётные записи входящих подключений: RFC 1929 для SOCKS5 и
+/// `Proxy-Authorization: Basic` для HTTP. Пустой список - вход без авторизации.
+#[derive(Debug, Default)]
+pub struct Credentials {
+    users: Vec<(String, String)>,
+}
+
+impl Credentials {
+    /// Разбирает записи вида `user:pass` из настройки `authentication`.
+    pub fn parse(entries: &[String]) -> Self {
+        let users = entries.iter()
+            .filter_map(|entry| match entry.split_once(':') {
+                Some((user, pass)) => Some((user.to_string(), pass.to_string())),
+                None => {
+                    log::warn!("Ignoring authentication entry without ':' separator");
+                    None
+                }
+            })
+            .collect();
+        Self { users }
+    }
+
+    pub fn required(&self) -> bool {
+        !self.users.is_empty()
+    }
+
+    pub fn verify(&self, user: &[u8], pass: &[u8]) -> bool {
+        self.users.iter().any(|(u, p)| u.as_bytes() == user && p.as_bytes() == pass)
+    }
+
+    /// Проверяет значение заголовка `Proxy-Authorization`.
+    pub fn verify_basic(&self, header: Option<&str>) -> bool {
+        let encoded = match header.and_then(|h| h.trim().split_once(' ')) {
+            Some((scheme, encoded)) if scheme.eq_ignore_ascii_case("basic") => encoded.trim(),
+            _ => return false,
+        };
+        let decoded = match general_purpose::STANDARD.decode(encoded) {
+            Ok(decoded) => decoded,
+            Err(_) => return false,
+        };
+        match decoded.iter().position(|&b| b == b':') {
+            Some(i) => self.verify(&decoded[..i], &decoded[i + 1..]),
+            None => false,
+        }
+    }
+}
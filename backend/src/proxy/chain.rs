@@ -0,0 +1,40 @@
+use anyhow::{anyhow, Result};
+use crate::models::{ProxyConfig, ProxyServerV2};
+
+/// Раскрывает сервер в список hop'ов для `outbound::connect_chain`. Обычный
+/// сервер - цепочка из одного hop'а, вложенные цепочки разворачиваются на месте.
+/// Цикл или ссылка на несуществующий сервер - ошибка.
+pub fn expand(server: &ProxyServerV2, servers: &[ProxyServerV2]) -> Result<Vec<ProxyServerV2>> {
+    let mut hops = Vec::new();
+    expand_into(server, servers, &mut Vec::new(), &mut hops)?;
+    Ok(hops)
+}
+
+fn expand_into<'a>(
+    server: &'a ProxyServerV2,
+    servers: &'a [ProxyServerV2],
+    path: &mut Vec<&'a ProxyServerV2>,
+    hops: &mut Vec<ProxyServerV2>,
+) -> Result<()> {
+    let ProxyConfig::Chain { hops: ids } = &server.config else {
+        hops.push(server.clone());
+        return Ok(());
+    };
+    if path.iter().any(|s| s.id == server.id) {
+        let names: Vec<&str> = path.iter().copied().chain([server]).map(|s| s.name.as_str()).collect();
+        return Err(anyhow!("Proxy chain contains a cycle: {}", names.join(" -> ")));
+    }
+    if ids.is_empty() {
+        return Err(anyhow!("Chain \"{}\" has no servers", server.name));
+    }
+
+    path.push(server);
+    for id in ids {
+        let hop = servers.iter()
+            .find(|s| &s.id == id)
+            .ok_or_else(|| anyhow!("Chain \"{}\" references unknown server {}", server.name, id))?;
+        expand_into(hop, servers, path, hops)?;
+    }
+    path.pop();
+    Ok(())
+}
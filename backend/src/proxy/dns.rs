@@ -0,0 +1,392 @@
+pub mod fake_ip;
+pub mod message;
+pub mod server;
+pub mod upstream;
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, Result};
+use crate::config::{DnsSettings, EnhancedMode};
+use crate::rules::{is_subdomain_of, normalize_domain};
+use fake_ip::FakeIpPool;
+use message::{Message, RecordData, RCODE_NAME_ERROR, RCODE_NO_ERROR, RCODE_SERVER_FAILURE, TYPE_A, TYPE_AAAA};
+use upstream::Upstream;
+
+/// Домен из nameserver-policy или fake-ip-filter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum DomainPattern {
+    // example.com
+    Exact(String),
+    // +.example.com: домен и все поддомены
+    Suffix(String),
+    // .example.com: только поддомены
+    Subdomains(String),
+    // *.example.com: ровно один уровень поддомена
+    OneLevel(String),
+}
+
+impl DomainPattern {
+    fn parse(pattern: &str) -> Option<Self> {
+        let pattern = normalize_domain(pattern.trim());
+        let (pattern, make): (&str, fn(String) -> Self) = if let Some(base) = pattern.strip_prefix("+.") {
+            (base, DomainPattern::Suffix)
+        } else if let Some(base) = pattern.strip_prefix("*.") {
+            (base, DomainPattern::OneLevel)
+        } else if let Some(base) = pattern.strip_prefix('.') {
+            (base, DomainPattern::Subdomains)
+        } else {
+            (pattern.as_str(), DomainPattern::Exact)
+        };
+        (!pattern.is_empty() && !pattern.contains(['*', '+'])).then(|| make(pattern.to_string()))
+    }
+
+    fn matches(&self, host: &str) -> bool {
+        match self {
+            DomainPattern::Exact(domain) => host == domain,
+            DomainPattern::Suffix(base) => is_subdomain_of(host, base),
+            DomainPattern::Subdomains(base) => host != base && is_subdomain_of(host, base),
+            DomainPattern::OneLevel(base) => host.strip_suffix(base.as_str())
+                .and_then(|prefix| prefix.strip_suffix('.'))
+                .is_some_and(|label| !label.is_empty() && !label.contains('.')),
+        }
+    }
+
+    // Точное совпадение важнее шаблонов, среди шаблонов - более длинный домен
+    fn specificity(&self) -> (bool, usize) {
+        match self {
+            DomainPattern::Exact(domain) => (true, domain.len()),
+            DomainPattern::Suffix(base) | DomainPattern::Subdomains(base) | DomainPattern::OneLevel(base) => (false, base.len()),
+        }
+    }
+}
+
+struct CacheEntry {
+    ips: Vec<IpAddr>,
+    expires: Instant,
+}
+
+/// Ответы по (имя, тип записи) на время их TTL.
+struct Cache {
+    entries: HashMap<(String, u16), CacheEntry>,
+    capacity: usize,
+}
+
+impl Cache {
+    fn get(&mut self, name: &str, qtype: u16) -> Option<Vec<IpAddr>> {
+        let key = (name.to_string(), qtype);
+        match self.entries.get(&key) {
+            Some(entry) if entry.expires > Instant::now() => Some(entry.ips.clone()),
+            Some(_) => {
+                self.entries.remove(&key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&mut self, name: &str, qtype: u16, ips: Vec<IpAddr>, ttl: u32) {
+        if ttl == 0 || self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() >= self.capacity {
+            let now = Instant::now();
+            self.entries.retain(|_, entry| entry.expires > now);
+        }
+        if self.entries.len() >= self.capacity {
+            // Вытесняем запись, которая истекла бы раньше всех
+            if let Some(key) = self.entries.iter().min_by_key(|(_, entry)| entry.expires).map(|(key, _)| key.clone()) {
+                self.entries.remove(&key);
+            }
+        }
+        let expires = Instant::now() + Duration::from_secs(ttl as u64);
+        self.entries.insert((name.to_string(), qtype), CacheEntry { ips, expires });
+    }
+}
+
+/// Резолвер для целей соединений. Когда встроенный DNS выключен,
+/// запросы уходят в системный резолвер.
+pub struct Resolver {
+    enabled: bool,
+    ipv6: bool,
+    timeout: Duration,
+    nameservers: Vec<Upstream>,
+    // Для имён DoT и DoH серверов
+    bootstrap: Vec<Upstream>,
+    // Отсортирована так, что первое совпадение - самое точное
+    policy: Vec<(DomainPattern, Vec<Upstream>)>,
+    cache: Mutex<Cache>,
+    // Есть только в режиме fake-ip
+    fake_ips: Option<FakeIpPool>,
+}
+
+impl Resolver {
+    /// Некорректные адреса серверов и шаблоны доменов пропускаются с предупреждением.
+    pub fn new(settings: &DnsSettings) -> Self {
+        let nameservers = parse_upstreams(&settings.nameserver);
+        if settings.enable && nameservers.is_empty() {
+            log::warn!("DNS is enabled, but no valid nameserver is configured");
+        }
+        let bootstrap: Vec<Upstream> = parse_upstreams(&settings.default_nameserver).into_iter()
+            .filter(|upstream| {
+                let plain = upstream.is_plain();
+                if !plain {
+                    log::warn!("Skipping default nameserver {}: only plain UDP and TCP servers are supported", upstream);
+                }
+                plain
+            })
+            .collect();
+
+        let mut policy: Vec<(DomainPattern, Vec<Upstream>)> = settings.nameserver_policy.iter()
+            .filter_map(|(pattern, servers)| {
+                let Some(domain) = DomainPattern::parse(pattern) else {
+                    log::warn!("Skipping invalid nameserver-policy domain {}", pattern);
+                    return None;
+                };
+                let upstreams = parse_upstreams(servers.as_slice());
+                (!upstreams.is_empty()).then_some((domain, upstreams))
+            })
+            .collect();
+        policy.sort_by_key(|(domain, _)| std::cmp::Reverse(domain.specificity()));
+
+        let fake_ips = match settings.enhanced_mode {
+            EnhancedMode::Normal => None,
+            EnhancedMode::FakeIp => match FakeIpPool::new(&settings.fake_ip_range, &settings.fake_ip_filter) {
+                Ok(pool) => Some(pool),
+                Err(e) => {
+                    log::warn!("Fake IP mode is disabled: {}", e);
+                    None
+                }
+            },
+        };
+        if fake_ips.is_some() && !settings.enable {
+            log::warn!("Fake IP mode is on, but DNS is disabled: direct connections will use the system resolver");
+        }
+
+        Self {
+            enabled: settings.enable,
+            ipv6: settings.ipv6,
+            timeout: Duration::from_millis(settings.timeout.max(1)),
+            nameservers,
+            bootstrap,
+            policy,
+            cache: Mutex::new(Cache { entries: HashMap::new(), capacity: settings.cache_size }),
+            fake_ips,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn fake_ips(&self) -> Option<&FakeIpPool> {
+        self.fake_ips.as_ref()
+    }
+
+    /// Домен, которому выдан fake-IP `host`. Остальные цели возвращаются как есть.
+    pub fn restore_domain<'a>(&self, host: &'a str) -> Cow<'a, str> {
+        let Some(pool) = &self.fake_ips else { return Cow::Borrowed(host) };
+        let ip = match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+            Ok(ip) if pool.contains(ip) => ip,
+            _ => return Cow::Borrowed(host),
+        };
+        match pool.lookup(ip) {
+            Some(domain) => {
+                log::debug!("Fake IP {} belongs to {}", ip, domain);
+                Cow::Owned(domain)
+            }
+            None => {
+                log::warn!("Fake IP {} is not assigned to any domain", ip);
+                Cow::Borrowed(host)
+            }
+        }
+    }
+
+    /// Пересылает запрос клиента серверам из nameserver-policy и возвращает
+    /// их ответ без изменений, кроме ID.
+    pub async fn forward(&self, request: &[u8]) -> Result<Vec<u8>> {
+        let query = Message::decode(request)?;
+        let name = query.questions.first().map(|question| question.name.as_str()).unwrap_or_default();
+
+        let mut request = request.to_vec();
+        let mut last_error = anyhow!("No nameserver configured for {}", name);
+        for upstream in self.upstreams_for(name) {
+            let id: u16 = rand::random();
+            request[..2].copy_from_slice(&id.to_be_bytes());
+            match self.exchange_raw(upstream, &request, id).await {
+                Ok(mut response) => {
+                    if response[3] & 0x0f == RCODE_SERVER_FAILURE {
+                        last_error = anyhow!("Nameserver {} answered with rcode {}", upstream, RCODE_SERVER_FAILURE);
+                        continue;
+                    }
+                    response[..2].copy_from_slice(&query.id.to_be_bytes());
+                    return Ok(response);
+                }
+                Err(e) => {
+                    log::debug!("Forwarding {} to {} failed: {:#}", name, upstream, e);
+                    last_error = e.context(format!("Nameserver {} failed", upstream));
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Адреса `host:port` для подключения.
+    pub async fn lookup(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        if !self.enabled {
+            return Ok(tokio::net::lookup_host((host, port)).await?.collect());
+        }
+        let ips = self.lookup_ip(host).await?;
+        Ok(ips.into_iter().map(|ip| SocketAddr::new(ip, port)).collect())
+    }
+
+    /// IPv4 адреса, затем IPv6, если они включены.
+    pub async fn lookup_ip(&self, host: &str) -> Result<Vec<IpAddr>> {
+        let host = normalize_domain(host.trim_start_matches('[').trim_end_matches(']'));
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![ip]);
+        }
+        if host == "localhost" {
+            let mut ips = vec![IpAddr::V4(Ipv4Addr::LOCALHOST)];
+            if self.ipv6 {
+                ips.push(IpAddr::V6(Ipv6Addr::LOCALHOST));
+            }
+            return Ok(ips);
+        }
+
+        let upstreams = self.upstreams_for(&host);
+        let (v4, v6) = if self.ipv6 {
+            tokio::join!(self.query(&host, TYPE_A, upstreams), self.query(&host, TYPE_AAAA, upstreams))
+        } else {
+            (self.query(&host, TYPE_A, upstreams).await, Ok(Vec::new()))
+        };
+
+        let ips = match (v4, v6) {
+            (Err(e), Err(_)) => return Err(e),
+            (v4, v6) => [v4.unwrap_or_default(), v6.unwrap_or_default()].concat(),
+        };
+        if ips.is_empty() {
+            return Err(anyhow!("No addresses found for {}", host));
+        }
+        Ok(ips)
+    }
+
+    fn upstreams_for(&self, host: &str) -> &[Upstream] {
+        self.policy.iter()
+            .find(|(domain, _)| domain.matches(host))
+            .map(|(_, upstreams)| upstreams.as_slice())
+            .unwrap_or(&self.nameservers)
+    }
+
+    // Опрашивает серверы по порядку до первого ответа NOERROR или NXDOMAIN
+    async fn query(&self, host: &str, qtype: u16, upstreams: &[Upstream]) -> Result<Vec<IpAddr>> {
+        if let Some(ips) = self.cache.lock().unwrap().get(host, qtype) {
+            return Ok(ips);
+        }
+
+        let mut last_error = anyhow!("No nameserver configured for {}", host);
+        for upstream in upstreams {
+            let query = Message::query(rand::random(), host, qtype);
+            let response = match self.exchange(upstream, &query).await {
+                Ok(response) => response,
+                Err(e) => {
+                    log::debug!("DNS query for {} to {} failed: {:#}", host, upstream, e);
+                    last_error = e.context(format!("Nameserver {} failed", upstream));
+                    continue;
+                }
+            };
+            match response.rcode() {
+                RCODE_NO_ERROR | RCODE_NAME_ERROR => {
+                    let (ips, ttl) = addresses(&response, qtype);
+                    log::debug!("Resolved {} via {}: {:?} (ttl {}s)", host, upstream, ips, ttl);
+                    self.cache.lock().unwrap().insert(host, qtype, ips.clone(), ttl);
+                    return Ok(ips);
+                }
+                rcode => last_error = anyhow!("Nameserver {} answered with rcode {}", upstream, rcode),
+            }
+        }
+        Err(last_error.context(format!("Failed to resolve {}", host)))
+    }
+
+    async fn exchange(&self, upstream: &Upstream, query: &Message) -> Result<Message> {
+        let response = self.exchange_raw(upstream, &query.encode()?, query.id).await?;
+        Message::decode(&response)
+    }
+
+    async fn exchange_raw(&self, upstream: &Upstream, request: &[u8], id: u16) -> Result<Vec<u8>> {
+        tokio::time::timeout(self.timeout, async {
+            let ip = match upstream.ip() {
+                Some(ip) => ip,
+                None => self.bootstrap(&upstream.host).await?,
+            };
+            upstream.exchange_raw(request, id, SocketAddr::new(ip, upstream.port)).await
+        })
+        .await
+        .map_err(|_| anyhow!("DNS query timed out after {} ms", self.timeout.as_millis()))?
+    }
+
+    // Адрес DoT или DoH сервера через default-nameserver, иначе через систему
+    async fn bootstrap(&self, host: &str) -> Result<IpAddr> {
+        if self.bootstrap.is_empty() {
+            return tokio::net::lookup_host((host, 0)).await?
+                .map(|addr| addr.ip())
+                .next()
+                .ok_or_else(|| anyhow!("No addresses found for nameserver {}", host));
+        }
+        if let Some(ip) = self.cache.lock().unwrap().get(host, TYPE_A).and_then(|ips| ips.first().copied()) {
+            return Ok(ip);
+        }
+
+        let mut last_error = anyhow!("No default nameserver answered for {}", host);
+        for upstream in &self.bootstrap {
+            let ip = upstream.ip().expect("default nameservers are IP addresses");
+            let query = Message::query(rand::random(), host, TYPE_A);
+            match upstream.exchange(&query, SocketAddr::new(ip, upstream.port)).await {
+                Ok(response) => {
+                    let (ips, ttl) = addresses(&response, TYPE_A);
+                    if let Some(ip) = ips.first().copied() {
+                        self.cache.lock().unwrap().insert(host, TYPE_A, ips, ttl);
+                        return Ok(ip);
+                    }
+                    last_error = anyhow!("Default nameserver {} has no address for {}", upstream, host);
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+}
+
+fn parse_upstreams(specs: &[String]) -> Vec<Upstream> {
+    specs.iter()
+        .filter_map(|spec| match Upstream::parse(spec) {
+            Ok(upstream) => Some(upstream),
+            Err(e) => {
+                log::warn!("Skipping nameserver: {}", e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Адреса нужного типа из ответа и время, на которое их можно запомнить.
+/// Пустой ответ хранится столько, сколько разрешает SOA.
+fn addresses(response: &Message, qtype: u16) -> (Vec<IpAddr>, u32) {
+    let records: Vec<_> = response.answers.iter().filter(|record| record.rtype == qtype).collect();
+    let ips: Vec<IpAddr> = records.iter()
+        .filter_map(|record| match record.data {
+            RecordData::A(ip) => Some(IpAddr::V4(ip)),
+            RecordData::Aaaa(ip) => Some(IpAddr::V6(ip)),
+            _ => None,
+        })
+        .collect();
+    let ttl = if ips.is_empty() {
+        response.negative_ttl().unwrap_or(0)
+    } else {
+        // Цепочка CNAME живёт не дольше самой короткой записи в ней
+        response.answers.iter().map(|record| record.ttl).min().unwrap_or(0)
+    };
+    (ips, ttl)
+}
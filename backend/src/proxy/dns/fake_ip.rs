@@ -0,0 +1,200 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Mutex;
+use anyhow::{anyhow, Context, Result};
+use ipnet::IpNet;
+use super::DomainPattern;
+use crate::models::FakeIpMapping;
+
+// Больше адресов не выдаём, даже если сеть шире (IPv6)
+const MAX_POOL_SIZE: u128 = 1 << 24;
+
+struct Entry {
+    domain: String,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct Inner {
+    // Адреса хранятся как смещение от начала пула
+    domains: HashMap<String, u32>,
+    entries: HashMap<u32, Entry>,
+    // last_used -> смещение; первый элемент вытесняется, когда пул заполнен
+    lru: BTreeMap<u64, u32>,
+    clock: u64,
+    cursor: u32,
+    // Изменённые с последнего сохранения
+    dirty: HashSet<u32>,
+}
+
+impl Inner {
+    fn touch(&mut self, offset: u32) {
+        let Some(entry) = self.entries.get_mut(&offset) else { return };
+        self.lru.remove(&entry.last_used);
+        self.clock += 1;
+        entry.last_used = self.clock;
+        self.lru.insert(self.clock, offset);
+        self.dirty.insert(offset);
+    }
+
+    fn insert(&mut self, offset: u32, domain: String, last_used: u64) {
+        self.domains.insert(domain.clone(), offset);
+        self.entries.insert(offset, Entry { domain, last_used });
+        self.lru.insert(last_used, offset);
+    }
+
+    fn remove(&mut self, offset: u32) -> Option<String> {
+        let entry = self.entries.remove(&offset)?;
+        self.lru.remove(&entry.last_used);
+        self.domains.remove(&entry.domain);
+        Some(entry.domain)
+    }
+}
+
+/// Пул fake-IP: двустороннее соответствие адресов и доменов. Когда свободные
+/// адреса заканчиваются, домен, к которому дольше всех не обращались,
+/// отдаёт свой адрес новому.
+pub struct FakeIpPool {
+    net: IpNet,
+    capacity: u32,
+    filter: Vec<DomainPattern>,
+    inner: Mutex<Inner>,
+}
+
+impl FakeIpPool {
+    /// Адреса сети `range` без адреса сети и широковещательного.
+    pub fn new(range: &str, filter: &[String]) -> Result<Self> {
+        let net: IpNet = range.trim().parse().with_context(|| format!("Invalid fake-ip-range {}", range))?;
+        let net = net.trunc();
+        let hosts = match net {
+            IpNet::V4(net) => (1u128 << (32 - net.prefix_len())).saturating_sub(2),
+            IpNet::V6(net) => 1u128.checked_shl(128 - net.prefix_len() as u32).unwrap_or(u128::MAX).saturating_sub(2),
+        };
+        if hosts == 0 {
+            return Err(anyhow!("fake-ip-range {} is too small", range));
+        }
+
+        let filter = filter.iter()
+            .filter_map(|pattern| {
+                let domain = DomainPattern::parse(pattern);
+                if domain.is_none() {
+                    log::warn!("Skipping invalid fake-ip-filter domain {}", pattern);
+                }
+                domain
+            })
+            .collect();
+
+        Ok(Self {
+            net,
+            capacity: hosts.min(MAX_POOL_SIZE) as u32,
+            filter,
+            inner: Mutex::new(Inner::default()),
+        })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.net.contains(&ip)
+    }
+
+    pub fn is_ipv6(&self) -> bool {
+        matches!(self.net, IpNet::V6(_))
+    }
+
+    /// Домен из fake-ip-filter, которому нужен настоящий адрес.
+    pub fn is_filtered(&self, domain: &str) -> bool {
+        self.filter.iter().any(|pattern| pattern.matches(domain))
+    }
+
+    /// Адрес домена и признак того, что он только что выдан.
+    pub fn allocate(&self, domain: &str) -> (IpAddr, bool) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(&offset) = inner.domains.get(domain) {
+            inner.touch(offset);
+            return (self.ip_at(offset), false);
+        }
+
+        let offset = if (inner.entries.len() as u32) < self.capacity {
+            // Свободный адрес точно есть, ищем его от курсора по кругу
+            loop {
+                let offset = inner.cursor;
+                inner.cursor = (inner.cursor + 1) % self.capacity;
+                if !inner.entries.contains_key(&offset) {
+                    break offset;
+                }
+            }
+        } else {
+            let (_, offset) = inner.lru.pop_first().expect("full pool has entries");
+            if let Some(evicted) = inner.remove(offset) {
+                log::debug!("Fake IP {} moved from {} to {}", self.ip_at(offset), evicted, domain);
+            }
+            offset
+        };
+
+        inner.clock += 1;
+        let clock = inner.clock;
+        inner.insert(offset, domain.to_string(), clock);
+        inner.dirty.insert(offset);
+        (self.ip_at(offset), true)
+    }
+
+    /// Домен, которому выдан адрес `ip`.
+    pub fn lookup(&self, ip: IpAddr) -> Option<String> {
+        let offset = self.offset_of(ip)?;
+        let mut inner = self.inner.lock().unwrap();
+        inner.touch(offset);
+        inner.entries.get(&offset).map(|entry| entry.domain.clone())
+    }
+
+    /// Загружает сохранённые соответствия и возвращает, сколько их осталось
+    /// после отбрасывания повторов. Адреса вне текущего пула пропускаются.
+    pub fn restore(&self, mut mappings: Vec<FakeIpMapping>) -> usize {
+        mappings.sort_by_key(|mapping| mapping.last_used);
+        let mut inner = self.inner.lock().unwrap();
+        let before = inner.entries.len();
+        for mapping in mappings {
+            let Some(offset) = self.offset_of(mapping.ip) else { continue };
+            // При повторах побеждает более свежая запись
+            inner.remove(offset);
+            if let Some(&previous) = inner.domains.get(&mapping.domain) {
+                inner.remove(previous);
+            }
+            inner.clock = inner.clock.max(mapping.last_used) + 1;
+            let clock = inner.clock;
+            inner.insert(offset, mapping.domain, clock);
+        }
+        inner.entries.len().saturating_sub(before)
+    }
+
+    /// Изменённые с прошлого вызова соответствия, которые надо сохранить.
+    pub fn take_dirty(&self) -> Vec<FakeIpMapping> {
+        let mut inner = self.inner.lock().unwrap();
+        let dirty: Vec<u32> = inner.dirty.drain().collect();
+        dirty.into_iter()
+            .filter_map(|offset| inner.entries.get(&offset).map(|entry| FakeIpMapping {
+                ip: self.ip_at(offset),
+                domain: entry.domain.clone(),
+                last_used: entry.last_used,
+            }))
+            .collect()
+    }
+
+    fn ip_at(&self, offset: u32) -> IpAddr {
+        match self.net {
+            IpNet::V4(net) => IpAddr::V4(Ipv4Addr::from(u32::from(net.network()) + 1 + offset)),
+            IpNet::V6(net) => IpAddr::V6(Ipv6Addr::from(u128::from(net.network()) + 1 + offset as u128)),
+        }
+    }
+
+    fn offset_of(&self, ip: IpAddr) -> Option<u32> {
+        let offset = match (self.net, ip) {
+            (IpNet::V4(net), IpAddr::V4(ip)) if net.contains(&ip) => {
+                (u32::from(ip) as u128).checked_sub(u32::from(net.network()) as u128 + 1)?
+            }
+            (IpNet::V6(net), IpAddr::V6(ip)) if net.contains(&ip) => {
+                u128::from(ip).checked_sub(u128::from(net.network()) + 1)?
+            }
+            _ => return None,
+        };
+        (offset < self.capacity as u128).then_some(offset as u32)
+    }
+}
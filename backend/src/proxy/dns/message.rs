@@ -0,0 +1,284 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+use anyhow::{anyhow, Result};
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_CNAME: u16 = 5;
+pub const TYPE_SOA: u16 = 6;
+pub const TYPE_AAAA: u16 = 28;
+pub const CLASS_IN: u16 = 1;
+
+pub const RCODE_NO_ERROR: u8 = 0;
+pub const RCODE_SERVER_FAILURE: u8 = 2;
+pub const RCODE_NAME_ERROR: u8 = 3;
+
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_TRUNCATED: u16 = 0x0200;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const FLAG_RECURSION_AVAILABLE: u16 = 0x0080;
+
+const MAX_NAME_LENGTH: usize = 255;
+// Ограничение на переходы по указателям сжатия, чтобы не зациклиться
+const MAX_POINTERS: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Question {
+    pub name: String,
+    pub qtype: u16,
+    pub qclass: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub name: String,
+    pub rtype: u16,
+    pub class: u16,
+    pub ttl: u32,
+    pub data: RecordData,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordData {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Cname(String),
+    // Остальные типы хранятся как есть; имена внутри могут быть сжаты
+    Other(Vec<u8>),
+}
+
+/// DNS сообщение. Секция additional при разборе пропускается.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Message {
+    pub id: u16,
+    pub flags: u16,
+    pub questions: Vec<Question>,
+    pub answers: Vec<Record>,
+    pub authorities: Vec<Record>,
+}
+
+impl Record {
+    pub fn address(name: &str, ttl: u32, ip: std::net::IpAddr) -> Self {
+        let (rtype, data) = match ip {
+            std::net::IpAddr::V4(ip) => (TYPE_A, RecordData::A(ip)),
+            std::net::IpAddr::V6(ip) => (TYPE_AAAA, RecordData::Aaaa(ip)),
+        };
+        Self { name: name.to_string(), rtype, class: CLASS_IN, ttl, data }
+    }
+}
+
+impl Message {
+    /// Рекурсивный запрос одной записи.
+    pub fn query(id: u16, name: &str, qtype: u16) -> Self {
+        Self {
+            id,
+            flags: FLAG_RECURSION_DESIRED,
+            questions: vec![Question { name: name.to_string(), qtype, qclass: CLASS_IN }],
+            ..Default::default()
+        }
+    }
+
+    /// Ответ на этот запрос с тем же ID и вопросом.
+    pub fn reply(&self, rcode: u8, answers: Vec<Record>) -> Self {
+        Self {
+            id: self.id,
+            flags: FLAG_RESPONSE | FLAG_RECURSION_AVAILABLE | (self.flags & FLAG_RECURSION_DESIRED) | (rcode as u16 & 0x0f),
+            questions: self.questions.clone(),
+            answers,
+            authorities: Vec::new(),
+        }
+    }
+
+    /// Пустой ответ с флагом TC: клиент должен повторить запрос по TCP.
+    pub fn truncated_reply(&self) -> Self {
+        let mut reply = self.reply(RCODE_NO_ERROR, Vec::new());
+        reply.flags |= FLAG_TRUNCATED;
+        reply
+    }
+
+    pub fn is_response(&self) -> bool {
+        self.flags & FLAG_RESPONSE != 0
+    }
+
+    pub fn is_truncated(&self) -> bool {
+        self.flags & FLAG_TRUNCATED != 0
+    }
+
+    pub fn rcode(&self) -> u8 {
+        (self.flags & 0x0f) as u8
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity(512);
+        buf.extend_from_slice(&self.id.to_be_bytes());
+        buf.extend_from_slice(&self.flags.to_be_bytes());
+        for count in [self.questions.len(), self.answers.len(), self.authorities.len(), 0] {
+            let count = u16::try_from(count).map_err(|_| anyhow!("Too many DNS records"))?;
+            buf.extend_from_slice(&count.to_be_bytes());
+        }
+        for question in &self.questions {
+            encode_name(&mut buf, &question.name)?;
+            buf.extend_from_slice(&question.qtype.to_be_bytes());
+            buf.extend_from_slice(&question.qclass.to_be_bytes());
+        }
+        for record in self.answers.iter().chain(&self.authorities) {
+            encode_record(&mut buf, record)?;
+        }
+        Ok(buf)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        let mut reader = Reader { buf, pos: 0 };
+        let id = reader.u16()?;
+        let flags = reader.u16()?;
+        let questions = reader.u16()?;
+        let answers = reader.u16()?;
+        let authorities = reader.u16()?;
+        let _additional = reader.u16()?;
+
+        let questions = (0..questions)
+            .map(|_| Ok(Question { name: reader.name()?, qtype: reader.u16()?, qclass: reader.u16()? }))
+            .collect::<Result<Vec<_>>>()?;
+        let answers = (0..answers).map(|_| reader.record()).collect::<Result<Vec<_>>>()?;
+        let authorities = (0..authorities).map(|_| reader.record()).collect::<Result<Vec<_>>>()?;
+
+        Ok(Self { id, flags, questions, answers, authorities })
+    }
+
+    /// Время, на которое можно запомнить отрицательный ответ: TTL записи SOA,
+    /// но не больше её поля MINIMUM.
+    pub fn negative_ttl(&self) -> Option<u32> {
+        self.authorities.iter()
+            .filter(|record| record.rtype == TYPE_SOA)
+            .filter_map(|record| match &record.data {
+                RecordData::Other(rdata) if rdata.len() >= 4 => {
+                    let minimum = u32::from_be_bytes(rdata[rdata.len() - 4..].try_into().expect("4 bytes"));
+                    Some(record.ttl.min(minimum))
+                }
+                _ => None,
+            })
+            .min()
+    }
+}
+
+fn encode_name(buf: &mut Vec<u8>, name: &str) -> Result<()> {
+    let name = name.trim_end_matches('.');
+    if name.len() > MAX_NAME_LENGTH - 2 {
+        return Err(anyhow!("DNS name is too long: {}", name));
+    }
+    if !name.is_empty() {
+        for label in name.split('.') {
+            if label.is_empty() || label.len() > 63 {
+                return Err(anyhow!("Invalid DNS name: {}", name));
+            }
+            buf.push(label.len() as u8);
+            buf.extend_from_slice(label.as_bytes());
+        }
+    }
+    buf.push(0);
+    Ok(())
+}
+
+fn encode_record(buf: &mut Vec<u8>, record: &Record) -> Result<()> {
+    encode_name(buf, &record.name)?;
+    buf.extend_from_slice(&record.rtype.to_be_bytes());
+    buf.extend_from_slice(&record.class.to_be_bytes());
+    buf.extend_from_slice(&record.ttl.to_be_bytes());
+
+    let mut rdata = Vec::new();
+    match &record.data {
+        RecordData::A(ip) => rdata.extend_from_slice(&ip.octets()),
+        RecordData::Aaaa(ip) => rdata.extend_from_slice(&ip.octets()),
+        RecordData::Cname(name) => encode_name(&mut rdata, name)?,
+        RecordData::Other(data) => rdata.extend_from_slice(data),
+    }
+    let len = u16::try_from(rdata.len()).map_err(|_| anyhow!("DNS record data is too long"))?;
+    buf.extend_from_slice(&len.to_be_bytes());
+    buf.extend_from_slice(&rdata);
+    Ok(())
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8]> {
+        let end = self.pos.checked_add(len).filter(|&end| end <= self.buf.len())
+            .ok_or_else(|| anyhow!("DNS message is truncated"))?;
+        let bytes = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().expect("2 bytes")))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().expect("4 bytes")))
+    }
+
+    fn name(&mut self) -> Result<String> {
+        let (name, end) = read_name(self.buf, self.pos)?;
+        self.pos = end;
+        Ok(name)
+    }
+
+    fn record(&mut self) -> Result<Record> {
+        let name = self.name()?;
+        let rtype = self.u16()?;
+        let class = self.u16()?;
+        let ttl = self.u32()?;
+        let len = self.u16()? as usize;
+        let start = self.pos;
+        let rdata = self.take(len)?;
+
+        let data = match (rtype, len) {
+            (TYPE_A, 4) => RecordData::A(Ipv4Addr::from(<[u8; 4]>::try_from(rdata).expect("4 bytes"))),
+            (TYPE_AAAA, 16) => RecordData::Aaaa(Ipv6Addr::from(<[u8; 16]>::try_from(rdata).expect("16 bytes"))),
+            (TYPE_CNAME, _) => RecordData::Cname(read_name(self.buf, start)?.0),
+            _ => RecordData::Other(rdata.to_vec()),
+        };
+        Ok(Record { name, rtype, class, ttl, data })
+    }
+}
+
+/// Читает имя с учётом сжатия. Возвращает имя и позицию сразу после него
+/// в исходном месте сообщения.
+fn read_name(buf: &[u8], mut pos: usize) -> Result<(String, usize)> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut length = 0;
+    let mut end = None;
+    let mut pointers = 0;
+
+    loop {
+        let len = *buf.get(pos).ok_or_else(|| anyhow!("DNS message is truncated"))? as usize;
+        match len & 0xc0 {
+            0x00 if len == 0 => {
+                end.get_or_insert(pos + 1);
+                break;
+            }
+            0x00 => {
+                let label = buf.get(pos + 1..pos + 1 + len).ok_or_else(|| anyhow!("DNS message is truncated"))?;
+                length += len + 1;
+                if length > MAX_NAME_LENGTH {
+                    return Err(anyhow!("DNS name is too long"));
+                }
+                labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+                pos += 1 + len;
+            }
+            0xc0 => {
+                let low = *buf.get(pos + 1).ok_or_else(|| anyhow!("DNS message is truncated"))? as usize;
+                end.get_or_insert(pos + 2);
+                pointers += 1;
+                if pointers > MAX_POINTERS {
+                    return Err(anyhow!("DNS name compression loop"));
+                }
+                pos = ((len & 0x3f) << 8) | low;
+            }
+            _ => return Err(anyhow!("Unsupported DNS label type")),
+        }
+    }
+
+    Ok((labels.join("."), end.expect("end is set before break")))
+}
@@ -0,0 +1,156 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::Notify;
+use anyhow::Result;
+use crate::database::Database;
+use crate::rules::normalize_domain;
+use super::fake_ip::FakeIpPool;
+use super::message::{Message, Record, RCODE_NO_ERROR, RCODE_SERVER_FAILURE, TYPE_A, TYPE_AAAA};
+use super::Resolver;
+
+// Клиенты не должны надолго запоминать fake-IP: адрес может перейти другому домену
+const FAKE_IP_TTL: u32 = 1;
+// Без EDNS клиент принимает по UDP не больше 512 байт
+const MAX_UDP_RESPONSE: usize = 512;
+// Как часто сохранять порядок LRU; новые адреса сохраняются сразу после ответа
+const PERSIST_INTERVAL: Duration = Duration::from_secs(30);
+
+/// DNS сервер для клиентов на UDP и TCP. В режиме fake-ip на A и AAAA
+/// запросы отвечает адресами из пула, остальное пересылает вышестоящим серверам.
+pub async fn serve(resolver: Arc<Resolver>, db: Arc<Database>, addr: SocketAddr) -> Result<()> {
+    let socket = Arc::new(UdpSocket::bind(addr).await?);
+    let listener = TcpListener::bind(addr).await?;
+    let mode = if resolver.fake_ips().is_some() { "fake-ip" } else { "normal" };
+    log::info!("🧭 DNS server listening on {} ({} mode)", addr, mode);
+
+    // Пишет в базу одна задача, поэтому порядок LRU не перепутается
+    let allocated = Arc::new(Notify::new());
+    if resolver.fake_ips().is_some() {
+        let resolver = resolver.clone();
+        let allocated = allocated.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PERSIST_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = allocated.notified() => {}
+                }
+                if let Some(pool) = resolver.fake_ips() {
+                    persist(pool, &db).await;
+                }
+            }
+        });
+    }
+
+    {
+        let resolver = resolver.clone();
+        let allocated = allocated.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        log::error!("DNS server failed to accept a TCP connection: {}", e);
+                        continue;
+                    }
+                };
+                let resolver = resolver.clone();
+                let allocated = allocated.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = serve_tcp(stream, &resolver, &allocated).await {
+                        log::debug!("DNS connection from {} closed: {}", peer, e);
+                    }
+                });
+            }
+        });
+    }
+
+    let mut buf = vec![0u8; 65535];
+    loop {
+        let (n, peer) = socket.recv_from(&mut buf).await?;
+        let request = buf[..n].to_vec();
+        let socket = socket.clone();
+        let resolver = resolver.clone();
+        let allocated = allocated.clone();
+        tokio::spawn(async move {
+            let Some(mut response) = handle(&resolver, &allocated, &request).await else { return };
+            // Запрос без записей в additional точно без EDNS
+            if response.len() > MAX_UDP_RESPONSE && request[10..12] == [0, 0] {
+                match Message::decode(&request).and_then(|query| query.truncated_reply().encode()) {
+                    Ok(truncated) => response = truncated,
+                    Err(_) => return,
+                }
+            }
+            if let Err(e) = socket.send_to(&response, peer).await {
+                log::debug!("Failed to send DNS response to {}: {}", peer, e);
+            }
+        });
+    }
+}
+
+// Запросы по TCP предваряются длиной в два байта, соединение можно переиспользовать
+async fn serve_tcp(mut stream: TcpStream, resolver: &Resolver, allocated: &Notify) -> Result<()> {
+    loop {
+        let len = match stream.read_u16().await {
+            Ok(len) => len as usize,
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut request = vec![0u8; len];
+        stream.read_exact(&mut request).await?;
+
+        let Some(response) = handle(resolver, allocated, &request).await else { return Ok(()) };
+        let mut framed = Vec::with_capacity(response.len() + 2);
+        framed.extend_from_slice(&(response.len() as u16).to_be_bytes());
+        framed.extend_from_slice(&response);
+        stream.write_all(&framed).await?;
+    }
+}
+
+// Повреждённые запросы и чужие ответы остаются без ответа
+async fn handle(resolver: &Resolver, allocated: &Notify, request: &[u8]) -> Option<Vec<u8>> {
+    let query = Message::decode(request).ok().filter(|query| !query.is_response())?;
+    match answer(resolver, allocated, &query, request).await {
+        Ok(response) => Some(response),
+        Err(e) => {
+            let name = query.questions.first().map(|question| question.name.as_str()).unwrap_or_default();
+            log::warn!("DNS query for {} failed: {:#}", name, e);
+            query.reply(RCODE_SERVER_FAILURE, Vec::new()).encode().ok()
+        }
+    }
+}
+
+async fn answer(resolver: &Resolver, allocated: &Notify, query: &Message, request: &[u8]) -> Result<Vec<u8>> {
+    if let (Some(pool), [question]) = (resolver.fake_ips(), query.questions.as_slice()) {
+        let name = normalize_domain(&question.name);
+        if matches!(question.qtype, TYPE_A | TYPE_AAAA) && !name.is_empty() && !pool.is_filtered(&name) {
+            // Пул одного семейства адресов, на запрос другого отвечаем без записей
+            let answers = if pool.is_ipv6() == (question.qtype == TYPE_AAAA) {
+                let (ip, new) = pool.allocate(&name);
+                if new {
+                    log::debug!("Fake IP {} assigned to {}", ip, name);
+                    // Ответ не ждёт базу, адрес сохранит фоновая задача
+                    allocated.notify_one();
+                }
+                vec![Record::address(&question.name, FAKE_IP_TTL, ip)]
+            } else {
+                Vec::new()
+            };
+            return query.reply(RCODE_NO_ERROR, answers).encode();
+        }
+    }
+    resolver.forward(request).await
+}
+
+async fn persist(pool: &FakeIpPool, db: &Database) {
+    let mappings = pool.take_dirty();
+    if mappings.is_empty() {
+        return;
+    }
+    if let Err(e) = db.save_fake_ips(&mappings).await {
+        log::warn!("Failed to save {} fake IP mappings: {}", mappings.len(), e);
+    }
+}
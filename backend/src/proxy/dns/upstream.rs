@@ -0,0 +1,256 @@
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpStream, UdpSocket};
+use anyhow::{anyhow, Context, Result};
+use super::message::Message;
+use crate::proxy::http::{self, BodyKind};
+use crate::proxy::outbound::tls::{self, TlsOptions};
+
+// Больше DNS сообщение быть не может: длина в TCP задаётся двумя байтами
+const MAX_MESSAGE_SIZE: usize = 65535;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+    Udp,
+    Tcp,
+    Tls,
+    Https,
+}
+
+/// Вышестоящий DNS сервер.
+#[derive(Debug, Clone)]
+pub struct Upstream {
+    protocol: Protocol,
+    pub host: String,
+    pub port: u16,
+    // Путь запроса DoH
+    path: String,
+    tls: TlsOptions,
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let host = if self.host.contains(':') { format!("[{}]", self.host) } else { self.host.clone() };
+        match self.protocol {
+            Protocol::Udp => write!(f, "{}:{}", host, self.port),
+            Protocol::Tcp => write!(f, "tcp://{}:{}", host, self.port),
+            Protocol::Tls => write!(f, "tls://{}:{}", host, self.port),
+            Protocol::Https => write!(f, "https://{}:{}{}", host, self.port, self.path),
+        }
+    }
+}
+
+impl Upstream {
+    /// Разбирает адрес сервера: `8.8.8.8`, `udp://8.8.8.8:53`, `tcp://8.8.8.8`,
+    /// `tls://1.1.1.1:853`, `https://1.1.1.1/dns-query`. Параметры TLS
+    /// передаются после `#`: `skip-cert-verify=true`, `sni=...`, `ca-file=...`.
+    pub fn parse(spec: &str) -> Result<Self> {
+        let spec = spec.trim();
+        let with_scheme = if spec.contains("://") { spec.to_string() } else { format!("udp://{}", spec) };
+        let url = url::Url::parse(&with_scheme).with_context(|| format!("Invalid nameserver {}", spec))?;
+
+        let (protocol, default_port) = match url.scheme() {
+            "udp" => (Protocol::Udp, 53),
+            "tcp" => (Protocol::Tcp, 53),
+            "tls" => (Protocol::Tls, 853),
+            "https" => (Protocol::Https, 443),
+            scheme => return Err(anyhow!("Unsupported nameserver scheme {} in {}", scheme, spec)),
+        };
+        let host = url.host_str()
+            .ok_or_else(|| anyhow!("Nameserver {} has no host", spec))?
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string();
+        if matches!(protocol, Protocol::Udp | Protocol::Tcp) && host.parse::<IpAddr>().is_err() {
+            return Err(anyhow!("Plain nameserver {} must be an IP address", spec));
+        }
+        let path = match (protocol, url.path()) {
+            (Protocol::Https, "" | "/") => "/dns-query".to_string(),
+            (Protocol::Https, path) => match url.query() {
+                Some(query) => format!("{}?{}", path, query),
+                None => path.to_string(),
+            },
+            _ => String::new(),
+        };
+
+        let mut tls = TlsOptions::default();
+        if protocol == Protocol::Https {
+            tls.alpn = vec!["http/1.1".to_string()];
+        }
+        for (key, value) in url::form_urlencoded::parse(url.fragment().unwrap_or_default().as_bytes()) {
+            match key.as_ref() {
+                "skip-cert-verify" => tls.skip_cert_verify = value == "true" || value == "1",
+                "sni" => tls.sni = Some(value.into_owned()),
+                "ca-file" => tls.ca_file = Some(value.into_owned()),
+                _ => log::warn!("Ignoring unknown nameserver option {} in {}", key, spec),
+            }
+        }
+
+        Ok(Self {
+            protocol,
+            host,
+            port: url.port().unwrap_or(default_port),
+            path,
+            tls,
+        })
+    }
+
+    /// Адрес сервера, если он задан IP, а не именем.
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.host.parse().ok()
+    }
+
+    /// Сервер, который можно опросить без разрешения его имени.
+    pub fn is_plain(&self) -> bool {
+        matches!(self.protocol, Protocol::Udp | Protocol::Tcp)
+    }
+
+    /// Отправляет запрос серверу по адресу `addr` и ждёт ответ с тем же ID.
+    pub async fn exchange(&self, query: &Message, addr: SocketAddr) -> Result<Message> {
+        let response = self.exchange_raw(&query.encode()?, query.id, addr).await?;
+        Message::decode(&response)
+    }
+
+    /// То же для уже закодированного запроса. Ответ возвращается как есть,
+    /// без разбора и повторного кодирования.
+    pub async fn exchange_raw(&self, request: &[u8], id: u16, addr: SocketAddr) -> Result<Vec<u8>> {
+        let response = match self.protocol {
+            Protocol::Udp => {
+                let (response, truncated) = exchange_udp(request, id, addr).await?;
+                if !truncated {
+                    return Ok(response);
+                }
+                // Ответ не поместился в датаграмму, повторяем по TCP
+                exchange_stream(TcpStream::connect(addr).await?, request).await?
+            }
+            Protocol::Tcp => exchange_stream(TcpStream::connect(addr).await?, request).await?,
+            Protocol::Tls => {
+                let stream = tls::connect(TcpStream::connect(addr).await?, &self.host, &self.tls).await?;
+                exchange_stream(stream, request).await?
+            }
+            Protocol::Https => {
+                let stream = tls::connect(TcpStream::connect(addr).await?, &self.host, &self.tls).await?;
+                self.exchange_https(stream, request).await?
+            }
+        };
+
+        let header = Message::decode(&response)?;
+        if header.id != id || !header.is_response() {
+            return Err(anyhow!("Nameserver {} sent a mismatched response", self));
+        }
+        Ok(response)
+    }
+
+    // DoH по RFC 8484: POST application/dns-message поверх HTTP/1.1
+    async fn exchange_https<S>(&self, stream: S, request: &[u8]) -> Result<Vec<u8>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut stream = BufReader::new(stream);
+        let authority = match (self.host.contains(':'), self.port) {
+            (true, 443) => format!("[{}]", self.host),
+            (true, port) => format!("[{}]:{}", self.host, port),
+            (false, 443) => self.host.clone(),
+            (false, port) => format!("{}:{}", self.host, port),
+        };
+        let head = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: StealthCat\r\nAccept: application/dns-message\r\nContent-Type: application/dns-message\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.path, authority, request.len()
+        );
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(request).await?;
+        stream.flush().await?;
+
+        let response = http::read_response_head(&mut stream).await?;
+        if response.status != 200 {
+            return Err(anyhow!("Nameserver {} responded with HTTP {}", self, response.status));
+        }
+        let body = match response.body_kind("POST")? {
+            BodyKind::None => Vec::new(),
+            BodyKind::Length(len) if len as usize > MAX_MESSAGE_SIZE => {
+                return Err(anyhow!("DoH response of {} bytes is too large", len));
+            }
+            BodyKind::Length(len) => {
+                let mut body = vec![0u8; len as usize];
+                stream.read_exact(&mut body).await?;
+                body
+            }
+            BodyKind::UntilClose => {
+                let mut body = Vec::new();
+                (&mut stream).take(MAX_MESSAGE_SIZE as u64 + 1).read_to_end(&mut body).await?;
+                body
+            }
+            BodyKind::Chunked => read_chunked(&mut stream).await?,
+        };
+        if body.len() > MAX_MESSAGE_SIZE {
+            return Err(anyhow!("DoH response is too large"));
+        }
+        Ok(body)
+    }
+}
+
+// Возвращает ответ и признак того, что он обрезан
+async fn exchange_udp(request: &[u8], id: u16, addr: SocketAddr) -> Result<(Vec<u8>, bool)> {
+    let bind: SocketAddr = if addr.is_ipv4() {
+        (std::net::Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (std::net::Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(addr).await?;
+    socket.send(request).await?;
+
+    let mut buf = vec![0u8; MAX_MESSAGE_SIZE];
+    loop {
+        let n = socket.recv(&mut buf).await?;
+        // Посторонние и повреждённые датаграммы пропускаем
+        match Message::decode(&buf[..n]) {
+            Ok(response) if response.id == id && response.is_response() => {
+                return Ok((buf[..n].to_vec(), response.is_truncated()));
+            }
+            _ => continue,
+        }
+    }
+}
+
+// DNS поверх TCP и TLS: сообщение предваряется длиной в два байта
+async fn exchange_stream<S>(mut stream: S, request: &[u8]) -> Result<Vec<u8>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let len = u16::try_from(request.len()).map_err(|_| anyhow!("DNS query is too large"))?;
+    let mut framed = Vec::with_capacity(request.len() + 2);
+    framed.extend_from_slice(&len.to_be_bytes());
+    framed.extend_from_slice(request);
+    stream.write_all(&framed).await?;
+    stream.flush().await?;
+
+    let len = stream.read_u16().await.context("Nameserver closed the connection")? as usize;
+    let mut response = vec![0u8; len];
+    stream.read_exact(&mut response).await?;
+    Ok(response)
+}
+
+async fn read_chunked<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).await?;
+        let size = line.trim().split(';').next().unwrap_or_default();
+        let size = usize::from_str_radix(size, 16)
+            .map_err(|_| anyhow!("Invalid chunk size in DoH response: {}", line.trim()))?;
+        if size == 0 {
+            return Ok(body);
+        }
+        if body.len() + size > MAX_MESSAGE_SIZE {
+            return Err(anyhow!("DoH response is too large"));
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..]).await?;
+        // CRLF после данных чанка
+        let mut crlf = [0u8; 2];
+        reader.read_exact(&mut crlf).await?;
+    }
+}
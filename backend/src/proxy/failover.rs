@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::sync::broadcast;
+use super::health::CheckResult;
+use super::Router;
+use crate::config::FailoverSettings;
+use crate::database::Database;
+use crate::models::{LogEntry, LogLevel, ProxyServerV2};
+
+// Сколько событий хранится для отстающих подписчиков
+const EVENT_CAPACITY: usize = 64;
+
+/// Смена сервера, через который идёт трафик действия proxy.
+#[derive(Debug, Clone, Serialize)]
+pub struct FailoverEvent {
+    pub timestamp: DateTime<Utc>,
+    pub from_id: String,
+    pub from_name: String,
+    pub to_id: String,
+    pub to_name: String,
+    // true - возврат на выбранный пользователем сервер
+    pub restored: bool,
+    pub reason: String,
+}
+
+impl FailoverEvent {
+    pub fn message(&self) -> String {
+        if self.restored {
+            format!("Restored server \"{}\" instead of \"{}\": {}", self.to_name, self.from_name, self.reason)
+        } else {
+            format!("Failover from \"{}\" to \"{}\": {}", self.from_name, self.to_name, self.reason)
+        }
+    }
+}
+
+/// Состояние failover, общее для всех снимков маршрутизации.
+#[derive(Debug)]
+pub struct Failover {
+    settings: FailoverSettings,
+    // Сервер, который временно заменяет выбранный
+    replacement: RwLock<Option<String>>,
+    dial_failures: Mutex<HashMap<String, u32>>,
+    events: broadcast::Sender<FailoverEvent>,
+}
+
+impl Failover {
+    pub fn new(settings: FailoverSettings) -> Self {
+        Self {
+            settings,
+            replacement: RwLock::default(),
+            dial_failures: Mutex::default(),
+            events: broadcast::channel(EVENT_CAPACITY).0,
+        }
+    }
+
+    pub fn replacement(&self) -> Option<String> {
+        self.replacement.read().unwrap().clone()
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<FailoverEvent> {
+        self.events.subscribe()
+    }
+
+    /// Забывает замену: пользователь сам выбрал сервер.
+    pub fn reset(&self) {
+        *self.replacement.write().unwrap() = None;
+    }
+
+    /// Учитывает результат подключения через сервер. После `max-dial-failures`
+    /// неудач подряд текущий сервер заменяется.
+    pub fn report_dial(&self, router: &Router, server: &ProxyServerV2, result: Result<(), &anyhow::Error>) {
+        let streak = {
+            let mut failures = self.dial_failures.lock().unwrap();
+            match result {
+                Ok(()) => {
+                    failures.remove(&server.id);
+                    return;
+                }
+                Err(_) => {
+                    let streak = failures.entry(server.id.clone()).or_default();
+                    *streak = streak.saturating_add(1);
+                    *streak
+                }
+            }
+        };
+        if let Err(e) = result {
+            if streak >= self.settings.max_dial_failures.max(1) {
+                self.switch(router, &server.id, format!("{} failed connections in a row: {:#}", streak, e));
+            }
+        }
+    }
+
+    /// Реагирует на фоновую проверку: заменяет текущий сервер, если он её
+    /// не прошёл, и возвращает выбранный, когда тот снова работает. Других
+    /// путей назад нет: без health-check замена остаётся до выбора сервера
+    /// через API.
+    pub fn on_health_check(&self, router: &Router, results: &[CheckResult]) {
+        for result in results.iter().filter(|r| r.error.is_none()) {
+            self.dial_failures.lock().unwrap().remove(&result.server_id);
+        }
+
+        if let Some(current) = router.current_server() {
+            let failed = results.iter().find(|r| r.server_id == current.id && r.error.is_some());
+            if let Some(CheckResult { error: Some(error), .. }) = failed {
+                self.switch(router, &current.id, format!("health check failed: {}", error));
+                return;
+            }
+        }
+
+        if !self.settings.restore || self.replacement().is_none() {
+            return;
+        }
+        let Some(primary) = router.primary_server() else { return };
+        if results.iter().any(|r| r.server_id == primary.id && r.error.is_none()) {
+            self.switch_to(router, primary, true, "server is up again".to_string());
+        }
+    }
+
+    /// Серверы, на которые можно переключиться, в порядке предпочтения.
+    pub fn priority_servers<'a>(&self, router: &'a Router) -> Vec<&'a ProxyServerV2> {
+        if self.settings.priority.is_empty() {
+            return router.servers.iter().filter(|s| s.active).collect();
+        }
+        self.settings.priority.iter()
+            .filter_map(|name| {
+                let server = router.find_server(name);
+                if server.is_none() {
+                    log::warn!("Failover priority references unknown server \"{}\"", name);
+                }
+                server
+            })
+            .collect()
+    }
+
+    fn switch(&self, router: &Router, failed_id: &str, reason: String) {
+        // Серверы из явных правил и групп failover не заменяет
+        let in_use = router.current_server().is_some_and(|current| current.id == failed_id);
+        if !self.settings.enable || !in_use {
+            return;
+        }
+        let next = {
+            let failures = self.dial_failures.lock().unwrap();
+            let max_failures = self.settings.max_dial_failures.max(1);
+            self.priority_servers(router).into_iter().find(|server| {
+                // Сервер считается живым до max-failures неудач, но тот, что не
+                // прошёл последнюю проверку, не годится в замену: иначе трафик
+                // перескакивает между упавшими серверами
+                let health = router.health.health_of(server);
+                server.id != failed_id
+                    && health.is_alive()
+                    && health.failure_streak == 0
+                    && failures.get(&server.id).is_none_or(|&streak| streak < max_failures)
+            })
+        };
+        match next {
+            Some(next) => {
+                let restored = router.primary_server().is_some_and(|primary| primary.id == next.id);
+                self.switch_to(router, next, restored, reason);
+            }
+            None => log::warn!("No healthy server to fail over to ({})", reason),
+        }
+    }
+
+    fn switch_to(&self, router: &Router, next: &ProxyServerV2, restored: bool, reason: String) {
+        let event = {
+            let mut replacement = self.replacement.write().unwrap();
+            // Другое соединение могло переключить сервер раньше
+            let Some(current) = current_server(router, replacement.as_deref()) else { return };
+            if current.id == next.id {
+                return;
+            }
+            *replacement = (!restored).then(|| next.id.clone());
+            FailoverEvent {
+                timestamp: Utc::now(),
+                from_id: current.id.clone(),
+                from_name: current.name.clone(),
+                to_id: next.id.clone(),
+                to_name: next.name.clone(),
+                restored,
+                reason,
+            }
+        };
+
+        log::warn!("🔀 {}", event.message());
+        // Ошибка означает только, что подписчиков нет
+        let _ = self.events.send(event);
+    }
+}
+
+pub(super) fn current_server<'a>(router: &'a Router, replacement: Option<&str>) -> Option<&'a ProxyServerV2> {
+    replacement
+        .and_then(|id| router.servers.iter().find(|s| s.id == id))
+        .or_else(|| router.primary_server())
+}
+
+/// Сохраняет события failover в журнал.
+pub async fn record_events(mut events: broadcast::Receiver<FailoverEvent>, db: Arc<Database>) {
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                log::warn!("Failover journal skipped {} events", skipped);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        let entry = LogEntry {
+            timestamp: event.timestamp,
+            level: if event.restored { LogLevel::INFO } else { LogLevel::WARN },
+            message: event.message(),
+            server_id: Some(event.to_id.clone()),
+        };
+        if let Err(e) = db.insert_log(&entry).await {
+            log::error!("Failed to save failover event: {}", e);
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use anyhow::{anyhow, Result};
+use crate::models::{LoadBalanceStrategy, ProxyGroup, ProxyGroupType, ProxyServerV2};
+
+/// Участник группы после разрешения ссылки.
+#[derive(Debug, Clone, Copy)]
+pub enum Member<'a> {
+    Direct,
+    Block,
+    Server(&'a ProxyServerV2),
+}
+
+/// Участник с данными о его состоянии для выбора.
+#[derive(Debug, Clone, Copy)]
+pub struct Candidate<'a> {
+    // Ссылка из группы, как она записана в proxies
+    pub name: &'a str,
+    pub member: Member<'a>,
+    pub latency_ms: Option<u32>,
+    pub alive: bool,
+}
+
+impl Candidate<'_> {
+    fn is(&self, name_or_id: &str) -> bool {
+        self.name == name_or_id || matches!(self.member, Member::Server(s) if s.name == name_or_id || s.id == name_or_id)
+    }
+}
+
+/// Состояние групп, которое переживает замену снимка маршрутизации:
+/// текущий участник url-test и счётчики round-robin.
+#[derive(Debug, Default)]
+pub struct GroupState {
+    url_test: Mutex<HashMap<String, String>>,
+    round_robin: Mutex<HashMap<String, usize>>,
+}
+
+/// Разрешает ссылку из proxies: DIRECT, REJECT или сервер по имени или ID.
+pub fn resolve_member<'a>(name: &str, servers: &'a [ProxyServerV2]) -> Option<Member<'a>> {
+    match name.to_ascii_uppercase().as_str() {
+        "DIRECT" => Some(Member::Direct),
+        "REJECT" | "BLOCK" => Some(Member::Block),
+        _ => servers.iter()
+            .find(|s| s.name == name || s.id == name)
+            .map(Member::Server),
+    }
+}
+
+/// Проверяет группу перед сохранением: имя свободно, участники существуют
+/// и не являются группами, выбранный участник входит в группу.
+pub fn validate(group: &ProxyGroup, groups: &[ProxyGroup], servers: &[ProxyServerV2]) -> Result<()> {
+    let name = group.name.trim();
+    if name.is_empty() {
+        return Err(anyhow!("Group name must not be empty"));
+    }
+    if ["direct", "reject", "block"].contains(&name.to_ascii_lowercase().as_str()) {
+        return Err(anyhow!("Group name \"{}\" is reserved", name));
+    }
+    if groups.iter().any(|g| g.id != group.id && g.name == name) {
+        return Err(anyhow!("Group \"{}\" already exists", name));
+    }
+    if group.proxies.is_empty() {
+        return Err(anyhow!("Group \"{}\" has no proxies", name));
+    }
+    if let Some(unknown) = group.proxies.iter().find(|p| resolve_member(p, servers).is_none()) {
+        // Вложенных групп нет, поэтому нет и циклов
+        if unknown.as_str() == name || groups.iter().any(|g| g.name == *unknown) {
+            return Err(anyhow!("Group \"{}\" cannot contain group \"{}\": nested groups are not supported", name, unknown));
+        }
+        return Err(anyhow!("Group \"{}\" references unknown server \"{}\"", name, unknown));
+    }
+    if let Some(selected) = &group.selected {
+        let known = group.proxies.iter().any(|p| {
+            p == selected || matches!(resolve_member(p, servers), Some(Member::Server(s)) if s.id == *selected || s.name == *selected)
+        });
+        if !known {
+            return Err(anyhow!("\"{}\" is not a member of group \"{}\"", selected, name));
+        }
+    }
+    Ok(())
+}
+
+impl GroupState {
+    /// Выбирает участника группы. `destination` - адрес назначения для
+    /// consistent-hashing. Неработающие участники пропускаются, пока есть
+    /// хотя бы один работающий.
+    pub fn pick<'a>(&self, group: &ProxyGroup, candidates: &[Candidate<'a>], destination: &str) -> Result<Candidate<'a>> {
+        if candidates.is_empty() {
+            return Err(anyhow!("Group \"{}\" has no available proxies", group.name));
+        }
+        let alive: Vec<Candidate<'a>> = candidates.iter().copied().filter(|c| c.alive).collect();
+        let pool = if alive.is_empty() { candidates.to_vec() } else { alive };
+
+        let picked = match group.group_type {
+            ProxyGroupType::Select => group.selected.as_deref()
+                .and_then(|selected| candidates.iter().find(|c| c.is(selected)))
+                .copied()
+                .unwrap_or(candidates[0]),
+            ProxyGroupType::Fallback => pool[0],
+            ProxyGroupType::UrlTest => self.pick_fastest(group, &pool),
+            ProxyGroupType::LoadBalance => match group.strategy {
+                LoadBalanceStrategy::ConsistentHashing => consistent_hash(&pool, destination),
+                LoadBalanceStrategy::RoundRobin => {
+                    let mut counters = self.round_robin.lock().unwrap();
+                    let counter = counters.entry(group.id.clone()).or_default();
+                    let picked = pool[*counter % pool.len()];
+                    *counter = counter.wrapping_add(1);
+                    picked
+                }
+            },
+        };
+        Ok(picked)
+    }
+
+    // Текущий участник сохраняется, пока лучший быстрее него меньше чем на tolerance
+    fn pick_fastest<'a>(&self, group: &ProxyGroup, pool: &[Candidate<'a>]) -> Candidate<'a> {
+        let Some(fastest) = pool.iter().filter(|c| c.latency_ms.is_some()).min_by_key(|c| c.latency_ms) else {
+            return pool[0];
+        };
+        let mut current = self.url_test.lock().unwrap();
+        let kept = current.get(&group.id)
+            .and_then(|name| pool.iter().find(|c| c.name == name))
+            .filter(|c| match (c.latency_ms, fastest.latency_ms) {
+                (Some(latency), Some(best)) => latency <= best.saturating_add(group.tolerance),
+                _ => false,
+            });
+        match kept {
+            Some(kept) => *kept,
+            None => {
+                current.insert(group.id.clone(), fastest.name.to_string());
+                *fastest
+            }
+        }
+    }
+}
+
+// Rendezvous hashing: при изменении состава группы переезжают только
+// назначения выбывшего участника
+fn consistent_hash<'a>(pool: &[Candidate<'a>], destination: &str) -> Candidate<'a> {
+    *pool.iter()
+        .max_by_key(|c| {
+            let mut hasher = DefaultHasher::new();
+            destination.hash(&mut hasher);
+            c.name.hash(&mut hasher);
+            hasher.finish()
+        })
+        .expect("pool is not empty")
+}
@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use chrono::Utc;
+use futures_util::StreamExt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use anyhow::{anyhow, Context, Result};
+use super::{chain, outbound, ProxyEngine, ProxyStream};
+use crate::config::HealthCheckSettings;
+use crate::database::Database;
+use crate::models::{ProxyServerV2, ServerHealth};
+
+// Сколько серверов проверяется одновременно
+const CONCURRENT_CHECKS: usize = 16;
+const MAX_STATUS_LINE: usize = 8 * 1024;
+
+/// Результат одной проверки.
+#[derive(Debug, Clone)]
+pub struct CheckResult {
+    pub server_id: String,
+    pub server_name: String,
+    pub health: ServerHealth,
+    // Some, если сервер сменил состояние: true - поднялся, false - упал
+    pub changed: Option<bool>,
+    pub error: Option<String>,
+}
+
+/// Текущее состояние серверов, общее для всех снимков маршрутизации.
+#[derive(Debug, Default)]
+pub struct HealthTable {
+    servers: RwLock<HashMap<String, ServerHealth>>,
+}
+
+impl HealthTable {
+    pub fn get(&self, server_id: &str) -> Option<ServerHealth> {
+        self.servers.read().unwrap().get(server_id).cloned()
+    }
+
+    /// Состояние сервера: последняя проверка, иначе сохранённое в базе.
+    pub fn health_of(&self, server: &ProxyServerV2) -> ServerHealth {
+        self.get(&server.id).unwrap_or_else(|| ServerHealth::from_server(server))
+    }
+
+    /// Учитывает результат проверки. Сервер падает после `max_failures`
+    /// неудач подряд и поднимается после первой успешной проверки.
+    pub fn record(&self, server: &ProxyServerV2, latency: Result<u32, &anyhow::Error>, max_failures: u32) -> (ServerHealth, Option<bool>) {
+        let mut servers = self.servers.write().unwrap();
+        let health = servers.entry(server.id.clone())
+            .or_insert_with(|| ServerHealth::from_server(server));
+        let was_alive = health.is_alive();
+
+        health.last_check = Some(Utc::now());
+        match latency {
+            Ok(latency) => {
+                health.latency_ms = Some(latency);
+                health.failure_streak = 0;
+                health.up = Some(true);
+            }
+            Err(_) => {
+                health.latency_ms = None;
+                health.failure_streak = health.failure_streak.saturating_add(1);
+                if health.failure_streak >= max_failures.max(1) {
+                    health.up = Some(false);
+                } else if health.up.is_none() {
+                    health.up = Some(true);
+                }
+            }
+        }
+
+        let changed = (health.is_alive() != was_alive).then(|| health.is_alive());
+        (health.clone(), changed)
+    }
+}
+
+/// Фоновая проверка серверов по расписанию.
+pub struct HealthChecker {
+    engine: Arc<ProxyEngine>,
+    db: Arc<Database>,
+    settings: HealthCheckSettings,
+}
+
+impl HealthChecker {
+    pub fn new(engine: Arc<ProxyEngine>, db: Arc<Database>) -> Self {
+        let settings = engine.settings.health_check.clone();
+        Self { engine, db, settings }
+    }
+
+    /// Проверяет серверы каждые `interval` секунд, начиная сразу после запуска.
+    pub async fn run(self) {
+        let interval = Duration::from_secs(self.settings.interval.max(1));
+        log::info!("🩺 Health checks every {}s against {}", interval.as_secs(), self.settings.url);
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            self.check_all().await;
+        }
+    }
+
+    /// Одна проверка всех активных серверов и участников групп.
+    pub async fn check_all(&self) -> Vec<CheckResult> {
+        let router = self.engine.router();
+        let servers = router.checked_servers();
+        let timeout = Duration::from_millis(self.settings.timeout.max(1));
+
+        let results: Vec<CheckResult> = futures_util::stream::iter(servers)
+            .map(|server| {
+                let router = router.clone();
+                async move {
+                    let latency = url_test(&server, &router.servers, &self.settings.url, timeout).await;
+                    let (health, changed) = router.health.record(&server, latency.as_ref().copied(), self.settings.max_failures);
+                    CheckResult {
+                        server_id: server.id.clone(),
+                        server_name: server.name.clone(),
+                        health,
+                        changed,
+                        error: latency.err().map(|e| format!("{:#}", e)),
+                    }
+                }
+            })
+            .buffer_unordered(CONCURRENT_CHECKS)
+            .collect()
+            .await;
+
+        for result in &results {
+            match (result.changed, &result.error) {
+                (Some(false), Some(error)) => log::warn!(
+                    "Server \"{}\" is down after {} failed checks: {}",
+                    result.server_name, result.health.failure_streak, error
+                ),
+                (Some(true), _) => log::info!("Server \"{}\" is up again", result.server_name),
+                (_, Some(error)) => log::debug!("Health check of \"{}\" failed: {}", result.server_name, error),
+                _ => {}
+            }
+            if let Err(e) = self.db.update_server_health(&result.server_id, &result.health).await {
+                log::error!("Failed to save health of server {}: {}", result.server_id, e);
+            }
+        }
+        router.failover().on_health_check(&router, &results);
+        results
+    }
+}
+
+/// HTTP запрос к `url` через сервер; возвращает время до ответа в миллисекундах.
+/// Любой HTTP ответ означает, что сервер работает.
+pub async fn url_test(server: &ProxyServerV2, servers: &[ProxyServerV2], url: &str, timeout: Duration) -> Result<u32> {
+    let start = Instant::now();
+    tokio::time::timeout(timeout, async {
+        let (mut stream, host, path) = open_url(server, servers, url, timeout).await?;
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: StealthCat\r\nConnection: close\r\n\r\n",
+            path, host
+        );
+        stream.write_all(request.as_bytes()).await?;
+        stream.flush().await?;
+        read_status(&mut stream).await
+    })
+    .await
+    .map_err(|_| anyhow!("URL test timed out after {} ms", timeout.as_millis()))??;
+
+    Ok(start.elapsed().as_millis().min(u32::MAX as u128) as u32)
+}
+
+/// Открывает соединение к `url` через сервер, для https - с TLS поверх него.
+/// Возвращает поток, заголовок Host и путь запроса.
+pub async fn open_url(
+    server: &ProxyServerV2,
+    servers: &[ProxyServerV2],
+    url: &str,
+    connect_timeout: Duration,
+) -> Result<(ProxyStream, String, String)> {
+    let url = url::Url::parse(url).with_context(|| format!("Invalid test URL {}", url))?;
+    let https = match url.scheme() {
+        "http" => false,
+        "https" => true,
+        scheme => return Err(anyhow!("Unsupported test URL scheme {}", scheme)),
+    };
+    let host = url.host_str().ok_or_else(|| anyhow!("Test URL {} has no host", url))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']').to_string();
+    let port = url.port_or_known_default().unwrap_or(if https { 443 } else { 80 });
+    let path = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+    let authority = match url.port() {
+        Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+        None => url.host_str().unwrap_or_default().to_string(),
+    };
+
+    let hops = chain::expand(server, servers)?;
+    let stream = outbound::connect_chain(&hops, &host, port, connect_timeout).await?;
+    let stream: ProxyStream = if https {
+        let options = outbound::tls::TlsOptions {
+            alpn: vec!["http/1.1".to_string()],
+            ..Default::default()
+        };
+        Box::new(outbound::tls::connect(stream, &host, &options).await?)
+    } else {
+        stream
+    };
+    Ok((stream, authority, path))
+}
+
+/// Читает строку статуса HTTP ответа и возвращает код.
+pub async fn read_status<S: AsyncRead + Unpin>(stream: &mut S) -> Result<u16> {
+    let mut line = Vec::new();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= MAX_STATUS_LINE {
+            return Err(anyhow!("HTTP status line is too long"));
+        }
+        let byte = stream.read_u8().await.context("Connection closed before HTTP response")?;
+        line.push(byte);
+    }
+    let line = String::from_utf8_lossy(&line);
+    let mut parts = line.split_whitespace();
+    match (parts.next(), parts.next().and_then(|code| code.parse::<u16>().ok())) {
+        (Some(version), Some(code)) if version.starts_with("HTTP/") => Ok(code),
+        _ => Err(anyhow!("Invalid HTTP response: {}", line.trim_end())),
+    }
+}
@@ -0,0 +1,451 @@
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use anyhow::{anyhow, Result};
+
+// Ограничения на размер заголовков, чтобы клиент не мог забить память
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+const MAX_HEADERS: usize = 128;
+
+// Hop-by-hop заголовки (RFC 7230, 6.1), которые не передаются дальше прокси
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "proxy-connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "upgrade",
+];
+
+#[derive(Debug, Clone)]
+pub struct RequestHead {
+    pub method: String,
+    pub target: String,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ResponseHead {
+    pub version: String,
+    pub status: u16,
+    pub reason: String,
+    pub headers: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyKind {
+    None,
+    Length(u64),
+    Chunked,
+    UntilClose,
+}
+
+impl RequestHead {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    pub fn body_kind(&self) -> Result<BodyKind> {
+        // RFC 9112, 6.1: сервер и прокси могут понять длину тела по-разному,
+        // это путь к request smuggling
+        if self.header("transfer-encoding").is_some() && self.header("content-length").is_some() {
+            return Err(anyhow!("Request has both Transfer-Encoding and Content-Length"));
+        }
+        if self.header("transfer-encoding").is_some() {
+            // RFC 9112, 6.3: если последнее кодирование не chunked, длину тела
+            // запроса определить нельзя
+            if !is_chunked(&self.headers) {
+                return Err(anyhow!("Request Transfer-Encoding does not end with chunked"));
+            }
+            return Ok(BodyKind::Chunked);
+        }
+        match content_length(&self.headers)? {
+            Some(len) => Ok(BodyKind::Length(len)),
+            // У запроса без Content-Length и chunked тела нет
+            None => Ok(BodyKind::None),
+        }
+    }
+
+    pub fn wants_keep_alive(&self) -> bool {
+        keep_alive(&self.version, &self.headers)
+    }
+
+    pub fn is_upgrade(&self) -> bool {
+        self.header("upgrade").is_some() && connection_has(&self.headers, "upgrade")
+    }
+}
+
+impl ResponseHead {
+    pub fn body_kind(&self, request_method: &str) -> Result<BodyKind> {
+        if request_method.eq_ignore_ascii_case("HEAD")
+            || (100..200).contains(&self.status)
+            || self.status == 204
+            || self.status == 304
+        {
+            return Ok(BodyKind::None);
+        }
+        if is_chunked(&self.headers) {
+            return Ok(BodyKind::Chunked);
+        }
+        match content_length(&self.headers)? {
+            Some(len) => Ok(BodyKind::Length(len)),
+            None => Ok(BodyKind::UntilClose),
+        }
+    }
+
+    pub fn wants_keep_alive(&self) -> bool {
+        keep_alive(&self.version, &self.headers)
+    }
+}
+
+/// Читает заголовок HTTP запроса. Возвращает `None`, если клиент закрыл
+/// соединение до начала нового запроса.
+pub async fn read_request_head<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<RequestHead>> {
+    let lines = match read_head_lines(reader).await? {
+        Some(lines) => lines,
+        None => return Ok(None),
+    };
+
+    let mut parts = lines[0].split(' ').filter(|p| !p.is_empty());
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(m), Some(t), Some(v), None) => (m, t, v),
+        _ => return Err(anyhow!("Malformed request line: {}", lines[0])),
+    };
+    if !method.bytes().all(is_token_byte) {
+        return Err(anyhow!("Invalid request method: {}", method));
+    }
+    if !version.starts_with("HTTP/1.") {
+        return Err(anyhow!("Unsupported HTTP version: {}", version));
+    }
+
+    Ok(Some(RequestHead {
+        method: method.to_string(),
+        target: target.to_string(),
+        version: version.to_string(),
+        headers: parse_headers(&lines[1..])?,
+    }))
+}
+
+pub async fn read_response_head<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<ResponseHead> {
+    let lines = read_head_lines(reader).await?
+        .ok_or_else(|| anyhow!("Upstream closed connection before sending a response"))?;
+
+    let mut parts = lines[0].splitn(3, ' ');
+    let version = parts.next().unwrap_or_default();
+    if !version.starts_with("HTTP/1.") {
+        return Err(anyhow!("Malformed status line: {}", lines[0]));
+    }
+    let status = parts.next()
+        .and_then(|s| s.parse::<u16>().ok())
+        .ok_or_else(|| anyhow!("Malformed status line: {}", lines[0]))?;
+
+    Ok(ResponseHead {
+        version: version.to_string(),
+        status,
+        reason: parts.next().unwrap_or_default().to_string(),
+        headers: parse_headers(&lines[1..])?,
+    })
+}
+
+async fn read_head_lines<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<Vec<String>>> {
+    let mut lines = Vec::new();
+    let mut total = 0;
+    let mut buf = Vec::new();
+
+    loop {
+        buf.clear();
+        let n = reader.take((MAX_HEAD_SIZE - total + 1) as u64).read_until(b'\n', &mut buf).await?;
+        if n == 0 {
+            if lines.is_empty() && total == 0 {
+                return Ok(None);
+            }
+            return Err(anyhow!("Unexpected EOF while reading HTTP head"));
+        }
+        total += n;
+        if total > MAX_HEAD_SIZE {
+            return Err(anyhow!("HTTP head exceeds {} bytes", MAX_HEAD_SIZE));
+        }
+        if !buf.ends_with(b"\n") {
+            return Err(anyhow!("Unexpected EOF while reading HTTP head"));
+        }
+
+        let line = String::from_utf8_lossy(&buf);
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            // RFC 7230, 3.5: пустые строки перед request-line игнорируются
+            if lines.is_empty() {
+                continue;
+            }
+            return Ok(Some(lines));
+        }
+        if lines.len() > MAX_HEADERS {
+            return Err(anyhow!("Too many HTTP headers"));
+        }
+        lines.push(line.to_string());
+    }
+}
+
+fn parse_headers(lines: &[String]) -> Result<Vec<(String, String)>> {
+    let mut headers: Vec<(String, String)> = Vec::with_capacity(lines.len());
+    for line in lines {
+        // obs-fold: продолжение предыдущего заголовка
+        if line.starts_with(' ') || line.starts_with('\t') {
+            match headers.last_mut() {
+                Some((_, value)) => {
+                    value.push(' ');
+                    value.push_str(line.trim());
+                    continue;
+                }
+                None => return Err(anyhow!("Invalid header continuation")),
+            }
+        }
+        let (name, value) = line.split_once(':')
+            .ok_or_else(|| anyhow!("Malformed header line: {}", line))?;
+        if name.is_empty() || !name.bytes().all(is_token_byte) {
+            return Err(anyhow!("Invalid header name: {}", name));
+        }
+        headers.push((name.to_string(), value.trim().to_string()));
+    }
+    Ok(headers)
+}
+
+fn is_token_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+pub fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers.iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+fn connection_has(headers: &[(String, String)], token: &str) -> bool {
+    headers.iter()
+        .filter(|(n, _)| n.eq_ignore_ascii_case("connection") || n.eq_ignore_ascii_case("proxy-connection"))
+        .flat_map(|(_, v)| v.split(','))
+        .any(|t| t.trim().eq_ignore_ascii_case(token))
+}
+
+fn keep_alive(version: &str, headers: &[(String, String)]) -> bool {
+    if connection_has(headers, "close") {
+        return false;
+    }
+    version != "HTTP/1.0" || connection_has(headers, "keep-alive")
+}
+
+fn is_chunked(headers: &[(String, String)]) -> bool {
+    headers.iter()
+        .filter(|(n, _)| n.eq_ignore_ascii_case("transfer-encoding"))
+        .flat_map(|(_, v)| v.split(','))
+        .next_back()
+        .is_some_and(|t| t.trim().eq_ignore_ascii_case("chunked"))
+}
+
+fn content_length(headers: &[(String, String)]) -> Result<Option<u64>> {
+    let mut result = None;
+    for (_, value) in headers.iter().filter(|(n, _)| n.eq_ignore_ascii_case("content-length")) {
+        let len = value.trim().parse::<u64>()
+            .map_err(|_| anyhow!("Invalid Content-Length: {}", value))?;
+        // Разные значения Content-Length - признак request smuggling
+        if result.is_some_and(|prev| prev != len) {
+            return Err(anyhow!("Conflicting Content-Length headers"));
+        }
+        result = Some(len);
+    }
+    Ok(result)
+}
+
+/// Удаляет hop-by-hop заголовки, включая перечисленные в `Connection`.
+pub fn strip_hop_by_hop(headers: &[(String, String)]) -> Vec<(String, String)> {
+    let listed: Vec<String> = headers.iter()
+        .filter(|(n, _)| n.eq_ignore_ascii_case("connection") || n.eq_ignore_ascii_case("proxy-connection"))
+        .flat_map(|(_, v)| v.split(',').map(|t| t.trim().to_ascii_lowercase()))
+        .collect();
+
+    headers.iter()
+        .filter(|(name, _)| {
+            let name = name.to_ascii_lowercase();
+            !HOP_BY_HOP_HEADERS.contains(&name.as_str()) && !listed.contains(&name)
+        })
+        .cloned()
+        .collect()
+}
+
+/// Разбирает absolute-form URI (`http://host:port/path`) в host, port и origin-form.
+pub fn split_absolute_uri(uri: &str) -> Result<(String, u16, String)> {
+    let rest = match uri.get(..7) {
+        Some(scheme) if scheme.eq_ignore_ascii_case("http://") => &uri[7..],
+        _ => return Err(anyhow!("Unsupported URI scheme: {}", uri)),
+    };
+
+    let (authority, path) = match rest.find(['/', '?']) {
+        Some(pos) => (&rest[..pos], &rest[pos..]),
+        None => (rest, "/"),
+    };
+    // Userinfo в URI прокси игнорируем
+    let authority = authority.rsplit_once('@').map_or(authority, |(_, a)| a);
+    if authority.is_empty() {
+        return Err(anyhow!("Missing host in URI: {}", uri));
+    }
+
+    let (host, port) = split_authority(authority, 80)?;
+    let path = if path.starts_with('?') { format!("/{}", path) } else { path.to_string() };
+    Ok((host, port, path))
+}
+
+/// Разбирает `host[:port]`, в том числе IPv6 в квадратных скобках.
+pub fn split_authority(authority: &str, default_port: u16) -> Result<(String, u16)> {
+    if let Some(rest) = authority.strip_prefix('[') {
+        let (host, tail) = rest.split_once(']')
+            .ok_or_else(|| anyhow!("Invalid IPv6 authority: {}", authority))?;
+        let port = match tail.strip_prefix(':') {
+            Some(p) => p.parse::<u16>().map_err(|_| anyhow!("Invalid port number"))?,
+            None if tail.is_empty() => default_port,
+            None => return Err(anyhow!("Invalid authority: {}", authority)),
+        };
+        return Ok((host.to_string(), port));
+    }
+
+    match authority.rsplit_once(':') {
+        // Голый IPv6 без скобок и без порта
+        Some((host, _)) if host.contains(':') => Ok((authority.to_string(), default_port)),
+        Some((host, port)) => {
+            let port = port.parse::<u16>().map_err(|_| anyhow!("Invalid port number"))?;
+            Ok((host.to_string(), port))
+        }
+        None => Ok((authority.to_string(), default_port)),
+    }
+}
+
+pub async fn write_request_head<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    method: &str,
+    path: &str,
+    version: &str,
+    headers: &[(String, String)],
+) -> Result<()> {
+    let mut head = format!("{} {} {}\r\n", method, path, version);
+    push_headers(&mut head, headers);
+    writer.write_all(head.as_bytes()).await?;
+    Ok(())
+}
+
+pub async fn write_response_head<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    response: &ResponseHead,
+    headers: &[(String, String)],
+) -> Result<()> {
+    // Прокси отвечает клиенту от имени своей версии протокола
+    let mut head = format!("HTTP/1.1 {} {}\r\n", response.status, response.reason);
+    push_headers(&mut head, headers);
+    writer.write_all(head.as_bytes()).await?;
+    Ok(())
+}
+
+fn push_headers(head: &mut String, headers: &[(String, String)]) {
+    for (name, value) in headers {
+        head.push_str(name);
+        head.push_str(": ");
+        head.push_str(value);
+        head.push_str("\r\n");
+    }
+    head.push_str("\r\n");
+}
+
+/// Пересылает тело сообщения, сохраняя исходное кодирование (chunked передаётся как есть).
+pub async fn copy_body<R, W>(reader: &mut R, writer: &mut W, kind: BodyKind) -> Result<u64>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let copied = match kind {
+        BodyKind::None => 0,
+        BodyKind::Length(len) => copy_exact(reader, writer, len).await?,
+        BodyKind::UntilClose => tokio::io::copy(reader, writer).await?,
+        BodyKind::Chunked => copy_chunked(reader, writer).await?,
+    };
+    writer.flush().await?;
+    Ok(copied)
+}
+
+async fn copy_exact<R, W>(reader: &mut R, writer: &mut W, len: u64) -> Result<u64>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let copied = tokio::io::copy(&mut reader.take(len), writer).await?;
+    if copied != len {
+        return Err(anyhow!("Unexpected EOF: body truncated at {} of {} bytes", copied, len));
+    }
+    Ok(copied)
+}
+
+async fn copy_chunked<R, W>(reader: &mut R, writer: &mut W) -> Result<u64>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut total = 0;
+    let mut line = Vec::new();
+
+    loop {
+        line.clear();
+        read_chunk_line(reader, &mut line).await?;
+        writer.write_all(&line).await?;
+
+        let size_str = String::from_utf8_lossy(&line);
+        let size_str = size_str.trim_end().split(';').next().unwrap_or_default().trim();
+        let size = u64::from_str_radix(size_str, 16)
+            .map_err(|_| anyhow!("Invalid chunk size: {}", size_str))?;
+
+        if size == 0 {
+            // Трейлеры до пустой строки
+            loop {
+                line.clear();
+                read_chunk_line(reader, &mut line).await?;
+                writer.write_all(&line).await?;
+                if line == b"\r\n" || line == b"\n" {
+                    return Ok(total);
+                }
+            }
+        }
+
+        // Данные чанка вместе с завершающим CRLF
+        copy_exact(reader, writer, size).await?;
+        line.clear();
+        read_chunk_line(reader, &mut line).await?;
+        if line != b"\r\n" && line != b"\n" {
+            return Err(anyhow!("Missing CRLF after chunk data"));
+        }
+        writer.write_all(&line).await?;
+        total += size;
+    }
+}
+
+async fn read_chunk_line<R: AsyncBufRead + Unpin>(reader: &mut R, line: &mut Vec<u8>) -> Result<()> {
+    let n = reader.take(4096).read_until(b'\n', line).await?;
+    if n == 0 || !line.ends_with(b"\n") {
+        return Err(anyhow!("Unexpected EOF in chunked body"));
+    }
+    Ok(())
+}
+
+pub async fn write_simple_response<W: AsyncWrite + Unpin>(writer: &mut W, status: u16, reason: &str) -> Result<()> {
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        status, reason
+    );
+    writer.write_all(response.as_bytes()).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+pub async fn write_auth_required<W: AsyncWrite + Unpin>(writer: &mut W) -> Result<()> {
+    let response = "HTTP/1.1 407 Proxy Authentication Required\r\n\
+        Proxy-Authenticate: Basic realm=\"StealthCat\"\r\n\
+        Content-Length: 0\r\nConnection: close\r\n\r\n";
+    writer.write_all(response.as_bytes()).await?;
+    writer.flush().await?;
+    Ok(())
+}
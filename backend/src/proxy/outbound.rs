@@ -0,0 +1,289 @@
+use std::io;
+use std::time::Duration;
+use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use crate::models::{GrpcConfig, ProxyConfig, ProxyServerV2, WsConfig};
+use anyhow::{anyhow, Context, Result};
+
+mod grpc;
+mod http;
+mod shadowsocks;
+mod socks5;
+pub(super) mod tls;
+mod trojan;
+mod vless;
+mod vmess;
+mod ws;
+
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for T {}
+
+/// Поток до цели, независимо от того, через какой outbound он открыт.
+pub type ProxyStream = Box<dyn AsyncStream>;
+
+/// Открывает соединение с `host:port` через цепочку серверов (для обычного
+/// сервера - из одного). До первого подключаемся напрямую, каждый следующий
+/// открывается через поток предыдущего.
+pub async fn connect_chain(hops: &[ProxyServerV2], host: &str, port: u16, connect_timeout: Duration) -> Result<ProxyStream> {
+    let first = hops.first().ok_or_else(|| anyhow!("Proxy chain has no servers"))?;
+    let mut stream = dial((first.hostname.as_str(), first.port), connect_timeout).await
+        .with_context(|| format!("Failed to connect to {}:{}", first.hostname, first.port))?;
+    for (i, hop) in hops.iter().enumerate() {
+        let (next_host, next_port) = match hops.get(i + 1) {
+            Some(next) => (next.hostname.as_str(), next.port),
+            None => (host, port),
+        };
+        stream = connect_over(hop, stream, next_host, next_port).await?;
+    }
+    Ok(stream)
+}
+
+/// Рукопожатие outbound поверх уже открытого потока до сервера. Поток может
+/// быть TCP соединением или туннелем через другой outbound.
+pub async fn connect_over(server: &ProxyServerV2, stream: ProxyStream, host: &str, port: u16) -> Result<ProxyStream> {
+    let server_host = server.hostname.as_str();
+    match &server.config {
+        ProxyConfig::Http { username, password } => {
+            Ok(Box::new(http::connect(stream, credentials(username, password), host, port).await?))
+        }
+        ProxyConfig::Https { username, password, sni, skip_cert_verify, ca } => {
+            let stream = tls::connect(stream, server_host, &https_options(sni, *skip_cert_verify, ca)).await?;
+            Ok(Box::new(http::connect(stream, credentials(username, password), host, port).await?))
+        }
+        ProxyConfig::Socks5 { username, password } => {
+            Ok(Box::new(socks5::connect(stream, credentials(username, password), host, port).await?))
+        }
+        ProxyConfig::Shadowsocks { method, password } => {
+            shadowsocks::connect(stream, method, password, host, port).await
+        }
+        ProxyConfig::Trojan { password, .. } => {
+            trojan::connect(&trojan_options(server, password), stream, host, port).await
+        }
+        ProxyConfig::Vless(config) => vless::connect(stream, server_host, config, host, port).await,
+        ProxyConfig::Vmess(config) => vmess::connect(stream, server_host, config, host, port).await,
+        ProxyConfig::Chain { .. } => Err(anyhow!(
+            "Chain \"{}\" must be expanded into its hops before connecting", server.name
+        )),
+    }
+}
+
+fn https_options(sni: &Option<String>, skip_cert_verify: bool, ca: &Option<String>) -> tls::TlsOptions {
+    tls::TlsOptions {
+        sni: sni.clone(),
+        alpn: Vec::new(),
+        skip_cert_verify,
+        ca_file: ca.clone(),
+    }
+}
+
+/// Собирает TLS конфигурации серверов заранее, чтобы нечитаемый CA файл
+/// обнаруживался при загрузке роутера, а не при подключении.
+pub fn preload_tls(servers: &[ProxyServerV2]) -> Result<()> {
+    tls::preload(servers.iter().filter_map(|server| Some((server.name.as_str(), server_tls(server)?))))
+}
+
+// Параметры TLS сервера в том виде, в каком их получит tls::connect
+fn server_tls(server: &ProxyServerV2) -> Option<tls::TlsOptions> {
+    let (options, network) = match &server.config {
+        ProxyConfig::Https { sni, skip_cert_verify, ca, .. } => (Some(https_options(sni, *skip_cert_verify, ca)), ""),
+        ProxyConfig::Trojan { password, network, .. } => {
+            (Some(trojan_options(server, password).tls), network.as_deref().unwrap_or_default())
+        }
+        ProxyConfig::Vless(config) => (vless::tls_options(config), config.network.as_str()),
+        ProxyConfig::Vmess(config) => (vmess::tls_options(config), config.network.as_str()),
+        _ => return None,
+    };
+    let mut options = options?;
+    if network.eq_ignore_ascii_case("grpc") {
+        options.alpn = grpc_alpn();
+    }
+    Some(options)
+}
+
+// gRPC работает только поверх HTTP/2
+fn grpc_alpn() -> Vec<String> {
+    vec!["h2".to_string()]
+}
+
+async fn dial(server_addr: (&str, u16), connect_timeout: Duration) -> Result<ProxyStream> {
+    let tcp = connect_tcp(server_addr, connect_timeout).await?;
+    tcp.set_nodelay(true)?;
+    Ok(Box::new(tcp))
+}
+
+/// TCP подключение с таймаутом. Таймаут - ошибка `TimedOut`, чтобы входящий
+/// SOCKS5 ответил клиенту "host unreachable".
+pub async fn connect_tcp<A: ToSocketAddrs>(addr: A, connect_timeout: Duration) -> io::Result<TcpStream> {
+    tokio::time::timeout(connect_timeout, TcpStream::connect(addr))
+        .await
+        .map_err(|_| timed_out(connect_timeout))?
+}
+
+fn timed_out(connect_timeout: Duration) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("Connection timed out after {} ms", connect_timeout.as_millis()),
+    )
+}
+
+/// Транспорт V2Ray-протоколов поверх потока до сервера: TLS, если задан,
+/// затем tcp, WebSocket или gRPC.
+async fn transport(
+    stream: ProxyStream,
+    server_host: &str,
+    tls: Option<tls::TlsOptions>,
+    network: &str,
+    ws: Option<&WsConfig>,
+    grpc: Option<&GrpcConfig>,
+) -> Result<ProxyStream> {
+    let network = network.to_ascii_lowercase();
+    if !matches!(network.as_str(), "" | "tcp" | "ws" | "grpc") {
+        return Err(anyhow!("Transport \"{}\" is not supported yet", network));
+    }
+    let ws_host = ws.and_then(|ws| ws.host.as_deref()).filter(|h| !h.is_empty());
+    let mut authority = server_host.to_string();
+
+    let tls_enabled = tls.is_some();
+    let stream: ProxyStream = match tls {
+        Some(mut options) => {
+            // Для ws через CDN имя сервера обычно совпадает с Host
+            if options.sni.as_deref().is_none_or(str::is_empty) && network == "ws" {
+                options.sni = ws_host.map(str::to_string);
+            }
+            if network == "grpc" {
+                options.alpn = grpc_alpn();
+                if let Some(sni) = options.sni.as_deref().filter(|s| !s.is_empty()) {
+                    authority = sni.to_string();
+                }
+            }
+            Box::new(tls::connect(stream, server_host, &options).await?)
+        }
+        None => stream,
+    };
+    match network.as_str() {
+        "ws" => ws::connect(stream, server_host, &ws.cloned().unwrap_or_default()).await,
+        "grpc" => grpc::connect(stream, &authority, tls_enabled, &grpc.cloned().unwrap_or_default()).await,
+        _ => Ok(stream),
+    }
+}
+
+// Логин без пароля допустим, пустой логин означает вход без авторизации
+fn credentials<'a>(username: &'a Option<String>, password: &'a Option<String>) -> Option<(&'a str, &'a str)> {
+    username.as_deref()
+        .filter(|u| !u.is_empty())
+        .map(|u| (u, password.as_deref().unwrap_or_default()))
+}
+
+/// UDP сессия до одной цели. Отправка и приём могут идти одновременно.
+#[async_trait]
+pub trait UdpSession: Send + Sync {
+    async fn send(&self, payload: &[u8]) -> Result<()>;
+    async fn recv(&self, buf: &mut [u8]) -> Result<usize>;
+}
+
+// Прямая сессия - UDP сокет, подключённый к цели
+#[async_trait]
+impl UdpSession for UdpSocket {
+    async fn send(&self, payload: &[u8]) -> Result<()> {
+        UdpSocket::send(self, payload).await?;
+        Ok(())
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> Result<usize> {
+        Ok(UdpSocket::recv(self, buf).await?)
+    }
+}
+
+/// Читает UDP пакеты, уложенные в поток. `recv` сессии отменяется в
+/// `select!`, поэтому недочитанный пакет копится в буфере сессии, а не в
+/// локальных переменных, и дочитывается при следующем вызове.
+pub struct FrameReader<R> {
+    reader: R,
+    buf: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self { reader, buf: Vec::new() }
+    }
+
+    /// Возвращает данные следующего пакета. `parse` по началу буфера отдаёт
+    /// границы данных и конец пакета или `None`, если пакет ещё не пришёл целиком.
+    pub async fn next<F>(&mut self, parse: F) -> Result<Vec<u8>>
+    where
+        F: Fn(&[u8]) -> Result<Option<(usize, usize)>>,
+    {
+        loop {
+            if let Some((start, end)) = parse(&self.buf)? {
+                let payload = self.buf[start..end].to_vec();
+                self.buf.drain(..end);
+                return Ok(payload);
+            }
+            // read_buf при отмене не теряет данные
+            self.buf.reserve(4096);
+            if self.reader.read_buf(&mut self.buf).await? == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+        }
+    }
+}
+
+/// Может ли сервер передавать UDP.
+pub fn supports_udp(server: &ProxyServerV2) -> bool {
+    matches!(
+        server.config,
+        ProxyConfig::Shadowsocks { .. } | ProxyConfig::Trojan { .. } | ProxyConfig::Vless(_)
+    )
+}
+
+/// Открывает UDP сессию до `host:port` через прокси-сервер.
+pub async fn connect_udp(server: &ProxyServerV2, host: &str, port: u16, connect_timeout: Duration) -> Result<Box<dyn UdpSession>> {
+    let server_addr = (server.hostname.as_str(), server.port);
+    match &server.config {
+        ProxyConfig::Shadowsocks { method, password } => {
+            // Разрешение имени сервера ограничено тем же таймаутом, что и TCP подключение
+            let session = tokio::time::timeout(
+                connect_timeout,
+                shadowsocks::ShadowsocksUdp::connect(server_addr, method, password, host, port),
+            )
+            .await
+            .map_err(|_| timed_out(connect_timeout))??;
+            Ok(Box::new(session))
+        }
+        ProxyConfig::Trojan { password, .. } => {
+            let session = trojan::TrojanUdp::connect(&trojan_options(server, password), host, port, connect_timeout).await?;
+            Ok(Box::new(session))
+        }
+        ProxyConfig::Vless(config) => {
+            Ok(Box::new(vless::VlessUdp::connect(server_addr, config, host, port, connect_timeout).await?))
+        }
+        _ => Err(anyhow!(
+            "{:?} outbound does not support UDP (server \"{}\", target {}:{})",
+            server.protocol, server.name, host, port
+        )),
+    }
+}
+
+fn trojan_options<'a>(server: &'a ProxyServerV2, password: &'a str) -> trojan::TrojanOptions<'a> {
+    let (sni, alpn, skip_cert_verify, ca, network, ws, grpc) = match &server.config {
+        ProxyConfig::Trojan { sni, alpn, skip_cert_verify, ca, network, ws, grpc, .. } => {
+            (sni, alpn, *skip_cert_verify, ca, network, ws, grpc)
+        }
+        _ => unreachable!("trojan_options is only called for Trojan servers"),
+    };
+    trojan::TrojanOptions {
+        server: (server.hostname.as_str(), server.port),
+        password,
+        tls: tls::TlsOptions {
+            sni: sni.clone(),
+            alpn: alpn.clone().unwrap_or_default(),
+            skip_cert_verify,
+            ca_file: ca.clone(),
+        },
+        network: network.as_deref().unwrap_or_default(),
+        ws: ws.as_ref(),
+        grpc: grpc.as_ref(),
+    }
+}
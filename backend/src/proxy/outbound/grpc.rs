@@ -0,0 +1,283 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use bytes::{Buf, Bytes, BytesMut};
+use h2::client::ResponseFuture;
+use h2::{RecvStream, SendStream};
+use http::{Method, Request, StatusCode};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use anyhow::{anyhow, Context as _, Result};
+use super::ProxyStream;
+use crate::models::GrpcConfig;
+
+// Префикс gRPC сообщения: флаг сжатия и длина
+const MESSAGE_HEADER_LEN: usize = 5;
+// Префикс сообщения и поле protobuf: тег и длина-varint
+const MAX_OVERHEAD: usize = MESSAGE_HEADER_LEN + 1 + 5;
+const MAX_CHUNK: usize = 16 * 1024;
+
+enum Response {
+    // Сервер отвечает заголовками только вместе с первыми данными
+    Waiting(ResponseFuture),
+    Body(RecvStream),
+    Closed,
+}
+
+/// Поток поверх gRPC стрима `Tun` (или `TunMulti`): каждая запись - сообщение
+/// Hunk, чтение склеивает поля data входящих сообщений в поток байт.
+pub struct GunStream {
+    send: SendStream<Bytes>,
+    response: Response,
+    // Сырые байты gRPC сообщений и уже разобранные данные
+    incoming: BytesMut,
+    read_buf: Bytes,
+    write_closed: bool,
+}
+
+/// Открывает gun стрим поверх потока до сервера (TLS с ALPN h2 уже установлен,
+/// если нужен). `authority` - имя сервера для псевдозаголовка :authority.
+pub async fn connect(stream: ProxyStream, authority: &str, tls: bool, config: &GrpcConfig) -> Result<ProxyStream> {
+    let method = match config.mode.to_ascii_lowercase().as_str() {
+        "" | "gun" => "Tun",
+        "multi" => "TunMulti",
+        mode => return Err(anyhow!("Unknown gRPC mode \"{}\", expected gun or multi", mode)),
+    };
+    let authority = match authority.parse::<std::net::Ipv6Addr>() {
+        Ok(ip) => format!("[{}]", ip),
+        Err(_) => authority.to_string(),
+    };
+    let uri = format!(
+        "{}://{}/{}/{}",
+        if tls { "https" } else { "http" },
+        authority,
+        urlencoding::encode(&config.service_name),
+        method
+    );
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(&uri)
+        .header("content-type", "application/grpc")
+        .header("te", "trailers")
+        .header("user-agent", "grpc-go/1.48.0")
+        .body(())
+        .map_err(|e| anyhow!("Invalid gRPC request {}: {}", uri, e))?;
+
+    let (client, connection) = h2::client::handshake(stream).await
+        .context("HTTP/2 handshake failed")?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            log::debug!("gRPC connection closed: {}", e);
+        }
+    });
+    let mut client = client.ready().await.context("HTTP/2 connection is not ready")?;
+    let (response, send) = client.send_request(request, false)
+        .with_context(|| format!("Failed to open gRPC stream {}", uri))?;
+
+    Ok(Box::new(GunStream {
+        send,
+        response: Response::Waiting(response),
+        incoming: BytesMut::new(),
+        read_buf: Bytes::new(),
+        write_closed: false,
+    }))
+}
+
+fn encode_varint(mut value: usize, out: &mut BytesMut) {
+    while value >= 0x80 {
+        out.extend_from_slice(&[(value as u8) | 0x80]);
+        value >>= 7;
+    }
+    out.extend_from_slice(&[value as u8]);
+}
+
+fn decode_varint(buf: &mut &[u8]) -> io::Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = buf.split_first().ok_or_else(|| invalid_data("truncated protobuf varint"))?;
+        *buf = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid_data("protobuf varint is too long"))
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("gRPC: {}", message))
+}
+
+/// Сообщение Hunk { bytes data = 1; }. MultiHunk { repeated bytes data = 1; }
+/// с одним элементом кодируется так же.
+fn encode_hunk(data: &[u8]) -> Bytes {
+    let mut proto = BytesMut::with_capacity(data.len() + 6);
+    proto.extend_from_slice(&[0x0a]);
+    encode_varint(data.len(), &mut proto);
+    proto.extend_from_slice(data);
+
+    let mut message = BytesMut::with_capacity(MESSAGE_HEADER_LEN + proto.len());
+    message.extend_from_slice(&[0]);
+    message.extend_from_slice(&(proto.len() as u32).to_be_bytes());
+    message.extend_from_slice(&proto);
+    message.freeze()
+}
+
+/// Достаёт все поля data из Hunk или MultiHunk, остальные поля пропускает.
+fn decode_hunk(mut proto: &[u8], out: &mut BytesMut) -> io::Result<()> {
+    while !proto.is_empty() {
+        let key = decode_varint(&mut proto)?;
+        let len = match key & 0x07 {
+            0 => {
+                decode_varint(&mut proto)?;
+                0
+            }
+            1 => 8,
+            2 => decode_varint(&mut proto)? as usize,
+            5 => 4,
+            wire_type => return Err(invalid_data(&format!("unsupported protobuf wire type {}", wire_type))),
+        };
+        if len > proto.len() {
+            return Err(invalid_data("truncated protobuf field"));
+        }
+        if key == 0x0a {
+            out.extend_from_slice(&proto[..len]);
+        }
+        proto = &proto[len..];
+    }
+    Ok(())
+}
+
+fn h2_to_io_error(e: h2::Error) -> io::Error {
+    if e.is_io() {
+        e.into_io().expect("checked by is_io")
+    } else {
+        io::Error::other(e)
+    }
+}
+
+impl GunStream {
+    // Разбирает целые сообщения из incoming, возвращает true, если появились данные
+    fn decode_messages(&mut self) -> io::Result<bool> {
+        let mut data = BytesMut::new();
+        while self.incoming.len() >= MESSAGE_HEADER_LEN {
+            if self.incoming[0] != 0 {
+                return Err(invalid_data("compressed messages are not supported"));
+            }
+            let len = u32::from_be_bytes(self.incoming[1..5].try_into().unwrap()) as usize;
+            if self.incoming.len() < MESSAGE_HEADER_LEN + len {
+                break;
+            }
+            self.incoming.advance(MESSAGE_HEADER_LEN);
+            let message = self.incoming.split_to(len);
+            decode_hunk(&message, &mut data)?;
+        }
+        let decoded = !data.is_empty();
+        if decoded {
+            self.read_buf = data.freeze();
+        }
+        Ok(decoded)
+    }
+
+    fn poll_body(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<Option<&mut RecvStream>>> {
+        if let Response::Waiting(response) = &mut self.response {
+            let response = ready!(Pin::new(response).poll(cx)).map_err(h2_to_io_error)?;
+            if response.status() != StatusCode::OK {
+                return Poll::Ready(Err(io::Error::other(format!(
+                    "gRPC server responded with HTTP {}", response.status()
+                ))));
+            }
+            // Ответ только из заголовков означает ошибку вызова
+            if let Some(status) = response.headers().get("grpc-status").filter(|s| *s != "0") {
+                let message = response.headers().get("grpc-message")
+                    .and_then(|m| m.to_str().ok())
+                    .unwrap_or_default();
+                return Poll::Ready(Err(io::Error::other(format!(
+                    "gRPC call failed with status {:?}: {}", status, message
+                ))));
+            }
+            self.response = Response::Body(response.into_body());
+        }
+        match &mut self.response {
+            Response::Body(body) => Poll::Ready(Ok(Some(body))),
+            Response::Closed => Poll::Ready(Ok(None)),
+            Response::Waiting(_) => unreachable!("response is resolved above"),
+        }
+    }
+}
+
+impl AsyncRead for GunStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if !this.read_buf.is_empty() {
+                let n = buf.remaining().min(this.read_buf.len());
+                buf.put_slice(&this.read_buf.split_to(n));
+                return Poll::Ready(Ok(()));
+            }
+            if this.decode_messages()? {
+                continue;
+            }
+
+            let Some(body) = ready!(this.poll_body(cx))? else {
+                return Poll::Ready(Ok(()));
+            };
+            match ready!(body.poll_data(cx)) {
+                Some(Ok(chunk)) => {
+                    let _ = body.flow_control().release_capacity(chunk.len());
+                    this.incoming.extend_from_slice(&chunk);
+                }
+                Some(Err(e)) => return Poll::Ready(Err(h2_to_io_error(e))),
+                None => {
+                    this.response = Response::Closed;
+                    if !this.incoming.is_empty() {
+                        return Poll::Ready(Err(invalid_data("stream ended in the middle of a message")));
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl AsyncWrite for GunStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        if self.write_closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let wanted = buf.len().min(MAX_CHUNK) + MAX_OVERHEAD;
+        self.send.reserve_capacity(wanted);
+        // poll_capacity срабатывает только на прирост окна, поэтому сначала
+        // смотрим на уже выделенное
+        let capacity = loop {
+            let capacity = self.send.capacity();
+            if capacity > MAX_OVERHEAD {
+                break capacity;
+            }
+            match ready!(self.send.poll_capacity(cx)) {
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Poll::Ready(Err(h2_to_io_error(e))),
+                None => return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+            }
+        };
+
+        let n = buf.len().min(MAX_CHUNK).min(capacity - MAX_OVERHEAD);
+        self.send.send_data(encode_hunk(&buf[..n]), false).map_err(h2_to_io_error)?;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Отправкой кадров занимается задача соединения
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.write_closed {
+            self.write_closed = true;
+            self.send.send_data(Bytes::new(), true).map_err(h2_to_io_error)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
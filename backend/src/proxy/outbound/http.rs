@@ -0,0 +1,50 @@
+use std::net::IpAddr;
+use base64::{Engine as _, engine::general_purpose};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use anyhow::{anyhow, Result};
+use super::AsyncStream;
+
+// Заголовки ответа на CONNECT больше этого не ждём
+const MAX_RESPONSE_HEADER: usize = 8 * 1024;
+
+/// Открывает туннель к `host:port` методом CONNECT через уже установленное
+/// соединение с вышестоящим HTTP прокси.
+pub async fn connect<S: AsyncStream>(mut stream: S, credentials: Option<(&str, &str)>, host: &str, port: u16) -> Result<S> {
+    let authority = match host.parse::<IpAddr>() {
+        Ok(IpAddr::V6(ip)) => format!("[{}]:{}", ip, port),
+        _ => format!("{}:{}", host, port),
+    };
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", authority);
+    if let Some((username, password)) = credentials {
+        let token = general_purpose::STANDARD.encode(format!("{}:{}", username, password));
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", token));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+    stream.flush().await?;
+
+    // Читаем по байту, чтобы не захватить данные туннеля после заголовков
+    let mut response = Vec::with_capacity(256);
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_RESPONSE_HEADER {
+            return Err(anyhow!("Upstream proxy response header is too large"));
+        }
+        match stream.read_u8().await {
+            Ok(byte) => response.push(byte),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                return Err(anyhow!("Upstream proxy closed the connection during CONNECT"));
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or_default();
+    let status = status_line.split_whitespace().nth(1).and_then(|code| code.parse::<u16>().ok());
+    match status {
+        Some(200..=299) => Ok(stream),
+        Some(407) if credentials.is_some() => Err(anyhow!("Upstream proxy rejected the credentials")),
+        Some(407) => Err(anyhow!("Upstream proxy requires authentication")),
+        _ => Err(anyhow!("Upstream proxy refused CONNECT to {}: {}", authority, status_line)),
+    }
+}
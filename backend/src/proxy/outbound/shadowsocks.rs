@@ -0,0 +1,375 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use async_trait::async_trait;
+use md5::{Digest, Md5};
+use rand::RngCore;
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey};
+use ring::hkdf;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::UdpSocket;
+use anyhow::{anyhow, Result};
+use super::{ProxyStream, UdpSession};
+use crate::proxy::socks;
+
+// Максимальный размер полезной нагрузки одного чанка по спецификации AEAD
+const MAX_PAYLOAD: usize = 0x3FFF;
+const TAG_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const SUBKEY_INFO: &[u8] = b"ss-subkey";
+const READ_CHUNK: usize = 16 * 1024;
+
+/// AEAD шифры Shadowsocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cipher {
+    Aes128Gcm,
+    Aes256Gcm,
+    Chacha20IetfPoly1305,
+}
+
+impl Cipher {
+    pub fn from_method(method: &str) -> Result<Self> {
+        match method.to_ascii_lowercase().as_str() {
+            "aes-128-gcm" => Ok(Cipher::Aes128Gcm),
+            "aes-256-gcm" => Ok(Cipher::Aes256Gcm),
+            "chacha20-ietf-poly1305" | "chacha20-poly1305" => Ok(Cipher::Chacha20IetfPoly1305),
+            _ => Err(anyhow!("Unsupported Shadowsocks cipher: {}", method)),
+        }
+    }
+
+    fn algorithm(self) -> &'static aead::Algorithm {
+        match self {
+            Cipher::Aes128Gcm => &aead::AES_128_GCM,
+            Cipher::Aes256Gcm => &aead::AES_256_GCM,
+            Cipher::Chacha20IetfPoly1305 => &aead::CHACHA20_POLY1305,
+        }
+    }
+
+    /// Длина ключа; соль имеет ту же длину.
+    pub fn key_len(self) -> usize {
+        self.algorithm().key_len()
+    }
+}
+
+/// Мастер-ключ из пароля: EVP_BytesToKey с MD5, как в оригинальном Shadowsocks.
+pub fn derive_key(password: &str, key_len: usize) -> Vec<u8> {
+    let mut key = Vec::with_capacity(key_len + 16);
+    let mut prev: Vec<u8> = Vec::new();
+    while key.len() < key_len {
+        let mut hasher = Md5::new();
+        hasher.update(&prev);
+        hasher.update(password.as_bytes());
+        prev = hasher.finalize().to_vec();
+        key.extend_from_slice(&prev);
+    }
+    key.truncate(key_len);
+    key
+}
+
+struct KeyLen(usize);
+
+impl hkdf::KeyType for KeyLen {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+/// Ключ сессии: HKDF-SHA1(мастер-ключ, соль, "ss-subkey").
+fn session_key(cipher: Cipher, key: &[u8], salt: &[u8]) -> Result<LessSafeKey> {
+    let mut subkey = vec![0u8; cipher.key_len()];
+    hkdf::Salt::new(hkdf::HKDF_SHA1_FOR_LEGACY_USE_ONLY, salt)
+        .extract(key)
+        .expand(&[SUBKEY_INFO], KeyLen(subkey.len()))
+        .and_then(|okm| okm.fill(&mut subkey))
+        .map_err(|_| anyhow!("Failed to derive Shadowsocks subkey"))?;
+    let unbound = UnboundKey::new(cipher.algorithm(), &subkey)
+        .map_err(|_| anyhow!("Invalid Shadowsocks subkey length"))?;
+    Ok(LessSafeKey::new(unbound))
+}
+
+// Ключ сессии и счётчик nonce (little-endian), который растёт после каждой операции
+struct AeadState {
+    key: LessSafeKey,
+    nonce: [u8; NONCE_LEN],
+}
+
+impl AeadState {
+    fn new(key: LessSafeKey) -> Self {
+        Self { key, nonce: [0; NONCE_LEN] }
+    }
+
+    fn next_nonce(&mut self) -> Nonce {
+        let nonce = Nonce::assume_unique_for_key(self.nonce);
+        for byte in self.nonce.iter_mut() {
+            *byte = byte.wrapping_add(1);
+            if *byte != 0 {
+                break;
+            }
+        }
+        nonce
+    }
+
+    /// Дописывает в `out` зашифрованные данные вместе с тегом.
+    fn seal(&mut self, plain: &[u8], out: &mut Vec<u8>) {
+        let start = out.len();
+        out.extend_from_slice(plain);
+        let mut sealed = out.split_off(start);
+        let nonce = self.next_nonce();
+        self.key.seal_in_place_append_tag(nonce, Aad::empty(), &mut sealed)
+            .expect("AEAD seal cannot fail for chunk-sized input");
+        out.extend_from_slice(&sealed);
+    }
+
+    fn open<'a>(&mut self, sealed: &'a mut [u8]) -> io::Result<&'a mut [u8]> {
+        let nonce = self.next_nonce();
+        self.key.open_in_place(nonce, Aad::empty(), sealed)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Shadowsocks chunk authentication failed"))
+    }
+}
+
+enum ReadState {
+    Salt,
+    Length,
+    Payload(usize),
+}
+
+/// Поток в формате Shadowsocks AEAD поверх транспорта до сервера.
+/// Каждый чанк - зашифрованная длина и зашифрованные данные, у каждой части свой тег.
+pub struct ShadowsocksStream<S> {
+    inner: S,
+    cipher: Cipher,
+    key: Vec<u8>,
+    encoder: AeadState,
+    // Зашифрованные данные, ещё не отданные транспорту
+    pending: Vec<u8>,
+    pending_pos: usize,
+    decoder: Option<AeadState>,
+    read_state: ReadState,
+    raw: Vec<u8>,
+    plain: Vec<u8>,
+    plain_pos: usize,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> ShadowsocksStream<S> {
+    /// Готовит поток к `host:port`; соль и адрес цели уходят с первой записью.
+    pub fn new(inner: S, cipher: Cipher, key: Vec<u8>, host: &str, port: u16) -> Result<Self> {
+        let mut salt = vec![0u8; cipher.key_len()];
+        rand::thread_rng().fill_bytes(&mut salt);
+        let encoder = AeadState::new(session_key(cipher, &key, &salt)?);
+
+        let mut stream = Self {
+            inner,
+            cipher,
+            key,
+            encoder,
+            pending: salt,
+            pending_pos: 0,
+            decoder: None,
+            read_state: ReadState::Salt,
+            raw: Vec::new(),
+            plain: Vec::new(),
+            plain_pos: 0,
+        };
+        let mut address = Vec::new();
+        socks::encode_address(host, port, &mut address);
+        stream.encode_chunk(&address);
+        Ok(stream)
+    }
+
+    fn encode_chunk(&mut self, payload: &[u8]) {
+        let len = (payload.len() as u16).to_be_bytes();
+        self.encoder.seal(&len, &mut self.pending);
+        self.encoder.seal(payload, &mut self.pending);
+    }
+
+    fn poll_write_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.pending_pos < self.pending.len() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending[self.pending_pos..]))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pending_pos += written;
+        }
+        self.pending.clear();
+        self.pending_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+
+    /// Дочитывает из транспорта, пока в `raw` не окажется `needed` байт.
+    /// `Ok(false)` - транспорт закрыт до того, как пришли данные.
+    fn poll_fill(&mut self, cx: &mut Context<'_>, needed: usize) -> Poll<io::Result<bool>> {
+        let mut chunk = [0u8; READ_CHUNK];
+        while self.raw.len() < needed {
+            let mut buf = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut self.inner).poll_read(cx, &mut buf))?;
+            if buf.filled().is_empty() {
+                return Poll::Ready(Ok(false));
+            }
+            self.raw.extend_from_slice(buf.filled());
+        }
+        Poll::Ready(Ok(true))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for ShadowsocksStream<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if this.plain_pos < this.plain.len() {
+                let n = buf.remaining().min(this.plain.len() - this.plain_pos);
+                buf.put_slice(&this.plain[this.plain_pos..this.plain_pos + n]);
+                this.plain_pos += n;
+                return Poll::Ready(Ok(()));
+            }
+
+            let needed = match this.read_state {
+                ReadState::Salt => this.cipher.key_len(),
+                ReadState::Length => 2 + TAG_LEN,
+                ReadState::Payload(len) => len + TAG_LEN,
+            };
+            if !ready!(this.poll_fill(cx, needed))? {
+                // Закрытие между чанками - обычный конец потока
+                return if this.raw.is_empty() && !matches!(this.read_state, ReadState::Payload(_)) {
+                    Poll::Ready(Ok(()))
+                } else {
+                    Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()))
+                };
+            }
+
+            let mut sealed: Vec<u8> = this.raw.drain(..needed).collect();
+            match this.read_state {
+                ReadState::Salt => {
+                    let key = session_key(this.cipher, &this.key, &sealed)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+                    this.decoder = Some(AeadState::new(key));
+                    this.read_state = ReadState::Length;
+                }
+                ReadState::Length => {
+                    let decoder = this.decoder.as_mut().expect("decoder is set after salt");
+                    let len = decoder.open(&mut sealed)?;
+                    let len = u16::from_be_bytes([len[0], len[1]]) as usize & MAX_PAYLOAD;
+                    this.read_state = ReadState::Payload(len);
+                }
+                ReadState::Payload(_) => {
+                    let decoder = this.decoder.as_mut().expect("decoder is set after salt");
+                    let plain_len = decoder.open(&mut sealed)?.len();
+                    sealed.truncate(plain_len);
+                    this.plain = sealed;
+                    this.plain_pos = 0;
+                    this.read_state = ReadState::Length;
+                }
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for ShadowsocksStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        // Новые данные принимаем, только когда предыдущие ушли в транспорт
+        ready!(this.poll_write_pending(cx))?;
+        let n = buf.len().min(MAX_PAYLOAD);
+        this.encode_chunk(&buf[..n]);
+        // Пробуем отправить сразу; если транспорт занят, отправим при следующем вызове
+        if let Poll::Ready(Err(e)) = this.poll_write_pending(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_write_pending(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_write_pending(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Открывает TCP соединение к `host:port` через Shadowsocks сервер поверх
+/// уже открытого потока до него.
+pub async fn connect(stream: ProxyStream, method: &str, password: &str, host: &str, port: u16) -> Result<ProxyStream> {
+    let cipher = Cipher::from_method(method)?;
+    let key = derive_key(password, cipher.key_len());
+
+    let mut stream = ShadowsocksStream::new(stream, cipher, key, host, port)?;
+    // Заголовок отправляем сразу: протоколы, где первым говорит сервер, ждут его
+    stream.flush().await?;
+    Ok(Box::new(stream))
+}
+
+/// UDP через Shadowsocks: каждый пакет - своя соль и AEAD(адрес + данные) с нулевым nonce.
+pub struct ShadowsocksUdp {
+    socket: UdpSocket,
+    cipher: Cipher,
+    key: Vec<u8>,
+    target: Vec<u8>,
+}
+
+impl ShadowsocksUdp {
+    pub async fn connect(server_addr: (&str, u16), method: &str, password: &str, host: &str, port: u16) -> Result<Self> {
+        let cipher = Cipher::from_method(method)?;
+        let server = tokio::net::lookup_host(server_addr).await?
+            .next()
+            .ok_or_else(|| anyhow!("No addresses found for {}", server_addr.0))?;
+        let bind: std::net::SocketAddr = if server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }.parse()?;
+        let socket = UdpSocket::bind(bind).await?;
+        socket.connect(server).await?;
+
+        let mut target = Vec::new();
+        socks::encode_address(host, port, &mut target);
+        Ok(Self {
+            socket,
+            cipher,
+            key: derive_key(password, cipher.key_len()),
+            target,
+        })
+    }
+}
+
+#[async_trait]
+impl UdpSession for ShadowsocksUdp {
+    async fn send(&self, payload: &[u8]) -> Result<()> {
+        let mut packet = vec![0u8; self.cipher.key_len()];
+        rand::thread_rng().fill_bytes(&mut packet);
+        let mut state = AeadState::new(session_key(self.cipher, &self.key, &packet)?);
+
+        let mut plain = self.target.clone();
+        plain.extend_from_slice(payload);
+        state.seal(&plain, &mut packet);
+        self.socket.send(&packet).await?;
+        Ok(())
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> Result<usize> {
+        let mut packet = vec![0u8; 65535];
+        loop {
+            let len = self.socket.recv(&mut packet).await?;
+            let salt_len = self.cipher.key_len();
+            if len < salt_len + TAG_LEN {
+                log::debug!("Dropping short Shadowsocks UDP packet");
+                continue;
+            }
+            let (salt, sealed) = packet[..len].split_at_mut(salt_len);
+            let mut state = AeadState::new(session_key(self.cipher, &self.key, salt)?);
+            let plain = match state.open(sealed) {
+                Ok(plain) => plain,
+                Err(e) => {
+                    log::debug!("Dropping Shadowsocks UDP packet: {}", e);
+                    continue;
+                }
+            };
+            // Ответ начинается с адреса источника
+            let Some((_, addr_len)) = socks::parse_address(plain) else {
+                log::debug!("Dropping Shadowsocks UDP packet with invalid address");
+                continue;
+            };
+            let data = &plain[addr_len..];
+            let n = data.len().min(buf.len());
+            buf[..n].copy_from_slice(&data[..n]);
+            return Ok(n);
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use std::io;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use anyhow::{anyhow, Result};
+use super::AsyncStream;
+use crate::proxy::socks::{self, SOCKS5_VERSION};
+
+const AUTH_VERSION: u8 = 0x01;
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USER_PASS: u8 = 0x02;
+const CMD_CONNECT: u8 = 0x01;
+
+/// Открывает туннель к `host:port` командой CONNECT через уже установленное
+/// соединение с вышестоящим SOCKS5 сервером.
+pub async fn connect<S: AsyncStream>(mut stream: S, credentials: Option<(&str, &str)>, host: &str, port: u16) -> Result<S> {
+    let methods: &[u8] = if credentials.is_some() {
+        &[METHOD_NO_AUTH, METHOD_USER_PASS]
+    } else {
+        &[METHOD_NO_AUTH]
+    };
+    let mut greeting = vec![SOCKS5_VERSION, methods.len() as u8];
+    greeting.extend_from_slice(methods);
+    stream.write_all(&greeting).await?;
+
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await?;
+    if choice[0] != SOCKS5_VERSION {
+        return Err(anyhow!("Upstream server is not a SOCKS5 proxy"));
+    }
+    match choice[1] {
+        METHOD_NO_AUTH => {}
+        METHOD_USER_PASS => match credentials {
+            Some((username, password)) => authenticate(&mut stream, username, password).await?,
+            None => return Err(anyhow!("Upstream SOCKS5 proxy requires authentication")),
+        },
+        _ => return Err(anyhow!("Upstream SOCKS5 proxy accepted none of the offered authentication methods")),
+    }
+
+    let mut request = vec![SOCKS5_VERSION, CMD_CONNECT, 0x00];
+    socks::encode_address(host, port, &mut request);
+    stream.write_all(&request).await?;
+    stream.flush().await?;
+
+    // VER REP RSV, затем BND.ADDR, длина которого зависит от ATYP
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0x00 {
+        return Err(reply_error(reply[1]).into());
+    }
+    let address_len = match reply[3] {
+        0x01 => 4,
+        0x04 => 16,
+        0x03 => stream.read_u8().await? as usize,
+        atyp => return Err(anyhow!("Invalid address type {} in SOCKS5 reply", atyp)),
+    };
+    let mut bound = vec![0u8; address_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(stream)
+}
+
+// RFC 1929
+async fn authenticate<S: AsyncStream>(stream: &mut S, username: &str, password: &str) -> Result<()> {
+    if username.len() > 255 || password.len() > 255 {
+        return Err(anyhow!("SOCKS5 username and password must be at most 255 bytes"));
+    }
+    let mut request = vec![AUTH_VERSION, username.len() as u8];
+    request.extend_from_slice(username.as_bytes());
+    request.push(password.len() as u8);
+    request.extend_from_slice(password.as_bytes());
+    stream.write_all(&request).await?;
+    stream.flush().await?;
+
+    let mut status = [0u8; 2];
+    stream.read_exact(&mut status).await?;
+    if status[1] != 0x00 {
+        return Err(anyhow!("Upstream SOCKS5 proxy rejected the credentials"));
+    }
+    Ok(())
+}
+
+// Ошибки с теми же ErrorKind, что и у прямого подключения, чтобы входящий
+// SOCKS5 ответил клиенту тем же кодом
+fn reply_error(reply: u8) -> io::Error {
+    let (kind, message) = match reply {
+        0x02 => (io::ErrorKind::PermissionDenied, "connection not allowed by ruleset"),
+        0x03 => (io::ErrorKind::NetworkUnreachable, "network unreachable"),
+        0x04 => (io::ErrorKind::HostUnreachable, "host unreachable"),
+        0x05 => (io::ErrorKind::ConnectionRefused, "connection refused"),
+        0x06 => (io::ErrorKind::TimedOut, "TTL expired"),
+        0x07 => (io::ErrorKind::Unsupported, "command not supported"),
+        0x08 => (io::ErrorKind::Unsupported, "address type not supported"),
+        _ => (io::ErrorKind::Other, "general failure"),
+    };
+    io::Error::new(kind, format!("Upstream SOCKS5 proxy: {}", message))
+}
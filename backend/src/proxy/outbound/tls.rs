@@ -0,0 +1,122 @@
+use std::collections::hash_map::{Entry, HashMap};
+use std::io::BufReader;
+use std::sync::{Arc, LazyLock, RwLock};
+use std::time::SystemTime;
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+use anyhow::{anyhow, Context, Result};
+
+/// Параметры TLS клиента для outbound.
+#[derive(Debug, Clone, Default)]
+pub struct TlsOptions {
+    // Имя для SNI и проверки сертификата; по умолчанию - адрес сервера
+    pub sni: Option<String>,
+    pub alpn: Vec<String>,
+    pub skip_cert_verify: bool,
+    // PEM файл с корневым сертификатом в дополнение к webpki-roots
+    pub ca_file: Option<String>,
+}
+
+// Готовые конфигурации клиента по CA файлу, ALPN и skip-cert-verify: корневые
+// сертификаты и CA файл читаются при загрузке роутера, а не при каждом
+// подключении. SNI задаётся при подключении и в ключ не входит.
+type ConfigKey = (Option<String>, Vec<String>, bool);
+
+static CLIENT_CONFIGS: LazyLock<RwLock<HashMap<ConfigKey, Arc<ClientConfig>>>> = LazyLock::new(Default::default);
+
+fn config_key(options: &TlsOptions) -> ConfigKey {
+    (options.ca_file.clone(), options.alpn.clone(), options.skip_cert_verify)
+}
+
+/// Заново собирает конфигурации серверов, перечитывая CA файлы. При ошибке
+/// в любом из них кэш не меняется.
+pub fn preload<'a>(servers: impl IntoIterator<Item = (&'a str, TlsOptions)>) -> Result<()> {
+    let mut configs = HashMap::new();
+    for (name, options) in servers {
+        if let Entry::Vacant(entry) = configs.entry(config_key(&options)) {
+            let config = build_config(&options).with_context(|| format!("Invalid TLS settings for server \"{}\"", name))?;
+            entry.insert(Arc::new(config));
+        }
+    }
+    CLIENT_CONFIGS.write().unwrap().extend(configs);
+    Ok(())
+}
+
+fn client_config(options: &TlsOptions) -> Result<Arc<ClientConfig>> {
+    let key = config_key(options);
+    if let Some(config) = CLIENT_CONFIGS.read().unwrap().get(&key) {
+        return Ok(config.clone());
+    }
+    // Параметры не из списка серверов (DNS, проверка по URL) собираются при
+    // первом подключении
+    let config = Arc::new(build_config(options)?);
+    CLIENT_CONFIGS.write().unwrap().insert(key, config.clone());
+    Ok(config)
+}
+
+/// Устанавливает TLS сессию поверх уже открытого транспорта.
+pub async fn connect<S>(stream: S, server_host: &str, options: &TlsOptions) -> Result<TlsStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let sni = options.sni.as_deref().filter(|s| !s.is_empty()).unwrap_or(server_host);
+    let server_name = ServerName::try_from(sni)
+        .map_err(|_| anyhow!("Invalid TLS server name: {}", sni))?;
+    let connector = TlsConnector::from(client_config(options)?);
+    connector.connect(server_name, stream).await
+        .with_context(|| format!("TLS handshake with {} failed", sni))
+}
+
+fn build_config(options: &TlsOptions) -> Result<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            anchor.subject,
+            anchor.spki,
+            anchor.name_constraints,
+        )
+    }));
+    if let Some(path) = &options.ca_file {
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("Failed to open CA file {}", path))?;
+        let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+            .with_context(|| format!("Failed to read CA file {}", path))?;
+        if certs.is_empty() {
+            return Err(anyhow!("No certificates found in CA file {}", path));
+        }
+        for cert in certs {
+            roots.add(&Certificate(cert))
+                .map_err(|e| anyhow!("Invalid certificate in {}: {}", path, e))?;
+        }
+    }
+
+    let mut config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = options.alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
+    if options.skip_cert_verify {
+        config.dangerous().set_certificate_verifier(Arc::new(NoCertificateVerification));
+    }
+    Ok(config)
+}
+
+// skip-cert-verify: принимаем любой сертификат сервера
+struct NoCertificateVerification;
+
+impl ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
@@ -0,0 +1,113 @@
+use std::time::Duration;
+use async_trait::async_trait;
+use sha2::{Digest, Sha224};
+use tokio::io::{AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::sync::Mutex;
+use anyhow::{anyhow, Result};
+use super::tls::TlsOptions;
+use super::{dial, transport, FrameReader, ProxyStream, UdpSession};
+use crate::models::{GrpcConfig, WsConfig};
+use crate::proxy::socks;
+
+const CMD_CONNECT: u8 = 0x01;
+const CMD_UDP_ASSOCIATE: u8 = 0x03;
+const CRLF: &[u8] = b"\r\n";
+
+/// Параметры Trojan сервера из `ProxyConfig::Trojan`.
+pub struct TrojanOptions<'a> {
+    pub server: (&'a str, u16),
+    pub password: &'a str,
+    pub tls: TlsOptions,
+    pub network: &'a str,
+    pub ws: Option<&'a WsConfig>,
+    pub grpc: Option<&'a GrpcConfig>,
+}
+
+/// Заголовок запроса: hex(SHA224(password)) CRLF CMD ATYP DST.ADDR DST.PORT CRLF.
+fn request_header(password: &str, command: u8, host: &str, port: u16) -> Vec<u8> {
+    let mut header: Vec<u8> = Sha224::digest(password.as_bytes())
+        .iter()
+        .flat_map(|byte| format!("{:02x}", byte).into_bytes())
+        .collect();
+    header.extend_from_slice(CRLF);
+    header.push(command);
+    socks::encode_address(host, port, &mut header);
+    header.extend_from_slice(CRLF);
+    header
+}
+
+async fn handshake(options: &TrojanOptions<'_>, stream: ProxyStream, command: u8, host: &str, port: u16) -> Result<ProxyStream> {
+    let mut stream = transport(stream, options.server.0, Some(options.tls.clone()), options.network, options.ws, options.grpc).await?;
+    stream.write_all(&request_header(options.password, command, host, port)).await?;
+    stream.flush().await?;
+    Ok(stream)
+}
+
+/// Открывает TCP туннель к `host:port` через Trojan сервер поверх уже
+/// открытого потока до него.
+pub async fn connect(options: &TrojanOptions<'_>, stream: ProxyStream, host: &str, port: u16) -> Result<ProxyStream> {
+    handshake(options, stream, CMD_CONNECT, host, port).await
+}
+
+/// UDP поверх потока Trojan: каждый пакет - ATYP DST.ADDR DST.PORT LENGTH CRLF PAYLOAD.
+pub struct TrojanUdp {
+    host: String,
+    port: u16,
+    reader: Mutex<FrameReader<ReadHalf<ProxyStream>>>,
+    writer: Mutex<WriteHalf<ProxyStream>>,
+}
+
+impl TrojanUdp {
+    pub async fn connect(options: &TrojanOptions<'_>, host: &str, port: u16, connect_timeout: Duration) -> Result<Self> {
+        let stream = handshake(options, dial(options.server, connect_timeout).await?, CMD_UDP_ASSOCIATE, host, port).await?;
+        let (reader, writer) = tokio::io::split(stream);
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            reader: Mutex::new(FrameReader::new(reader)),
+            writer: Mutex::new(writer),
+        })
+    }
+}
+
+#[async_trait]
+impl UdpSession for TrojanUdp {
+    async fn send(&self, payload: &[u8]) -> Result<()> {
+        let len = u16::try_from(payload.len()).map_err(|_| anyhow!("UDP payload is too large"))?;
+        let mut packet = Vec::with_capacity(payload.len() + 32);
+        socks::encode_address(&self.host, self.port, &mut packet);
+        packet.extend_from_slice(&len.to_be_bytes());
+        packet.extend_from_slice(CRLF);
+        packet.extend_from_slice(payload);
+
+        let mut writer = self.writer.lock().await;
+        writer.write_all(&packet).await?;
+        writer.flush().await?;
+        Ok(())
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> Result<usize> {
+        let payload = self.reader.lock().await.next(parse_udp_packet).await?;
+        let n = payload.len().min(buf.len());
+        buf[..n].copy_from_slice(&payload[..n]);
+        Ok(n)
+    }
+}
+
+// Границы данных и конец пакета ATYP DST.ADDR DST.PORT LENGTH CRLF PAYLOAD
+fn parse_udp_packet(buf: &[u8]) -> Result<Option<(usize, usize)>> {
+    let Some(&atyp) = buf.first() else { return Ok(None) };
+    let address_len = match atyp {
+        0x01 => 1 + 4 + 2,
+        0x04 => 1 + 16 + 2,
+        0x03 => match buf.get(1) {
+            Some(&len) => 2 + len as usize + 2,
+            None => return Ok(None),
+        },
+        atyp => return Err(anyhow!("Invalid address type {} in Trojan UDP packet", atyp)),
+    };
+    let Some(len) = buf.get(address_len..address_len + 2) else { return Ok(None) };
+    let start = address_len + 2 + CRLF.len();
+    let end = start + u16::from_be_bytes([len[0], len[1]]) as usize;
+    Ok((buf.len() >= end).then_some((start, end)))
+}
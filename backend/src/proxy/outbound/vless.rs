@@ -0,0 +1,211 @@
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf, ReadHalf, WriteHalf};
+use tokio::sync::Mutex;
+use anyhow::{anyhow, Result};
+use super::tls::TlsOptions;
+use super::{dial, transport, AsyncStream, FrameReader, ProxyStream, UdpSession};
+use crate::models::VlessConfig;
+
+const VERSION: u8 = 0x00;
+const CMD_TCP: u8 = 0x01;
+const CMD_UDP: u8 = 0x02;
+
+// Типы адреса VLESS отличаются от SOCKS5
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x02;
+const ATYP_IPV6: u8 = 0x03;
+
+/// Проверяет, что параметры сервера поддерживаются, до подключения.
+fn check_config(config: &VlessConfig) -> Result<()> {
+    if let Some(flow) = config.flow.as_deref().filter(|f| !f.is_empty()) {
+        return Err(anyhow!("VLESS flow \"{}\" is not supported yet; remove flow to use this server", flow));
+    }
+    if !config.encryption.is_empty() && !config.encryption.eq_ignore_ascii_case("none") {
+        return Err(anyhow!("VLESS encryption \"{}\" is not supported, only \"none\"", config.encryption));
+    }
+    match config.security.to_ascii_lowercase().as_str() {
+        "" | "none" | "tls" => Ok(()),
+        other => Err(anyhow!("VLESS security \"{}\" is not supported yet", other)),
+    }
+}
+
+/// Заголовок запроса: Version UUID AddonsLen Command Port ATYP Address.
+fn request_header(uuid: &[u8; 16], command: u8, host: &str, port: u16) -> Vec<u8> {
+    let mut header = Vec::with_capacity(24 + host.len());
+    header.push(VERSION);
+    header.extend_from_slice(uuid);
+    // Addons (protobuf) нужны только для flow
+    header.push(0);
+    header.push(command);
+    header.extend_from_slice(&port.to_be_bytes());
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            header.push(ATYP_IPV4);
+            header.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            header.push(ATYP_IPV6);
+            header.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            let domain = &host.as_bytes()[..host.len().min(255)];
+            header.push(ATYP_DOMAIN);
+            header.push(domain.len() as u8);
+            header.extend_from_slice(domain);
+        }
+    }
+    header
+}
+
+/// Параметры TLS для security=tls; `None` - без TLS.
+pub(super) fn tls_options(config: &VlessConfig) -> Option<TlsOptions> {
+    config.security.eq_ignore_ascii_case("tls").then(|| TlsOptions {
+        sni: config.sni.clone(),
+        alpn: config.alpn.clone().unwrap_or_default(),
+        ..Default::default()
+    })
+}
+
+async fn handshake(
+    stream: ProxyStream,
+    server_host: &str,
+    config: &VlessConfig,
+    command: u8,
+    host: &str,
+    port: u16,
+) -> Result<VlessStream<ProxyStream>> {
+    check_config(config)?;
+    let uuid = uuid::Uuid::parse_str(&config.uuid)
+        .map_err(|e| anyhow!("Invalid VLESS uuid {}: {}", config.uuid, e))?;
+
+    let stream = transport(stream, server_host, tls_options(config), &config.network, config.ws.as_ref(), config.grpc.as_ref()).await?;
+
+    let mut stream = VlessStream::new(stream);
+    stream.write_all(&request_header(uuid.as_bytes(), command, host, port)).await?;
+    stream.flush().await?;
+    Ok(stream)
+}
+
+/// Открывает TCP туннель к `host:port` через VLESS сервер поверх уже
+/// открытого потока до него.
+pub async fn connect(stream: ProxyStream, server_host: &str, config: &VlessConfig, host: &str, port: u16) -> Result<ProxyStream> {
+    Ok(Box::new(handshake(stream, server_host, config, CMD_TCP, host, port).await?))
+}
+
+enum ResponseState {
+    // Version и AddonsLen
+    Header { buf: [u8; 2], filled: usize },
+    Addons(usize),
+    Done,
+}
+
+/// Поток VLESS: перед данными сервер присылает Version AddonsLen Addons,
+/// которые отбрасываются при первом чтении.
+pub struct VlessStream<S> {
+    inner: S,
+    response: ResponseState,
+}
+
+impl<S> VlessStream<S> {
+    fn new(inner: S) -> Self {
+        Self { inner, response: ResponseState::Header { buf: [0; 2], filled: 0 } }
+    }
+}
+
+impl<S: AsyncStream> AsyncRead for VlessStream<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            match &mut this.response {
+                ResponseState::Header { buf: header, filled } => {
+                    let mut read = ReadBuf::new(&mut header[*filled..]);
+                    ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read))?;
+                    let n = read.filled().len();
+                    if n == 0 {
+                        return Poll::Ready(Err(io::Error::new(io::ErrorKind::UnexpectedEof, "VLESS server closed before response")));
+                    }
+                    *filled += n;
+                    if *filled == 2 {
+                        if header[0] != VERSION {
+                            return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, "Unexpected VLESS response version")));
+                        }
+                        this.response = ResponseState::Addons(header[1] as usize);
+                    }
+                }
+                ResponseState::Addons(0) => this.response = ResponseState::Done,
+                ResponseState::Addons(remaining) => {
+                    let mut skip = [0u8; 255];
+                    let mut read = ReadBuf::new(&mut skip[..*remaining]);
+                    ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read))?;
+                    let n = read.filled().len();
+                    if n == 0 {
+                        return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                    }
+                    *remaining -= n;
+                }
+                ResponseState::Done => return Pin::new(&mut this.inner).poll_read(cx, buf),
+            }
+        }
+    }
+}
+
+impl<S: AsyncStream> AsyncWrite for VlessStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// UDP через VLESS: сессия к одной цели, каждый пакет - Length(2) и данные.
+pub struct VlessUdp {
+    reader: Mutex<FrameReader<ReadHalf<VlessStream<ProxyStream>>>>,
+    writer: Mutex<WriteHalf<VlessStream<ProxyStream>>>,
+}
+
+impl VlessUdp {
+    pub async fn connect(server: (&str, u16), config: &VlessConfig, host: &str, port: u16, connect_timeout: Duration) -> Result<Self> {
+        let stream = handshake(dial(server, connect_timeout).await?, server.0, config, CMD_UDP, host, port).await?;
+        let (reader, writer) = tokio::io::split(stream);
+        Ok(Self { reader: Mutex::new(FrameReader::new(reader)), writer: Mutex::new(writer) })
+    }
+}
+
+#[async_trait]
+impl UdpSession for VlessUdp {
+    async fn send(&self, payload: &[u8]) -> Result<()> {
+        let len = u16::try_from(payload.len()).map_err(|_| anyhow!("UDP payload is too large"))?;
+        let mut packet = Vec::with_capacity(payload.len() + 2);
+        packet.extend_from_slice(&len.to_be_bytes());
+        packet.extend_from_slice(payload);
+        let mut writer = self.writer.lock().await;
+        writer.write_all(&packet).await?;
+        writer.flush().await?;
+        Ok(())
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> Result<usize> {
+        let payload = self.reader.lock().await.next(parse_udp_packet).await?;
+        let n = payload.len().min(buf.len());
+        buf[..n].copy_from_slice(&payload[..n]);
+        Ok(n)
+    }
+}
+
+// Границы данных и конец пакета Length(2) Payload
+fn parse_udp_packet(buf: &[u8]) -> Result<Option<(usize, usize)>> {
+    let Some(len) = buf.get(..2) else { return Ok(None) };
+    let end = 2 + u16::from_be_bytes([len[0], len[1]]) as usize;
+    Ok((buf.len() >= end).then_some((2, end)))
+}
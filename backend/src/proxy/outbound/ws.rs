@@ -0,0 +1,196 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use base64::{Engine as _, engine::general_purpose};
+use futures_util::{Sink, Stream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::tungstenite::handshake::client::{generate_key, Request};
+use tokio_tungstenite::tungstenite::http::{HeaderName, HeaderValue};
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::WebSocketStream;
+use anyhow::{anyhow, Context as _, Result};
+use super::ProxyStream;
+use crate::models::WsConfig;
+
+const DEFAULT_EARLY_DATA_HEADER: &str = "Sec-WebSocket-Protocol";
+
+type Handshake = Pin<Box<dyn Future<Output = Result<WebSocketStream<ProxyStream>, WsError>> + Send>>;
+
+enum State {
+    // С early data рукопожатие ждёт первой записи
+    Pending(Option<(ProxyStream, Request)>),
+    Connecting(Handshake),
+    Open(WebSocketStream<ProxyStream>),
+}
+
+/// Поток поверх WebSocket: каждая запись - binary сообщение, чтение склеивает
+/// сообщения обратно в поток байт.
+pub struct WsStream {
+    state: State,
+    max_early_data: usize,
+    early_data_header: String,
+    read_buf: Vec<u8>,
+    read_pos: usize,
+}
+
+/// Открывает WebSocket поверх потока до сервера (TLS уже установлен, если нужен wss).
+pub async fn connect(stream: ProxyStream, server_host: &str, config: &WsConfig) -> Result<ProxyStream> {
+    let request = build_request(server_host, config)?;
+    let early_data_header = config.early_data_header.clone()
+        .filter(|h| !h.is_empty())
+        .unwrap_or_else(|| DEFAULT_EARLY_DATA_HEADER.to_string());
+
+    let state = if config.max_early_data > 0 {
+        State::Pending(Some((stream, request)))
+    } else {
+        State::Open(handshake(stream, request).await.context("WebSocket handshake failed")?)
+    };
+    Ok(Box::new(WsStream {
+        state,
+        max_early_data: config.max_early_data,
+        early_data_header,
+        read_buf: Vec::new(),
+        read_pos: 0,
+    }))
+}
+
+fn build_request(server_host: &str, config: &WsConfig) -> Result<Request> {
+    let path = if config.path.starts_with('/') {
+        config.path.clone()
+    } else {
+        format!("/{}", config.path)
+    };
+    let host = config.host.as_deref().filter(|h| !h.is_empty()).unwrap_or(server_host);
+    // tungstenite проверяет схему ws/wss, на провод уходят только путь и Host
+    let authority = match host.parse::<std::net::Ipv6Addr>() {
+        Ok(ip) => format!("[{}]", ip),
+        Err(_) => host.to_string(),
+    };
+    Request::builder()
+        .method("GET")
+        .uri(format!("ws://{}{}", authority, path))
+        .header("Host", host)
+        .header("Connection", "Upgrade")
+        .header("Upgrade", "websocket")
+        .header("Sec-WebSocket-Version", "13")
+        .header("Sec-WebSocket-Key", generate_key())
+        .body(())
+        .map_err(|e| anyhow!("Invalid WebSocket path \"{}\" or host \"{}\": {}", path, host, e))
+}
+
+async fn handshake(stream: ProxyStream, request: Request) -> Result<WebSocketStream<ProxyStream>, WsError> {
+    let (ws, _) = tokio_tungstenite::client_async(request, stream).await?;
+    Ok(ws)
+}
+
+fn to_io_error(e: WsError) -> io::Error {
+    match e {
+        WsError::Io(e) => e,
+        e => io::Error::other(e),
+    }
+}
+
+impl WsStream {
+    fn start_handshake(&mut self, early_data: &[u8]) -> io::Result<()> {
+        let State::Pending(pending) = &mut self.state else {
+            return Ok(());
+        };
+        let (stream, mut request) = pending.take().expect("pending handshake is started once");
+        if !early_data.is_empty() {
+            // Как у Xray: base64url без выравнивания
+            let value = HeaderValue::from_str(&general_purpose::URL_SAFE_NO_PAD.encode(early_data))
+                .map_err(io::Error::other)?;
+            let name = self.early_data_header.parse::<HeaderName>()
+                .map_err(io::Error::other)?;
+            request.headers_mut().insert(name, value);
+        }
+        self.state = State::Connecting(Box::pin(handshake(stream, request)));
+        Ok(())
+    }
+
+    fn poll_open(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<&mut WebSocketStream<ProxyStream>>> {
+        if matches!(self.state, State::Pending(_)) {
+            self.start_handshake(&[])?;
+        }
+        if let State::Connecting(handshake) = &mut self.state {
+            let ws = ready!(handshake.as_mut().poll(cx)).map_err(to_io_error)?;
+            self.state = State::Open(ws);
+        }
+        match &mut self.state {
+            State::Open(ws) => Poll::Ready(Ok(ws)),
+            _ => unreachable!("handshake is started above"),
+        }
+    }
+}
+
+impl AsyncRead for WsStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if this.read_pos < this.read_buf.len() {
+                let n = buf.remaining().min(this.read_buf.len() - this.read_pos);
+                buf.put_slice(&this.read_buf[this.read_pos..this.read_pos + n]);
+                this.read_pos += n;
+                return Poll::Ready(Ok(()));
+            }
+
+            let ws = ready!(this.poll_open(cx))?;
+            match ready!(Pin::new(ws).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => {
+                    this.read_buf = data;
+                    this.read_pos = 0;
+                }
+                Some(Ok(Message::Text(text))) => {
+                    this.read_buf = text.into_bytes();
+                    this.read_pos = 0;
+                }
+                // Ping/Pong обрабатывает tungstenite
+                Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => {}
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Err(WsError::ConnectionClosed | WsError::AlreadyClosed)) => return Poll::Ready(Ok(())),
+                Some(Err(e)) => return Poll::Ready(Err(to_io_error(e))),
+            }
+        }
+    }
+}
+
+impl AsyncWrite for WsStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if matches!(this.state, State::Pending(_)) && !buf.is_empty() {
+            // Первые данные уходят в заголовке рукопожатия
+            let n = buf.len().min(this.max_early_data);
+            this.start_handshake(&buf[..n])?;
+            return Poll::Ready(Ok(n));
+        }
+
+        let ws = ready!(this.poll_open(cx))?;
+        ready!(Pin::new(&mut *ws).poll_ready(cx)).map_err(to_io_error)?;
+        Pin::new(&mut *ws).start_send(Message::Binary(buf.to_vec())).map_err(to_io_error)?;
+        // Сразу пробуем отправить, не дожидаясь flush
+        if let Poll::Ready(Err(e)) = Pin::new(ws).poll_flush(cx) {
+            return Poll::Ready(Err(to_io_error(e)));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if matches!(self.state, State::Pending(_)) {
+            return Poll::Ready(Ok(()));
+        }
+        let ws = ready!(self.poll_open(cx))?;
+        Pin::new(ws).poll_flush(cx).map_err(to_io_error)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if matches!(self.state, State::Pending(_)) {
+            return Poll::Ready(Ok(()));
+        }
+        let ws = ready!(self.poll_open(cx))?;
+        match ready!(Pin::new(ws).poll_close(cx)) {
+            Ok(()) | Err(WsError::ConnectionClosed | WsError::AlreadyClosed) => Poll::Ready(Ok(())),
+            Err(e) => Poll::Ready(Err(to_io_error(e))),
+        }
+    }
+}
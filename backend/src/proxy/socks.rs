@@ -0,0 +1,335 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use anyhow::{anyhow, Result};
+use super::auth::Credentials;
+use super::{open_route, udp, Router};
+
+pub const SOCKS4_VERSION: u8 = 0x04;
+pub const SOCKS5_VERSION: u8 = 0x05;
+const AUTH_VERSION: u8 = 0x01;
+
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USER_PASS: u8 = 0x02;
+const METHOD_NOT_ACCEPTABLE: u8 = 0xFF;
+
+const CMD_CONNECT: u8 = 0x01;
+const CMD_UDP_ASSOCIATE: u8 = 0x03;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+// Коды ответа из RFC 1928, раздел 6
+const REP_SUCCEEDED: u8 = 0x00;
+const REP_GENERAL_FAILURE: u8 = 0x01;
+const REP_NOT_ALLOWED: u8 = 0x02;
+const REP_NETWORK_UNREACHABLE: u8 = 0x03;
+const REP_HOST_UNREACHABLE: u8 = 0x04;
+const REP_CONNECTION_REFUSED: u8 = 0x05;
+const REP_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const REP_ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+// Коды ответа SOCKS4
+const SOCKS4_REPLY_VERSION: u8 = 0x00;
+const SOCKS4_GRANTED: u8 = 0x5A;
+const SOCKS4_REJECTED: u8 = 0x5B;
+
+// USERID и домен SOCKS4a - строки до нулевого байта
+const SOCKS4_MAX_STRING: usize = 255;
+
+/// Обрабатывает SOCKS5 соединение (RFC 1928): согласование метода,
+/// авторизация и CONNECT через общий движок правил.
+pub async fn handle_socks5(
+    mut stream: TcpStream,
+    client_addr: SocketAddr,
+    router: Arc<Router>,
+    credentials: Arc<Credentials>,
+) -> Result<()> {
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header).await?;
+    if header[0] != SOCKS5_VERSION {
+        return Err(anyhow!("Unsupported SOCKS version: {}", header[0]));
+    }
+    let mut methods = vec![0u8; header[1] as usize];
+    stream.read_exact(&mut methods).await?;
+
+    let method = if credentials.required() {
+        METHOD_USER_PASS
+    } else {
+        METHOD_NO_AUTH
+    };
+    if !methods.contains(&method) {
+        stream.write_all(&[SOCKS5_VERSION, METHOD_NOT_ACCEPTABLE]).await?;
+        return Err(anyhow!("No acceptable SOCKS5 auth method offered by {}", client_addr));
+    }
+    stream.write_all(&[SOCKS5_VERSION, method]).await?;
+
+    if method == METHOD_USER_PASS {
+        authenticate(&mut stream, client_addr, &credentials).await?;
+    }
+
+    let mut request = [0u8; 4];
+    stream.read_exact(&mut request).await?;
+    let [version, command, _reserved, atyp] = request;
+    if version != SOCKS5_VERSION {
+        return Err(anyhow!("Unsupported SOCKS version in request: {}", version));
+    }
+
+    let (host, port) = match read_address(&mut stream, atyp).await? {
+        Some(target) => target,
+        None => {
+            send_reply(&mut stream, REP_ADDRESS_NOT_SUPPORTED).await?;
+            return Err(anyhow!("Unsupported SOCKS5 address type: {}", atyp));
+        }
+    };
+
+    if command == CMD_UDP_ASSOCIATE {
+        return udp_associate(stream, client_addr, port, router).await;
+    }
+    if command != CMD_CONNECT {
+        send_reply(&mut stream, REP_COMMAND_NOT_SUPPORTED).await?;
+        return Err(anyhow!("Unsupported SOCKS5 command {} for {}:{}", command, host, port));
+    }
+
+    log::info!("SOCKS5 CONNECT from {} to {}:{}", client_addr, host, port);
+
+    match open_route(&router, &host, port).await {
+        Ok(Some(mut target)) => {
+            send_reply(&mut stream, REP_SUCCEEDED).await?;
+            if let Err(e) = tokio::io::copy_bidirectional(&mut stream, &mut target).await {
+                log::warn!("SOCKS5 tunnel error for {}:{}: {}", host, port, e);
+            }
+            log::info!("SOCKS5 tunnel closed for {}:{}", host, port);
+            Ok(())
+        }
+        Ok(None) => {
+            send_reply(&mut stream, REP_NOT_ALLOWED).await?;
+            Ok(())
+        }
+        Err(e) => {
+            log::error!("Failed to connect to {}:{}: {}", host, port, e);
+            send_reply(&mut stream, reply_code(&e)).await?;
+            Err(anyhow!("Connection failed: {}", e))
+        }
+    }
+}
+
+/// UDP ASSOCIATE: открывает relay-сокет на адресе, куда пришло TCP соединение,
+/// и держит ассоциацию, пока клиент не закроет управляющее соединение.
+/// `client_port` - порт, с которого клиент обещал слать датаграммы (0 - любой).
+async fn udp_associate(
+    mut stream: TcpStream,
+    client_addr: SocketAddr,
+    client_port: u16,
+    router: Arc<Router>,
+) -> Result<()> {
+    let relay = match UdpSocket::bind((stream.local_addr()?.ip(), 0)).await {
+        Ok(relay) => relay,
+        Err(e) => {
+            send_reply(&mut stream, REP_GENERAL_FAILURE).await?;
+            return Err(anyhow!("Failed to bind UDP relay: {}", e));
+        }
+    };
+    let relay_addr = relay.local_addr()?;
+    send_reply_with_addr(&mut stream, REP_SUCCEEDED, relay_addr).await?;
+
+    log::info!("SOCKS5 UDP ASSOCIATE from {}, relay on {}", client_addr, relay_addr);
+    let expected_port = (client_port != 0).then_some(client_port);
+    udp::run_association(stream, relay, client_addr.ip(), expected_port, router).await;
+    log::info!("SOCKS5 UDP association closed for {}", client_addr);
+    Ok(())
+}
+
+/// Подсогласование username/password (RFC 1929).
+async fn authenticate(stream: &mut TcpStream, client_addr: SocketAddr, credentials: &Credentials) -> Result<()> {
+    let version = stream.read_u8().await?;
+    if version != AUTH_VERSION {
+        return Err(anyhow!("Unsupported SOCKS5 auth version: {}", version));
+    }
+    let user = read_string(stream).await?;
+    let pass = read_string(stream).await?;
+
+    if credentials.verify(&user, &pass) {
+        stream.write_all(&[AUTH_VERSION, 0x00]).await?;
+        Ok(())
+    } else {
+        stream.write_all(&[AUTH_VERSION, 0x01]).await?;
+        Err(anyhow!("SOCKS5 authentication failed for {}", client_addr))
+    }
+}
+
+/// Обрабатывает SOCKS4 и SOCKS4a CONNECT. В SOCKS4 нет пароля,
+/// поэтому при включённой авторизации такие соединения отклоняются.
+pub async fn handle_socks4(
+    mut stream: TcpStream,
+    client_addr: SocketAddr,
+    router: Arc<Router>,
+    credentials: Arc<Credentials>,
+) -> Result<()> {
+    let mut request = [0u8; 8];
+    stream.read_exact(&mut request).await?;
+    let version = request[0];
+    let command = request[1];
+    let port = u16::from_be_bytes([request[2], request[3]]);
+    let ip = Ipv4Addr::new(request[4], request[5], request[6], request[7]);
+    if version != SOCKS4_VERSION {
+        return Err(anyhow!("Unsupported SOCKS version: {}", version));
+    }
+
+    // USERID не проверяем, но дочитываем
+    read_null_terminated(&mut stream).await?;
+
+    // SOCKS4a: адрес 0.0.0.x (x != 0) означает, что за USERID следует домен
+    let octets = ip.octets();
+    let host = if octets[..3] == [0, 0, 0] && octets[3] != 0 {
+        let domain = read_null_terminated(&mut stream).await?;
+        String::from_utf8(domain).map_err(|_| anyhow!("SOCKS4a domain is not valid UTF-8"))?
+    } else {
+        ip.to_string()
+    };
+
+    if credentials.required() {
+        send_socks4_reply(&mut stream, SOCKS4_REJECTED).await?;
+        return Err(anyhow!("SOCKS4 from {} rejected: authentication is required", client_addr));
+    }
+    if command != CMD_CONNECT {
+        send_socks4_reply(&mut stream, SOCKS4_REJECTED).await?;
+        return Err(anyhow!("Unsupported SOCKS4 command {} for {}:{}", command, host, port));
+    }
+
+    log::info!("SOCKS4 CONNECT from {} to {}:{}", client_addr, host, port);
+
+    match open_route(&router, &host, port).await {
+        Ok(Some(mut target)) => {
+            send_socks4_reply(&mut stream, SOCKS4_GRANTED).await?;
+            if let Err(e) = tokio::io::copy_bidirectional(&mut stream, &mut target).await {
+                log::warn!("SOCKS4 tunnel error for {}:{}: {}", host, port, e);
+            }
+            log::info!("SOCKS4 tunnel closed for {}:{}", host, port);
+            Ok(())
+        }
+        Ok(None) => {
+            send_socks4_reply(&mut stream, SOCKS4_REJECTED).await?;
+            Ok(())
+        }
+        Err(e) => {
+            log::error!("Failed to connect to {}:{}: {}", host, port, e);
+            send_socks4_reply(&mut stream, SOCKS4_REJECTED).await?;
+            Err(anyhow!("Connection failed: {}", e))
+        }
+    }
+}
+
+async fn read_null_terminated(stream: &mut TcpStream) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    loop {
+        match stream.read_u8().await? {
+            0 => return Ok(buf),
+            _ if buf.len() >= SOCKS4_MAX_STRING => return Err(anyhow!("SOCKS4 string is too long")),
+            byte => buf.push(byte),
+        }
+    }
+}
+
+async fn send_socks4_reply(stream: &mut TcpStream, reply: u8) -> io::Result<()> {
+    stream.write_all(&[SOCKS4_REPLY_VERSION, reply, 0, 0, 0, 0, 0, 0]).await
+}
+
+async fn read_string<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let len = reader.read_u8().await? as usize;
+    let mut buf = vec![0u8; len];
+    reader.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+/// Читает DST.ADDR и DST.PORT. `None` - неизвестный тип адреса.
+async fn read_address<R: AsyncRead + Unpin>(reader: &mut R, atyp: u8) -> Result<Option<(String, u16)>> {
+    let host = match atyp {
+        ATYP_IPV4 => {
+            let mut octets = [0u8; 4];
+            reader.read_exact(&mut octets).await?;
+            Ipv4Addr::from(octets).to_string()
+        }
+        ATYP_IPV6 => {
+            let mut octets = [0u8; 16];
+            reader.read_exact(&mut octets).await?;
+            Ipv6Addr::from(octets).to_string()
+        }
+        ATYP_DOMAIN => {
+            let domain = read_string(reader).await?;
+            String::from_utf8(domain).map_err(|_| anyhow!("SOCKS5 domain is not valid UTF-8"))?
+        }
+        _ => return Ok(None),
+    };
+    let port = reader.read_u16().await?;
+    Ok(Some((host, port)))
+}
+
+/// Разбирает ATYP, DST.ADDR и DST.PORT из буфера. Возвращает цель и
+/// число прочитанных байт; `None` - адрес обрезан или неизвестного типа.
+pub fn parse_address(buf: &[u8]) -> Option<((String, u16), usize)> {
+    let (host, len) = match *buf.first()? {
+        ATYP_IPV4 => {
+            let octets: [u8; 4] = buf.get(1..5)?.try_into().ok()?;
+            (Ipv4Addr::from(octets).to_string(), 5)
+        }
+        ATYP_IPV6 => {
+            let octets: [u8; 16] = buf.get(1..17)?.try_into().ok()?;
+            (Ipv6Addr::from(octets).to_string(), 17)
+        }
+        ATYP_DOMAIN => {
+            let domain_len = *buf.get(1)? as usize;
+            let domain = std::str::from_utf8(buf.get(2..2 + domain_len)?).ok()?;
+            (domain.to_string(), 2 + domain_len)
+        }
+        _ => return None,
+    };
+    let port = buf.get(len..len + 2)?;
+    Some(((host, u16::from_be_bytes([port[0], port[1]])), len + 2))
+}
+
+/// Кодирует адрес в формате SOCKS5 (ATYP, DST.ADDR, DST.PORT); этот же формат
+/// используют Shadowsocks и Trojan.
+pub fn encode_address(host: &str, port: u16, out: &mut Vec<u8>) {
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            out.push(ATYP_IPV4);
+            out.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            out.push(ATYP_IPV6);
+            out.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            // Длина домена в SOCKS5 - один байт
+            let domain = &host.as_bytes()[..host.len().min(255)];
+            out.push(ATYP_DOMAIN);
+            out.push(domain.len() as u8);
+            out.extend_from_slice(domain);
+        }
+    }
+    out.extend_from_slice(&port.to_be_bytes());
+}
+
+// BND.ADDR у исходящего соединения через outbound неизвестен, отвечаем 0.0.0.0:0
+async fn send_reply(stream: &mut TcpStream, reply: u8) -> io::Result<()> {
+    send_reply_with_addr(stream, reply, SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))).await
+}
+
+async fn send_reply_with_addr(stream: &mut TcpStream, reply: u8, bind: SocketAddr) -> io::Result<()> {
+    let mut response = vec![SOCKS5_VERSION, reply, 0x00];
+    encode_address(&bind.ip().to_string(), bind.port(), &mut response);
+    stream.write_all(&response).await
+}
+
+fn reply_code(error: &anyhow::Error) -> u8 {
+    match error.downcast_ref::<io::Error>().map(|e| e.kind()) {
+        Some(io::ErrorKind::ConnectionRefused) => REP_CONNECTION_REFUSED,
+        Some(io::ErrorKind::NetworkUnreachable) => REP_NETWORK_UNREACHABLE,
+        Some(io::ErrorKind::HostUnreachable | io::ErrorKind::TimedOut) => REP_HOST_UNREACHABLE,
+        _ => REP_GENERAL_FAILURE,
+    }
+}
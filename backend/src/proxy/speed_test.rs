@@ -0,0 +1,143 @@
+use std::time::{Duration, Instant};
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use anyhow::{anyhow, Result};
+use super::health::{open_url, read_status};
+use crate::config::SpeedTestSettings;
+use crate::models::ProxyServerV2;
+
+const BUFFER_SIZE: usize = 64 * 1024;
+const MAX_RESPONSE_HEAD: usize = 64 * 1024;
+
+/// Результат замера. Скорости - в байтах в секунду.
+#[derive(Debug, Clone, Serialize)]
+pub struct SpeedTestResult {
+    // Подключение через сервер до тестового хоста, включая TLS для https
+    pub latency_ms: u32,
+    pub ttfb_ms: u32,
+    pub download_speed: u64,
+    pub upload_speed: u64,
+    pub downloaded_bytes: u64,
+    pub uploaded_bytes: u64,
+}
+
+/// Замеряет задержку, время до первого байта и скорость скачивания и отдачи
+/// через сервер.
+pub async fn run(server: &ProxyServerV2, servers: &[ProxyServerV2], settings: &SpeedTestSettings) -> Result<SpeedTestResult> {
+    let timeout = stage_timeout(settings);
+    let (latency_ms, ttfb_ms, downloaded_bytes, download_speed) = stage("Download", timeout, download(server, servers, settings)).await?;
+    let (uploaded_bytes, upload_speed) = stage("Upload", timeout, upload(server, servers, settings)).await?;
+
+    Ok(SpeedTestResult {
+        latency_ms,
+        ttfb_ms,
+        download_speed,
+        upload_speed,
+        downloaded_bytes,
+        uploaded_bytes,
+    })
+}
+
+// Подключение ограничено тем же таймаутом, что и этап целиком
+fn stage_timeout(settings: &SpeedTestSettings) -> Duration {
+    Duration::from_millis(settings.timeout.max(1))
+}
+
+async fn stage<T>(name: &str, timeout: Duration, future: impl std::future::Future<Output = Result<T>>) -> Result<T> {
+    tokio::time::timeout(timeout, future).await
+        .map_err(|_| anyhow!("{} test timed out after {} ms", name, timeout.as_millis()))?
+        .map_err(|e| e.context(format!("{} test failed", name)))
+}
+
+async fn download(server: &ProxyServerV2, servers: &[ProxyServerV2], settings: &SpeedTestSettings) -> Result<(u32, u32, u64, u64)> {
+    let start = Instant::now();
+    let (mut stream, host, path) = open_url(server, servers, &settings.download_url, stage_timeout(settings)).await?;
+    let latency = millis(start.elapsed());
+
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: StealthCat\r\nAccept: */*\r\nConnection: close\r\n\r\n",
+        path, host
+    );
+    let sent = Instant::now();
+    stream.write_all(request.as_bytes()).await?;
+    stream.flush().await?;
+
+    let mut buf = vec![0u8; BUFFER_SIZE];
+    let mut head = Vec::new();
+    let mut ttfb = None;
+    // Тело начинается после пустой строки; его начало могло прийти вместе с заголовками
+    let (mut received, body_start) = loop {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Err(anyhow!("Connection closed before HTTP response"));
+        }
+        ttfb.get_or_insert_with(|| sent.elapsed());
+        head.extend_from_slice(&buf[..n]);
+        if let Some(end) = head.windows(4).position(|w| w == b"\r\n\r\n") {
+            check_status(&head)?;
+            break ((head.len() - end - 4) as u64, Instant::now());
+        }
+        if head.len() > MAX_RESPONSE_HEAD {
+            return Err(anyhow!("HTTP response head is too long"));
+        }
+    };
+
+    while received < settings.download_bytes {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        received += n as u64;
+    }
+    if received == 0 {
+        return Err(anyhow!("{} returned no data", settings.download_url));
+    }
+
+    let ttfb = millis(ttfb.unwrap_or_default());
+    Ok((latency, ttfb, received, per_second(received, body_start.elapsed())))
+}
+
+async fn upload(server: &ProxyServerV2, servers: &[ProxyServerV2], settings: &SpeedTestSettings) -> Result<(u64, u64)> {
+    let (mut stream, host, path) = open_url(server, servers, &settings.upload_url, stage_timeout(settings)).await?;
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: StealthCat\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        path, host, settings.upload_bytes
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let start = Instant::now();
+    let chunk = vec![0u8; BUFFER_SIZE];
+    let mut sent = 0u64;
+    while sent < settings.upload_bytes {
+        let n = (settings.upload_bytes - sent).min(BUFFER_SIZE as u64) as usize;
+        stream.write_all(&chunk[..n]).await?;
+        sent += n as u64;
+    }
+    stream.flush().await?;
+
+    // Отдача завершена, когда сервер ответил на запрос
+    let status = read_status(&mut stream).await?;
+    if !(200..300).contains(&status) {
+        return Err(anyhow!("{} responded with HTTP {}", settings.upload_url, status));
+    }
+    Ok((sent, per_second(sent, start.elapsed())))
+}
+
+fn check_status(head: &[u8]) -> Result<()> {
+    let line = head.split(|&b| b == b'\n').next().unwrap_or_default();
+    let line = String::from_utf8_lossy(line);
+    let status = line.split_whitespace().nth(1).and_then(|code| code.parse::<u16>().ok())
+        .ok_or_else(|| anyhow!("Invalid HTTP response: {}", line.trim_end()))?;
+    if !(200..300).contains(&status) {
+        return Err(anyhow!("Test URL responded with HTTP {}", status));
+    }
+    Ok(())
+}
+
+fn millis(duration: Duration) -> u32 {
+    duration.as_millis().min(u32::MAX as u128) as u32
+}
+
+fn per_second(bytes: u64, elapsed: Duration) -> u64 {
+    (bytes as f64 / elapsed.as_secs_f64().max(0.001)) as u64
+}
@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use super::outbound::UdpSession;
+use super::{open_udp_route, socks, Router};
+
+// Время жизни сессии без трафика и их число задаются udp-timeout и
+// udp-max-sessions; просроченные сессии ищутся не реже этого интервала
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+// Датаграммы, ожидающие открытия сессии; при переполнении новые отбрасываются
+const SESSION_QUEUE: usize = 64;
+const MAX_DATAGRAM: usize = 65535;
+
+type Target = (String, u16);
+
+// Сессия NAT: все датаграммы ассоциации к одной цели идут через один outbound
+struct Session {
+    queue: mpsc::Sender<Vec<u8>>,
+    last_active: Arc<Mutex<Instant>>,
+    task: JoinHandle<()>,
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Ретранслирует датаграммы SOCKS5 UDP ASSOCIATE, пока открыто управляющее
+/// TCP соединение. Каждая новая цель маршрутизируется правилами отдельно.
+pub async fn run_association(
+    mut control: TcpStream,
+    relay: UdpSocket,
+    client_ip: IpAddr,
+    expected_port: Option<u16>,
+    router: Arc<Router>,
+) {
+    let relay = Arc::new(relay);
+    let client_ip = client_ip.to_canonical();
+    // UDP адрес клиента фиксируется по первой подходящей датаграмме
+    let mut client: Option<SocketAddr> = None;
+    let mut sessions: HashMap<Target, Session> = HashMap::new();
+    let idle_timeout = router.udp_timeout;
+    let max_sessions = router.udp_max_sessions;
+    let mut sweep = tokio::time::interval(SWEEP_INTERVAL.min(idle_timeout));
+    let mut control_buf = [0u8; 64];
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+
+    loop {
+        tokio::select! {
+            read = control.read(&mut control_buf) => match read {
+                Ok(0) | Err(_) => break,
+                // Данные по управляющему соединению после запроса не ожидаются
+                Ok(_) => continue,
+            },
+            _ = sweep.tick() => {
+                sessions.retain(|target, session| {
+                    let alive = session.last_active.lock().unwrap().elapsed() < idle_timeout;
+                    if !alive {
+                        log::debug!("UDP session to {}:{} expired", target.0, target.1);
+                    }
+                    alive
+                });
+            }
+            received = relay.recv_from(&mut buf) => {
+                let (len, source) = match received {
+                    Ok(received) => received,
+                    Err(e) => {
+                        log::warn!("UDP relay receive error: {}", e);
+                        continue;
+                    }
+                };
+
+                if source.ip().to_canonical() != client_ip || expected_port.is_some_and(|p| p != source.port()) {
+                    log::debug!("Dropping UDP datagram from unexpected source {}", source);
+                    continue;
+                }
+                match client {
+                    Some(addr) if addr != source => {
+                        log::debug!("Dropping UDP datagram from {}: association is bound to {}", source, addr);
+                        continue;
+                    }
+                    Some(_) => {}
+                    None => client = Some(source),
+                }
+
+                let (target, payload) = match parse_datagram(&buf[..len]) {
+                    Ok(parsed) => parsed,
+                    Err(reason) => {
+                        log::debug!("Dropping UDP datagram from {}: {}", source, reason);
+                        continue;
+                    }
+                };
+
+                if !sessions.contains_key(&target) {
+                    if sessions.len() >= max_sessions {
+                        log::warn!("Too many UDP sessions for {}, dropping datagram to {}:{}", source, target.0, target.1);
+                        continue;
+                    }
+                    let session = open_session(target.clone(), source, relay.clone(), router.clone());
+                    sessions.insert(target.clone(), session);
+                }
+
+                let session = &sessions[&target];
+                *session.last_active.lock().unwrap() = Instant::now();
+                if session.queue.try_send(payload.to_vec()).is_err() {
+                    log::debug!("UDP session queue to {}:{} is full, dropping datagram", target.0, target.1);
+                }
+            }
+        }
+    }
+}
+
+/// Разбирает заголовок UDP запроса RFC 1928: RSV(2) FRAG(1) ATYP DST.ADDR DST.PORT DATA.
+fn parse_datagram(datagram: &[u8]) -> Result<(Target, &[u8]), &'static str> {
+    if datagram.len() < 3 {
+        return Err("datagram is too short");
+    }
+    // Фрагментацию не поддерживаем: такие датаграммы RFC 1928 требует отбрасывать
+    if datagram[2] != 0 {
+        return Err("fragmented datagrams are not supported");
+    }
+    let (target, len) = socks::parse_address(&datagram[3..]).ok_or("invalid destination address")?;
+    Ok((target, &datagram[3 + len..]))
+}
+
+fn open_session(target: Target, client: SocketAddr, relay: Arc<UdpSocket>, router: Arc<Router>) -> Session {
+    let (queue, datagrams) = mpsc::channel(SESSION_QUEUE);
+    let last_active = Arc::new(Mutex::new(Instant::now()));
+    let task = tokio::spawn(run_session(target, client, relay, router, datagrams, last_active.clone()));
+    Session { queue, last_active, task }
+}
+
+async fn run_session(
+    (host, port): Target,
+    client: SocketAddr,
+    relay: Arc<UdpSocket>,
+    router: Arc<Router>,
+    mut datagrams: mpsc::Receiver<Vec<u8>>,
+    last_active: Arc<Mutex<Instant>>,
+) {
+    let outbound: Box<dyn UdpSession> = match open_udp_route(&router, &host, port).await {
+        Ok(Some(outbound)) => outbound,
+        result => {
+            if let Err(e) = result {
+                log::warn!("UDP session to {}:{} failed: {}", host, port, e);
+            }
+            // Заблокированную цель держим в таблице до истечения сессии,
+            // чтобы не маршрутизировать каждую датаграмму заново
+            while datagrams.recv().await.is_some() {}
+            return;
+        }
+    };
+
+    // Заголовок ответа: источник - цель, к которой обращался клиент
+    let mut header = vec![0u8, 0, 0];
+    socks::encode_address(&host, port, &mut header);
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+
+    loop {
+        tokio::select! {
+            datagram = datagrams.recv() => {
+                let Some(datagram) = datagram else { return };
+                if let Err(e) = outbound.send(&datagram).await {
+                    log::debug!("UDP send to {}:{} failed: {}", host, port, e);
+                }
+            }
+            received = outbound.recv(&mut buf) => {
+                let len = match received {
+                    Ok(len) => len,
+                    // ICMP port unreachable на подключённом сокете не закрывает сессию
+                    Err(e) if is_refused(&e) => continue,
+                    Err(e) => {
+                        log::warn!("UDP session to {}:{} closed: {}", host, port, e);
+                        while datagrams.recv().await.is_some() {}
+                        return;
+                    }
+                };
+                *last_active.lock().unwrap() = Instant::now();
+
+                let mut response = Vec::with_capacity(header.len() + len);
+                response.extend_from_slice(&header);
+                response.extend_from_slice(&buf[..len]);
+                if let Err(e) = relay.send_to(&response, client).await {
+                    log::debug!("UDP relay send to {} failed: {}", client, e);
+                }
+            }
+        }
+    }
+}
+
+fn is_refused(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<std::io::Error>()
+        .is_some_and(|e| e.kind() == std::io::ErrorKind::ConnectionRefused)
+}
@@ -0,0 +1,184 @@
+use std::net::IpAddr;
+use ipnet::IpNet;
+use regex::{Regex, RegexBuilder};
+use anyhow::{anyhow, Result};
+use crate::models::Rule;
+
+mod index;
+
+pub use index::{find_linear, RuleIndex};
+
+/// Условие правила, разобранное один раз при загрузке.
+#[derive(Debug, Clone)]
+pub enum RuleMatcher {
+    Domain(String),
+    DomainSuffix(String),
+    DomainKeyword(String),
+    DomainWildcard(Regex),
+    DomainRegex(Regex),
+    IpCidr(IpNet),
+    DstPort(u16),
+}
+
+#[derive(Debug, Clone)]
+pub struct CompiledRule {
+    pub rule: Rule,
+    pub matcher: RuleMatcher,
+}
+
+impl RuleMatcher {
+    pub fn compile(rule_type: &str, pattern: &str) -> Result<Self> {
+        let pattern = pattern.trim();
+        if pattern.is_empty() {
+            return Err(anyhow!("Rule pattern must not be empty"));
+        }
+
+        match rule_type {
+            // Старые правила вида "*.example.com" с типом domain работают как glob
+            "domain" if is_glob(pattern) => Ok(RuleMatcher::DomainWildcard(compile_glob(pattern)?)),
+            "domain" => Ok(RuleMatcher::Domain(normalize_domain(pattern))),
+            "domain-suffix" => {
+                let suffix = normalize_domain(pattern.trim_start_matches('.'));
+                if suffix.is_empty() {
+                    return Err(anyhow!("Invalid domain suffix: {}", pattern));
+                }
+                Ok(RuleMatcher::DomainSuffix(suffix))
+            },
+            "domain-keyword" => Ok(RuleMatcher::DomainKeyword(pattern.to_ascii_lowercase())),
+            "domain-wildcard" => Ok(RuleMatcher::DomainWildcard(compile_glob(pattern)?)),
+            "domain-regex" => RegexBuilder::new(pattern)
+                .case_insensitive(true)
+                .size_limit(REGEX_SIZE_LIMIT)
+                .build()
+                .map(RuleMatcher::DomainRegex)
+                .map_err(|e| anyhow!("Invalid domain regex: {}", e)),
+            "ip-cidr" => Ok(RuleMatcher::IpCidr(parse_cidr(pattern)?)),
+            "ip-cidr6" => match parse_cidr(pattern)? {
+                net @ IpNet::V6(_) => Ok(RuleMatcher::IpCidr(net)),
+                IpNet::V4(_) => Err(anyhow!("ip-cidr6 rule requires an IPv6 network: {}", pattern)),
+            },
+            "dst-port" => pattern.parse::<u16>()
+                .map(RuleMatcher::DstPort)
+                .map_err(|_| anyhow!("Invalid port: {}", pattern)),
+            _ => Err(anyhow!("Unknown rule type: {}", rule_type)),
+        }
+    }
+
+    pub fn is_ip_rule(&self) -> bool {
+        matches!(self, RuleMatcher::IpCidr(_))
+    }
+
+    /// `host` должен быть уже нормализован через `normalize_domain`.
+    /// `resolved` - адреса доменной цели, если её разрешили заранее.
+    pub fn matches(&self, host: &str, port: u16, resolved: &[IpAddr]) -> bool {
+        match self {
+            RuleMatcher::Domain(domain) => host == domain,
+            RuleMatcher::DomainSuffix(suffix) => is_subdomain_of(host, suffix),
+            RuleMatcher::DomainKeyword(keyword) => host.contains(keyword.as_str()),
+            RuleMatcher::DomainWildcard(re) | RuleMatcher::DomainRegex(re) => re.is_match(host),
+            RuleMatcher::IpCidr(net) => match host.parse::<IpAddr>() {
+                Ok(ip) => net.contains(&ip.to_canonical()),
+                Err(_) => resolved.iter().any(|ip| net.contains(&ip.to_canonical())),
+            },
+            RuleMatcher::DstPort(expected) => *expected == port,
+        }
+    }
+}
+
+impl CompiledRule {
+    pub fn compile(rule: &Rule) -> Result<Self> {
+        Ok(Self {
+            rule: rule.clone(),
+            matcher: RuleMatcher::compile(&rule.rule_type, &rule.pattern)?,
+        })
+    }
+
+    pub fn matches(&self, host: &str, port: u16, resolved: &[IpAddr]) -> bool {
+        self.rule.enabled && self.matcher.matches(host, port, resolved)
+    }
+}
+
+pub const RULE_TYPES: &[&str] = &[
+    "domain",
+    "domain-suffix",
+    "domain-keyword",
+    "domain-wildcard",
+    "domain-regex",
+    "ip-cidr",
+    "ip-cidr6",
+    "dst-port",
+];
+
+/// Проверяет правило перед сохранением через API. Опечатка в типе - тоже
+/// ошибка: такое правило никогда бы не сработало.
+pub fn validate_rule(rule: &Rule) -> Result<()> {
+    if !RULE_TYPES.contains(&rule.rule_type.as_str()) {
+        return Err(anyhow!(
+            "Unknown rule type: {} (supported: {})", rule.rule_type, RULE_TYPES.join(", ")
+        ));
+    }
+    RuleMatcher::compile(&rule.rule_type, &rule.pattern).map(|_| ())
+}
+
+/// Компилирует правила в порядке проверки, пропуская некорректные.
+pub fn compile_rules(rules: &[Rule]) -> Vec<CompiledRule> {
+    let mut compiled: Vec<CompiledRule> = rules.iter()
+        .filter_map(|rule| match CompiledRule::compile(rule) {
+            Ok(compiled) => Some(compiled),
+            Err(e) => {
+                log::warn!("Skipping rule {} ({}): {}", rule.id, rule.name, e);
+                None
+            }
+        })
+        .collect();
+    // Большее значение priority проверяется раньше, как в get_rules;
+    // при равенстве сохраняется исходный порядок
+    compiled.sort_by_key(|c| std::cmp::Reverse(c.rule.priority));
+    compiled
+}
+
+pub fn normalize_domain(host: &str) -> String {
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// `google.com` совпадает с `google.com` и `mail.google.com`, но не с `notgoogle.com`.
+pub fn is_subdomain_of(host: &str, suffix: &str) -> bool {
+    match host.strip_suffix(suffix) {
+        Some("") => true,
+        Some(prefix) => prefix.ends_with('.'),
+        None => false,
+    }
+}
+
+// Ограничение на размер скомпилированного регулярного выражения из правила
+const REGEX_SIZE_LIMIT: usize = 1 << 20;
+
+fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?'])
+}
+
+/// Glob по имени целиком: `*` - любая последовательность символов, `?` - один символ.
+fn compile_glob(pattern: &str) -> Result<Regex> {
+    let mut re = String::from("^");
+    for ch in normalize_domain(pattern).chars() {
+        match ch {
+            '*' => re.push_str(".*"),
+            '?' => re.push('.'),
+            _ => re.push_str(&regex::escape(ch.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    re.push('$');
+    Regex::new(&re).map_err(|e| anyhow!("Invalid domain wildcard {}: {}", pattern, e))
+}
+
+fn parse_cidr(pattern: &str) -> Result<IpNet> {
+    let (addr, prefix) = pattern.split_once('/')
+        .ok_or_else(|| anyhow!("CIDR must have a prefix length: {}", pattern))?;
+    let addr: IpAddr = addr.parse()
+        .map_err(|_| anyhow!("Invalid network address: {}", addr))?;
+    let prefix: u8 = prefix.parse()
+        .map_err(|_| anyhow!("Invalid prefix length: {}", prefix))?;
+    let net = IpNet::new(addr, prefix)
+        .map_err(|_| anyhow!("Prefix length {} is out of range for {}", prefix, addr))?;
+    Ok(net.trunc())
+}
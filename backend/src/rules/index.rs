@@ -0,0 +1,279 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use aho_corasick::AhoCorasick;
+use super::{CompiledRule, RuleMatcher};
+
+/// Индекс правил для быстрого поиска первого совпадения.
+///
+/// Правила хранятся в порядке проверки, и каждая структура возвращает
+/// наименьшую позицию подходящего правила. Поэтому результат совпадает с
+/// линейным перебором, но не зависит от общего числа правил:
+/// домены ищутся в дереве меток, сети - в префиксном дереве по битам адреса,
+/// ключевые слова - автоматом Ахо-Корасик. Glob и regex правила, которые
+/// не индексируются, проверяются перебором только до лучшей найденной позиции.
+#[derive(Clone)]
+pub struct RuleIndex {
+    rules: Vec<CompiledRule>,
+    domains: DomainTrie,
+    keywords: Option<KeywordMatcher>,
+    networks: CidrTree,
+    ports: HashMap<u16, usize>,
+    // Позиции правил, которые проверяются перебором
+    scanned: Vec<usize>,
+}
+
+impl RuleIndex {
+    /// `rules` должны быть уже упорядочены по приоритету.
+    pub fn new(rules: Vec<CompiledRule>) -> Self {
+        let mut domains = DomainTrie::default();
+        let mut keywords: Vec<(String, usize)> = Vec::new();
+        let mut networks = CidrTree::default();
+        let mut ports = HashMap::new();
+        let mut scanned = Vec::new();
+
+        for (pos, compiled) in rules.iter().enumerate() {
+            if !compiled.rule.enabled {
+                continue;
+            }
+            match &compiled.matcher {
+                RuleMatcher::Domain(domain) => domains.insert(domain, pos, false),
+                RuleMatcher::DomainSuffix(suffix) => domains.insert(suffix, pos, true),
+                RuleMatcher::DomainKeyword(keyword) => keywords.push((keyword.clone(), pos)),
+                RuleMatcher::IpCidr(net) => networks.insert(net, pos),
+                RuleMatcher::DstPort(port) => {
+                    ports.entry(*port).or_insert(pos);
+                }
+                RuleMatcher::DomainWildcard(_) | RuleMatcher::DomainRegex(_) => scanned.push(pos),
+            }
+        }
+
+        Self {
+            rules,
+            domains,
+            keywords: KeywordMatcher::new(keywords),
+            networks,
+            ports,
+            scanned,
+        }
+    }
+
+    pub fn rules(&self) -> &[CompiledRule] {
+        &self.rules
+    }
+
+    pub fn has_ip_rules(&self) -> bool {
+        !self.networks.is_empty()
+    }
+
+    /// `host` должен быть уже нормализован через `normalize_domain`.
+    pub fn find(&self, host: &str, port: u16, resolved: &[IpAddr]) -> Option<&CompiledRule> {
+        let mut best = self.domains.find(host);
+
+        if let Some(keywords) = &self.keywords {
+            best = min_pos(best, keywords.find(host));
+        }
+        match host.parse::<IpAddr>() {
+            Ok(ip) => best = min_pos(best, self.networks.find(ip)),
+            Err(_) => {
+                for ip in resolved {
+                    best = min_pos(best, self.networks.find(*ip));
+                }
+            }
+        }
+        best = min_pos(best, self.ports.get(&port).copied());
+
+        for &pos in &self.scanned {
+            if best.is_some_and(|b| pos > b) {
+                break;
+            }
+            if self.rules[pos].matcher.matches(host, port, resolved) {
+                best = Some(pos);
+                break;
+            }
+        }
+
+        best.map(|pos| &self.rules[pos])
+    }
+}
+
+/// Линейный поиск первого совпадения; эталон для индекса.
+pub fn find_linear<'a>(rules: &'a [CompiledRule], host: &str, port: u16, resolved: &[IpAddr]) -> Option<&'a CompiledRule> {
+    rules.iter().find(|compiled| compiled.matches(host, port, resolved))
+}
+
+// Дерево доменных меток, начиная с TLD: "mail.google.com" -> com -> google -> mail
+#[derive(Clone)]
+struct DomainTrie {
+    nodes: Vec<DomainNode>,
+}
+
+#[derive(Clone, Default)]
+struct DomainNode {
+    children: HashMap<Box<str>, usize>,
+    exact: Option<usize>,
+    suffix: Option<usize>,
+}
+
+impl Default for DomainTrie {
+    fn default() -> Self {
+        Self { nodes: vec![DomainNode::default()] }
+    }
+}
+
+impl DomainTrie {
+    fn insert(&mut self, domain: &str, pos: usize, suffix: bool) {
+        let mut node = 0;
+        for label in domain.rsplit('.') {
+            node = match self.nodes[node].children.get(label) {
+                Some(&child) => child,
+                None => {
+                    self.nodes.push(DomainNode::default());
+                    let child = self.nodes.len() - 1;
+                    self.nodes[node].children.insert(label.into(), child);
+                    child
+                }
+            };
+        }
+        let slot = if suffix { &mut self.nodes[node].suffix } else { &mut self.nodes[node].exact };
+        // При дубликатах выигрывает правило с меньшей позицией
+        if slot.is_none_or(|existing| pos < existing) {
+            *slot = Some(pos);
+        }
+    }
+
+    fn find(&self, host: &str) -> Option<usize> {
+        let mut best: Option<usize> = None;
+        let mut node = 0;
+        for label in host.rsplit('.') {
+            node = match self.nodes[node].children.get(label) {
+                Some(&child) => child,
+                None => return best,
+            };
+            best = min_pos(best, self.nodes[node].suffix);
+        }
+        min_pos(best, self.nodes[node].exact)
+    }
+}
+
+#[derive(Clone)]
+struct KeywordMatcher {
+    automaton: AhoCorasick,
+    // Позиция первого правила для каждого уникального ключевого слова
+    positions: Vec<usize>,
+}
+
+impl KeywordMatcher {
+    fn new(keywords: Vec<(String, usize)>) -> Option<Self> {
+        if keywords.is_empty() {
+            return None;
+        }
+
+        let mut unique: HashMap<String, usize> = HashMap::new();
+        for (keyword, pos) in keywords {
+            unique.entry(keyword).and_modify(|p| *p = (*p).min(pos)).or_insert(pos);
+        }
+        let (patterns, positions): (Vec<String>, Vec<usize>) = unique.into_iter().unzip();
+
+        match AhoCorasick::new(&patterns) {
+            Ok(automaton) => Some(Self { automaton, positions }),
+            Err(e) => {
+                log::error!("Failed to build keyword automaton: {}", e);
+                None
+            }
+        }
+    }
+
+    fn find(&self, host: &str) -> Option<usize> {
+        self.automaton.find_overlapping_iter(host)
+            .map(|m| self.positions[m.pattern().as_usize()])
+            .min()
+    }
+}
+
+// Префиксные деревья по битам адреса, отдельно для IPv4 и IPv6
+#[derive(Clone, Default)]
+struct CidrTree {
+    v4: BitTrie,
+    v6: BitTrie,
+    len: usize,
+}
+
+impl CidrTree {
+    fn insert(&mut self, net: &ipnet::IpNet, pos: usize) {
+        self.len += 1;
+        match net {
+            ipnet::IpNet::V4(net) => self.v4.insert(u32::from(net.network()) as u128, 32, net.prefix_len(), pos),
+            ipnet::IpNet::V6(net) => self.v6.insert(u128::from(net.network()), 128, net.prefix_len(), pos),
+        }
+    }
+
+    fn find(&self, ip: IpAddr) -> Option<usize> {
+        match ip.to_canonical() {
+            IpAddr::V4(ip) => self.v4.find(u32::from(ip) as u128, 32),
+            IpAddr::V6(ip) => self.v6.find(u128::from(ip), 128),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+#[derive(Clone)]
+struct BitTrie {
+    nodes: Vec<BitNode>,
+}
+
+#[derive(Clone, Default)]
+struct BitNode {
+    // 0 - нет потомка: корень не может быть чьим-то потомком
+    children: [usize; 2],
+    pos: Option<usize>,
+}
+
+impl Default for BitTrie {
+    fn default() -> Self {
+        Self { nodes: vec![BitNode::default()] }
+    }
+}
+
+impl BitTrie {
+    fn insert(&mut self, addr: u128, width: u8, prefix_len: u8, pos: usize) {
+        let mut node = 0;
+        for i in 0..prefix_len {
+            let bit = ((addr >> (width - 1 - i)) & 1) as usize;
+            if self.nodes[node].children[bit] == 0 {
+                self.nodes.push(BitNode::default());
+                let child = self.nodes.len() - 1;
+                self.nodes[node].children[bit] = child;
+            }
+            node = self.nodes[node].children[bit];
+        }
+        let slot = &mut self.nodes[node].pos;
+        if slot.is_none_or(|existing| pos < existing) {
+            *slot = Some(pos);
+        }
+    }
+
+    fn find(&self, addr: u128, width: u8) -> Option<usize> {
+        let mut node = 0;
+        let mut best = self.nodes[0].pos;
+        for i in 0..width {
+            let bit = ((addr >> (width - 1 - i)) & 1) as usize;
+            node = self.nodes[node].children[bit];
+            if node == 0 {
+                break;
+            }
+            best = min_pos(best, self.nodes[node].pos);
+        }
+        best
+    }
+}
+
+fn min_pos(a: Option<usize>, b: Option<usize>) -> Option<usize> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
@@ -1,4 +1,4 @@
-use crate::models::{ProxyServerV2, VlessConfig, VmessConfig, ProxyConfig, ProxyProtocol}; // Удаляем Subscription
+use crate::models::{ProxyServerV2, VlessConfig, VmessConfig, WsConfig, GrpcConfig, ProxyConfig, ProxyProtocol}; // Удаляем Subscription
 use anyhow::{Result, anyhow};
 use base64::{Engine as _, engine::general_purpose};
 use regex::Regex;
@@ -7,6 +7,12 @@ use std::collections::HashMap;
 
 pub struct SubscriptionParser;
 
+impl Default for SubscriptionParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl SubscriptionParser {
     pub fn new() -> Self {
         Self
@@ -24,12 +30,19 @@ impl SubscriptionParser {
             let name = urlencoding::decode(caps.get(5).unwrap().as_str())?.to_string();
 
             let query_params = self.parse_query_params(params)?;
+            let network = query_params.get("type").unwrap_or(&"tcp".to_string()).clone();
+            let ws = (network == "ws").then(|| {
+                self.parse_ws_config(query_params.get("path").map(String::as_str), query_params.get("host").map(String::as_str))
+            });
+            let grpc = (network == "grpc").then(|| {
+                self.parse_grpc_config(query_params.get("serviceName").map(String::as_str), query_params.get("mode").map(String::as_str))
+            }).transpose()?;
             
             let vless_config = VlessConfig {
                 uuid,
                 flow: query_params.get("flow").cloned(),
                 encryption: query_params.get("encryption").unwrap_or(&"none".to_string()).clone(),
-                network: query_params.get("type").unwrap_or(&"tcp".to_string()).clone(),
+                network,
                 security: query_params.get("security").unwrap_or(&"none".to_string()).clone(),
                 sni: query_params.get("sni").cloned(),
                 alpn: query_params.get("alpn").map(|s| s.split(',').map(|s| s.to_string()).collect()),
@@ -37,6 +50,8 @@ impl SubscriptionParser {
                 pbk: query_params.get("pbk").cloned(),
                 sid: query_params.get("sid").cloned(),
                 spx: query_params.get("spx").cloned(),
+                ws,
+                grpc,
             };
 
             Ok(ProxyServerV2 {
@@ -54,6 +69,8 @@ impl SubscriptionParser {
                 upload_speed: None,
                 download_speed: None,
                 subscription_id: None, // Добавить эту строку
+                failure_streak: 0,
+                up: None,
             })
         } else {
             Err(anyhow!("Invalid VLESS URL format"))
@@ -71,14 +88,22 @@ impl SubscriptionParser {
         let json_str = String::from_utf8(decoded)?;
         let config: Value = serde_json::from_str(&json_str)?;
 
+        let network = config["net"].as_str().unwrap_or("tcp").to_string();
+        let ws = (network == "ws").then(|| self.parse_ws_config(config["path"].as_str(), config["host"].as_str()));
+        // В формате v2rayN для grpc path - имя сервиса, type - режим
+        let grpc = (network == "grpc").then(|| self.parse_grpc_config(config["path"].as_str(), config["type"].as_str())).transpose()?;
+
         let vmess_config = VmessConfig {
             uuid: config["id"].as_str().unwrap_or("").to_string(),
             alter_id: config["aid"].as_u64().unwrap_or(0) as u16,
             security: config["scy"].as_str().unwrap_or("auto").to_string(),
-            network: config["net"].as_str().unwrap_or("tcp").to_string(),
+            network,
             tls: config["tls"].as_str().unwrap_or("") == "tls",
             sni: config["sni"].as_str().map(|s| s.to_string()),
             alpn: config["alpn"].as_str().map(|s| s.split(',').map(|s| s.to_string()).collect()),
+            skip_cert_verify: false,
+            ws,
+            grpc,
         };
 
         Ok(ProxyServerV2 {
@@ -96,6 +121,8 @@ impl SubscriptionParser {
             upload_speed: None,
             download_speed: None,
             subscription_id: None, // Добавить эту строку
+            failure_streak: 0,
+            up: None,
         })
     }
 
@@ -140,6 +167,40 @@ impl SubscriptionParser {
         Ok(servers)
     }
 
+    // В ссылках early data задаётся в пути: /path?ed=2048
+    fn parse_ws_config(&self, path: Option<&str>, host: Option<&str>) -> WsConfig {
+        let path = path.filter(|p| !p.is_empty()).unwrap_or("/");
+        let (base, query) = path.split_once('?').unwrap_or((path, ""));
+        let mut max_early_data = 0;
+        let mut rest = Vec::new();
+        for param in query.split('&').filter(|p| !p.is_empty()) {
+            match param.strip_prefix("ed=").map(str::parse::<usize>) {
+                Some(Ok(ed)) => max_early_data = ed,
+                _ => rest.push(param),
+            }
+        }
+
+        WsConfig {
+            path: if rest.is_empty() { base.to_string() } else { format!("{}?{}", base, rest.join("&")) },
+            host: host.filter(|h| !h.is_empty()).map(str::to_string),
+            max_early_data,
+            early_data_header: None,
+        }
+    }
+
+    fn parse_grpc_config(&self, service_name: Option<&str>, mode: Option<&str>) -> Result<GrpcConfig> {
+        // none у vmess означает режим по умолчанию
+        let mode = match mode.unwrap_or_default().to_ascii_lowercase().as_str() {
+            "" | "none" | "gun" => "gun",
+            "multi" => "multi",
+            mode => return Err(anyhow!("Unknown gRPC mode \"{}\", expected gun or multi", mode)),
+        };
+        Ok(GrpcConfig {
+            service_name: service_name.unwrap_or_default().to_string(),
+            mode: mode.to_string(),
+        })
+    }
+
     fn parse_query_params(&self, params: &str) -> Result<HashMap<String, String>> {
         let mut map = HashMap::new();
         
@@ -2,18 +2,28 @@ use actix::prelude::*;
 use actix_web::{web, Error, HttpRequest, HttpResponse};
 use actix_web_actors::ws;
 use serde_json::json;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use crate::proxy::failover::FailoverEvent;
+use crate::proxy::ProxyEngine;
 
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub struct WebSocketSession {
     hb: Instant,
+    failover_events: Option<broadcast::Receiver<FailoverEvent>>,
 }
 
 impl WebSocketSession {
     pub fn new() -> Self {
-        Self { hb: Instant::now() }
+        Self { hb: Instant::now(), failover_events: None }
+    }
+
+    /// Сессия, которая пересылает клиенту события failover.
+    pub fn with_failover_events(events: broadcast::Receiver<FailoverEvent>) -> Self {
+        Self { hb: Instant::now(), failover_events: Some(events) }
     }
 
     fn hb(&self, ctx: &mut <Self as Actor>::Context) {
@@ -41,6 +51,12 @@ impl WebSocketSession {
     }
 }
 
+impl Default for WebSocketSession {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Actor for WebSocketSession {
     type Context = ws::WebsocketContext<Self>;
 
@@ -54,7 +70,34 @@ impl Actor for WebSocketSession {
         ctx.run_interval(Duration::from_secs(1), |act, ctx| {
             act.send_status_update(ctx);
         });
+
+        if let Some(events) = self.failover_events.take() {
+            // Отставший подписчик пропускает старые события
+            let events = futures_util::stream::unfold(events, |mut events| async move {
+                loop {
+                    match events.recv().await {
+                        Ok(event) => return Some((event, events)),
+                        Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(broadcast::error::RecvError::Closed) => return None,
+                    }
+                }
+            });
+            ctx.add_stream(events);
+        }
+    }
+}
+
+impl StreamHandler<FailoverEvent> for WebSocketSession {
+    fn handle(&mut self, event: FailoverEvent, ctx: &mut Self::Context) {
+        let message = json!({
+            "type": "failover",
+            "payload": event
+        });
+        ctx.text(message.to_string());
     }
+
+    // Конец потока событий не закрывает сессию
+    fn finished(&mut self, _ctx: &mut Self::Context) {}
 }
 
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebSocketSession {
@@ -84,6 +127,7 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebSocketSession
 pub async fn websocket_handler(
     req: HttpRequest,
     stream: web::Payload,
+    engine: web::Data<Arc<ProxyEngine>>,
 ) -> Result<HttpResponse, Error> {
-    ws::start(WebSocketSession::new(), &req, stream)
+    ws::start(WebSocketSession::with_failover_events(engine.failover_events()), &req, stream)
 }
\ No newline at end of file
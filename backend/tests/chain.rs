@@ -0,0 +1,91 @@
+mod common;
+
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use stealthcat_backend::models::{ProxyConfig, ProxyProtocol, ProxyServerV2};
+use stealthcat_backend::proxy::chain;
+use common::*;
+
+const UPSTREAM: ([u8; 4], u16) = ([127, 0, 0, 1], 1080);
+
+fn socks5(name: &str) -> ProxyServerV2 {
+    server(name, ProxyProtocol::SOCKS5, ProxyConfig::Socks5 { username: None, password: None }, UPSTREAM.into())
+}
+
+fn shadowsocks(name: &str) -> ProxyServerV2 {
+    let config = ProxyConfig::Shadowsocks { method: "aes-256-gcm".to_string(), password: "secret".to_string() };
+    server(name, ProxyProtocol::Shadowsocks, config, UPSTREAM.into())
+}
+
+fn chain_of(name: &str, hops: &[&str]) -> ProxyServerV2 {
+    let hops = hops.iter().map(|hop| format!("{}-id", hop)).collect();
+    server(name, ProxyProtocol::Chain, ProxyConfig::Chain { hops }, UPSTREAM.into())
+}
+
+fn names(hops: &[ProxyServerV2]) -> Vec<&str> {
+    hops.iter().map(|s| s.name.as_str()).collect()
+}
+
+#[test]
+fn plain_server_is_a_single_hop() {
+    let server = socks5("a");
+    assert_eq!(names(&chain::expand(&server, &[]).unwrap()), ["a"]);
+}
+
+#[test]
+fn nested_chains_are_flattened_in_order() {
+    let servers = vec![
+        socks5("a"),
+        shadowsocks("b"),
+        socks5("c"),
+        chain_of("inner", &["b", "c"]),
+    ];
+    let outer = chain_of("outer", &["a", "inner", "a"]);
+    // Повтор сервера без вложенности в себя - не цикл
+    assert_eq!(names(&chain::expand(&outer, &servers).unwrap()), ["a", "b", "c", "a"]);
+}
+
+#[test]
+fn cycle_is_rejected() {
+    let servers = vec![socks5("a"), chain_of("first", &["a", "second"]), chain_of("second", &["first"])];
+    let error = chain::expand(&servers[1], &servers).unwrap_err().to_string();
+    assert_eq!(error, "Proxy chain contains a cycle: first -> second -> first");
+}
+
+#[test]
+fn self_reference_is_rejected() {
+    let servers = vec![chain_of("loop", &["loop"])];
+    let error = chain::expand(&servers[0], &servers).unwrap_err().to_string();
+    assert!(error.contains("cycle: loop -> loop"), "{}", error);
+}
+
+#[test]
+fn missing_hop_is_rejected() {
+    let servers = vec![socks5("a"), chain_of("inner", &["gone"])];
+    let outer = chain_of("outer", &["a", "inner"]);
+    let error = chain::expand(&outer, &servers).unwrap_err().to_string();
+    assert_eq!(error, "Chain \"inner\" references unknown server gone-id");
+}
+
+#[test]
+fn empty_chain_is_rejected() {
+    let servers = vec![chain_of("empty", &[])];
+    let outer = chain_of("outer", &["empty"]);
+    let error = chain::expand(&outer, &servers).unwrap_err().to_string();
+    assert_eq!(error, "Chain \"empty\" has no servers");
+}
+
+#[tokio::test]
+async fn udp_is_not_sent_through_a_chain() {
+    let echo = start_udp_echo_server().await;
+    // SOCKS5 hop не умеет UDP, поэтому цепочка целиком работает только для TCP
+    let servers = vec![socks5("a"), shadowsocks("b"), chain_of("chain", &["a", "b"])];
+    let proxy = start_engine(vec![port_rule(echo.port(), "chain")], servers).await;
+
+    let (_control, relay) = socks5_udp_associate(proxy).await;
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    client.send_to(&udp_datagram(echo, b"hello"), relay).await.unwrap();
+    let mut buf = vec![0u8; 1024];
+    let reply = tokio::time::timeout(Duration::from_millis(500), client.recv_from(&mut buf)).await;
+    assert!(reply.is_err(), "datagram must not reach the target through a chain");
+}
@@ -0,0 +1,296 @@
+#![allow(dead_code)]
+
+pub mod vmess;
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+use stealthcat_backend::config::ConfigManager;
+use stealthcat_backend::database::Database;
+use stealthcat_backend::models::{MihomoConfig, ProxyConfig, ProxyProtocol, ProxyServerV2, Rule};
+use stealthcat_backend::proxy::ProxyEngine;
+
+/// Запускает mixed listener движка с заданными правилами и серверами.
+pub async fn start_engine(rules: Vec<Rule>, servers: Vec<ProxyServerV2>) -> SocketAddr {
+    start_engine_with_config(ConfigManager::get_default_config(), rules, servers).await
+}
+
+/// То же, что `start_engine`, но с настройками из `config`.
+pub async fn start_engine_with_config(config: MihomoConfig, rules: Vec<Rule>, servers: Vec<ProxyServerV2>) -> SocketAddr {
+    serve_engine(Arc::new(ProxyEngine::new(config, rules, servers, Vec::new()))).await
+}
+
+/// Запускает mixed listener уже созданного движка.
+pub async fn serve_engine(engine: Arc<ProxyEngine>) -> SocketAddr {
+    let addr = free_addr();
+    tokio::spawn(async move {
+        engine.start_mixed_server(addr).await.expect("mixed listener failed");
+    });
+    wait_for_listener(addr).await;
+    addr
+}
+
+fn free_addr() -> SocketAddr {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap()
+}
+
+async fn wait_for_listener(addr: SocketAddr) {
+    for _ in 0..100 {
+        if TcpStream::connect(addr).await.is_ok() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("listener on {} did not start", addr);
+}
+
+pub fn server(name: &str, protocol: ProxyProtocol, config: ProxyConfig, addr: SocketAddr) -> ProxyServerV2 {
+    ProxyServerV2 {
+        id: format!("{}-id", name),
+        name: name.to_string(),
+        hostname: addr.ip().to_string(),
+        port: addr.port(),
+        protocol,
+        config,
+        latency_ms: None,
+        last_ping: None,
+        active: true,
+        country: None,
+        city: None,
+        upload_speed: None,
+        download_speed: None,
+        subscription_id: None,
+        failure_streak: 0,
+        up: None,
+    }
+}
+
+/// Правило, отправляющее трафик на порт `port` через сервер `action`.
+pub fn port_rule(port: u16, action: &str) -> Rule {
+    Rule {
+        id: format!("rule-{}", port),
+        name: format!("port {}", port),
+        rule_type: "dst-port".to_string(),
+        pattern: port.to_string(),
+        action: action.to_string(),
+        priority: 0,
+        enabled: true,
+    }
+}
+
+/// TCP сервер, который возвращает всё полученное.
+pub async fn start_echo_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+    addr
+}
+
+/// UDP сервер, который возвращает каждую датаграмму с префиксом "echo:".
+pub async fn start_udp_echo_server() -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 65535];
+        loop {
+            let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+            let mut reply = b"echo:".to_vec();
+            reply.extend_from_slice(&buf[..len]);
+            socket.send_to(&reply, peer).await.unwrap();
+        }
+    });
+    addr
+}
+
+/// Тестовый URL: отвечает 204 и сообщает путь каждого запроса.
+pub async fn start_endpoint() -> (SocketAddr, mpsc::UnboundedReceiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (requests, received) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let requests = requests.clone();
+            tokio::spawn(async move {
+                let mut stream = BufReader::new(stream);
+                let mut line = String::new();
+                stream.read_line(&mut line).await.unwrap();
+                let _ = requests.send(line.split_whitespace().nth(1).unwrap_or_default().to_string());
+                stream.write_all(b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n").await.unwrap();
+            });
+        }
+    });
+    (addr, received)
+}
+
+/// Вышестоящий HTTP прокси с CONNECT; пока `up` сброшен, сразу закрывает соединения.
+pub async fn start_upstream(up: Arc<AtomicBool>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            if !up.load(Ordering::SeqCst) {
+                continue;
+            }
+            tokio::spawn(async move {
+                let mut stream = BufReader::new(stream);
+                let mut request = String::new();
+                stream.read_line(&mut request).await.unwrap();
+                let target = request.split_whitespace().nth(1).unwrap().to_string();
+                loop {
+                    let mut header = String::new();
+                    stream.read_line(&mut header).await.unwrap();
+                    if header == "\r\n" {
+                        break;
+                    }
+                }
+                let mut upstream = TcpStream::connect(target).await.unwrap();
+                stream.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n").await.unwrap();
+                let _ = tokio::io::copy_bidirectional(&mut stream, &mut upstream).await;
+            });
+        }
+    });
+    addr
+}
+
+/// Пустая база во временном каталоге.
+pub async fn temp_database() -> Arc<Database> {
+    let path = std::env::temp_dir().join(format!("stealthcat-test-{}.db", uuid::Uuid::new_v4()));
+    Arc::new(Database::new(&format!("sqlite://{}?mode=rwc", path.display())).await.unwrap())
+}
+
+/// SOCKS5 CONNECT через движок без авторизации.
+pub async fn socks5_connect(proxy: SocketAddr, target: SocketAddr) -> TcpStream {
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    stream.write_all(&[5, 1, 0]).await.unwrap();
+    let mut method = [0u8; 2];
+    stream.read_exact(&mut method).await.unwrap();
+    assert_eq!(method, [5, 0]);
+
+    let mut request = vec![5, 1, 0];
+    push_address(&mut request, target);
+    stream.write_all(&request).await.unwrap();
+
+    let mut reply = [0u8; 10];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[1], 0, "SOCKS5 CONNECT to {} failed with reply {}", target, reply[1]);
+    stream
+}
+
+/// SOCKS5 CONNECT к `host:port`, где `host` - IP или домен; возвращает
+/// поток и код ответа.
+pub async fn socks5_request(proxy: SocketAddr, host: &str, port: u16) -> (TcpStream, u8) {
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    stream.write_all(&[5, 1, 0]).await.unwrap();
+    let mut method = [0u8; 2];
+    stream.read_exact(&mut method).await.unwrap();
+
+    let mut request = vec![5, 1, 0];
+    match host.parse() {
+        Ok(ip) => push_address(&mut request, SocketAddr::new(ip, port)),
+        Err(_) => {
+            request.extend_from_slice(&[3, host.len() as u8]);
+            request.extend_from_slice(host.as_bytes());
+            request.extend_from_slice(&port.to_be_bytes());
+        }
+    }
+    stream.write_all(&request).await.unwrap();
+    // Ответ движка всегда с IPv4 BND.ADDR
+    let mut reply = [0u8; 10];
+    stream.read_exact(&mut reply).await.unwrap();
+    (stream, reply[1])
+}
+
+/// Открывает UDP ассоциацию; возвращает управляющее соединение и адрес relay.
+pub async fn socks5_udp_associate(proxy: SocketAddr) -> (TcpStream, SocketAddr) {
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    stream.write_all(&[5, 1, 0]).await.unwrap();
+    let mut method = [0u8; 2];
+    stream.read_exact(&mut method).await.unwrap();
+
+    stream.write_all(&[5, 3, 0, 1, 0, 0, 0, 0, 0, 0]).await.unwrap();
+    let mut reply = [0u8; 10];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[1], 0, "UDP ASSOCIATE failed with reply {}", reply[1]);
+    let ip = std::net::Ipv4Addr::new(reply[4], reply[5], reply[6], reply[7]);
+    let port = u16::from_be_bytes([reply[8], reply[9]]);
+    (stream, SocketAddr::from((ip, port)))
+}
+
+/// Датаграмма SOCKS5 UDP к `target`.
+pub fn udp_datagram(target: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let mut datagram = vec![0, 0, 0];
+    push_address(&mut datagram, target);
+    datagram.extend_from_slice(payload);
+    datagram
+}
+
+pub fn push_address(buf: &mut Vec<u8>, addr: SocketAddr) {
+    match addr {
+        SocketAddr::V4(v4) => {
+            buf.push(1);
+            buf.extend_from_slice(&v4.ip().octets());
+        }
+        SocketAddr::V6(v6) => {
+            buf.push(4);
+            buf.extend_from_slice(&v6.ip().octets());
+        }
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+/// Читает адрес SOCKS5 (ATYP, адрес, порт) и возвращает его и длину.
+pub fn parse_address(buf: &[u8]) -> (SocketAddr, usize) {
+    match buf[0] {
+        1 => {
+            let ip = std::net::Ipv4Addr::new(buf[1], buf[2], buf[3], buf[4]);
+            (SocketAddr::from((ip, u16::from_be_bytes([buf[5], buf[6]]))), 7)
+        }
+        4 => {
+            let octets: [u8; 16] = buf[1..17].try_into().unwrap();
+            let ip = std::net::Ipv6Addr::from(octets);
+            (SocketAddr::from((ip, u16::from_be_bytes([buf[17], buf[18]]))), 19)
+        }
+        atyp => panic!("unexpected address type {}", atyp),
+    }
+}
+
+/// Payload, на котором видны ошибки на границах чанков.
+pub fn test_payload(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 31 % 251) as u8).collect()
+}
+
+/// Пишет payload и читает столько же байт обратно, одновременно.
+pub async fn echo_round_trip<S>(stream: S, payload: &[u8]) -> Vec<u8>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + 'static,
+{
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let to_send = payload.to_vec();
+    let writer_task = tokio::spawn(async move {
+        writer.write_all(&to_send).await.unwrap();
+        writer.flush().await.unwrap();
+        writer
+    });
+    let mut received = vec![0u8; payload.len()];
+    tokio::time::timeout(Duration::from_secs(10), reader.read_exact(&mut received))
+        .await
+        .expect("echo timed out")
+        .unwrap();
+    writer_task.await.unwrap();
+    received
+}
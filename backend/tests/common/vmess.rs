@@ -0,0 +1,232 @@
+use std::rc::Rc;
+use aes::cipher::{generic_array::GenericArray, BlockDecrypt, KeyInit};
+use aes::Aes128;
+use md5::Md5;
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey};
+use sha2::{Digest, Sha256};
+use sha3::digest::{ExtendableOutput, Update, XofReader};
+use sha3::Shake128;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+// Минимальный VMess AEAD сервер (alterId 0), написанный по спецификации
+// v2ray отдельно от клиента в крейте
+
+pub const UUID: &str = "b831381d-6324-4d53-ad4f-8cda48b30811";
+
+type Hash = Rc<dyn Fn(&[u8]) -> Vec<u8>>;
+
+// HMAC, у которого хэш-функцией служит другой HMAC
+fn hmac_over(hash: Hash, key: &[u8]) -> Hash {
+    let mut block = [0u8; 64];
+    if key.len() > 64 {
+        let hashed = hash(key);
+        block[..hashed.len()].copy_from_slice(&hashed);
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    Rc::new(move |data: &[u8]| {
+        let inner: Vec<u8> = block.iter().map(|b| b ^ 0x36).chain(data.iter().copied()).collect();
+        let inner_hash = hash(&inner);
+        let outer: Vec<u8> = block.iter().map(|b| b ^ 0x5c).chain(inner_hash).collect();
+        hash(&outer)
+    })
+}
+
+fn kdf(key: &[u8], path: &[&[u8]]) -> Vec<u8> {
+    let mut hash: Hash = Rc::new(|data: &[u8]| Sha256::digest(data).to_vec());
+    hash = hmac_over(hash, b"VMess AEAD KDF");
+    for element in path {
+        hash = hmac_over(hash, element);
+    }
+    hash(key)
+}
+
+fn cmd_key() -> Vec<u8> {
+    let uuid = uuid::Uuid::parse_str(UUID).unwrap();
+    Md5::new()
+        .chain_update(uuid.as_bytes())
+        .chain_update(b"c48619fe-8f02-49e0-b9e9-edf763e17e21")
+        .finalize()
+        .to_vec()
+}
+
+fn aes_gcm(key: &[u8]) -> LessSafeKey {
+    LessSafeKey::new(UnboundKey::new(&aead::AES_128_GCM, &key[..16]).unwrap())
+}
+
+fn nonce(bytes: &[u8]) -> Nonce {
+    Nonce::try_assume_unique_for_key(&bytes[..12]).unwrap()
+}
+
+/// Чанки тела в одну сторону: маска длины из SHAKE128(IV) и AEAD со счётчиком.
+struct Body {
+    aead: Option<LessSafeKey>,
+    iv: Vec<u8>,
+    count: u16,
+    shake: sha3::Shake128Reader,
+}
+
+impl Body {
+    fn new(security: u8, key: &[u8], iv: &[u8]) -> Self {
+        let aead = match security {
+            3 => Some(aes_gcm(key)),
+            4 => {
+                let first = Md5::digest(key);
+                let second = Md5::digest(first);
+                let key = [first.as_slice(), second.as_slice()].concat();
+                Some(LessSafeKey::new(UnboundKey::new(&aead::CHACHA20_POLY1305, &key).unwrap()))
+            }
+            5 => None,
+            _ => panic!("unexpected security {}", security),
+        };
+        let mut shake = Shake128::default();
+        shake.update(iv);
+        Self { aead, iv: iv.to_vec(), count: 0, shake: shake.finalize_xof() }
+    }
+
+    fn mask(&mut self) -> u16 {
+        let mut mask = [0u8; 2];
+        self.shake.read(&mut mask);
+        u16::from_be_bytes(mask)
+    }
+
+    fn nonce(&mut self) -> Nonce {
+        let mut bytes = self.iv[..12].to_vec();
+        bytes[..2].copy_from_slice(&self.count.to_be_bytes());
+        self.count += 1;
+        nonce(&bytes)
+    }
+
+    /// Читает один чанк; `None` - завершающий пустой чанк.
+    async fn read_chunk<R: AsyncRead + Unpin>(&mut self, reader: &mut R) -> Option<Vec<u8>> {
+        let size = (reader.read_u16().await.ok()? ^ self.mask()) as usize;
+        let mut chunk = vec![0u8; size];
+        reader.read_exact(&mut chunk).await.ok()?;
+        if self.aead.is_some() {
+            let nonce = self.nonce();
+            let len = self.aead.as_ref().unwrap().open_in_place(nonce, Aad::empty(), &mut chunk).unwrap().len();
+            chunk.truncate(len);
+        }
+        (!chunk.is_empty()).then_some(chunk)
+    }
+
+    fn encode(&mut self, payload: &[u8]) -> Vec<u8> {
+        let mut sealed = payload.to_vec();
+        if self.aead.is_some() {
+            let nonce = self.nonce();
+            self.aead.as_ref().unwrap().seal_in_place_append_tag(nonce, Aad::empty(), &mut sealed).unwrap();
+        }
+        let mut chunk = ((sealed.len() as u16) ^ self.mask()).to_be_bytes().to_vec();
+        chunk.extend_from_slice(&sealed);
+        chunk
+    }
+}
+
+// Что сервер увидел в заголовке запроса
+#[derive(Debug)]
+pub struct Request {
+    pub security: u8,
+    pub target: String,
+}
+
+/// Обслуживает одного клиента: проверяет заголовок и проксирует тело до цели.
+pub async fn handle_vmess_client<S>(stream: S, requests: mpsc::UnboundedSender<Request>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let cmd_key = cmd_key();
+
+    // AuthID: после расшифровки последние 4 байта - CRC32 первых 12
+    let mut auth_id = [0u8; 16];
+    reader.read_exact(&mut auth_id).await.unwrap();
+    let auth_key = kdf(&cmd_key, &[b"AES Auth ID Encryption"]);
+    let mut block = GenericArray::from(auth_id);
+    Aes128::new(GenericArray::from_slice(&auth_key[..16])).decrypt_block(&mut block);
+    if crc32fast::hash(&block[..12]).to_be_bytes() != block[12..] {
+        return;
+    }
+
+    let mut length = [0u8; 18];
+    reader.read_exact(&mut length).await.unwrap();
+    let mut connection_nonce = [0u8; 8];
+    reader.read_exact(&mut connection_nonce).await.unwrap();
+    let path = |label: &'static [u8]| kdf(&cmd_key, &[label, &auth_id, &connection_nonce]);
+    aes_gcm(&path(b"VMess Header AEAD Key_Length"))
+        .open_in_place(nonce(&path(b"VMess Header AEAD Nonce_Length")), Aad::from(&auth_id), &mut length)
+        .unwrap();
+    let mut header = vec![0u8; u16::from_be_bytes([length[0], length[1]]) as usize + 16];
+    reader.read_exact(&mut header).await.unwrap();
+    let header_len = aes_gcm(&path(b"VMess Header AEAD Key"))
+        .open_in_place(nonce(&path(b"VMess Header AEAD Nonce")), Aad::from(&auth_id), &mut header)
+        .unwrap()
+        .len();
+    header.truncate(header_len);
+
+    // Ver IV Key V Opt P|Sec Rsv Cmd Port ATYP Addr Padding F
+    assert_eq!(header[0], 1);
+    let iv = header[1..17].to_vec();
+    let key = header[17..33].to_vec();
+    let response_auth = header[33];
+    assert_eq!(header[34] & 0x05, 0x05, "ChunkStream and ChunkMasking must be set");
+    let padding = (header[35] >> 4) as usize;
+    let security = header[35] & 0x0f;
+    assert_eq!(header[37], 1, "only TCP is expected");
+    let port = u16::from_be_bytes([header[38], header[39]]);
+    let (host, addr_end) = match header[40] {
+        1 => (std::net::Ipv4Addr::from(<[u8; 4]>::try_from(&header[41..45]).unwrap()).to_string(), 45),
+        2 => {
+            let len = header[41] as usize;
+            (String::from_utf8(header[42..42 + len].to_vec()).unwrap(), 42 + len)
+        }
+        3 => (std::net::Ipv6Addr::from(<[u8; 16]>::try_from(&header[41..57]).unwrap()).to_string(), 57),
+        atyp => panic!("unexpected address type {}", atyp),
+    };
+    let checksum_at = addr_end + padding;
+    let fnv = header[..checksum_at]
+        .iter()
+        .fold(0x811c9dc5u32, |hash, byte| (hash ^ *byte as u32).wrapping_mul(0x01000193));
+    assert_eq!(fnv.to_be_bytes(), header[checksum_at..checksum_at + 4]);
+
+    let target = format!("{}:{}", host, port);
+    let _ = requests.send(Request { security, target: target.clone() });
+    let upstream = TcpStream::connect(&target).await.unwrap();
+    let (mut upstream_reader, mut upstream_writer) = upstream.into_split();
+
+    // Ответ: зашифрованные длина и заголовок V Opt Cmd CmdLen
+    let response_key = Sha256::digest(&key)[..16].to_vec();
+    let response_iv = Sha256::digest(&iv)[..16].to_vec();
+    let mut response_header = vec![response_auth, 0, 0, 0];
+    let mut response_length = (response_header.len() as u16).to_be_bytes().to_vec();
+    aes_gcm(&kdf(&response_key, &[b"AEAD Resp Header Len Key"]))
+        .seal_in_place_append_tag(nonce(&kdf(&response_iv, &[b"AEAD Resp Header Len IV"])), Aad::empty(), &mut response_length)
+        .unwrap();
+    aes_gcm(&kdf(&response_key, &[b"AEAD Resp Header Key"]))
+        .seal_in_place_append_tag(nonce(&kdf(&response_iv, &[b"AEAD Resp Header IV"])), Aad::empty(), &mut response_header)
+        .unwrap();
+    writer.write_all(&response_length).await.unwrap();
+    writer.write_all(&response_header).await.unwrap();
+
+    let mut request_body = Body::new(security, &key, &iv);
+    let uplink = async move {
+        while let Some(chunk) = request_body.read_chunk(&mut reader).await {
+            upstream_writer.write_all(&chunk).await.unwrap();
+        }
+        let _ = upstream_writer.shutdown().await;
+    };
+    let mut response_body = Body::new(security, &response_key, &response_iv);
+    let downlink = async move {
+        let mut buf = vec![0u8; 8000];
+        loop {
+            let n = upstream_reader.read(&mut buf).await.unwrap_or(0);
+            let chunk = response_body.encode(&buf[..n]);
+            if writer.write_all(&chunk).await.is_err() || n == 0 {
+                break;
+            }
+        }
+        let _ = writer.shutdown().await;
+    };
+    tokio::join!(uplink, downlink);
+}
@@ -0,0 +1,442 @@
+mod common;
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::BufReader;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio_rustls::TlsAcceptor;
+use stealthcat_backend::config::{DnsSettings, Nameservers};
+use stealthcat_backend::models::*;
+use stealthcat_backend::proxy::dns::message::{Message, Record, RCODE_NAME_ERROR, RCODE_NO_ERROR, TYPE_A, TYPE_AAAA};
+use stealthcat_backend::proxy::dns::fake_ip::FakeIpPool;
+use stealthcat_backend::proxy::dns::Resolver;
+use stealthcat_backend::proxy::ProxyEngine;
+use common::*;
+
+fn cert_path(name: &str) -> String {
+    format!("{}/tests/certs/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+fn tls_acceptor() -> TlsAcceptor {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(std::fs::File::open(cert_path("server.pem")).unwrap()))
+        .unwrap()
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    let key = rustls_pemfile::pkcs8_private_keys(&mut BufReader::new(std::fs::File::open(cert_path("server.key")).unwrap()))
+        .unwrap()
+        .remove(0);
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, PrivateKey(key))
+        .unwrap();
+    TlsAcceptor::from(Arc::new(config))
+}
+
+/// Тестовый DNS сервер: UDP и TCP на одном порту, DoT и DoH на своих.
+/// Имена, начинающиеся с `big.`, по UDP приходят с флагом TC.
+struct NameServer {
+    addr: SocketAddr,
+    tls_addr: SocketAddr,
+    https_addr: SocketAddr,
+    queries: Arc<AtomicUsize>,
+}
+
+impl NameServer {
+    async fn start(records: &[(&str, &str)], ttl: u32) -> Self {
+        let mut zone: HashMap<String, Vec<IpAddr>> = HashMap::new();
+        for (name, ip) in records {
+            zone.entry(name.to_string()).or_default().push(ip.parse().unwrap());
+        }
+        let zone = Arc::new(zone);
+        let queries = Arc::new(AtomicUsize::new(0));
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = udp.local_addr().unwrap();
+        let tcp = TcpListener::bind(addr).await.unwrap();
+        let tls = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tls_addr = tls.local_addr().unwrap();
+        let https = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let https_addr = https.local_addr().unwrap();
+
+        let (udp_zone, udp_queries) = (zone.clone(), queries.clone());
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 65535];
+            loop {
+                let (n, peer) = udp.recv_from(&mut buf).await.unwrap();
+                let query = Message::decode(&buf[..n]).unwrap();
+                udp_queries.fetch_add(1, Ordering::SeqCst);
+                let mut response = answer(&query, &udp_zone, ttl);
+                if query.questions[0].name.starts_with("big.") {
+                    response.answers.clear();
+                    response.flags |= 0x0200;
+                }
+                udp.send_to(&response.encode().unwrap(), peer).await.unwrap();
+            }
+        });
+
+        spawn_acceptor(tcp, None, zone.clone(), queries.clone(), ttl, false);
+        spawn_acceptor(tls, Some(tls_acceptor()), zone.clone(), queries.clone(), ttl, false);
+        spawn_acceptor(https, Some(tls_acceptor()), zone, queries.clone(), ttl, true);
+
+        Self { addr, tls_addr, https_addr, queries }
+    }
+
+    fn queries(&self) -> usize {
+        self.queries.load(Ordering::SeqCst)
+    }
+}
+
+fn spawn_acceptor(
+    listener: TcpListener,
+    acceptor: Option<TlsAcceptor>,
+    zone: Arc<HashMap<String, Vec<IpAddr>>>,
+    queries: Arc<AtomicUsize>,
+    ttl: u32,
+    https: bool,
+) {
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (acceptor, zone, queries) = (acceptor.clone(), zone.clone(), queries.clone());
+            tokio::spawn(async move {
+                match acceptor {
+                    Some(acceptor) => {
+                        let Ok(stream) = acceptor.accept(stream).await else { return };
+                        serve_stream(stream, &zone, &queries, ttl, https).await;
+                    }
+                    None => serve_stream(stream, &zone, &queries, ttl, https).await,
+                }
+            });
+        }
+    });
+}
+
+async fn serve_stream<S>(stream: S, zone: &HashMap<String, Vec<IpAddr>>, queries: &AtomicUsize, ttl: u32, https: bool)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut stream = tokio::io::BufReader::new(stream);
+    let request = if https {
+        let mut line = String::new();
+        stream.read_line(&mut line).await.unwrap();
+        assert!(line.starts_with("POST /dns-query "), "unexpected DoH request {}", line);
+        let mut len = 0;
+        loop {
+            let mut header = String::new();
+            stream.read_line(&mut header).await.unwrap();
+            if header == "\r\n" {
+                break;
+            }
+            if let Some(value) = header.to_ascii_lowercase().strip_prefix("content-length:") {
+                len = value.trim().parse().unwrap();
+            }
+        }
+        let mut body = vec![0u8; len];
+        stream.read_exact(&mut body).await.unwrap();
+        body
+    } else {
+        let len = stream.read_u16().await.unwrap() as usize;
+        let mut body = vec![0u8; len];
+        stream.read_exact(&mut body).await.unwrap();
+        body
+    };
+
+    queries.fetch_add(1, Ordering::SeqCst);
+    let response = answer(&Message::decode(&request).unwrap(), zone, ttl).encode().unwrap();
+    if https {
+        let head = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/dns-message\r\nContent-Length: {}\r\n\r\n",
+            response.len()
+        );
+        stream.write_all(head.as_bytes()).await.unwrap();
+    } else {
+        stream.write_all(&(response.len() as u16).to_be_bytes()).await.unwrap();
+    }
+    stream.write_all(&response).await.unwrap();
+    stream.flush().await.unwrap();
+}
+
+fn answer(query: &Message, zone: &HashMap<String, Vec<IpAddr>>, ttl: u32) -> Message {
+    let question = &query.questions[0];
+    let Some(ips) = zone.get(&question.name) else {
+        return query.reply(RCODE_NAME_ERROR, Vec::new());
+    };
+    let answers = ips.iter()
+        .filter(|ip| match question.qtype {
+            TYPE_A => ip.is_ipv4(),
+            TYPE_AAAA => ip.is_ipv6(),
+            _ => false,
+        })
+        .map(|ip| Record::address(&question.name, ttl, *ip))
+        .collect();
+    query.reply(RCODE_NO_ERROR, answers)
+}
+
+fn settings(nameservers: &[String]) -> DnsSettings {
+    DnsSettings {
+        enable: true,
+        nameserver: nameservers.to_vec(),
+        timeout: 1000,
+        ..Default::default()
+    }
+}
+
+fn ip(value: &str) -> IpAddr {
+    value.parse().unwrap()
+}
+
+#[tokio::test]
+async fn udp_answers_are_cached_for_their_ttl() {
+    let server = NameServer::start(&[("example.test", "10.0.0.1")], 1).await;
+    let resolver = Resolver::new(&settings(&[server.addr.to_string()]));
+
+    assert_eq!(resolver.lookup_ip("Example.Test.").await.unwrap(), vec![ip("10.0.0.1")]);
+    assert_eq!(resolver.lookup_ip("example.test").await.unwrap(), vec![ip("10.0.0.1")]);
+    assert_eq!(server.queries(), 1);
+
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    resolver.lookup_ip("example.test").await.unwrap();
+    assert_eq!(server.queries(), 2);
+}
+
+#[tokio::test]
+async fn ipv6_adds_aaaa_records() {
+    let server = NameServer::start(&[("dual.test", "10.0.0.2"), ("dual.test", "fd00::2")], 60).await;
+    let mut settings = settings(&[server.addr.to_string()]);
+    settings.ipv6 = true;
+    let resolver = Resolver::new(&settings);
+
+    assert_eq!(resolver.lookup_ip("dual.test").await.unwrap(), vec![ip("10.0.0.2"), ip("fd00::2")]);
+    let addrs = resolver.lookup("dual.test", 443).await.unwrap();
+    assert_eq!(addrs[0], "10.0.0.2:443".parse::<SocketAddr>().unwrap());
+}
+
+#[tokio::test]
+async fn truncated_udp_answer_is_retried_over_tcp() {
+    let server = NameServer::start(&[("big.test", "10.0.0.3")], 60).await;
+    let resolver = Resolver::new(&settings(&[server.addr.to_string()]));
+
+    assert_eq!(resolver.lookup_ip("big.test").await.unwrap(), vec![ip("10.0.0.3")]);
+    assert_eq!(server.queries(), 2);
+}
+
+#[tokio::test]
+async fn tcp_dot_and_doh_upstreams() {
+    let server = NameServer::start(&[("secure.test", "10.0.0.4")], 60).await;
+    let ca = cert_path("ca.pem");
+    let upstreams = [
+        format!("tcp://{}", server.addr),
+        format!("tls://{}#ca-file={}", server.tls_addr, ca),
+        format!("https://localhost:{}/dns-query#ca-file={}", server.https_addr.port(), ca),
+    ];
+    for upstream in upstreams {
+        let resolver = Resolver::new(&settings(std::slice::from_ref(&upstream)));
+        let ips = resolver.lookup_ip("secure.test").await
+            .unwrap_or_else(|e| panic!("{} failed: {:#}", upstream, e));
+        assert_eq!(ips, vec![ip("10.0.0.4")], "{}", upstream);
+    }
+    assert_eq!(server.queries(), 3);
+}
+
+#[tokio::test]
+async fn dot_rejects_untrusted_certificate() {
+    let server = NameServer::start(&[("secure.test", "10.0.0.4")], 60).await;
+    let resolver = Resolver::new(&settings(&[format!("tls://{}", server.tls_addr)]));
+    assert!(resolver.lookup_ip("secure.test").await.is_err());
+
+    let resolver = Resolver::new(&settings(&[format!("tls://{}#skip-cert-verify=true", server.tls_addr)]));
+    assert_eq!(resolver.lookup_ip("secure.test").await.unwrap(), vec![ip("10.0.0.4")]);
+}
+
+#[tokio::test]
+async fn nameserver_policy_routes_domains_to_their_resolvers() {
+    let public = NameServer::start(&[("app.corp.test", "203.0.113.1"), ("www.example.test", "203.0.113.2")], 60).await;
+    let corp = NameServer::start(&[("app.corp.test", "10.10.0.1"), ("corp.test", "10.10.0.2")], 60).await;
+    let mut settings = settings(&[public.addr.to_string()]);
+    settings.nameserver_policy = BTreeMap::from([
+        ("+.corp.test".to_string(), Nameservers::Many(vec![corp.addr.to_string()])),
+        ("www.example.test".to_string(), Nameservers::One(format!("tcp://{}", corp.addr))),
+        ("+.example.test".to_string(), Nameservers::One(public.addr.to_string())),
+    ]);
+    let resolver = Resolver::new(&settings);
+
+    assert_eq!(resolver.lookup_ip("app.corp.test").await.unwrap(), vec![ip("10.10.0.1")]);
+    assert_eq!(resolver.lookup_ip("corp.test").await.unwrap(), vec![ip("10.10.0.2")]);
+    assert_eq!(corp.queries(), 2);
+    // Точное совпадение важнее +.example.test
+    assert!(resolver.lookup_ip("www.example.test").await.is_err());
+    assert_eq!(corp.queries(), 3);
+    assert_eq!(public.queries(), 0);
+}
+
+#[tokio::test]
+async fn unreachable_nameserver_falls_back_to_next() {
+    let server = NameServer::start(&[("example.test", "10.0.0.5")], 60).await;
+    // Сокет, который молчит
+    let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut settings = settings(&[silent.local_addr().unwrap().to_string(), server.addr.to_string()]);
+    settings.timeout = 200;
+    let resolver = Resolver::new(&settings);
+
+    assert_eq!(resolver.lookup_ip("example.test").await.unwrap(), vec![ip("10.0.0.5")]);
+    let error = resolver.lookup_ip("missing.test").await.unwrap_err();
+    assert!(format!("{:#}", error).contains("missing.test"));
+}
+
+fn dns_config(server: &NameServer) -> MihomoConfig {
+    MihomoConfig {
+        raw_config: format!("dns:\n  enable: true\n  nameserver:\n    - {}\n", server.addr),
+        format: ConfigFormat::YAML,
+        last_modified: chrono::Utc::now(),
+    }
+}
+
+async fn http_connect(proxy: SocketAddr, target: &str) -> (String, TcpStream) {
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    stream.write_all(format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", target, target).as_bytes()).await.unwrap();
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        response.push(stream.read_u8().await.unwrap());
+    }
+    let status = String::from_utf8_lossy(&response).lines().next().unwrap().to_string();
+    (status, stream)
+}
+
+#[tokio::test]
+async fn direct_connections_use_the_builtin_resolver() {
+    let echo = start_echo_server().await;
+    let server = NameServer::start(&[("echo.test", "127.0.0.1")], 60).await;
+    let proxy = start_engine_with_config(dns_config(&server), Vec::new(), Vec::new()).await;
+
+    let (status, stream) = http_connect(proxy, &format!("echo.test:{}", echo.port())).await;
+    assert!(status.contains(" 200 "), "{}", status);
+    let payload = test_payload(1024);
+    assert_eq!(echo_round_trip(stream, &payload).await, payload);
+    assert_eq!(server.queries(), 1);
+}
+
+#[tokio::test]
+async fn resolved_addresses_feed_ip_cidr_rules() {
+    let echo = start_echo_server().await;
+    let server = NameServer::start(&[("blocked.test", "127.0.0.2"), ("allowed.test", "127.0.0.1")], 60).await;
+    let rule = Rule {
+        id: "loopback".to_string(),
+        name: "loopback".to_string(),
+        rule_type: "ip-cidr".to_string(),
+        pattern: "127.0.0.2/32".to_string(),
+        action: "block".to_string(),
+        priority: 0,
+        enabled: true,
+    };
+    let proxy = start_engine_with_config(dns_config(&server), vec![rule], Vec::new()).await;
+
+    let (status, _) = http_connect(proxy, &format!("blocked.test:{}", echo.port())).await;
+    assert!(status.contains(" 403 "), "{}", status);
+    // Второй запрос к тому же имени берётся из кэша
+    let (status, _) = http_connect(proxy, &format!("blocked.test:{}", echo.port())).await;
+    assert!(status.contains(" 403 "), "{}", status);
+    assert_eq!(server.queries(), 1);
+
+    // 127.0.0.1 правило не задевает, соединение идёт напрямую на полученный адрес
+    let (status, stream) = http_connect(proxy, &format!("allowed.test:{}", echo.port())).await;
+    assert!(status.contains(" 200 "), "{}", status);
+    let payload = test_payload(64);
+    assert_eq!(echo_round_trip(stream, &payload).await, payload);
+    assert_eq!(server.queries(), 2);
+}
+
+fn fake_ip_pool(range: &str) -> FakeIpPool {
+    FakeIpPool::new(range, &[]).unwrap()
+}
+
+fn mapping(ip_addr: &str, domain: &str, last_used: u64) -> FakeIpMapping {
+    FakeIpMapping { ip: ip(ip_addr), domain: domain.to_string(), last_used }
+}
+
+#[test]
+fn fake_ips_are_allocated_and_looked_up() {
+    let pool = fake_ip_pool("198.18.0.0/24");
+    assert_eq!(pool.allocate("a.test"), (ip("198.18.0.1"), true));
+    assert_eq!(pool.allocate("b.test"), (ip("198.18.0.2"), true));
+    assert_eq!(pool.allocate("a.test"), (ip("198.18.0.1"), false));
+
+    assert_eq!(pool.lookup(ip("198.18.0.2")).as_deref(), Some("b.test"));
+    assert_eq!(pool.lookup(ip("198.18.0.3")), None);
+    assert_eq!(pool.lookup(ip("10.0.0.1")), None);
+    assert_eq!(pool.take_dirty().len(), 2);
+    assert!(pool.take_dirty().is_empty());
+}
+
+#[test]
+fn full_fake_ip_pool_evicts_least_recently_used() {
+    // В /30 всего два адреса
+    let pool = fake_ip_pool("198.18.0.0/30");
+    pool.allocate("a.test");
+    pool.allocate("b.test");
+    // Обращение к a делает вытесняемым b
+    assert_eq!(pool.lookup(ip("198.18.0.1")).as_deref(), Some("a.test"));
+
+    assert_eq!(pool.allocate("c.test"), (ip("198.18.0.2"), true));
+    assert_eq!(pool.lookup(ip("198.18.0.2")).as_deref(), Some("c.test"));
+    assert_eq!(pool.allocate("b.test"), (ip("198.18.0.1"), true));
+    assert_eq!(pool.allocate("c.test"), (ip("198.18.0.2"), false));
+}
+
+#[test]
+fn restore_keeps_the_newest_mapping_of_a_domain() {
+    let pool = fake_ip_pool("198.18.0.0/24");
+    let restored = pool.restore(vec![
+        mapping("198.18.0.2", "a.test", 9),
+        mapping("198.18.0.1", "a.test", 5),
+        mapping("198.18.0.3", "b.test", 1),
+        mapping("10.0.0.1", "outside.test", 7),
+    ]);
+    assert_eq!(restored, 2);
+    assert_eq!(pool.lookup(ip("198.18.0.1")), None);
+    assert_eq!(pool.lookup(ip("198.18.0.2")).as_deref(), Some("a.test"));
+    assert_eq!(pool.allocate("a.test"), (ip("198.18.0.2"), false));
+
+    // Новые домены не занимают восстановленные адреса
+    assert_eq!(pool.allocate("c.test"), (ip("198.18.0.1"), true));
+    assert_eq!(pool.allocate("d.test"), (ip("198.18.0.4"), true));
+}
+
+#[tokio::test]
+async fn fake_ip_connections_are_routed_by_domain() {
+    let echo = start_echo_server().await;
+    let server = NameServer::start(&[("allowed.test", "127.0.0.1"), ("blocked.test", "127.0.0.1")], 60).await;
+    let mut config = dns_config(&server);
+    config.raw_config.push_str("  enhanced-mode: fake-ip\n  fake-ip-range: 198.18.0.0/24\n");
+    let rule = Rule {
+        id: "blocked".to_string(),
+        name: "blocked".to_string(),
+        rule_type: "domain".to_string(),
+        pattern: "blocked.test".to_string(),
+        action: "block".to_string(),
+        priority: 0,
+        enabled: true,
+    };
+    let engine = Arc::new(ProxyEngine::new(config, vec![rule], Vec::new(), Vec::new()));
+    let router = engine.router();
+    let pool = router.dns().fake_ips().unwrap();
+    let (allowed, _) = pool.allocate("allowed.test");
+    let (blocked, _) = pool.allocate("blocked.test");
+    let proxy = serve_engine(engine).await;
+
+    let (status, _) = http_connect(proxy, &format!("{}:{}", blocked, echo.port())).await;
+    assert!(status.contains(" 403 "), "{}", status);
+
+    // Прокси подключается к настоящему адресу домена, а не к fake-IP
+    let (status, stream) = http_connect(proxy, &format!("{}:{}", allowed, echo.port())).await;
+    assert!(status.contains(" 200 "), "{}", status);
+    let payload = test_payload(64);
+    assert_eq!(echo_round_trip(stream, &payload).await, payload);
+    assert_eq!(server.queries(), 1);
+}
@@ -0,0 +1,118 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use stealthcat_backend::models::*;
+use stealthcat_backend::proxy::failover::FailoverEvent;
+use stealthcat_backend::proxy::health::HealthChecker;
+use stealthcat_backend::proxy::ProxyEngine;
+use common::*;
+
+fn http_server(name: &str, addr: SocketAddr) -> ProxyServerV2 {
+    server(name, ProxyProtocol::HTTP, ProxyConfig::Http { username: None, password: None }, addr)
+}
+
+/// Адрес, на котором никто не слушает.
+fn closed_addr() -> SocketAddr {
+    std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+/// Движок с выбранным сервером "a" и правилом proxy для `port`.
+async fn setup(settings: &str, servers: Vec<ProxyServerV2>, port: u16) -> (Arc<ProxyEngine>, HealthChecker) {
+    let db = temp_database().await;
+    for server in &servers {
+        db.insert_server_v2(server).await.unwrap();
+    }
+    let config = MihomoConfig {
+        raw_config: settings.to_string(),
+        format: ConfigFormat::YAML,
+        last_modified: chrono::Utc::now(),
+    };
+    let engine = Arc::new(ProxyEngine::new(config, vec![port_rule(port, "proxy")], servers, Vec::new()));
+    engine.select_server(Some("a-id".to_string()));
+    let checker = HealthChecker::new(engine.clone(), db);
+    (engine, checker)
+}
+
+fn current(engine: &ProxyEngine) -> String {
+    engine.router().current_server().unwrap().name.clone()
+}
+
+async fn next_event(events: &mut broadcast::Receiver<FailoverEvent>) -> FailoverEvent {
+    tokio::time::timeout(Duration::from_secs(5), events.recv()).await
+        .expect("no failover event")
+        .unwrap()
+}
+
+#[tokio::test]
+async fn dial_failures_switch_to_next_member() {
+    let echo = start_echo_server().await;
+    let alive = start_upstream(Arc::new(AtomicBool::new(true))).await;
+    let settings = "health-check:\n  enable: false\nfailover:\n  max-dial-failures: 2\n  priority: [a, b]\n";
+    let servers = vec![http_server("a", closed_addr()), http_server("b", alive)];
+    let (engine, _) = setup(settings, servers, echo.port()).await;
+    let mut events = engine.failover_events();
+    let proxy = serve_engine(engine.clone()).await;
+
+    for _ in 0..2 {
+        let (_, reply) = socks5_request(proxy, "127.0.0.1", echo.port()).await;
+        assert_ne!(reply, 0);
+    }
+    let event = next_event(&mut events).await;
+    assert_eq!((event.from_name.as_str(), event.to_name.as_str(), event.restored), ("a", "b", false));
+    assert!(event.reason.starts_with("2 failed connections in a row"), "{}", event.reason);
+    assert_eq!(current(&engine), "b");
+
+    let stream = socks5_connect(proxy, echo).await;
+    assert_eq!(echo_round_trip(stream, b"after failover").await, b"after failover");
+}
+
+#[tokio::test]
+async fn selected_server_is_restored_when_its_health_comes_back() {
+    let (endpoint, _requests) = start_endpoint().await;
+    let up = Arc::new(AtomicBool::new(false));
+    let servers = vec![
+        http_server("a", start_upstream(up.clone()).await),
+        http_server("b", start_upstream(Arc::new(AtomicBool::new(true))).await),
+    ];
+    let settings = format!("health-check:\n  url: http://{}/generate_204\nfailover:\n  priority: [a, b]\n", endpoint);
+    let (engine, checker) = setup(&settings, servers, 80).await;
+    let mut events = engine.failover_events();
+
+    checker.check_all().await;
+    let event = next_event(&mut events).await;
+    assert_eq!((event.from_name.as_str(), event.to_name.as_str(), event.restored), ("a", "b", false));
+    assert_eq!(current(&engine), "b");
+
+    up.store(true, Ordering::SeqCst);
+    checker.check_all().await;
+    let event = next_event(&mut events).await;
+    assert_eq!((event.from_name.as_str(), event.to_name.as_str(), event.restored), ("b", "a", true));
+    assert_eq!(current(&engine), "a");
+    assert_eq!(engine.router().failover().replacement(), None);
+}
+
+#[tokio::test]
+async fn member_that_failed_its_last_check_is_skipped() {
+    let (endpoint, _requests) = start_endpoint().await;
+    let down = Arc::new(AtomicBool::new(false));
+    // b не прошёл проверку, но до max-failures ещё считается живым
+    let servers = vec![
+        http_server("a", start_upstream(down.clone()).await),
+        http_server("b", start_upstream(down).await),
+        http_server("c", start_upstream(Arc::new(AtomicBool::new(true))).await),
+    ];
+    let settings = format!(
+        "health-check:\n  url: http://{}/generate_204\n  max-failures: 3\nfailover:\n  priority: [a, b, c]\n",
+        endpoint
+    );
+    let (engine, checker) = setup(&settings, servers, 80).await;
+
+    checker.check_all().await;
+    assert_eq!(current(&engine), "c");
+    checker.check_all().await;
+    assert_eq!(current(&engine), "c");
+}
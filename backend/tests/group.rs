@@ -0,0 +1,244 @@
+mod common;
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+use actix_web::{web, App};
+use serde_json::json;
+use stealthcat_backend::api;
+use stealthcat_backend::config::ConfigManager;
+use stealthcat_backend::models::*;
+use stealthcat_backend::proxy::group::{self, Candidate, GroupState, Member};
+use stealthcat_backend::proxy::{ProxyEngine, RouteAction};
+use common::*;
+
+fn http_server(name: &str) -> ProxyServerV2 {
+    let addr: SocketAddr = ([127, 0, 0, 1], 8080).into();
+    server(name, ProxyProtocol::HTTP, ProxyConfig::Http { username: None, password: None }, addr)
+}
+
+fn proxy_group(name: &str, group_type: ProxyGroupType, proxies: &[&str]) -> ProxyGroup {
+    ProxyGroup {
+        id: format!("{}-id", name),
+        name: name.to_string(),
+        group_type,
+        proxies: proxies.iter().map(|p| p.to_string()).collect(),
+        selected: None,
+        tolerance: 0,
+        strategy: LoadBalanceStrategy::default(),
+    }
+}
+
+fn candidate(server: &ProxyServerV2, latency_ms: Option<u32>, alive: bool) -> Candidate<'_> {
+    Candidate { name: &server.name, member: Member::Server(server), latency_ms, alive }
+}
+
+fn pick<'a>(state: &GroupState, group: &ProxyGroup, candidates: &[Candidate<'a>], destination: &str) -> &'a str {
+    state.pick(group, candidates, destination).unwrap().name
+}
+
+#[test]
+fn select_uses_selected_member_or_the_first() {
+    let servers = [http_server("a"), http_server("b")];
+    // Выбранный вручную участник не заменяется, даже если он не работает
+    let candidates = [candidate(&servers[0], None, true), candidate(&servers[1], None, false)];
+    let state = GroupState::default();
+    let mut group = proxy_group("manual", ProxyGroupType::Select, &["a", "b"]);
+
+    assert_eq!(pick(&state, &group, &candidates, "example.com"), "a");
+    group.selected = Some("b".to_string());
+    assert_eq!(pick(&state, &group, &candidates, "example.com"), "b");
+    group.selected = Some("b-id".to_string());
+    assert_eq!(pick(&state, &group, &candidates, "example.com"), "b");
+    group.selected = Some("gone".to_string());
+    assert_eq!(pick(&state, &group, &candidates, "example.com"), "a");
+}
+
+#[test]
+fn url_test_switches_only_when_the_gap_exceeds_tolerance() {
+    let servers = [http_server("a"), http_server("b")];
+    let state = GroupState::default();
+    let mut group = proxy_group("auto", ProxyGroupType::UrlTest, &["a", "b"]);
+    group.tolerance = 50;
+    let pick_with = |a: u32, b: u32| {
+        let candidates = [candidate(&servers[0], Some(a), true), candidate(&servers[1], Some(b), true)];
+        pick(&state, &group, &candidates, "example.com").to_string()
+    };
+
+    assert_eq!(pick_with(100, 120), "a");
+    assert_eq!(pick_with(150, 100), "a");
+    assert_eq!(pick_with(151, 100), "b");
+    assert_eq!(pick_with(100, 140), "b");
+
+    // Упавший текущий участник сменяется сразу
+    let candidates = [candidate(&servers[0], Some(100), true), candidate(&servers[1], None, false)];
+    assert_eq!(pick(&state, &group, &candidates, "example.com"), "a");
+}
+
+#[test]
+fn consistent_hashing_moves_only_destinations_of_removed_member() {
+    let servers = [http_server("a"), http_server("b"), http_server("c")];
+    let state = GroupState::default();
+    let group = proxy_group("balance", ProxyGroupType::LoadBalance, &["a", "b", "c"]);
+    let all: Vec<Candidate> = servers.iter().map(|s| candidate(s, None, true)).collect();
+    let destinations: Vec<String> = (0..300).map(|i| format!("host{}.example.com", i)).collect();
+
+    let before: HashMap<&str, &str> = destinations.iter()
+        .map(|d| (d.as_str(), pick(&state, &group, &all, d)))
+        .collect();
+    for destination in &destinations {
+        assert_eq!(pick(&state, &group, &all, destination), before[destination.as_str()]);
+    }
+    for server in &servers {
+        assert!(before.values().any(|name| *name == server.name), "{} got no destinations", server.name);
+    }
+
+    // b не работает: его назначения расходятся по остальным, прочие остаются на месте
+    let without_b = [all[0], candidate(&servers[1], None, false), all[2]];
+    for destination in &destinations {
+        let after = pick(&state, &group, &without_b, destination);
+        match before[destination.as_str()] {
+            "b" => assert_ne!(after, "b"),
+            name => assert_eq!(after, name, "{} moved", destination),
+        }
+    }
+}
+
+#[test]
+fn round_robin_rotates_over_alive_members() {
+    let servers = [http_server("a"), http_server("b"), http_server("c")];
+    let state = GroupState::default();
+    let mut group = proxy_group("rotate", ProxyGroupType::LoadBalance, &["a", "b", "c"]);
+    group.strategy = LoadBalanceStrategy::RoundRobin;
+    let all: Vec<Candidate> = servers.iter().map(|s| candidate(s, None, true)).collect();
+
+    let picks: Vec<&str> = (0..4).map(|_| pick(&state, &group, &all, "example.com")).collect();
+    assert_eq!(picks, ["a", "b", "c", "a"]);
+
+    let state = GroupState::default();
+    let without_b = [all[0], candidate(&servers[1], None, false), all[2]];
+    let picks: Vec<&str> = (0..3).map(|_| pick(&state, &group, &without_b, "example.com")).collect();
+    assert_eq!(picks, ["a", "c", "a"]);
+}
+
+#[test]
+fn validate_rejects_unknown_members_and_nested_groups() {
+    let servers = vec![http_server("a"), http_server("b")];
+    let existing = proxy_group("first", ProxyGroupType::Select, &["a", "DIRECT"]);
+    let groups = vec![existing.clone()];
+    let error = |group: &ProxyGroup| group::validate(group, &groups, &servers).unwrap_err().to_string();
+
+    assert!(group::validate(&existing, &groups, &servers).is_ok());
+    assert!(group::validate(&proxy_group("ids", ProxyGroupType::Fallback, &["a-id", "REJECT"]), &groups, &servers).is_ok());
+
+    assert_eq!(
+        error(&proxy_group("second", ProxyGroupType::Select, &["a", "gone"])),
+        "Group \"second\" references unknown server \"gone\""
+    );
+    assert_eq!(
+        error(&proxy_group("loop", ProxyGroupType::Select, &["a", "loop"])),
+        "Group \"loop\" cannot contain group \"loop\": nested groups are not supported"
+    );
+    // first -> second -> first невозможно, потому что second не может ссылаться на first
+    assert_eq!(
+        error(&proxy_group("second", ProxyGroupType::Fallback, &["first", "b"])),
+        "Group \"second\" cannot contain group \"first\": nested groups are not supported"
+    );
+
+    let mut duplicate = proxy_group("first", ProxyGroupType::Select, &["b"]);
+    duplicate.id = "other-id".to_string();
+    assert_eq!(error(&duplicate), "Group \"first\" already exists");
+    assert_eq!(error(&proxy_group("Direct", ProxyGroupType::Select, &["b"])), "Group name \"Direct\" is reserved");
+    assert_eq!(error(&proxy_group("empty", ProxyGroupType::Select, &[])), "Group \"empty\" has no proxies");
+    let mut selected = proxy_group("manual", ProxyGroupType::Select, &["a"]);
+    selected.selected = Some("b".to_string());
+    assert_eq!(error(&selected), "\"b\" is not a member of group \"manual\"");
+}
+
+fn route(engine: &ProxyEngine, port: u16) -> (String, Option<String>) {
+    let decision = engine.router().route("example.com", port, &[]).unwrap();
+    let target = match decision.action {
+        RouteAction::Proxy(server) => server.name,
+        action => action.to_string(),
+    };
+    (target, decision.group)
+}
+
+#[tokio::test]
+async fn rule_targets_group_by_name() {
+    let mut manual = proxy_group("manual", ProxyGroupType::Select, &["a", "b"]);
+    manual.selected = Some("b".to_string());
+    let blocked = proxy_group("blocked", ProxyGroupType::Fallback, &["REJECT", "a"]);
+    let rules = vec![port_rule(443, "manual"), port_rule(80, "blocked")];
+    let engine = ProxyEngine::new(
+        ConfigManager::get_default_config(),
+        rules,
+        vec![http_server("a"), http_server("b")],
+        vec![manual, blocked],
+    );
+
+    assert_eq!(route(&engine, 443), ("b".to_string(), Some("manual".to_string())));
+    assert_eq!(route(&engine, 80), ("BLOCK".to_string(), Some("blocked".to_string())));
+    assert_eq!(route(&engine, 8443), ("DIRECT".to_string(), None));
+}
+
+/// Сервис с маршрутами групп, как в main.rs.
+macro_rules! groups_service {
+    ($engine:expr, $db:expr) => {
+        init_service(
+            App::new()
+                .app_data(web::Data::new($db.clone()))
+                .app_data(web::Data::new($engine.clone()))
+                .route("/api/groups", web::post().to(api::create_group))
+                .route("/api/groups/{id}", web::put().to(api::update_group))
+                .route("/api/groups/{id}/select", web::post().to(api::select_group_proxy)),
+        )
+        .await
+    };
+}
+
+#[actix_web::test]
+async fn group_api_validates_and_reloads_routing() {
+    let db = temp_database().await;
+    let servers = vec![http_server("a"), http_server("b")];
+    for server in &servers {
+        db.insert_server_v2(server).await.unwrap();
+    }
+    let rule = port_rule(443, "manual");
+    db.insert_rule(&rule).await.unwrap();
+    let engine = Arc::new(ProxyEngine::new(ConfigManager::get_default_config(), vec![rule], servers, Vec::new()));
+    let app = groups_service!(engine, db);
+
+    let create = |body: serde_json::Value| TestRequest::post().uri("/api/groups").set_json(body).to_request();
+    let response = call_service(&app, create(json!({"name": "manual", "group_type": "select", "proxies": ["a", "gone"]}))).await;
+    assert_eq!(response.status(), 400);
+    let body: serde_json::Value = read_body_json(response).await;
+    assert_eq!(body["error"]["message"], "Invalid group: Group \"manual\" references unknown server \"gone\"");
+
+    let response = call_service(&app, create(json!({"name": "manual", "group_type": "select", "proxies": ["a", "b"]}))).await;
+    assert_eq!(response.status(), 201);
+    let body: serde_json::Value = read_body_json(response).await;
+    let id = body["data"]["id"].as_str().unwrap().to_string();
+    assert_eq!(route(&engine, 443), ("a".to_string(), Some("manual".to_string())));
+
+    let select = |proxy: &str| TestRequest::post()
+        .uri(&format!("/api/groups/{}/select", id))
+        .set_json(json!({"proxy": proxy}))
+        .to_request();
+    assert_eq!(call_service(&app, select("b")).await.status(), 200);
+    assert_eq!(route(&engine, 443), ("b".to_string(), Some("manual".to_string())));
+    assert_eq!(call_service(&app, select("gone")).await.status(), 400);
+    assert_eq!(db.get_group(&id).await.unwrap().unwrap().selected.as_deref(), Some("b"));
+
+    // Вручную переключаются только select-группы
+    let update = TestRequest::put()
+        .uri(&format!("/api/groups/{}", id))
+        .set_json(json!({"name": "manual", "group_type": "fallback", "proxies": ["a", "b"]}))
+        .to_request();
+    assert_eq!(call_service(&app, update).await.status(), 200);
+    let response = call_service(&app, select("a")).await;
+    assert_eq!(response.status(), 400);
+    let body: serde_json::Value = read_body_json(response).await;
+    assert_eq!(body["error"]["message"], "Group \"manual\" is fallback, only select groups can be switched manually");
+}
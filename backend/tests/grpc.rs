@@ -0,0 +1,424 @@
+mod common;
+
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use base64::{Engine as _, engine::general_purpose};
+use bytes::{Buf, Bytes, BytesMut};
+use rustls::{Certificate, PrivateKey, ServerConfig};
+use sha2::{Digest, Sha224};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::TlsAcceptor;
+use stealthcat_backend::models::{GrpcConfig, ProxyConfig, ProxyProtocol, ProxyServerV2, VlessConfig, VmessConfig};
+use stealthcat_backend::proxy::health;
+use stealthcat_backend::subscription::SubscriptionParser;
+use common::vmess::handle_vmess_client;
+use common::*;
+
+const VLESS_UUID: &str = "27848739-7e62-4138-9fd3-098a63964b6b";
+const PASSWORD: &str = "trojan-grpc-password";
+const SERVICE: &str = "my.Service";
+
+// Что сервер увидел в запросе gRPC
+#[derive(Debug)]
+struct Call {
+    path: String,
+    authority: String,
+    content_type: Option<String>,
+}
+
+#[derive(Clone, Copy)]
+enum Protocol {
+    Vless,
+    Vmess,
+    Trojan,
+}
+
+fn cert_path(name: &str) -> String {
+    format!("{}/tests/certs/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+fn tls_acceptor() -> TlsAcceptor {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(std::fs::File::open(cert_path("server.pem")).unwrap()))
+        .unwrap()
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    let key = rustls_pemfile::pkcs8_private_keys(&mut BufReader::new(std::fs::File::open(cert_path("server.key")).unwrap()))
+        .unwrap()
+        .remove(0);
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, PrivateKey(key))
+        .unwrap();
+    config.alpn_protocols = vec![b"h2".to_vec()];
+    TlsAcceptor::from(Arc::new(config))
+}
+
+/// gun сервер на h2: сообщения Hunk склеиваются в поток, поверх которого
+/// работает минимальный сервер протокола. С `status` вызов сразу
+/// завершается ответом из одних заголовков с этим grpc-status.
+async fn start_grpc_server(protocol: Protocol, tls: bool, status: Option<&'static str>) -> (SocketAddr, mpsc::UnboundedReceiver<Call>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let acceptor = tls.then(tls_acceptor);
+    let (calls, received) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let acceptor = acceptor.clone();
+            let calls = calls.clone();
+            tokio::spawn(async move {
+                match acceptor {
+                    Some(acceptor) => {
+                        if let Ok(stream) = acceptor.accept(stream).await {
+                            handle_grpc_client(stream, protocol, status, calls).await;
+                        }
+                    }
+                    None => handle_grpc_client(stream, protocol, status, calls).await,
+                }
+            });
+        }
+    });
+    (addr, received)
+}
+
+async fn handle_grpc_client<S>(stream: S, protocol: Protocol, status: Option<&'static str>, calls: mpsc::UnboundedSender<Call>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let Ok(mut connection) = h2::server::handshake(stream).await else { return };
+    let Some(Ok((request, mut respond))) = connection.accept().await else { return };
+    // Соединение нужно опрашивать, иначе кадры не ходят
+    tokio::spawn(async move { while let Some(Ok(_)) = connection.accept().await {} });
+
+    let multi = request.uri().path().ends_with("/TunMulti");
+    let _ = calls.send(Call {
+        path: request.uri().path().to_string(),
+        authority: request.uri().authority().map(|a| a.to_string()).unwrap_or_default(),
+        content_type: request.headers().get("content-type").map(|v| v.to_str().unwrap().to_string()),
+    });
+
+    let response = http::Response::builder().status(200).header("content-type", "application/grpc");
+    if let Some(status) = status {
+        let response = response.header("grpc-status", status).header("grpc-message", "unavailable").body(()).unwrap();
+        respond.send_response(response, true).unwrap();
+        return;
+    }
+    let mut send = respond.send_response(response.body(()).unwrap(), false).unwrap();
+    let mut body = request.into_body();
+
+    let (app, bridge) = tokio::io::duplex(64 * 1024);
+    let (mut bridge_reader, mut bridge_writer) = tokio::io::split(bridge);
+    tokio::spawn(async move {
+        let mut incoming = BytesMut::new();
+        while let Some(Ok(chunk)) = body.data().await {
+            let _ = body.flow_control().release_capacity(chunk.len());
+            incoming.extend_from_slice(&chunk);
+            while incoming.len() >= 5 {
+                assert_eq!(incoming[0], 0, "messages must not be compressed");
+                let len = u32::from_be_bytes(incoming[1..5].try_into().unwrap()) as usize;
+                if incoming.len() < 5 + len {
+                    break;
+                }
+                incoming.advance(5);
+                let message = incoming.split_to(len);
+                for data in decode_hunk(&message) {
+                    if bridge_writer.write_all(&data).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+        let _ = bridge_writer.shutdown().await;
+    });
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 32 * 1024];
+        loop {
+            let n = bridge_reader.read(&mut buf).await.unwrap_or(0);
+            if n == 0 {
+                let mut trailers = http::HeaderMap::new();
+                trailers.insert("grpc-status", "0".parse().unwrap());
+                let _ = send.send_trailers(trailers);
+                break;
+            }
+            if send.send_data(encode_hunk(&buf[..n], multi), false).is_err() {
+                break;
+            }
+        }
+    });
+
+    match protocol {
+        Protocol::Vless => serve_vless(app).await,
+        Protocol::Vmess => handle_vmess_client(app, mpsc::unbounded_channel().0).await,
+        Protocol::Trojan => serve_trojan(app).await,
+    }
+}
+
+fn push_varint(mut value: usize, out: &mut Vec<u8>) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(buf: &mut &[u8]) -> usize {
+    let mut value = 0;
+    for shift in (0..).step_by(7) {
+        let byte = buf.get_u8();
+        value |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    value
+}
+
+/// gRPC сообщение с данными в поле 1. MultiHunk делится на два поля data,
+/// чтобы клиент склеивал повторяющееся поле.
+fn encode_hunk(data: &[u8], multi: bool) -> Bytes {
+    let parts = if multi && data.len() > 1 { data.split_at(data.len() / 2) } else { (data, &[][..]) };
+    let mut proto = Vec::new();
+    for part in [parts.0, parts.1].into_iter().filter(|p| !p.is_empty()) {
+        proto.push(0x0a);
+        push_varint(part.len(), &mut proto);
+        proto.extend_from_slice(part);
+    }
+    let mut message = vec![0];
+    message.extend_from_slice(&(proto.len() as u32).to_be_bytes());
+    message.extend_from_slice(&proto);
+    message.into()
+}
+
+fn decode_hunk(mut proto: &[u8]) -> Vec<Vec<u8>> {
+    let mut fields = Vec::new();
+    while !proto.is_empty() {
+        assert_eq!(read_varint(&mut proto), 0x0a, "only the data field is expected");
+        let len = read_varint(&mut proto);
+        fields.push(proto[..len].to_vec());
+        proto = &proto[len..];
+    }
+    fields
+}
+
+async fn serve_vless(mut stream: DuplexStream) {
+    let mut header = [0u8; 18];
+    stream.read_exact(&mut header).await.unwrap();
+    assert_eq!(header[0], 0);
+    assert_eq!(&header[1..17], uuid::Uuid::parse_str(VLESS_UUID).unwrap().as_bytes());
+    let mut addons = vec![0u8; header[17] as usize];
+    stream.read_exact(&mut addons).await.unwrap();
+    assert_eq!(stream.read_u8().await.unwrap(), 1, "only TCP is expected");
+    let port = stream.read_u16().await.unwrap();
+    assert_eq!(stream.read_u8().await.unwrap(), 1, "IPv4 target is expected");
+    let mut octets = [0u8; 4];
+    stream.read_exact(&mut octets).await.unwrap();
+    stream.write_all(&[0, 0]).await.unwrap();
+    relay(stream, SocketAddr::from((octets, port))).await;
+}
+
+async fn serve_trojan(mut stream: DuplexStream) {
+    let mut hash = [0u8; 56];
+    stream.read_exact(&mut hash).await.unwrap();
+    let expected: String = Sha224::digest(PASSWORD).iter().map(|b| format!("{:02x}", b)).collect();
+    assert_eq!(&hash[..], expected.as_bytes());
+    let mut crlf = [0u8; 2];
+    stream.read_exact(&mut crlf).await.unwrap();
+    assert_eq!(stream.read_u8().await.unwrap(), 1, "only CONNECT is expected");
+    let mut address = [0u8; 7];
+    stream.read_exact(&mut address).await.unwrap();
+    stream.read_exact(&mut crlf).await.unwrap();
+    relay(stream, parse_address(&address).0).await;
+}
+
+async fn relay(mut stream: DuplexStream, target: SocketAddr) {
+    let mut upstream = TcpStream::connect(target).await.unwrap();
+    let _ = tokio::io::copy_bidirectional(&mut stream, &mut upstream).await;
+}
+
+fn grpc_config(mode: &str) -> GrpcConfig {
+    GrpcConfig { service_name: SERVICE.to_string(), mode: mode.to_string() }
+}
+
+fn grpc_server(protocol: Protocol, addr: SocketAddr, mode: &str) -> ProxyServerV2 {
+    match protocol {
+        Protocol::Vless => server(
+            "grpc",
+            ProxyProtocol::VLESS,
+            ProxyConfig::Vless(VlessConfig {
+                uuid: VLESS_UUID.to_string(),
+                flow: None,
+                encryption: "none".to_string(),
+                network: "grpc".to_string(),
+                security: "none".to_string(),
+                sni: None,
+                alpn: None,
+                fp: None,
+                pbk: None,
+                sid: None,
+                spx: None,
+                ws: None,
+                grpc: Some(grpc_config(mode)),
+            }),
+            addr,
+        ),
+        Protocol::Vmess => server(
+            "grpc",
+            ProxyProtocol::VMess,
+            ProxyConfig::Vmess(VmessConfig {
+                uuid: common::vmess::UUID.to_string(),
+                alter_id: 0,
+                security: "aes-128-gcm".to_string(),
+                network: "grpc".to_string(),
+                tls: false,
+                sni: None,
+                alpn: None,
+                skip_cert_verify: false,
+                ws: None,
+                grpc: Some(grpc_config(mode)),
+            }),
+            addr,
+        ),
+        Protocol::Trojan => server(
+            "grpc",
+            ProxyProtocol::Trojan,
+            ProxyConfig::Trojan {
+                password: PASSWORD.to_string(),
+                // Сертификат выписан на localhost, SNI становится и :authority
+                sni: Some("localhost".to_string()),
+                alpn: None,
+                skip_cert_verify: false,
+                ca: Some(cert_path("ca.pem")),
+                network: Some("grpc".to_string()),
+                ws: None,
+                grpc: Some(grpc_config(mode)),
+            },
+            addr,
+        ),
+    }
+}
+
+async fn round_trip(protocol: Protocol, mode: &str) -> Call {
+    let echo = start_echo_server().await;
+    let tls = matches!(protocol, Protocol::Trojan);
+    let (grpc, mut calls) = start_grpc_server(protocol, tls, None).await;
+    let proxy = start_engine(vec![port_rule(echo.port(), "grpc")], vec![grpc_server(protocol, grpc, mode)]).await;
+
+    let stream = socks5_connect(proxy, echo).await;
+    // Больше MAX_CHUNK и окна HTTP/2 по умолчанию в обе стороны
+    let payload = test_payload(256 * 1024);
+    assert_eq!(echo_round_trip(stream, &payload).await, payload);
+
+    let call = calls.recv().await.unwrap();
+    assert_eq!(call.content_type.as_deref(), Some("application/grpc"));
+    call
+}
+
+#[tokio::test]
+async fn vless_over_gun_round_trip() {
+    let call = round_trip(Protocol::Vless, "gun").await;
+    assert_eq!(call.path, "/my.Service/Tun");
+    assert!(call.authority.starts_with("127.0.0.1"), "{}", call.authority);
+}
+
+#[tokio::test]
+async fn vless_over_multi_round_trip() {
+    let call = round_trip(Protocol::Vless, "multi").await;
+    assert_eq!(call.path, "/my.Service/TunMulti");
+}
+
+#[tokio::test]
+async fn vmess_over_gun_round_trip() {
+    let call = round_trip(Protocol::Vmess, "gun").await;
+    assert_eq!(call.path, "/my.Service/Tun");
+}
+
+#[tokio::test]
+async fn vmess_over_multi_round_trip() {
+    let call = round_trip(Protocol::Vmess, "multi").await;
+    assert_eq!(call.path, "/my.Service/TunMulti");
+}
+
+#[tokio::test]
+async fn trojan_over_gun_with_tls_round_trip() {
+    let call = round_trip(Protocol::Trojan, "gun").await;
+    assert_eq!(call.path, "/my.Service/Tun");
+    assert_eq!(call.authority, "localhost");
+}
+
+#[tokio::test]
+async fn trojan_over_multi_with_tls_round_trip() {
+    let call = round_trip(Protocol::Trojan, "multi").await;
+    assert_eq!(call.path, "/my.Service/TunMulti");
+}
+
+#[tokio::test]
+async fn non_zero_grpc_status_is_an_error() {
+    let (grpc, mut calls) = start_grpc_server(Protocol::Vless, false, Some("14")).await;
+    let server = grpc_server(Protocol::Vless, grpc, "gun");
+    let error = health::url_test(&server, &[], "http://127.0.0.1:80/", Duration::from_secs(5)).await.unwrap_err();
+    let error = format!("{:#}", error);
+    assert!(error.contains("gRPC call failed with status \"14\": unavailable"), "{}", error);
+    assert_eq!(calls.recv().await.unwrap().path, "/my.Service/Tun");
+}
+
+#[tokio::test]
+async fn unknown_mode_fails_before_the_call() {
+    let (grpc, mut calls) = start_grpc_server(Protocol::Vless, false, None).await;
+    let server = grpc_server(Protocol::Vless, grpc, "stream");
+    let error = health::url_test(&server, &[], "http://127.0.0.1:80/", Duration::from_secs(5)).await.unwrap_err();
+    assert!(format!("{:#}", error).contains("Unknown gRPC mode \"stream\""), "{:#}", error);
+    assert!(calls.try_recv().is_err());
+}
+
+fn vmess_link(net: &str, path: &str, header_type: &str) -> String {
+    let config = serde_json::json!({
+        "v": "2", "ps": "node", "add": "example.com", "port": 443, "id": common::vmess::UUID,
+        "aid": 0, "net": net, "path": path, "type": header_type, "tls": "tls",
+    });
+    format!("vmess://{}", general_purpose::STANDARD.encode(config.to_string()))
+}
+
+#[test]
+fn vless_link_keeps_grpc_parameters() {
+    let link = format!("vless://{}@example.com:443?type=grpc&security=tls&serviceName=my.Service&mode=multi#node", VLESS_UUID);
+    let server = SubscriptionParser::new().parse_vless_url(&link).unwrap();
+    let ProxyConfig::Vless(config) = server.config else { panic!("expected VLESS config") };
+    let grpc = config.grpc.expect("gRPC parameters must be kept");
+    assert_eq!((grpc.service_name.as_str(), grpc.mode.as_str()), (SERVICE, "multi"));
+
+    // Без mode - режим gun
+    let link = format!("vless://{}@example.com:443?type=grpc&serviceName=my.Service#node", VLESS_UUID);
+    let ProxyConfig::Vless(config) = SubscriptionParser::new().parse_vless_url(&link).unwrap().config else { unreachable!() };
+    assert_eq!(config.grpc.unwrap().mode, "gun");
+}
+
+#[test]
+fn vmess_link_keeps_grpc_parameters() {
+    let server = SubscriptionParser::new().parse_vmess_url(&vmess_link("grpc", SERVICE, "multi")).unwrap();
+    let ProxyConfig::Vmess(config) = server.config else { panic!("expected VMess config") };
+    let grpc = config.grpc.expect("gRPC parameters must be kept");
+    assert_eq!((grpc.service_name.as_str(), grpc.mode.as_str()), (SERVICE, "multi"));
+
+    // type=none у v2rayN означает режим по умолчанию
+    let server = SubscriptionParser::new().parse_vmess_url(&vmess_link("grpc", SERVICE, "none")).unwrap();
+    let ProxyConfig::Vmess(config) = server.config else { unreachable!() };
+    assert_eq!(config.grpc.unwrap().mode, "gun");
+}
+
+#[test]
+fn link_with_unknown_grpc_mode_is_rejected() {
+    let parser = SubscriptionParser::new();
+    let link = format!("vless://{}@example.com:443?type=grpc&serviceName=my.Service&mode=stream#node", VLESS_UUID);
+    let error = parser.parse_vless_url(&link).unwrap_err();
+    assert_eq!(error.to_string(), "Unknown gRPC mode \"stream\", expected gun or multi");
+    assert!(parser.parse_vmess_url(&vmess_link("grpc", SERVICE, "stream")).is_err());
+
+    // У других транспортов type - тип заголовка, а не режим gRPC
+    assert!(parser.parse_vmess_url(&vmess_link("tcp", "", "http")).is_ok());
+}
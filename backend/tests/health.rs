@@ -0,0 +1,273 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use actix_web::{test, web, App};
+use stealthcat_backend::api;
+use stealthcat_backend::database::Database;
+use stealthcat_backend::models::*;
+use stealthcat_backend::proxy::health::{self, read_status, HealthChecker};
+use stealthcat_backend::proxy::{ProxyEngine, RouteAction};
+use common::*;
+
+/// Сервер, который принимает соединение и молчит.
+async fn start_silent_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut connections = Vec::new();
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf).await;
+            connections.push(stream);
+        }
+    });
+    addr
+}
+
+/// Отвечает на любой запрос байтами `response` и дочитывает запрос до конца.
+async fn start_raw_endpoint(response: Vec<u8>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let response = response.clone();
+            tokio::spawn(async move {
+                let mut head = Vec::new();
+                while !head.ends_with(b"\r\n\r\n") {
+                    let Ok(byte) = stream.read_u8().await else { return };
+                    head.push(byte);
+                }
+                let _ = stream.write_all(&response).await;
+                let _ = tokio::io::copy(&mut stream, &mut tokio::io::sink()).await;
+            });
+        }
+    });
+    addr
+}
+
+fn http_server(name: &str, addr: SocketAddr) -> ProxyServerV2 {
+    server(name, ProxyProtocol::HTTP, ProxyConfig::Http { username: None, password: None }, addr)
+}
+
+/// Движок и проверка с настройками health-check для тестового URL.
+async fn setup(
+    endpoint: SocketAddr,
+    settings: &str,
+    servers: Vec<ProxyServerV2>,
+    groups: Vec<ProxyGroup>,
+) -> (Arc<ProxyEngine>, Arc<Database>, HealthChecker) {
+    let db = temp_database().await;
+    for server in &servers {
+        db.insert_server_v2(server).await.unwrap();
+    }
+    let config = MihomoConfig {
+        raw_config: format!("health-check:\n  url: http://{}/generate_204\n{}", endpoint, settings),
+        format: ConfigFormat::YAML,
+        last_modified: chrono::Utc::now(),
+    };
+    let rules = vec![port_rule(80, "auto")];
+    let engine = Arc::new(ProxyEngine::new(config, rules, servers, groups));
+    let checker = HealthChecker::new(engine.clone(), db.clone());
+    (engine, db, checker)
+}
+
+#[tokio::test]
+async fn healthy_server_gets_latency_and_last_check() {
+    let (endpoint, mut requests) = start_endpoint().await;
+    let upstream = start_upstream(Arc::new(AtomicBool::new(true))).await;
+    let (_, db, checker) = setup(endpoint, "", vec![http_server("a", upstream)], Vec::new()).await;
+
+    let results = checker.check_all().await;
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].error, None);
+    assert_eq!(results[0].health.up, Some(true));
+    assert_eq!(results[0].changed, None);
+    assert_eq!(requests.recv().await.unwrap(), "/generate_204");
+
+    let stored = &db.get_servers_v2().await.unwrap()[0];
+    assert!(stored.latency_ms.is_some());
+    assert!(stored.last_ping.is_some());
+    assert_eq!(stored.failure_streak, 0);
+    assert_eq!(stored.up, Some(true));
+}
+
+#[tokio::test]
+async fn server_goes_down_after_failure_streak_and_recovers() {
+    let (endpoint, _requests) = start_endpoint().await;
+    let up = Arc::new(AtomicBool::new(false));
+    let upstream = start_upstream(up.clone()).await;
+    let (engine, db, checker) = setup(endpoint, "  max-failures: 2\n", vec![http_server("a", upstream)], Vec::new()).await;
+
+    let first = checker.check_all().await.remove(0);
+    assert!(first.error.is_some());
+    assert_eq!((first.health.failure_streak, first.health.up, first.changed), (1, Some(true), None));
+
+    let second = checker.check_all().await.remove(0);
+    assert_eq!((second.health.failure_streak, second.health.up, second.changed), (2, Some(false), Some(false)));
+    assert_eq!(second.health.latency_ms, None);
+    let stored = &db.get_servers_v2().await.unwrap()[0];
+    assert_eq!((stored.failure_streak, stored.up), (2, Some(false)));
+    assert_eq!(engine.router().health().get("a-id").unwrap().up, Some(false));
+
+    up.store(true, Ordering::SeqCst);
+    let third = checker.check_all().await.remove(0);
+    assert_eq!((third.health.failure_streak, third.health.up, third.changed), (0, Some(true), Some(true)));
+    assert!(third.health.latency_ms.is_some());
+}
+
+#[tokio::test]
+async fn timeout_counts_as_failure() {
+    let (endpoint, _requests) = start_endpoint().await;
+    let silent = start_silent_server().await;
+    let (_, _, checker) = setup(endpoint, "  timeout: 200\n", vec![http_server("a", silent)], Vec::new()).await;
+
+    let result = checker.check_all().await.remove(0);
+    assert!(result.error.unwrap().contains("timed out"));
+    assert_eq!(result.health.failure_streak, 1);
+}
+
+#[tokio::test]
+async fn fallback_group_skips_down_server() {
+    let (endpoint, _requests) = start_endpoint().await;
+    let dead = start_upstream(Arc::new(AtomicBool::new(false))).await;
+    let alive = start_upstream(Arc::new(AtomicBool::new(true))).await;
+    let mut servers = vec![http_server("a", dead), http_server("b", alive)];
+    // Участники групп проверяются, даже если сервер не активен
+    servers[1].active = false;
+    let group = ProxyGroup {
+        id: "auto-id".to_string(),
+        name: "auto".to_string(),
+        group_type: ProxyGroupType::Fallback,
+        proxies: vec!["a".to_string(), "b".to_string()],
+        selected: None,
+        tolerance: 0,
+        strategy: LoadBalanceStrategy::default(),
+    };
+    let (engine, _, checker) = setup(endpoint, "  max-failures: 1\n", servers, vec![group]).await;
+
+    let route = |engine: &ProxyEngine| match engine.router().route("example.com", 80, &[]).unwrap().action {
+        RouteAction::Proxy(server) => server.name,
+        action => panic!("unexpected action {}", action),
+    };
+    assert_eq!(route(&engine), "a");
+
+    assert_eq!(checker.check_all().await.len(), 2);
+    assert_eq!(route(&engine), "b");
+}
+
+#[tokio::test]
+async fn scheduled_checks_run_in_background() {
+    let (endpoint, mut requests) = start_endpoint().await;
+    let upstream = start_upstream(Arc::new(AtomicBool::new(true))).await;
+    let (_, db, checker) = setup(endpoint, "  interval: 1\n", vec![http_server("a", upstream)], Vec::new()).await;
+    tokio::spawn(checker.run());
+
+    for _ in 0..2 {
+        tokio::time::timeout(Duration::from_secs(5), requests.recv()).await
+            .expect("health check did not run")
+            .unwrap();
+    }
+    let stored = &db.get_servers_v2().await.unwrap()[0];
+    assert_eq!(stored.up, Some(true));
+}
+
+#[tokio::test]
+async fn read_status_parses_status_line() {
+    let mut response: &[u8] = b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n";
+    assert_eq!(read_status(&mut response).await.unwrap(), 204);
+    // Читается только строка статуса
+    assert_eq!(response, b"Connection: close\r\n\r\n");
+}
+
+#[tokio::test]
+async fn read_status_rejects_too_long_line() {
+    let line = vec![b'a'; 16 * 1024];
+    let error = read_status(&mut &line[..]).await.unwrap_err().to_string();
+    assert_eq!(error, "HTTP status line is too long");
+}
+
+#[tokio::test]
+async fn read_status_rejects_non_http_response() {
+    let error = read_status(&mut &b"SSH-2.0-OpenSSH_9.6\r\n"[..]).await.unwrap_err().to_string();
+    assert_eq!(error, "Invalid HTTP response: SSH-2.0-OpenSSH_9.6");
+    let error = read_status(&mut &b"HTTP/1.1 OK\r\n"[..]).await.unwrap_err().to_string();
+    assert_eq!(error, "Invalid HTTP response: HTTP/1.1 OK");
+    let error = read_status(&mut &b"HTTP/1.1 200"[..]).await.unwrap_err().to_string();
+    assert_eq!(error, "Connection closed before HTTP response");
+}
+
+#[tokio::test]
+async fn url_test_fails_on_non_http_and_endless_responses() {
+    let upstream = start_upstream(Arc::new(AtomicBool::new(true))).await;
+    let servers = vec![http_server("a", upstream)];
+    let cases = [
+        (b"SSH-2.0-OpenSSH_9.6\r\n".to_vec(), "Invalid HTTP response"),
+        (vec![b'a'; 16 * 1024], "too long"),
+    ];
+    for (response, expected) in cases {
+        let endpoint = start_raw_endpoint(response).await;
+        let url = format!("http://{}/generate_204", endpoint);
+        let error = health::url_test(&servers[0], &servers, &url, Duration::from_secs(5)).await.unwrap_err();
+        assert!(error.to_string().contains(expected), "{:#}", error);
+    }
+}
+
+/// Сервис с одним маршрутом замера скорости, как в main.rs.
+macro_rules! speed_test_service {
+    ($engine:expr, $db:expr) => {
+        test::init_service(
+            App::new()
+                .app_data(web::Data::new($db.clone()))
+                .app_data(web::Data::new($engine.clone()))
+                .route("/api/servers-v2/{id}/test", web::post().to(api::test_server_speed)),
+        )
+        .await
+    };
+}
+
+#[actix_web::test]
+async fn speed_test_of_unknown_server_is_not_found() {
+    let (endpoint, _requests) = start_endpoint().await;
+    let (engine, db, _) = setup(endpoint, "", Vec::new(), Vec::new()).await;
+    let app = speed_test_service!(engine, db);
+
+    let request = test::TestRequest::post().uri("/api/servers-v2/missing/test").to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), 404);
+    let body: serde_json::Value = test::read_body_json(response).await;
+    assert_eq!(body["success"], false);
+    assert_eq!(body["error"]["message"], "Server not found");
+}
+
+#[actix_web::test]
+async fn speed_test_reports_non_http_upload_response() {
+    let (endpoint, _requests) = start_endpoint().await;
+    let upstream = start_upstream(Arc::new(AtomicBool::new(true))).await;
+    let download = start_raw_endpoint(b"HTTP/1.1 200 OK\r\nContent-Length: 16\r\n\r\n0123456789abcdef".to_vec()).await;
+    let upload = start_raw_endpoint(b"SSH-2.0-OpenSSH_9.6\r\n".to_vec()).await;
+    let (engine, db, _) = setup(endpoint, "", vec![http_server("a", upstream)], Vec::new()).await;
+    let app = speed_test_service!(engine, db);
+
+    let request = test::TestRequest::post()
+        .uri("/api/servers-v2/a-id/test")
+        .set_json(SpeedTestRequest {
+            download_url: Some(format!("http://{}/down", download)),
+            upload_url: Some(format!("http://{}/up", upload)),
+            download_bytes: Some(16),
+            upload_bytes: Some(1024),
+            timeout: Some(5000),
+        })
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), 502);
+    let body: serde_json::Value = test::read_body_json(response).await;
+    let message = body["error"]["message"].as_str().unwrap();
+    assert!(message.contains("Upload test failed: Invalid HTTP response: SSH-2.0-OpenSSH_9.6"), "{}", message);
+}
@@ -0,0 +1,311 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use base64::{Engine as _, engine::general_purpose};
+use stealthcat_backend::models::{ConfigFormat, MihomoConfig};
+use common::*;
+
+/// Тестовый HTTP сервер: отвечает телом запроса и сообщает заголовки
+/// каждого полученного запроса.
+struct Origin {
+    addr: SocketAddr,
+    connections: Arc<AtomicUsize>,
+    requests: mpsc::UnboundedReceiver<Vec<String>>,
+}
+
+async fn start_origin() -> Origin {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let connections = Arc::new(AtomicUsize::new(0));
+    let (tx, requests) = mpsc::unbounded_channel();
+
+    let counter = connections.clone();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            counter.fetch_add(1, Ordering::SeqCst);
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let mut stream = BufReader::new(stream);
+                while let Some(head) = read_head(&mut stream).await {
+                    let body = read_body(&mut stream, &head).await;
+                    let _ = tx.send(head);
+                    let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+                    stream.write_all(response.as_bytes()).await.unwrap();
+                    stream.write_all(&body).await.unwrap();
+                }
+            });
+        }
+    });
+    Origin { addr, connections, requests }
+}
+
+async fn read_head(stream: &mut BufReader<TcpStream>) -> Option<Vec<String>> {
+    let mut head = Vec::new();
+    loop {
+        let mut line = String::new();
+        if stream.read_line(&mut line).await.ok()? == 0 {
+            return None;
+        }
+        let line = line.trim_end().to_string();
+        if line.is_empty() {
+            return Some(head);
+        }
+        head.push(line);
+    }
+}
+
+fn header<'a>(head: &'a [String], name: &str) -> Option<&'a str> {
+    head.iter()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.trim())
+}
+
+// Тело по Content-Length или chunked
+async fn read_body(stream: &mut BufReader<TcpStream>, head: &[String]) -> Vec<u8> {
+    let mut body = Vec::new();
+    if header(head, "transfer-encoding").is_some() {
+        loop {
+            let mut line = String::new();
+            stream.read_line(&mut line).await.unwrap();
+            let size = usize::from_str_radix(line.trim(), 16).unwrap();
+            let mut chunk = vec![0u8; size + 2];
+            stream.read_exact(&mut chunk).await.unwrap();
+            if size == 0 {
+                return body;
+            }
+            body.extend_from_slice(&chunk[..size]);
+        }
+    }
+    if let Some(len) = header(head, "content-length") {
+        body.resize(len.parse().unwrap(), 0);
+        stream.read_exact(&mut body).await.unwrap();
+    }
+    body
+}
+
+async fn read_response(stream: &mut BufReader<TcpStream>) -> (String, Vec<u8>) {
+    let head = tokio::time::timeout(Duration::from_secs(5), read_head(stream))
+        .await
+        .unwrap()
+        .expect("proxy closed the connection without a response");
+    let body = read_body(stream, &head).await;
+    (head[0].clone(), body)
+}
+
+async fn send(proxy: SocketAddr, request: &[u8]) -> (String, Vec<u8>) {
+    let mut stream = BufReader::new(TcpStream::connect(proxy).await.unwrap());
+    stream.write_all(request).await.unwrap();
+    read_response(&mut stream).await
+}
+
+#[tokio::test]
+async fn content_length_body_is_forwarded_in_origin_form() {
+    let mut origin = start_origin().await;
+    let proxy = start_engine(Vec::new(), Vec::new()).await;
+
+    let request = format!(
+        "POST http://{}/echo?x=1 HTTP/1.1\r\nHost: {}\r\nProxy-Connection: keep-alive\r\nContent-Length: 5\r\n\r\nhello",
+        origin.addr, origin.addr
+    );
+    let (status, body) = send(proxy, request.as_bytes()).await;
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    assert_eq!(body, b"hello");
+
+    let head = origin.requests.recv().await.unwrap();
+    assert_eq!(head[0], "POST /echo?x=1 HTTP/1.1");
+    assert!(header(&head, "proxy-connection").is_none());
+}
+
+#[tokio::test]
+async fn chunked_body_is_forwarded() {
+    let mut origin = start_origin().await;
+    let proxy = start_engine(Vec::new(), Vec::new()).await;
+
+    let request = format!(
+        "POST http://{}/ HTTP/1.1\r\nHost: {}\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n",
+        origin.addr, origin.addr
+    );
+    let (status, body) = send(proxy, request.as_bytes()).await;
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    assert_eq!(body, b"hello world");
+    let head = origin.requests.recv().await.unwrap();
+    assert_eq!(header(&head, "transfer-encoding"), Some("chunked"));
+}
+
+#[tokio::test]
+async fn keep_alive_reuses_client_and_origin_connections() {
+    let origin = start_origin().await;
+    let proxy = start_engine(Vec::new(), Vec::new()).await;
+
+    let mut stream = BufReader::new(TcpStream::connect(proxy).await.unwrap());
+    for body in ["first", "second"] {
+        let request = format!(
+            "POST http://{}/ HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\n\r\n{}",
+            origin.addr, origin.addr, body.len(), body
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let (status, received) = read_response(&mut stream).await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(received, body.as_bytes());
+    }
+    assert_eq!(origin.connections.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn oversized_head_is_rejected() {
+    let origin = start_origin().await;
+    let proxy = start_engine(Vec::new(), Vec::new()).await;
+
+    let request = format!(
+        "GET http://{}/ HTTP/1.1\r\nHost: {}\r\nX-Padding: {}\r\n\r\n",
+        origin.addr, origin.addr, "a".repeat(70 * 1024)
+    );
+    let (status, _) = send(proxy, request.as_bytes()).await;
+    assert_eq!(status, "HTTP/1.1 400 Bad Request");
+    assert_eq!(origin.connections.load(Ordering::SeqCst), 0);
+}
+
+#[tokio::test]
+async fn chunked_request_with_content_length_is_rejected() {
+    let origin = start_origin().await;
+    let proxy = start_engine(Vec::new(), Vec::new()).await;
+
+    let request = format!(
+        "POST http://{}/ HTTP/1.1\r\nHost: {}\r\nContent-Length: 4\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n",
+        origin.addr, origin.addr
+    );
+    let (status, _) = send(proxy, request.as_bytes()).await;
+    assert_eq!(status, "HTTP/1.1 400 Bad Request");
+    assert_eq!(origin.connections.load(Ordering::SeqCst), 0);
+}
+
+#[tokio::test]
+async fn transfer_encoding_not_ending_with_chunked_is_rejected() {
+    let origin = start_origin().await;
+    let proxy = start_engine(Vec::new(), Vec::new()).await;
+
+    for encoding in ["gzip", "chunked, gzip", "chunked, identity"] {
+        let request = format!(
+            "POST http://{}/ HTTP/1.1\r\nHost: {}\r\nTransfer-Encoding: {}\r\n\r\n0\r\n\r\n",
+            origin.addr, origin.addr, encoding
+        );
+        let (status, _) = send(proxy, request.as_bytes()).await;
+        assert_eq!(status, "HTTP/1.1 400 Bad Request", "{}", encoding);
+    }
+    assert_eq!(origin.connections.load(Ordering::SeqCst), 0);
+}
+
+fn auth_config() -> MihomoConfig {
+    MihomoConfig {
+        raw_config: "authentication:\n  - \"user:secret\"\n".to_string(),
+        format: ConfigFormat::YAML,
+        last_modified: chrono::Utc::now(),
+    }
+}
+
+fn basic(credentials: &str) -> String {
+    format!("Proxy-Authorization: Basic {}\r\n", general_purpose::STANDARD.encode(credentials))
+}
+
+/// CONNECT с дополнительными заголовками; возвращает заголовок ответа и поток.
+async fn connect_with(proxy: SocketAddr, target: SocketAddr, headers: &str) -> (Vec<String>, BufReader<TcpStream>) {
+    let mut stream = BufReader::new(TcpStream::connect(proxy).await.unwrap());
+    let request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n{}\r\n", target, target, headers);
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let head = tokio::time::timeout(Duration::from_secs(5), read_head(&mut stream))
+        .await
+        .unwrap()
+        .expect("proxy closed the connection without a response");
+    (head, stream)
+}
+
+async fn tunnel_round_trip(stream: &mut BufReader<TcpStream>, payload: &[u8]) -> Vec<u8> {
+    stream.write_all(payload).await.unwrap();
+    let mut received = vec![0u8; payload.len()];
+    tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut received)).await.unwrap().unwrap();
+    received
+}
+
+#[tokio::test]
+async fn mixed_port_detects_protocol_by_first_byte() {
+    let echo = start_echo_server().await;
+    let mut origin = start_origin().await;
+    let proxy = start_engine(Vec::new(), Vec::new()).await;
+
+    // 0x05 - SOCKS5
+    let stream = socks5_connect(proxy, echo).await;
+    assert_eq!(echo_round_trip(stream, b"socks5").await, b"socks5");
+
+    // 0x04 - SOCKS4
+    let SocketAddr::V4(v4) = echo else { unreachable!() };
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    let mut request = vec![4, 1];
+    request.extend_from_slice(&v4.port().to_be_bytes());
+    request.extend_from_slice(&v4.ip().octets());
+    request.push(0);
+    stream.write_all(&request).await.unwrap();
+    let mut reply = [0u8; 8];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[1], 0x5A);
+    assert_eq!(echo_round_trip(stream, b"socks4").await, b"socks4");
+
+    // Остальное - HTTP: CONNECT и обычный запрос
+    let (head, mut stream) = connect_with(proxy, echo, "").await;
+    assert_eq!(head[0], "HTTP/1.1 200 Connection Established");
+    assert_eq!(tunnel_round_trip(&mut stream, b"connect").await, b"connect");
+
+    let request = format!("GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n", origin.addr, origin.addr);
+    let (status, _) = send(proxy, request.as_bytes()).await;
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    assert_eq!(origin.requests.recv().await.unwrap()[0], "GET / HTTP/1.1");
+}
+
+#[tokio::test]
+async fn requests_without_valid_credentials_get_407() {
+    let echo = start_echo_server().await;
+    let origin = start_origin().await;
+    let proxy = start_engine_with_config(auth_config(), Vec::new(), Vec::new()).await;
+
+    for headers in [String::new(), basic("user:wrong"), "Proxy-Authorization: Bearer token\r\n".to_string()] {
+        let (head, _) = connect_with(proxy, echo, &headers).await;
+        assert_eq!(head[0], "HTTP/1.1 407 Proxy Authentication Required");
+        assert_eq!(header(&head, "proxy-authenticate"), Some("Basic realm=\"StealthCat\""));
+
+        let request = format!("GET http://{}/ HTTP/1.1\r\nHost: {}\r\n{}\r\n", origin.addr, origin.addr, headers);
+        let mut stream = BufReader::new(TcpStream::connect(proxy).await.unwrap());
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let head = read_head(&mut stream).await.unwrap();
+        assert_eq!(head[0], "HTTP/1.1 407 Proxy Authentication Required");
+        assert_eq!(header(&head, "proxy-authenticate"), Some("Basic realm=\"StealthCat\""));
+    }
+    assert_eq!(origin.connections.load(Ordering::SeqCst), 0);
+}
+
+#[tokio::test]
+async fn valid_basic_credentials_are_accepted() {
+    let echo = start_echo_server().await;
+    let mut origin = start_origin().await;
+    let proxy = start_engine_with_config(auth_config(), Vec::new(), Vec::new()).await;
+
+    let (head, mut stream) = connect_with(proxy, echo, &basic("user:secret")).await;
+    assert_eq!(head[0], "HTTP/1.1 200 Connection Established");
+    assert_eq!(tunnel_round_trip(&mut stream, b"authorized").await, b"authorized");
+
+    // Учётные данные прокси не уходят на сервер назначения
+    let request = format!(
+        "POST http://{}/ HTTP/1.1\r\nHost: {}\r\n{}Content-Length: 5\r\n\r\nhello",
+        origin.addr, origin.addr, basic("user:secret")
+    );
+    let (status, body) = send(proxy, request.as_bytes()).await;
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    assert_eq!(body, b"hello");
+    assert!(header(&origin.requests.recv().await.unwrap(), "proxy-authorization").is_none());
+}
@@ -0,0 +1,90 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use stealthcat_backend::models::*;
+use stealthcat_backend::proxy::{ProxyEngine, RouteAction};
+use common::*;
+
+fn http_server(name: &str, addr: SocketAddr) -> ProxyServerV2 {
+    server(name, ProxyProtocol::HTTP, ProxyConfig::Http { username: None, password: None }, addr)
+}
+
+fn yaml(raw: &str) -> MihomoConfig {
+    MihomoConfig {
+        raw_config: raw.to_string(),
+        format: ConfigFormat::YAML,
+        last_modified: chrono::Utc::now(),
+    }
+}
+
+fn route(engine: &ProxyEngine, port: u16) -> (String, Option<String>) {
+    let decision = engine.router().route("127.0.0.1", port, &[]).unwrap();
+    (decision.action.to_string(), decision.rule_id)
+}
+
+#[tokio::test]
+async fn bypass_sends_everything_direct() {
+    let echo = start_echo_server().await;
+    // Правило ведёт на сервер, до которого не подключиться
+    let dead = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let rules = vec![port_rule(echo.port(), "a")];
+    let engine = Arc::new(ProxyEngine::new(yaml("mode: direct\n"), rules, vec![http_server("a", dead)], Vec::new()));
+    assert_eq!(engine.mode(), ProxyMode::Bypass);
+    let proxy = serve_engine(engine.clone()).await;
+
+    assert_eq!(route(&engine, echo.port()), ("DIRECT".to_string(), None));
+    let stream = socks5_connect(proxy, echo).await;
+    assert_eq!(echo_round_trip(stream, b"bypass").await, b"bypass");
+}
+
+#[tokio::test]
+async fn global_ignores_rules() {
+    let echo = start_echo_server().await;
+    let upstream = start_upstream(Arc::new(AtomicBool::new(true))).await;
+    let servers = vec![http_server("a", upstream)];
+    let engine = Arc::new(ProxyEngine::new(yaml("mode: global\n"), vec![port_rule(echo.port(), "block")], servers, Vec::new()));
+    engine.select_server(Some("a-id".to_string()));
+    let proxy = serve_engine(engine.clone()).await;
+
+    let decision = engine.router().route("127.0.0.1", echo.port(), &[]).unwrap();
+    assert!(matches!(&decision.action, RouteAction::Proxy(server) if server.name == "a"), "{}", decision.action);
+    assert_eq!(decision.rule_id, None);
+    let stream = socks5_connect(proxy, echo).await;
+    assert_eq!(echo_round_trip(stream, b"global").await, b"global");
+
+    // В режиме правил то же соединение блокируется
+    engine.set_mode(ProxyMode::PAC);
+    assert_eq!(route(&engine, echo.port()), ("BLOCK".to_string(), Some(format!("rule-{}", echo.port()))));
+}
+
+#[tokio::test]
+async fn global_without_servers_is_an_error() {
+    let engine = ProxyEngine::new(yaml("mode: global\n"), Vec::new(), Vec::new(), Vec::new());
+    let error = engine.router().route("example.com", 443, &[]).unwrap_err();
+    assert_eq!(error.to_string(), "Global mode requires a proxy, but no server is selected");
+}
+
+#[tokio::test]
+async fn persisted_mode_survives_reload() {
+    let db = temp_database().await;
+    db.set_proxy_mode(ProxyMode::Bypass).await.unwrap();
+    db.insert_rule(&port_rule(443, "block")).await.unwrap();
+
+    // Как при запуске в main.rs: режим из базы важнее режима из конфига
+    let engine = ProxyEngine::new(yaml("mode: global\n"), Vec::new(), Vec::new(), Vec::new());
+    if let Some(mode) = db.get_proxy_mode().await.unwrap() {
+        engine.set_mode(mode);
+    }
+    engine.reload(&db).await.unwrap();
+    assert_eq!(engine.mode(), ProxyMode::Bypass);
+    assert_eq!(route(&engine, 443), ("DIRECT".to_string(), None));
+
+    engine.set_mode(ProxyMode::PAC);
+    db.set_proxy_mode(ProxyMode::PAC).await.unwrap();
+    engine.reload(&db).await.unwrap();
+    assert_eq!(engine.mode(), ProxyMode::PAC);
+    assert_eq!(route(&engine, 443), ("BLOCK".to_string(), Some("rule-443".to_string())));
+    assert_eq!(db.get_proxy_mode().await.unwrap(), Some(ProxyMode::PAC));
+}
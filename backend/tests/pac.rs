@@ -0,0 +1,89 @@
+use stealthcat_backend::config::ProxySettings;
+use stealthcat_backend::models::{ProxyMode, Rule};
+use stealthcat_backend::pac;
+use stealthcat_backend::rules::compile_rules;
+
+const PROXY: &str = "PROXY 127.0.0.1:8081";
+
+fn rule(name: &str, rule_type: &str, pattern: &str, action: &str, priority: i32) -> Rule {
+    Rule {
+        id: name.to_string(),
+        name: name.to_string(),
+        rule_type: rule_type.to_string(),
+        pattern: pattern.to_string(),
+        action: action.to_string(),
+        priority,
+        enabled: true,
+    }
+}
+
+fn generate(rules: &[Rule]) -> String {
+    pac::generate(&compile_rules(rules), ProxyMode::PAC, PROXY, false)
+}
+
+#[test]
+fn rules_are_emitted_in_order_and_fall_back_to_direct() {
+    let pac = generate(&[
+        rule("ads", "domain-suffix", "ads.example", "block", 1),
+        rule("local", "ip-cidr", "192.168.0.0/16", "direct", 0),
+    ]);
+    let ads = pac.find("dnsDomainIs(host, \".ads.example\")) return \"PROXY 127.0.0.1:8081\";").unwrap();
+    let local = pac.find("isInNet(hostIp(), \"192.168.0.0\", \"255.255.0.0\")) return \"DIRECT\";").unwrap();
+    assert!(ads < local);
+    assert!(pac.ends_with("  return \"DIRECT\";\n}\n"), "{}", pac);
+}
+
+#[test]
+fn regex_rule_stops_the_file_and_falls_through_to_proxy() {
+    // Regex совпадает с example.com раньше, чем правило direct ниже
+    let pac = generate(&[
+        rule("ads", "domain-suffix", "ads.example", "block", 2),
+        rule("regex", "domain-regex", "^(www\\.)?example\\.com$", "proxy", 1),
+        rule("example", "domain", "example.com", "direct", 0),
+    ]);
+    assert!(pac.contains("dnsDomainIs(host, \".ads.example\")"), "{}", pac);
+    assert!(pac.contains("// regex (not expressible in PAC)"), "{}", pac);
+    assert!(!pac.contains("host == \"example.com\""), "rules below the regex must not be emitted:\n{}", pac);
+    assert!(pac.ends_with("  return \"PROXY 127.0.0.1:8081\";\n}\n"), "{}", pac);
+}
+
+#[test]
+fn port_and_ipv6_rules_also_fall_through_to_proxy() {
+    for (rule_type, pattern) in [("dst-port", "443"), ("ip-cidr6", "2001:db8::/32")] {
+        let pac = generate(&[
+            rule("first", rule_type, pattern, "direct", 1),
+            rule("example", "domain", "example.com", "direct", 0),
+        ]);
+        assert!(!pac.contains("example.com"), "{}", pac);
+        assert!(pac.ends_with("  return \"PROXY 127.0.0.1:8081\";\n}\n"), "{}", pac);
+    }
+}
+
+#[test]
+fn disabled_unexpressible_rule_is_ignored() {
+    let mut regex = rule("regex", "domain-regex", "example", "proxy", 1);
+    regex.enabled = false;
+    let pac = generate(&[regex, rule("example", "domain", "example.com", "direct", 0)]);
+    assert!(pac.contains("if (host == \"example.com\") return \"DIRECT\";"), "{}", pac);
+    assert!(pac.ends_with("  return \"DIRECT\";\n}\n"), "{}", pac);
+}
+
+#[test]
+fn proxy_directive_brackets_ipv6_hosts() {
+    let settings = ProxySettings { port: Some(8080), socks_port: Some(1080), mixed_port: Some(8081), ..ProxySettings::default() };
+    for host in ["::1", "[::1]"] {
+        assert_eq!(
+            pac::proxy_directive(&settings, host),
+            "PROXY [::1]:8081; PROXY [::1]:8080; SOCKS5 [::1]:1080",
+        );
+    }
+    assert_eq!(
+        pac::proxy_directive(&settings, "192.168.1.2"),
+        "PROXY 192.168.1.2:8081; PROXY 192.168.1.2:8080; SOCKS5 192.168.1.2:1080",
+    );
+
+    // Без явных портов используется mixed порт по умолчанию
+    let directive = pac::proxy_directive(&ProxySettings::default(), "fe80::1");
+    assert!(directive.starts_with("PROXY [fe80::1]:"), "{}", directive);
+    assert!(directive.contains("; SOCKS5 [fe80::1]:"), "{}", directive);
+}
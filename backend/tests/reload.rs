@@ -0,0 +1,141 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use actix_web::test::{call_service, init_service, TestRequest};
+use actix_web::{web, App};
+use serde_json::json;
+use tokio::sync::RwLock;
+use stealthcat_backend::api;
+use stealthcat_backend::config::ConfigManager;
+use stealthcat_backend::models::*;
+use stealthcat_backend::proxy::{ProxyEngine, RouteAction};
+use common::*;
+
+fn http_server(name: &str, addr: SocketAddr) -> ProxyServerV2 {
+    server(name, ProxyProtocol::HTTP, ProxyConfig::Http { username: None, password: None }, addr)
+}
+
+fn group(name: &str, group_type: ProxyGroupType, proxies: &[&str]) -> ProxyGroup {
+    ProxyGroup {
+        id: format!("{}-id", name),
+        name: name.to_string(),
+        group_type,
+        proxies: proxies.iter().map(|p| p.to_string()).collect(),
+        selected: None,
+        tolerance: 0,
+        strategy: LoadBalanceStrategy::RoundRobin,
+    }
+}
+
+fn route(engine: &ProxyEngine, port: u16) -> String {
+    match engine.router().route("127.0.0.1", port, &[]).unwrap().action {
+        RouteAction::Proxy(server) => server.name,
+        action => action.to_string(),
+    }
+}
+
+/// Сервис с маршрутами правил, серверов и режима, как в main.rs.
+macro_rules! api_service {
+    ($engine:expr, $db:expr) => {
+        init_service(
+            App::new()
+                .app_data(web::Data::new(Arc::new(RwLock::new(AppState::new()))))
+                .app_data(web::Data::new($db.clone()))
+                .app_data(web::Data::new($engine.clone()))
+                .route("/api/rules", web::post().to(api::create_rule))
+                .route("/api/rules/{id}", web::put().to(api::update_rule))
+                .route("/api/rules/{id}", web::delete().to(api::delete_rule))
+                .route("/api/servers-v2", web::post().to(api::create_server))
+                .route("/api/select-server", web::post().to(api::select_server))
+                .route("/api/mode", web::post().to(api::set_mode)),
+        )
+        .await
+    };
+}
+
+fn post(uri: &str, body: serde_json::Value) -> TestRequest {
+    TestRequest::post().uri(uri).set_json(body)
+}
+
+#[actix_web::test]
+async fn api_changes_reach_the_next_connection() {
+    let echo = start_echo_server().await;
+    let up = Arc::new(AtomicBool::new(false));
+    let relay = start_upstream(up.clone()).await;
+    let db = temp_database().await;
+    let engine = Arc::new(ProxyEngine::new(ConfigManager::get_default_config(), Vec::new(), Vec::new(), Vec::new()));
+    let proxy = serve_engine(engine.clone()).await;
+    let app = api_service!(engine, db);
+
+    let open = socks5_connect(proxy, echo).await;
+
+    let rule = json!({"name": "echo", "type": "dst-port", "pattern": echo.port().to_string(), "action": "reject"});
+    assert_eq!(call_service(&app, post("/api/rules", rule).to_request()).await.status(), 201);
+    assert_eq!(socks5_request(proxy, "127.0.0.1", echo.port()).await.1, 0x02);
+    // Открытый туннель работает со своим снимком
+    assert_eq!(echo_round_trip(open, b"still open").await, b"still open");
+
+    // Правило переводится на новый сервер; пока он закрывает соединения, CONNECT не проходит
+    let relay_server = json!({"name": "relay", "hostname": "127.0.0.1", "port": relay.port(), "protocol": "HTTP", "active": true});
+    assert_eq!(call_service(&app, post("/api/servers-v2", relay_server).to_request()).await.status(), 200);
+    let mut rule = db.get_rules().await.unwrap().remove(0);
+    rule.action = "relay".to_string();
+    let update = TestRequest::put().uri(&format!("/api/rules/{}", rule.id)).set_json(&rule).to_request();
+    assert_eq!(call_service(&app, update).await.status(), 200);
+    assert_ne!(socks5_request(proxy, "127.0.0.1", echo.port()).await.1, 0);
+    up.store(true, Ordering::SeqCst);
+    let (stream, reply) = socks5_request(proxy, "127.0.0.1", echo.port()).await;
+    assert_eq!(reply, 0);
+    assert_eq!(echo_round_trip(stream, b"via relay").await, b"via relay");
+
+    // Без правила соединение идёт напрямую, мимо отключённого relay
+    up.store(false, Ordering::SeqCst);
+    let delete = TestRequest::delete().uri(&format!("/api/rules/{}", rule.id)).to_request();
+    assert_eq!(call_service(&app, delete).await.status(), 200);
+    let (stream, reply) = socks5_request(proxy, "127.0.0.1", echo.port()).await;
+    assert_eq!(reply, 0);
+    assert_eq!(echo_round_trip(stream, b"direct").await, b"direct");
+}
+
+#[actix_web::test]
+async fn reload_keeps_selection_group_state_health_and_mode() {
+    let db = temp_database().await;
+    let addr: SocketAddr = ([127, 0, 0, 1], 8080).into();
+    let servers = vec![http_server("a", addr), http_server("b", addr), http_server("c", addr)];
+    let groups = vec![
+        group("rotate", ProxyGroupType::LoadBalance, &["a", "c"]),
+        group("fallback", ProxyGroupType::Fallback, &["b", "a"]),
+    ];
+    let rules = vec![port_rule(1, "rotate"), port_rule(2, "fallback")];
+    for server in &servers {
+        db.insert_server_v2(server).await.unwrap();
+    }
+    for group in &groups {
+        db.insert_group(group).await.unwrap();
+    }
+    for rule in &rules {
+        db.insert_rule(rule).await.unwrap();
+    }
+    let engine = Arc::new(ProxyEngine::new(ConfigManager::get_default_config(), rules, servers.clone(), groups));
+    let app = api_service!(engine, db);
+    let add_rule = |port: u16| post("/api/rules", json!({"type": "dst-port", "pattern": port.to_string(), "action": "direct"})).to_request();
+
+    assert_eq!(route(&engine, 1), "a");
+    engine.router().health().record(&servers[1], Err(&anyhow::anyhow!("down")), 1);
+    assert_eq!(route(&engine, 2), "a");
+    assert_eq!(call_service(&app, post("/api/select-server", json!({"server_id": "c-id"})).to_request()).await.status(), 200);
+
+    // Новое правило перезагружает роутер
+    assert_eq!(call_service(&app, add_rule(3)).await.status(), 201);
+    assert_eq!(route(&engine, 3), "DIRECT");
+    assert_eq!(route(&engine, 1), "c", "round-robin must continue, not restart");
+    assert_eq!(route(&engine, 2), "a", "b must stay down");
+    assert!(!engine.router().health().health_of(&servers[1]).is_alive());
+
+    assert_eq!(call_service(&app, post("/api/mode", json!({"mode": "Global"})).to_request()).await.status(), 200);
+    assert_eq!(call_service(&app, add_rule(4)).await.status(), 201);
+    assert_eq!(engine.mode(), ProxyMode::Global);
+    assert_eq!(route(&engine, 4), "c");
+}
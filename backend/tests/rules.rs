@@ -0,0 +1,276 @@
+mod common;
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use stealthcat_backend::models::Rule;
+use stealthcat_backend::rules::{compile_rules, find_linear, normalize_domain, validate_rule, RuleIndex, RuleMatcher};
+use common::temp_database;
+
+fn rule(rule_type: &str, pattern: &str) -> Rule {
+    Rule {
+        id: "rule".to_string(),
+        name: "rule".to_string(),
+        rule_type: rule_type.to_string(),
+        pattern: pattern.to_string(),
+        action: "direct".to_string(),
+        priority: 0,
+        enabled: true,
+    }
+}
+
+fn ranked(id: &str, rule_type: &str, pattern: &str, priority: i32) -> Rule {
+    Rule {
+        id: id.to_string(),
+        name: id.to_string(),
+        priority,
+        ..rule(rule_type, pattern)
+    }
+}
+
+fn matches(rule_type: &str, pattern: &str, host: &str) -> bool {
+    RuleMatcher::compile(rule_type, pattern).unwrap().matches(host, 443, &[])
+}
+
+#[test]
+fn ipv4_prefix_matches_up_to_its_boundary() {
+    assert!(matches("ip-cidr", "10.1.0.0/23", "10.1.0.0"));
+    assert!(matches("ip-cidr", "10.1.0.0/23", "10.1.1.255"));
+    assert!(!matches("ip-cidr", "10.1.0.0/23", "10.1.2.0"));
+    assert!(!matches("ip-cidr", "10.1.0.0/23", "10.0.255.255"));
+    // Раньше сравнивались строки, и 10.1.10.5 совпадал с 10.1.1.0/24
+    assert!(!matches("ip-cidr", "10.1.1.0/24", "10.1.10.5"));
+}
+
+#[test]
+fn host_bits_in_network_address_are_ignored() {
+    assert!(matches("ip-cidr", "192.168.1.77/24", "192.168.1.1"));
+    assert!(matches("ip-cidr", "0.0.0.0/0", "8.8.8.8"));
+    assert!(matches("ip-cidr", "8.8.8.8/32", "8.8.8.8"));
+    assert!(!matches("ip-cidr", "8.8.8.8/32", "8.8.8.9"));
+}
+
+#[test]
+fn ipv6_prefix_matches() {
+    assert!(matches("ip-cidr6", "2001:db8::/32", "2001:db8:ffff::1"));
+    assert!(!matches("ip-cidr6", "2001:db8::/32", "2001:db9::1"));
+    assert!(matches("ip-cidr", "fe80::/10", "febf::1"));
+    assert!(!matches("ip-cidr6", "2001:db8::/32", "192.0.2.1"));
+    // IPv4-mapped адрес проверяется как IPv4
+    assert!(matches("ip-cidr", "10.0.0.0/8", "::ffff:10.0.0.1"));
+}
+
+#[test]
+fn resolved_addresses_are_checked_for_domains() {
+    let matcher = RuleMatcher::compile("ip-cidr", "10.0.0.0/8").unwrap();
+    let resolved: Vec<IpAddr> = vec!["10.2.3.4".parse().unwrap()];
+    assert!(matcher.matches("example.com", 443, &resolved));
+    assert!(!matcher.matches("example.com", 443, &[]));
+}
+
+#[test]
+fn invalid_prefix_lengths_are_rejected() {
+    for pattern in ["10.0.0.0/33", "10.0.0.0/-1", "10.0.0.0/", "10.0.0.0", "2001:db8::/129", "10.0.0.0/abc"] {
+        assert!(validate_rule(&rule("ip-cidr", pattern)).is_err(), "{} was accepted", pattern);
+    }
+    assert!(validate_rule(&rule("ip-cidr6", "10.0.0.0/8")).is_err());
+    assert!(validate_rule(&rule("ip-cidr", "10.0.0.0/32")).is_ok());
+    assert!(validate_rule(&rule("ip-cidr6", "::/0")).is_ok());
+}
+
+#[test]
+fn unknown_rule_type_is_rejected() {
+    let error = validate_rule(&rule("ip-cird", "10.0.0.0/8")).unwrap_err();
+    assert!(error.to_string().contains("Unknown rule type: ip-cird"), "{}", error);
+    assert!(validate_rule(&rule("domain-suffix", "example.com")).is_ok());
+}
+
+#[tokio::test]
+async fn higher_priority_is_checked_first() {
+    let db = temp_database().await;
+    let rules = [
+        ranked("low", "domain-suffix", "example.com", 1),
+        ranked("high", "domain", "www.example.com", 10),
+        ranked("lowest", "domain-keyword", "example", -5),
+    ];
+    for rule in &rules {
+        db.insert_rule(rule).await.unwrap();
+    }
+    let stored = db.get_rules().await.unwrap();
+    let ids: Vec<&str> = stored.iter().map(|r| r.id.as_str()).collect();
+    assert_eq!(ids, ["high", "low", "lowest"]);
+
+    // Порядок в базе и после compile_rules одинаков
+    let compiled = compile_rules(&rules);
+    let first = |host: &str| find_linear(&compiled, host, 443, &[]).map(|c| c.rule.id.clone());
+    assert_eq!(first("www.example.com").as_deref(), Some("high"));
+    assert_eq!(first("mail.example.com").as_deref(), Some("low"));
+    assert_eq!(first("example.org").as_deref(), Some("lowest"));
+}
+
+const LABELS: &[&str] = &["a", "ads", "cdn", "mail", "example", "test", "api1", "api22"];
+const TLDS: &[&str] = &["com", "net", "org"];
+const PORTS: &[u16] = &[22, 80, 443, 8080];
+
+fn random_domain(rng: &mut StdRng) -> String {
+    let mut labels: Vec<&str> = (0..rng.gen_range(1..=3)).map(|_| *LABELS.choose(rng).unwrap()).collect();
+    labels.push(TLDS.choose(rng).unwrap());
+    labels.join(".")
+}
+
+fn random_ipv4(rng: &mut StdRng) -> Ipv4Addr {
+    // Узкий диапазон, чтобы сети пересекались
+    Ipv4Addr::new(10, rng.gen_range(0..4), rng.gen_range(0..4), rng.gen())
+}
+
+fn random_ipv6(rng: &mut StdRng) -> Ipv6Addr {
+    Ipv6Addr::new(0x2001, 0xdb8, rng.gen_range(0..4), 0, 0, 0, rng.gen_range(0..4), rng.gen())
+}
+
+fn random_rule(rng: &mut StdRng, i: usize) -> Rule {
+    // Порты и ключевые слова совпадают часто, поэтому они реже остальных
+    let (rule_type, pattern) = match rng.gen_range(0..16) {
+        0..=2 => ("domain", random_domain(rng)),
+        3..=5 => ("domain-suffix", random_domain(rng)),
+        6 => ("domain-keyword", LABELS[1..].choose(rng).unwrap().to_string()),
+        7..=9 => ("domain-wildcard", format!("*.{}", random_domain(rng))),
+        10..=11 => ("domain-regex", format!(r"^api\d+\.{}\.", LABELS.choose(rng).unwrap())),
+        12..=13 => ("ip-cidr", format!("{}/{}", random_ipv4(rng), rng.gen_range(8..=32))),
+        14 => ("ip-cidr6", format!("{}/{}", random_ipv6(rng), rng.gen_range(32..=128))),
+        _ => ("dst-port", PORTS.choose(rng).unwrap().to_string()),
+    };
+    Rule {
+        enabled: rng.gen_ratio(9, 10),
+        // Мало значений, чтобы было много равных приоритетов
+        ..ranked(&format!("{}-{}", rule_type, i), rule_type, &pattern, rng.gen_range(0..5))
+    }
+}
+
+// Запрос: домен с адресами из DNS или IP-литерал
+fn random_query(rng: &mut StdRng) -> (String, u16, Vec<IpAddr>) {
+    let port = *PORTS.choose(rng).unwrap();
+    match rng.gen_range(0..4) {
+        0 => (random_ipv4(rng).to_string(), port, Vec::new()),
+        1 => (random_ipv6(rng).to_string(), port, Vec::new()),
+        2 => {
+            let resolved = vec![IpAddr::V4(random_ipv4(rng)), IpAddr::V6(random_ipv6(rng))];
+            (random_domain(rng), port, resolved)
+        }
+        _ => (random_domain(rng).to_uppercase(), port, Vec::new()),
+    }
+}
+
+// Возвращает, сколько запросов нашли правило
+fn assert_index_agrees(rules: &[Rule], queries: &[(String, u16, Vec<IpAddr>)]) -> usize {
+    let compiled = compile_rules(rules);
+    let index = RuleIndex::new(compiled.clone());
+    let mut matched = 0;
+    for (host, port, resolved) in queries {
+        let host = normalize_domain(host);
+        let linear = find_linear(&compiled, &host, *port, resolved).map(|c| &c.rule.id);
+        let indexed = index.find(&host, *port, resolved).map(|c| &c.rule.id);
+        assert_eq!(indexed, linear, "{}:{} resolved to {:?}", host, port, resolved);
+        matched += linear.is_some() as usize;
+    }
+    matched
+}
+
+#[test]
+fn index_agrees_with_linear_scan_on_mixed_rules() {
+    let mut rng = StdRng::seed_from_u64(6);
+    let mut matched = 0;
+    for _ in 0..50 {
+        let rules: Vec<Rule> = (0..rng.gen_range(1..200)).map(|i| random_rule(&mut rng, i)).collect();
+        let queries: Vec<_> = (0..200).map(|_| random_query(&mut rng)).collect();
+        matched += assert_index_agrees(&rules, &queries);
+    }
+    assert!(matched > 50 * 200 / 4, "only {} queries matched a rule", matched);
+}
+
+#[test]
+fn equal_priorities_keep_insertion_order() {
+    let rules = vec![
+        ranked("keyword", "domain-keyword", "exam", 1),
+        ranked("regex", "domain-regex", r"^www\.", 1),
+        ranked("suffix", "domain-suffix", "example.com", 1),
+        ranked("exact", "domain", "www.example.com", 1),
+        ranked("port", "dst-port", "443", 1),
+    ];
+    for rotation in 0..rules.len() {
+        let mut rules = rules.clone();
+        rules.rotate_left(rotation);
+        let compiled = compile_rules(&rules);
+        let index = RuleIndex::new(compiled.clone());
+        let expected = &rules[0].id;
+        assert_eq!(&find_linear(&compiled, "www.example.com", 443, &[]).unwrap().rule.id, expected);
+        assert_eq!(&index.find("www.example.com", 443, &[]).unwrap().rule.id, expected);
+    }
+}
+
+#[test]
+fn disabled_rules_are_skipped_by_index() {
+    let mut rules = vec![
+        ranked("exact", "domain", "www.example.com", 9),
+        ranked("suffix", "domain-suffix", "example.com", 9),
+        ranked("keyword", "domain-keyword", "exam", 9),
+        ranked("wildcard", "domain-wildcard", "*.example.com", 9),
+        ranked("regex", "domain-regex", "example", 9),
+        ranked("cidr", "ip-cidr", "10.0.0.0/8", 9),
+        ranked("cidr6", "ip-cidr6", "2001:db8::/32", 9),
+        ranked("port", "dst-port", "443", 9),
+    ];
+    for rule in &mut rules {
+        rule.enabled = false;
+    }
+    rules.push(ranked("fallback", "domain-suffix", "com", 0));
+    let resolved: Vec<IpAddr> = vec!["10.1.2.3".parse().unwrap(), "2001:db8::1".parse().unwrap()];
+    let index = RuleIndex::new(compile_rules(&rules));
+    assert_eq!(index.find("www.example.com", 443, &resolved).unwrap().rule.id, "fallback");
+    assert!(index.find("10.1.2.3", 443, &[]).is_none());
+    assert!(index.find("2001:db8::1", 443, &[]).is_none());
+    assert_index_agrees(&rules, &[
+        ("www.example.com".to_string(), 443, resolved),
+        ("10.1.2.3".to_string(), 443, Vec::new()),
+        ("2001:db8::1".to_string(), 80, Vec::new()),
+    ]);
+}
+
+#[test]
+fn index_respects_cidr_boundaries() {
+    let rules = vec![
+        ranked("host", "ip-cidr", "10.1.1.1/32", 4),
+        ranked("narrow", "ip-cidr", "10.1.0.0/23", 3),
+        ranked("wide", "ip-cidr", "10.0.0.0/8", 2),
+        ranked("host6", "ip-cidr6", "2001:db8::1/128", 4),
+        ranked("narrow6", "ip-cidr6", "2001:db8::/48", 3),
+        ranked("wide6", "ip-cidr6", "2001::/16", 2),
+        ranked("any", "ip-cidr", "0.0.0.0/0", 0),
+    ];
+    let index = RuleIndex::new(compile_rules(&rules));
+    let cases = [
+        ("10.1.1.1", "host"),
+        ("10.1.1.0", "narrow"),
+        ("10.1.0.0", "narrow"),
+        ("10.1.1.255", "narrow"),
+        ("10.1.2.0", "wide"),
+        ("10.0.255.255", "wide"),
+        ("10.255.255.255", "wide"),
+        ("11.0.0.0", "any"),
+        ("9.255.255.255", "any"),
+        ("::ffff:10.1.1.1", "host"),
+        ("2001:db8::1", "host6"),
+        ("2001:db8::2", "narrow6"),
+        ("2001:db8:0:ffff:ffff:ffff:ffff:ffff", "narrow6"),
+        ("2001:db8:1::", "wide6"),
+        ("2001:ffff:ffff:ffff:ffff:ffff:ffff:ffff", "wide6"),
+    ];
+    for (ip, expected) in cases {
+        assert_eq!(index.find(ip, 443, &[]).map(|c| c.rule.id.as_str()), Some(expected), "{}", ip);
+    }
+    // 0.0.0.0/0 не покрывает IPv6
+    assert!(index.find("2002::1", 443, &[]).is_none());
+
+    let queries: Vec<_> = cases.iter().map(|(ip, _)| (ip.to_string(), 443, Vec::new())).collect();
+    assert_index_agrees(&rules, &queries);
+}
@@ -0,0 +1,268 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+use md5::{Digest, Md5};
+use rand::RngCore;
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey};
+use ring::hkdf;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use stealthcat_backend::models::{ProxyConfig, ProxyProtocol};
+use common::*;
+
+// Минимальный Shadowsocks AEAD сервер, написанный по спецификации отдельно
+// от клиента в крейте
+
+fn algorithm(method: &str) -> &'static aead::Algorithm {
+    match method {
+        "aes-128-gcm" => &aead::AES_128_GCM,
+        "aes-256-gcm" => &aead::AES_256_GCM,
+        "chacha20-ietf-poly1305" => &aead::CHACHA20_POLY1305,
+        _ => panic!("unknown method {}", method),
+    }
+}
+
+fn master_key(password: &str, len: usize) -> Vec<u8> {
+    let mut key = Vec::new();
+    let mut prev = Vec::new();
+    while key.len() < len {
+        prev = Md5::new().chain_update(&prev).chain_update(password).finalize().to_vec();
+        key.extend_from_slice(&prev);
+    }
+    key.truncate(len);
+    key
+}
+
+struct Len(usize);
+
+impl hkdf::KeyType for Len {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+struct Codec {
+    key: LessSafeKey,
+    counter: u128,
+}
+
+impl Codec {
+    fn new(method: &str, password: &str, salt: &[u8]) -> Self {
+        let alg = algorithm(method);
+        let master = master_key(password, alg.key_len());
+        let mut subkey = vec![0u8; alg.key_len()];
+        hkdf::Salt::new(hkdf::HKDF_SHA1_FOR_LEGACY_USE_ONLY, salt)
+            .extract(&master)
+            .expand(&[b"ss-subkey"], Len(subkey.len()))
+            .unwrap()
+            .fill(&mut subkey)
+            .unwrap();
+        Self { key: LessSafeKey::new(UnboundKey::new(alg, &subkey).unwrap()), counter: 0 }
+    }
+
+    fn nonce(&mut self) -> Nonce {
+        let bytes = self.counter.to_le_bytes();
+        self.counter += 1;
+        Nonce::assume_unique_for_key(bytes[..12].try_into().unwrap())
+    }
+
+    fn seal(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut buf = data.to_vec();
+        let nonce = self.nonce();
+        self.key.seal_in_place_append_tag(nonce, Aad::empty(), &mut buf).unwrap();
+        buf
+    }
+
+    fn open(&mut self, mut data: Vec<u8>) -> Option<Vec<u8>> {
+        let nonce = self.nonce();
+        let len = self.key.open_in_place(nonce, Aad::empty(), &mut data).ok()?.len();
+        data.truncate(len);
+        Some(data)
+    }
+}
+
+async fn read_chunk<R: AsyncRead + Unpin>(reader: &mut R, codec: &mut Codec) -> Option<Vec<u8>> {
+    let mut len = vec![0u8; 2 + 16];
+    reader.read_exact(&mut len).await.ok()?;
+    let len = codec.open(len)?;
+    let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+    let mut payload = vec![0u8; len + 16];
+    reader.read_exact(&mut payload).await.ok()?;
+    codec.open(payload)
+}
+
+async fn write_chunk<W: AsyncWrite + Unpin>(writer: &mut W, codec: &mut Codec, data: &[u8]) -> std::io::Result<()> {
+    let mut out = codec.seal(&(data.len() as u16).to_be_bytes());
+    out.extend_from_slice(&codec.seal(data));
+    writer.write_all(&out).await
+}
+
+async fn start_ss_server(method: &'static str, password: &'static str) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(handle_ss_client(stream, method, password));
+        }
+    });
+    addr
+}
+
+async fn handle_ss_client(stream: TcpStream, method: &'static str, password: &'static str) {
+    let key_len = algorithm(method).key_len();
+    let (mut client_rx, mut client_tx) = stream.into_split();
+
+    let mut salt = vec![0u8; key_len];
+    if client_rx.read_exact(&mut salt).await.is_err() {
+        return;
+    }
+    let mut decoder = Codec::new(method, password, &salt);
+    // Неверный пароль - закрываем соединение, как настоящий сервер
+    let Some(header) = read_chunk(&mut client_rx, &mut decoder).await else { return };
+    let (target, addr_len) = parse_address(&header);
+    let target = TcpStream::connect(target).await.unwrap();
+    let (mut target_rx, mut target_tx) = target.into_split();
+    target_tx.write_all(&header[addr_len..]).await.unwrap();
+
+    let upstream = tokio::spawn(async move {
+        while let Some(data) = read_chunk(&mut client_rx, &mut decoder).await {
+            if target_tx.write_all(&data).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut server_salt = vec![0u8; key_len];
+    rand::thread_rng().fill_bytes(&mut server_salt);
+    let mut encoder = Codec::new(method, password, &server_salt);
+    client_tx.write_all(&server_salt).await.unwrap();
+    let mut buf = vec![0u8; 0x3FFF];
+    loop {
+        match target_rx.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => {
+                if write_chunk(&mut client_tx, &mut encoder, &buf[..n]).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+    upstream.abort();
+}
+
+async fn start_ss_udp_server(method: &'static str, password: &'static str) -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    let key_len = algorithm(method).key_len();
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 65535];
+        loop {
+            let (len, client) = socket.recv_from(&mut buf).await.unwrap();
+            let (salt, sealed) = buf[..len].split_at(key_len);
+            let Some(plain) = Codec::new(method, password, salt).open(sealed.to_vec()) else { continue };
+            let (target, addr_len) = parse_address(&plain);
+
+            let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            relay.send_to(&plain[addr_len..], target).await.unwrap();
+            let mut reply = vec![0u8; 65535];
+            let (reply_len, source) = relay.recv_from(&mut reply).await.unwrap();
+
+            let mut response_plain = Vec::new();
+            push_address(&mut response_plain, source);
+            response_plain.extend_from_slice(&reply[..reply_len]);
+            let mut response = vec![0u8; key_len];
+            rand::thread_rng().fill_bytes(&mut response);
+            let sealed = Codec::new(method, password, &response).seal(&response_plain);
+            response.extend_from_slice(&sealed);
+            socket.send_to(&response, client).await.unwrap();
+        }
+    });
+    addr
+}
+
+fn ss_server(ss_addr: SocketAddr, method: &str, password: &str) -> stealthcat_backend::models::ProxyServerV2 {
+    server(
+        "ss",
+        ProxyProtocol::Shadowsocks,
+        ProxyConfig::Shadowsocks { method: method.to_string(), password: password.to_string() },
+        ss_addr,
+    )
+}
+
+async fn assert_tcp_round_trip(method: &'static str) {
+    let echo = start_echo_server().await;
+    let ss = start_ss_server(method, "test-password").await;
+    let proxy = start_engine(vec![port_rule(echo.port(), "ss")], vec![ss_server(ss, method, "test-password")]).await;
+
+    let stream = socks5_connect(proxy, echo).await;
+    // Больше одного чанка 0x3FFF, чтобы проверить разбиение и счётчик nonce
+    let payload = test_payload(200 * 1024);
+    assert_eq!(echo_round_trip(stream, &payload).await, payload);
+}
+
+#[tokio::test]
+async fn aes_128_gcm_round_trip() {
+    assert_tcp_round_trip("aes-128-gcm").await;
+}
+
+#[tokio::test]
+async fn aes_256_gcm_round_trip() {
+    assert_tcp_round_trip("aes-256-gcm").await;
+}
+
+#[tokio::test]
+async fn chacha20_ietf_poly1305_round_trip() {
+    assert_tcp_round_trip("chacha20-ietf-poly1305").await;
+}
+
+#[tokio::test]
+async fn wrong_password_closes_tunnel() {
+    let echo = start_echo_server().await;
+    let ss = start_ss_server("aes-256-gcm", "server-password").await;
+    let proxy = start_engine(vec![port_rule(echo.port(), "ss")], vec![ss_server(ss, "aes-256-gcm", "client-password")]).await;
+
+    let mut stream = socks5_connect(proxy, echo).await;
+    stream.write_all(b"hello").await.unwrap();
+    let mut buf = [0u8; 16];
+    let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await.unwrap();
+    assert!(matches!(read, Ok(0) | Err(_)), "tunnel must not deliver data with a wrong password");
+}
+
+#[tokio::test]
+async fn unsupported_cipher_fails_connect() {
+    let echo = start_echo_server().await;
+    let ss = start_ss_server("aes-256-gcm", "test-password").await;
+    let proxy = start_engine(vec![port_rule(echo.port(), "ss")], vec![ss_server(ss, "rc4-md5", "test-password")]).await;
+
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    stream.write_all(&[5, 1, 0]).await.unwrap();
+    let mut method = [0u8; 2];
+    stream.read_exact(&mut method).await.unwrap();
+    let mut request = vec![5, 1, 0];
+    push_address(&mut request, echo);
+    stream.write_all(&request).await.unwrap();
+    let mut reply = [0u8; 10];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_ne!(reply[1], 0);
+}
+
+#[tokio::test]
+async fn udp_round_trip() {
+    let echo = start_udp_echo_server().await;
+    let ss = start_ss_udp_server("chacha20-ietf-poly1305", "test-password").await;
+    let proxy = start_engine(
+        vec![port_rule(echo.port(), "ss")],
+        vec![ss_server(ss, "chacha20-ietf-poly1305", "test-password")],
+    ).await;
+
+    let (_control, relay) = socks5_udp_associate(proxy).await;
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    client.send_to(&udp_datagram(echo, b"ping"), relay).await.unwrap();
+
+    let mut buf = vec![0u8; 1024];
+    let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+    let (_, addr_len) = parse_address(&buf[3..len]);
+    assert_eq!(&buf[3 + addr_len..len], b"echo:ping");
+}
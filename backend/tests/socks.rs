@@ -0,0 +1,169 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use stealthcat_backend::models::{ConfigFormat, MihomoConfig, Rule};
+use common::*;
+
+fn auth_config() -> MihomoConfig {
+    MihomoConfig {
+        raw_config: "authentication:\n  - \"user:secret\"\n".to_string(),
+        format: ConfigFormat::YAML,
+        last_modified: chrono::Utc::now(),
+    }
+}
+
+fn domain_rule(domain: &str, action: &str) -> Rule {
+    Rule {
+        id: format!("rule-{}", domain),
+        rule_type: "domain".to_string(),
+        pattern: domain.to_string(),
+        priority: 1,
+        ..port_rule(0, action)
+    }
+}
+
+/// Согласование метода 0x02 и подсогласование RFC 1929; возвращает статус.
+async fn socks5_login(stream: &mut TcpStream, user: &str, pass: &str) -> u8 {
+    stream.write_all(&[5, 1, 2]).await.unwrap();
+    let mut method = [0u8; 2];
+    stream.read_exact(&mut method).await.unwrap();
+    assert_eq!(method, [5, 2]);
+
+    let mut request = vec![1, user.len() as u8];
+    request.extend_from_slice(user.as_bytes());
+    request.push(pass.len() as u8);
+    request.extend_from_slice(pass.as_bytes());
+    stream.write_all(&request).await.unwrap();
+    let mut status = [0u8; 2];
+    stream.read_exact(&mut status).await.unwrap();
+    assert_eq!(status[0], 1);
+    status[1]
+}
+
+async fn socks5_connect_request(stream: &mut TcpStream, command: u8, target: SocketAddr) -> u8 {
+    let mut request = vec![5, command, 0];
+    push_address(&mut request, target);
+    stream.write_all(&request).await.unwrap();
+    let mut reply = [0u8; 10];
+    stream.read_exact(&mut reply).await.unwrap();
+    reply[1]
+}
+
+/// Запрос SOCKS4; при `domain` отправляется SOCKS4a с адресом 0.0.0.1.
+async fn socks4_request(proxy: SocketAddr, command: u8, target: SocketAddr, domain: Option<&str>) -> (TcpStream, u8) {
+    let SocketAddr::V4(target) = target else { panic!("SOCKS4 needs an IPv4 target") };
+    let mut request = vec![4, command];
+    request.extend_from_slice(&target.port().to_be_bytes());
+    match domain {
+        Some(_) => request.extend_from_slice(&[0, 0, 0, 1]),
+        None => request.extend_from_slice(&target.ip().octets()),
+    }
+    request.extend_from_slice(b"user\0");
+    if let Some(domain) = domain {
+        request.extend_from_slice(domain.as_bytes());
+        request.push(0);
+    }
+
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    stream.write_all(&request).await.unwrap();
+    let mut reply = [0u8; 8];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[0], 0);
+    (stream, reply[1])
+}
+
+async fn assert_closed(mut stream: TcpStream) {
+    let mut buf = [0u8; 16];
+    let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await.unwrap();
+    assert!(matches!(read, Ok(0) | Err(_)), "connection must be closed, got {:?}", read);
+}
+
+#[tokio::test]
+async fn socks5_login_with_valid_credentials() {
+    let echo = start_echo_server().await;
+    let proxy = start_engine_with_config(auth_config(), Vec::new(), Vec::new()).await;
+
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    assert_eq!(socks5_login(&mut stream, "user", "secret").await, 0);
+    assert_eq!(socks5_connect_request(&mut stream, 1, echo).await, 0);
+    assert_eq!(echo_round_trip(stream, b"authenticated").await, b"authenticated");
+}
+
+#[tokio::test]
+async fn socks5_login_failure_closes_connection() {
+    let proxy = start_engine_with_config(auth_config(), Vec::new(), Vec::new()).await;
+
+    for (user, pass) in [("user", "wrong"), ("other", "secret"), ("", "")] {
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        assert_eq!(socks5_login(&mut stream, user, pass).await, 1, "{}:{}", user, pass);
+        assert_closed(stream).await;
+    }
+
+    // Без метода 0x02 в списке клиент получает 0xFF
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    stream.write_all(&[5, 1, 0]).await.unwrap();
+    let mut method = [0u8; 2];
+    stream.read_exact(&mut method).await.unwrap();
+    assert_eq!(method, [5, 0xFF]);
+    assert_closed(stream).await;
+}
+
+#[tokio::test]
+async fn socks4_connect_by_ip() {
+    let echo = start_echo_server().await;
+    let proxy = start_engine(Vec::new(), Vec::new()).await;
+
+    let (stream, reply) = socks4_request(proxy, 1, echo, None).await;
+    assert_eq!(reply, 0x5A);
+    let payload = test_payload(64 * 1024);
+    assert_eq!(echo_round_trip(stream, &payload).await, payload);
+}
+
+#[tokio::test]
+async fn socks4a_connect_by_domain() {
+    let echo = start_echo_server().await;
+    // Домен маршрутизируется правилами как домен, а не как 0.0.0.1
+    let rule = domain_rule("localhost", "DIRECT");
+    let proxy = start_engine(vec![rule, port_rule(echo.port(), "REJECT")], Vec::new()).await;
+
+    let (stream, reply) = socks4_request(proxy, 1, echo, Some("localhost")).await;
+    assert_eq!(reply, 0x5A);
+    assert_eq!(echo_round_trip(stream, b"socks4a").await, b"socks4a");
+
+    let (stream, reply) = socks4_request(proxy, 1, echo, None).await;
+    assert_eq!(reply, 0x5B);
+    assert_closed(stream).await;
+}
+
+#[tokio::test]
+async fn socks4_is_rejected_when_authentication_is_required() {
+    let echo = start_echo_server().await;
+    let proxy = start_engine_with_config(auth_config(), Vec::new(), Vec::new()).await;
+
+    let (stream, reply) = socks4_request(proxy, 1, echo, None).await;
+    assert_eq!(reply, 0x5B);
+    assert_closed(stream).await;
+}
+
+#[tokio::test]
+async fn unsupported_commands_are_rejected() {
+    let echo = start_echo_server().await;
+    let proxy = start_engine(Vec::new(), Vec::new()).await;
+
+    // BIND (0x02) и неизвестная команда
+    for command in [2, 9] {
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        stream.write_all(&[5, 1, 0]).await.unwrap();
+        let mut method = [0u8; 2];
+        stream.read_exact(&mut method).await.unwrap();
+        assert_eq!(socks5_connect_request(&mut stream, command, echo).await, 0x07);
+        assert_closed(stream).await;
+
+        let (stream, reply) = socks4_request(proxy, command, echo, None).await;
+        assert_eq!(reply, 0x5B);
+        assert_closed(stream).await;
+    }
+}
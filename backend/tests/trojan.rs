@@ -0,0 +1,294 @@
+mod common;
+
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use rustls::{Certificate, PrivateKey, ServerConfig};
+use sha2::{Digest, Sha224};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+use tokio_rustls::TlsAcceptor;
+use stealthcat_backend::config::ConfigManager;
+use stealthcat_backend::models::{ProxyConfig, ProxyProtocol, ProxyServerV2};
+use stealthcat_backend::proxy::ProxyEngine;
+use common::*;
+
+const PASSWORD: &str = "trojan-test-password";
+
+fn cert_path(name: &str) -> String {
+    format!("{}/tests/certs/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+fn tls_acceptor() -> TlsAcceptor {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(std::fs::File::open(cert_path("server.pem")).unwrap()))
+        .unwrap()
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    let key = rustls_pemfile::pkcs8_private_keys(&mut BufReader::new(std::fs::File::open(cert_path("server.key")).unwrap()))
+        .unwrap()
+        .remove(0);
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, PrivateKey(key))
+        .unwrap();
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    TlsAcceptor::from(Arc::new(config))
+}
+
+// Что увидел сервер при рукопожатии: SNI и выбранный ALPN
+#[derive(Debug)]
+struct Handshake {
+    sni: Option<String>,
+    alpn: Option<String>,
+}
+
+/// TLS сервер, который понимает Trojan CONNECT и UDP ASSOCIATE.
+async fn start_trojan_server() -> (SocketAddr, mpsc::UnboundedReceiver<Handshake>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let acceptor = tls_acceptor();
+    let (handshakes, received) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let acceptor = acceptor.clone();
+            let handshakes = handshakes.clone();
+            tokio::spawn(async move {
+                let Ok(stream) = acceptor.accept(stream).await else { return };
+                let (_, connection) = stream.get_ref();
+                let _ = handshakes.send(Handshake {
+                    sni: connection.server_name().map(str::to_string),
+                    alpn: connection.alpn_protocol().map(|p| String::from_utf8_lossy(p).into_owned()),
+                });
+                handle_trojan_client(stream).await;
+            });
+        }
+    });
+    (addr, received)
+}
+
+async fn read_address<R: AsyncRead + Unpin>(reader: &mut R) -> SocketAddr {
+    let atyp = reader.read_u8().await.unwrap();
+    let len = if atyp == 1 { 4 + 2 } else { 16 + 2 };
+    let mut buf = vec![atyp; 1 + len];
+    reader.read_exact(&mut buf[1..]).await.unwrap();
+    parse_address(&buf).0
+}
+
+async fn handle_trojan_client(mut stream: tokio_rustls::server::TlsStream<TcpStream>) {
+    let mut hash = [0u8; 56];
+    stream.read_exact(&mut hash).await.unwrap();
+    let expected: String = Sha224::digest(PASSWORD).iter().map(|b| format!("{:02x}", b)).collect();
+    if hash != expected.as_bytes() {
+        return;
+    }
+    let mut crlf = [0u8; 2];
+    stream.read_exact(&mut crlf).await.unwrap();
+    let command = stream.read_u8().await.unwrap();
+    let target = read_address(&mut stream).await;
+    stream.read_exact(&mut crlf).await.unwrap();
+
+    match command {
+        1 => {
+            let mut upstream = TcpStream::connect(target).await.unwrap();
+            let _ = tokio::io::copy_bidirectional(&mut stream, &mut upstream).await;
+        }
+        3 => {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            loop {
+                let Ok(atyp) = stream.read_u8().await else { return };
+                let len = if atyp == 1 { 4 + 2 } else { 16 + 2 };
+                let mut addr = vec![atyp; 1 + len];
+                stream.read_exact(&mut addr[1..]).await.unwrap();
+                let destination = parse_address(&addr).0;
+                let payload_len = stream.read_u16().await.unwrap() as usize;
+                stream.read_exact(&mut crlf).await.unwrap();
+                let mut payload = vec![0u8; payload_len];
+                stream.read_exact(&mut payload).await.unwrap();
+
+                socket.send_to(&payload, destination).await.unwrap();
+                let mut reply = vec![0u8; 65535];
+                let (reply_len, source) = socket.recv_from(&mut reply).await.unwrap();
+                let mut packet = Vec::new();
+                push_address(&mut packet, source);
+                packet.extend_from_slice(&(reply_len as u16).to_be_bytes());
+                packet.extend_from_slice(b"\r\n");
+                packet.extend_from_slice(&reply[..reply_len]);
+                if payload.starts_with(b"slow") {
+                    // Ответ по байту, чтобы клиент успел отправить что-то посреди пакета
+                    for byte in packet {
+                        stream.write_all(&[byte]).await.unwrap();
+                        stream.flush().await.unwrap();
+                        tokio::time::sleep(Duration::from_millis(10)).await;
+                    }
+                } else {
+                    stream.write_all(&packet).await.unwrap();
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn trojan_server(
+    addr: SocketAddr,
+    sni: Option<&str>,
+    alpn: Option<Vec<&str>>,
+    skip_cert_verify: bool,
+    ca: Option<String>,
+) -> ProxyServerV2 {
+    server(
+        "trojan",
+        ProxyProtocol::Trojan,
+        ProxyConfig::Trojan {
+            password: PASSWORD.to_string(),
+            sni: sni.map(str::to_string),
+            alpn: alpn.map(|a| a.into_iter().map(str::to_string).collect()),
+            skip_cert_verify,
+            ca,
+            network: None,
+            ws: None,
+            grpc: None,
+        },
+        addr,
+    )
+}
+
+#[tokio::test]
+async fn connect_with_custom_ca_sni_and_alpn() {
+    let echo = start_echo_server().await;
+    let (trojan, mut handshakes) = start_trojan_server().await;
+    let config = trojan_server(trojan, Some("localhost"), Some(vec!["http/1.1"]), false, Some(cert_path("ca.pem")));
+    let proxy = start_engine(vec![port_rule(echo.port(), "trojan")], vec![config]).await;
+
+    let stream = socks5_connect(proxy, echo).await;
+    let payload = test_payload(64 * 1024);
+    assert_eq!(echo_round_trip(stream, &payload).await, payload);
+
+    let handshake = handshakes.recv().await.unwrap();
+    assert_eq!(handshake.sni.as_deref(), Some("localhost"));
+    assert_eq!(handshake.alpn.as_deref(), Some("http/1.1"));
+}
+
+#[tokio::test]
+async fn skip_cert_verify_accepts_unknown_certificate() {
+    let echo = start_echo_server().await;
+    let (trojan, _handshakes) = start_trojan_server().await;
+    let config = trojan_server(trojan, Some("localhost"), None, true, None);
+    let proxy = start_engine(vec![port_rule(echo.port(), "trojan")], vec![config]).await;
+
+    let stream = socks5_connect(proxy, echo).await;
+    assert_eq!(echo_round_trip(stream, b"hello trojan").await, b"hello trojan");
+}
+
+#[tokio::test]
+async fn untrusted_certificate_is_rejected() {
+    let echo = start_echo_server().await;
+    let (trojan, _handshakes) = start_trojan_server().await;
+    let config = trojan_server(trojan, Some("localhost"), None, false, None);
+    let proxy = start_engine(vec![port_rule(echo.port(), "trojan")], vec![config]).await;
+
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    stream.write_all(&[5, 1, 0]).await.unwrap();
+    let mut method = [0u8; 2];
+    stream.read_exact(&mut method).await.unwrap();
+    let mut request = vec![5, 1, 0];
+    push_address(&mut request, echo);
+    stream.write_all(&request).await.unwrap();
+    let mut reply = [0u8; 10];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_ne!(reply[1], 0, "handshake with an untrusted certificate must fail");
+}
+
+#[tokio::test]
+async fn udp_associate_round_trip() {
+    let echo = start_udp_echo_server().await;
+    let (trojan, _handshakes) = start_trojan_server().await;
+    let config = trojan_server(trojan, None, None, false, Some(cert_path("ca.pem")));
+    let proxy = start_engine(vec![port_rule(echo.port(), "trojan")], vec![config]).await;
+
+    let (_control, relay) = socks5_udp_associate(proxy).await;
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    for message in [&b"first"[..], b"second"] {
+        client.send_to(&udp_datagram(echo, message), relay).await.unwrap();
+        let mut buf = vec![0u8; 1024];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+        let (_, addr_len) = parse_address(&buf[3..len]);
+        assert_eq!(&buf[3 + addr_len..len], [&b"echo:"[..], message].concat());
+    }
+}
+
+#[tokio::test]
+async fn udp_reply_survives_sends_in_the_middle_of_a_packet() {
+    let echo = start_udp_echo_server().await;
+    let (trojan, _handshakes) = start_trojan_server().await;
+    let config = trojan_server(trojan, None, None, false, Some(cert_path("ca.pem")));
+    let proxy = start_engine(vec![port_rule(echo.port(), "trojan")], vec![config]).await;
+
+    let (_control, relay) = socks5_udp_associate(proxy).await;
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    client.send_to(&udp_datagram(echo, b"slow"), relay).await.unwrap();
+    // Пока ответ на первый пакет приходит по байту, отправляем ещё
+    let messages: [&[u8]; 3] = [b"a", b"b", b"c"];
+    for message in messages {
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        client.send_to(&udp_datagram(echo, message), relay).await.unwrap();
+    }
+
+    for expected in [&b"slow"[..], b"a", b"b", b"c"] {
+        let mut buf = vec![0u8; 1024];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+        let (_, addr_len) = parse_address(&buf[3..len]);
+        assert_eq!(&buf[3 + addr_len..len], [&b"echo:"[..], expected].concat());
+    }
+}
+
+#[tokio::test]
+async fn ca_file_is_read_once_per_load() {
+    let echo = start_echo_server().await;
+    let (trojan, _handshakes) = start_trojan_server().await;
+    let ca = std::env::temp_dir().join(format!("stealthcat-ca-{}.pem", uuid::Uuid::new_v4()));
+    std::fs::copy(cert_path("ca.pem"), &ca).unwrap();
+    let config = trojan_server(trojan, Some("localhost"), None, false, Some(ca.display().to_string()));
+    let proxy = start_engine(vec![port_rule(echo.port(), "trojan")], vec![config]).await;
+
+    // После загрузки файл больше не нужен: подключения используют готовую конфигурацию
+    std::fs::remove_file(&ca).unwrap();
+    for _ in 0..2 {
+        let stream = socks5_connect(proxy, echo).await;
+        assert_eq!(echo_round_trip(stream, b"cached").await, b"cached");
+    }
+}
+
+#[tokio::test]
+async fn bad_ca_file_fails_reload() {
+    let echo = start_echo_server().await;
+    let db = temp_database().await;
+    let engine = ProxyEngine::new(ConfigManager::get_default_config(), Vec::new(), Vec::new(), Vec::new());
+
+    let missing = trojan_server(echo, Some("localhost"), None, false, Some("/nonexistent/ca.pem".to_string()));
+    db.insert_server_v2(&missing).await.unwrap();
+    db.insert_rule(&port_rule(echo.port(), "trojan")).await.unwrap();
+    let error = engine.reload(&db).await.unwrap_err();
+    assert_eq!(
+        format!("{:#}", error),
+        "Invalid TLS settings for server \"trojan\": Failed to open CA file /nonexistent/ca.pem: No such file or directory (os error 2)"
+    );
+    // Прежний снимок остаётся в силе
+    assert!(engine.router().rules().is_empty());
+
+    // Файл без сертификатов
+    let key = trojan_server(echo, Some("localhost"), None, false, Some(cert_path("server.key")));
+    db.update_server(&key).await.unwrap();
+    let error = engine.reload(&db).await.unwrap_err();
+    assert!(format!("{:#}", error).contains("No certificates found in CA file"), "{:#}", error);
+
+    let valid = trojan_server(echo, Some("localhost"), None, false, Some(cert_path("ca.pem")));
+    db.update_server(&valid).await.unwrap();
+    engine.reload(&db).await.unwrap();
+    assert_eq!(engine.router().rules().len(), 1);
+}
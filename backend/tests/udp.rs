@@ -0,0 +1,187 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use stealthcat_backend::models::{ConfigFormat, MihomoConfig};
+use common::*;
+
+fn udp_config(settings: &str) -> MihomoConfig {
+    MihomoConfig {
+        raw_config: settings.to_string(),
+        format: ConfigFormat::YAML,
+        last_modified: chrono::Utc::now(),
+    }
+}
+
+/// UDP сервер, отвечающий адресом, с которого пришла датаграмма: по нему
+/// видно, через какую сессию прокси прошёл пакет.
+async fn start_peer_echo_server() -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 65535];
+        loop {
+            let (_, peer) = socket.recv_from(&mut buf).await.unwrap();
+            socket.send_to(peer.to_string().as_bytes(), peer).await.unwrap();
+        }
+    });
+    addr
+}
+
+/// UDP ASSOCIATE, в котором клиент заранее сообщает порт своих датаграмм.
+async fn udp_associate_from(proxy: SocketAddr, client_port: u16) -> (TcpStream, SocketAddr) {
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    stream.write_all(&[5, 1, 0]).await.unwrap();
+    let mut method = [0u8; 2];
+    stream.read_exact(&mut method).await.unwrap();
+
+    let mut request = vec![5, 3, 0];
+    push_address(&mut request, SocketAddr::from(([127, 0, 0, 1], client_port)));
+    stream.write_all(&request).await.unwrap();
+    let mut reply = [0u8; 10];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[1], 0, "UDP ASSOCIATE failed with reply {}", reply[1]);
+    (stream, parse_address(&reply[3..]).0)
+}
+
+/// Ответ relay: адрес источника из заголовка и данные, или None, если
+/// ничего не пришло.
+async fn recv_reply(socket: &UdpSocket) -> Option<(SocketAddr, Vec<u8>)> {
+    let mut buf = vec![0u8; 65535];
+    let (len, _) = tokio::time::timeout(Duration::from_millis(300), socket.recv_from(&mut buf)).await.ok()?.unwrap();
+    assert_eq!(&buf[..3], [0, 0, 0]);
+    let (source, addr_len) = parse_address(&buf[3..len]);
+    Some((source, buf[3 + addr_len..len].to_vec()))
+}
+
+async fn client_socket() -> UdpSocket {
+    UdpSocket::bind("127.0.0.1:0").await.unwrap()
+}
+
+#[tokio::test]
+async fn direct_relay_round_trip() {
+    let echo = start_udp_echo_server().await;
+    let proxy = start_engine(Vec::new(), Vec::new()).await;
+
+    let (_control, relay) = socks5_udp_associate(proxy).await;
+    let client = client_socket().await;
+    for message in [&b"first"[..], b"second", &test_payload(8 * 1024)] {
+        client.send_to(&udp_datagram(echo, message), relay).await.unwrap();
+        let (source, payload) = recv_reply(&client).await.expect("no reply from the relay");
+        assert_eq!(source, echo);
+        assert_eq!(payload, [&b"echo:"[..], message].concat());
+    }
+}
+
+#[tokio::test]
+async fn fragmented_and_malformed_datagrams_are_dropped() {
+    let echo = start_udp_echo_server().await;
+    let proxy = start_engine(Vec::new(), Vec::new()).await;
+
+    let (_control, relay) = socks5_udp_associate(proxy).await;
+    let client = client_socket().await;
+    let mut fragment = udp_datagram(echo, b"fragment");
+    fragment[2] = 1;
+    let malformed: [&[u8]; 4] = [
+        &fragment,
+        &[0, 0],
+        &[0, 0, 0, 9, 127, 0, 0, 1, 0, 80],
+        &[0, 0, 0, 1, 127, 0],
+    ];
+    for datagram in malformed {
+        client.send_to(datagram, relay).await.unwrap();
+    }
+
+    // Ассоциация продолжает работать, и первым приходит ответ на верную датаграмму
+    client.send_to(&udp_datagram(echo, b"valid"), relay).await.unwrap();
+    assert_eq!(recv_reply(&client).await.unwrap().1, b"echo:valid");
+    assert!(recv_reply(&client).await.is_none());
+}
+
+#[tokio::test]
+async fn datagrams_from_other_sources_are_ignored() {
+    let echo = start_udp_echo_server().await;
+    let proxy = start_engine(Vec::new(), Vec::new()).await;
+
+    // Клиент фиксируется по первой датаграмме
+    let (_control, relay) = socks5_udp_associate(proxy).await;
+    let client = client_socket().await;
+    let stranger = client_socket().await;
+    client.send_to(&udp_datagram(echo, b"client"), relay).await.unwrap();
+    assert_eq!(recv_reply(&client).await.unwrap().1, b"echo:client");
+    stranger.send_to(&udp_datagram(echo, b"stranger"), relay).await.unwrap();
+    assert!(recv_reply(&stranger).await.is_none());
+    assert!(recv_reply(&client).await.is_none());
+
+    // Порт, названный в запросе, действует и до первой датаграммы
+    let client = client_socket().await;
+    let (_control, relay) = udp_associate_from(proxy, client.local_addr().unwrap().port()).await;
+    stranger.send_to(&udp_datagram(echo, b"stranger"), relay).await.unwrap();
+    assert!(recv_reply(&stranger).await.is_none());
+    client.send_to(&udp_datagram(echo, b"client"), relay).await.unwrap();
+    assert_eq!(recv_reply(&client).await.unwrap().1, b"echo:client");
+}
+
+#[tokio::test]
+async fn association_ends_with_control_connection() {
+    let echo = start_udp_echo_server().await;
+    let proxy = start_engine(Vec::new(), Vec::new()).await;
+
+    let (control, relay) = socks5_udp_associate(proxy).await;
+    let client = client_socket().await;
+    client.send_to(&udp_datagram(echo, b"open"), relay).await.unwrap();
+    assert_eq!(recv_reply(&client).await.unwrap().1, b"echo:open");
+
+    drop(control);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let _ = client.send_to(&udp_datagram(echo, b"closed"), relay).await;
+    assert!(recv_reply(&client).await.is_none());
+}
+
+#[tokio::test]
+async fn idle_sessions_expire() {
+    let upstream = start_peer_echo_server().await;
+    let proxy = start_engine_with_config(udp_config("udp-timeout: 300\n"), Vec::new(), Vec::new()).await;
+
+    let (_control, relay) = socks5_udp_associate(proxy).await;
+    let client = client_socket().await;
+    let exchange = || async {
+        client.send_to(&udp_datagram(upstream, b"ping"), relay).await.unwrap();
+        String::from_utf8(recv_reply(&client).await.expect("no reply from the relay").1).unwrap()
+    };
+
+    // Пока идёт трафик, сессия и её исходящий сокет сохраняются
+    let session = exchange().await;
+    for _ in 0..8 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(exchange().await, session);
+    }
+
+    tokio::time::sleep(Duration::from_millis(800)).await;
+    assert_ne!(exchange().await, session, "idle session must be replaced by a new one");
+}
+
+#[tokio::test]
+async fn session_count_is_capped() {
+    let upstreams = [start_peer_echo_server().await, start_peer_echo_server().await, start_peer_echo_server().await];
+    let config = udp_config("udp-max-sessions: 2\nudp-timeout: 300\n");
+    let proxy = start_engine_with_config(config, Vec::new(), Vec::new()).await;
+
+    let (_control, relay) = socks5_udp_associate(proxy).await;
+    let client = client_socket().await;
+    for upstream in &upstreams[..2] {
+        client.send_to(&udp_datagram(*upstream, b"ping"), relay).await.unwrap();
+        assert_eq!(recv_reply(&client).await.unwrap().0, *upstream);
+    }
+    client.send_to(&udp_datagram(upstreams[2], b"ping"), relay).await.unwrap();
+    assert!(recv_reply(&client).await.is_none(), "third session must not be opened");
+    client.send_to(&udp_datagram(upstreams[0], b"ping"), relay).await.unwrap();
+    assert_eq!(recv_reply(&client).await.unwrap().0, upstreams[0]);
+
+    // Место освобождается, когда сессии истекают
+    tokio::time::sleep(Duration::from_millis(800)).await;
+    client.send_to(&udp_datagram(upstreams[2], b"ping"), relay).await.unwrap();
+    assert_eq!(recv_reply(&client).await.unwrap().0, upstreams[2]);
+}
@@ -0,0 +1,290 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use base64::{Engine as _, engine::general_purpose};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::sync::mpsc;
+use stealthcat_backend::models::{ConfigFormat, MihomoConfig, ProxyConfig, ProxyProtocol, ProxyServerV2};
+use common::*;
+
+const USERNAME: &str = "user";
+const PASSWORD: &str = "secret";
+
+/// Вышестоящий HTTP прокси: отвечает на CONNECT кодом `status` (407 при
+/// неверной авторизации), после 200 возвращает всё полученное.
+async fn start_http_upstream(status: u16, require_auth: bool) -> (SocketAddr, mpsc::UnboundedReceiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (requests, received) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let requests = requests.clone();
+            tokio::spawn(async move {
+                let mut head = Vec::new();
+                while !head.ends_with(b"\r\n\r\n") {
+                    let Ok(byte) = stream.read_u8().await else { return };
+                    head.push(byte);
+                }
+                let head = String::from_utf8(head).unwrap();
+                let token = general_purpose::STANDARD.encode(format!("{}:{}", USERNAME, PASSWORD));
+                let authorized = !require_auth || head.contains(&format!("Proxy-Authorization: Basic {}\r\n", token));
+                let _ = requests.send(head);
+
+                let status = if authorized { status } else { 407 };
+                let response = format!("HTTP/1.1 {} Status\r\nContent-Length: 0\r\n\r\n", status);
+                stream.write_all(response.as_bytes()).await.unwrap();
+                if status == 200 {
+                    let (mut reader, mut writer) = stream.split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                }
+            });
+        }
+    });
+    (addr, received)
+}
+
+// Что вышестоящий SOCKS5 сервер прочитал из запроса CONNECT
+#[derive(Debug)]
+struct Socks5Request {
+    methods: Vec<u8>,
+    atyp: u8,
+    host: String,
+    port: u16,
+}
+
+/// Вышестоящий SOCKS5 сервер: отвечает кодом `reply`, BND.ADDR - домен,
+/// после успеха возвращает всё полученное.
+async fn start_socks5_upstream(reply: u8, require_auth: bool) -> (SocketAddr, mpsc::UnboundedReceiver<Socks5Request>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (requests, received) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let requests = requests.clone();
+            tokio::spawn(async move {
+                let mut greeting = [0u8; 2];
+                stream.read_exact(&mut greeting).await.unwrap();
+                let mut methods = vec![0u8; greeting[1] as usize];
+                stream.read_exact(&mut methods).await.unwrap();
+                if !require_auth {
+                    stream.write_all(&[5, 0]).await.unwrap();
+                } else if methods.contains(&2) {
+                    stream.write_all(&[5, 2]).await.unwrap();
+                    // RFC 1929: VER ULEN UNAME PLEN PASSWD
+                    let _version = stream.read_u8().await.unwrap();
+                    let mut username = vec![0u8; stream.read_u8().await.unwrap() as usize];
+                    stream.read_exact(&mut username).await.unwrap();
+                    let mut password = vec![0u8; stream.read_u8().await.unwrap() as usize];
+                    stream.read_exact(&mut password).await.unwrap();
+                    if username != USERNAME.as_bytes() || password != PASSWORD.as_bytes() {
+                        stream.write_all(&[1, 1]).await.unwrap();
+                        return;
+                    }
+                    stream.write_all(&[1, 0]).await.unwrap();
+                } else {
+                    stream.write_all(&[5, 0xff]).await.unwrap();
+                    return;
+                }
+
+                let mut request = [0u8; 4];
+                stream.read_exact(&mut request).await.unwrap();
+                let atyp = request[3];
+                let host = match atyp {
+                    1 => {
+                        let mut octets = [0u8; 4];
+                        stream.read_exact(&mut octets).await.unwrap();
+                        std::net::IpAddr::from(octets).to_string()
+                    }
+                    3 => {
+                        let mut domain = vec![0u8; stream.read_u8().await.unwrap() as usize];
+                        stream.read_exact(&mut domain).await.unwrap();
+                        String::from_utf8(domain).unwrap()
+                    }
+                    4 => {
+                        let mut octets = [0u8; 16];
+                        stream.read_exact(&mut octets).await.unwrap();
+                        std::net::IpAddr::from(octets).to_string()
+                    }
+                    atyp => panic!("unexpected address type {}", atyp),
+                };
+                let port = stream.read_u16().await.unwrap();
+                let _ = requests.send(Socks5Request { methods, atyp, host, port });
+
+                let bound = b"bound.example";
+                let mut response = vec![5, reply, 0, 3, bound.len() as u8];
+                response.extend_from_slice(bound);
+                response.extend_from_slice(&1080u16.to_be_bytes());
+                stream.write_all(&response).await.unwrap();
+                if reply == 0 {
+                    let (mut reader, mut writer) = stream.split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                }
+            });
+        }
+    });
+    (addr, received)
+}
+
+fn upstream(protocol: ProxyProtocol, addr: SocketAddr, password: Option<&str>) -> ProxyServerV2 {
+    let username = password.map(|_| USERNAME.to_string());
+    let password = password.map(str::to_string);
+    let config = match protocol {
+        ProxyProtocol::HTTP => ProxyConfig::Http { username, password },
+        _ => ProxyConfig::Socks5 { username, password },
+    };
+    server("upstream", protocol, config, addr)
+}
+
+// Порт цели нужен только правилу, туннель замыкается на вышестоящем прокси
+const TARGET_PORT: u16 = 7001;
+
+#[tokio::test]
+async fn http_connect_sends_credentials() {
+    let (http, mut requests) = start_http_upstream(200, true).await;
+    let config = upstream(ProxyProtocol::HTTP, http, Some(PASSWORD));
+    let proxy = start_engine(vec![port_rule(TARGET_PORT, "upstream")], vec![config]).await;
+
+    let (stream, reply) = socks5_request(proxy, "127.0.0.1", TARGET_PORT).await;
+    assert_eq!(reply, 0);
+    let payload = test_payload(64 * 1024);
+    assert_eq!(echo_round_trip(stream, &payload).await, payload);
+    let head = requests.recv().await.unwrap();
+    assert!(head.starts_with("CONNECT 127.0.0.1:7001 HTTP/1.1\r\n"), "{}", head);
+}
+
+#[tokio::test]
+async fn http_connect_encodes_ipv6_and_domain_targets() {
+    let (http, mut requests) = start_http_upstream(200, false).await;
+    let config = upstream(ProxyProtocol::HTTP, http, None);
+    let proxy = start_engine(vec![port_rule(TARGET_PORT, "upstream")], vec![config]).await;
+
+    for (host, authority) in [("::1", "[::1]:7001"), ("example.com", "example.com:7001")] {
+        let (stream, reply) = socks5_request(proxy, host, TARGET_PORT).await;
+        assert_eq!(reply, 0);
+        assert_eq!(echo_round_trip(stream, b"hello").await, b"hello");
+        let head = requests.recv().await.unwrap();
+        assert!(head.starts_with(&format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", authority)), "{}", head);
+        assert!(!head.contains("Proxy-Authorization"));
+    }
+}
+
+#[tokio::test]
+async fn http_wrong_credentials_fail() {
+    let (http, _requests) = start_http_upstream(200, true).await;
+    let config = upstream(ProxyProtocol::HTTP, http, Some("wrong"));
+    let proxy = start_engine(vec![port_rule(TARGET_PORT, "upstream")], vec![config]).await;
+
+    let (_stream, reply) = socks5_request(proxy, "127.0.0.1", TARGET_PORT).await;
+    assert_ne!(reply, 0, "407 from the upstream proxy must fail the connection");
+}
+
+#[tokio::test]
+async fn http_non_200_response_fails() {
+    let (http, _requests) = start_http_upstream(403, false).await;
+    let config = upstream(ProxyProtocol::HTTP, http, None);
+    let proxy = start_engine(vec![port_rule(TARGET_PORT, "upstream")], vec![config]).await;
+
+    let (_stream, reply) = socks5_request(proxy, "127.0.0.1", TARGET_PORT).await;
+    assert_ne!(reply, 0, "403 from the upstream proxy must fail the connection");
+}
+
+#[tokio::test]
+async fn socks5_user_pass_authentication() {
+    let (socks, mut requests) = start_socks5_upstream(0, true).await;
+    let config = upstream(ProxyProtocol::SOCKS5, socks, Some(PASSWORD));
+    let proxy = start_engine(vec![port_rule(TARGET_PORT, "upstream")], vec![config]).await;
+
+    let (stream, reply) = socks5_request(proxy, "127.0.0.1", TARGET_PORT).await;
+    assert_eq!(reply, 0);
+    let payload = test_payload(64 * 1024);
+    assert_eq!(echo_round_trip(stream, &payload).await, payload);
+    let request = requests.recv().await.unwrap();
+    assert_eq!(request.methods, [0, 2]);
+    assert_eq!((request.atyp, request.host.as_str(), request.port), (1, "127.0.0.1", TARGET_PORT));
+}
+
+#[tokio::test]
+async fn socks5_encodes_ipv6_and_domain_targets() {
+    let (socks, mut requests) = start_socks5_upstream(0, false).await;
+    let config = upstream(ProxyProtocol::SOCKS5, socks, None);
+    let proxy = start_engine(vec![port_rule(TARGET_PORT, "upstream")], vec![config]).await;
+
+    for (host, atyp) in [("::1", 4), ("example.com", 3)] {
+        let (stream, reply) = socks5_request(proxy, host, TARGET_PORT).await;
+        assert_eq!(reply, 0);
+        assert_eq!(echo_round_trip(stream, b"hello").await, b"hello");
+        let request = requests.recv().await.unwrap();
+        assert_eq!(request.methods, [0]);
+        assert_eq!((request.atyp, request.host.as_str(), request.port), (atyp, host, TARGET_PORT));
+    }
+}
+
+#[tokio::test]
+async fn socks5_wrong_credentials_fail() {
+    let (socks, mut requests) = start_socks5_upstream(0, true).await;
+    let config = upstream(ProxyProtocol::SOCKS5, socks, Some("wrong"));
+    let proxy = start_engine(vec![port_rule(TARGET_PORT, "upstream")], vec![config]).await;
+
+    let (_stream, reply) = socks5_request(proxy, "127.0.0.1", TARGET_PORT).await;
+    assert_ne!(reply, 0);
+    assert!(requests.try_recv().is_err(), "CONNECT must not be sent after failed authentication");
+}
+
+#[tokio::test]
+async fn socks5_missing_credentials_fail() {
+    let (socks, _requests) = start_socks5_upstream(0, true).await;
+    let config = upstream(ProxyProtocol::SOCKS5, socks, None);
+    let proxy = start_engine(vec![port_rule(TARGET_PORT, "upstream")], vec![config]).await;
+
+    let (_stream, reply) = socks5_request(proxy, "127.0.0.1", TARGET_PORT).await;
+    assert_ne!(reply, 0);
+}
+
+#[tokio::test]
+async fn socks5_reply_code_is_passed_to_client() {
+    let (socks, _requests) = start_socks5_upstream(5, false).await;
+    let config = upstream(ProxyProtocol::SOCKS5, socks, None);
+    let proxy = start_engine(vec![port_rule(TARGET_PORT, "upstream")], vec![config]).await;
+
+    // 5 - connection refused
+    let (_stream, reply) = socks5_request(proxy, "127.0.0.1", TARGET_PORT).await;
+    assert_eq!(reply, 5);
+}
+
+/// Адрес, подключение к которому зависает: очередь listener'а заполнена и
+/// ядро отбрасывает новые SYN. Соединения в очереди держит возвращаемый Vec.
+async fn start_blackhole() -> (SocketAddr, TcpListener, Vec<TcpStream>) {
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let listener = socket.listen(1).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut queued = Vec::new();
+    while let Ok(Ok(stream)) = tokio::time::timeout(Duration::from_millis(200), TcpStream::connect(addr)).await {
+        queued.push(stream);
+    }
+    (addr, listener, queued)
+}
+
+#[tokio::test]
+async fn dial_gives_up_after_connect_timeout() {
+    let (blackhole, _listener, _queued) = start_blackhole().await;
+    let config = MihomoConfig {
+        raw_config: "connect-timeout: 300\n".to_string(),
+        format: ConfigFormat::YAML,
+        last_modified: chrono::Utc::now(),
+    };
+    let servers = vec![upstream(ProxyProtocol::SOCKS5, blackhole, None)];
+    let proxy = start_engine_with_config(config, vec![port_rule(TARGET_PORT, "upstream")], servers).await;
+
+    let start = Instant::now();
+    let (_stream, reply) = tokio::time::timeout(Duration::from_secs(5), socks5_request(proxy, "127.0.0.1", TARGET_PORT))
+        .await
+        .expect("dial must not hang");
+    // 4 - host unreachable
+    assert_eq!(reply, 4);
+    assert!(start.elapsed() >= Duration::from_millis(300), "{:?}", start.elapsed());
+}
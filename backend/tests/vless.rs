@@ -0,0 +1,234 @@
+mod common;
+
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+use stealthcat_backend::models::{ProxyConfig, ProxyProtocol, ProxyServerV2, VlessConfig};
+use common::*;
+
+const UUID: &str = "27848739-7e62-4138-9fd3-098a63964b6b";
+
+// Что сервер прочитал из заголовка запроса
+#[derive(Debug)]
+struct Request {
+    command: u8,
+    host: String,
+    port: u16,
+}
+
+/// VLESS сервер без TLS: TCP туннель и UDP. `version` - первый байт ответа.
+async fn start_vless_server(version: u8) -> (SocketAddr, mpsc::UnboundedReceiver<Request>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (requests, received) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let requests = requests.clone();
+            tokio::spawn(handle_vless_client(stream, version, requests));
+        }
+    });
+    (addr, received)
+}
+
+async fn handle_vless_client(mut stream: TcpStream, version: u8, requests: mpsc::UnboundedSender<Request>) {
+    let mut header = [0u8; 18];
+    if stream.read_exact(&mut header).await.is_err() {
+        return;
+    }
+    assert_eq!(header[0], 0);
+    assert_eq!(&header[1..17], uuid::Uuid::parse_str(UUID).unwrap().as_bytes());
+    let mut addons = vec![0u8; header[17] as usize];
+    stream.read_exact(&mut addons).await.unwrap();
+    let command = stream.read_u8().await.unwrap();
+    let port = stream.read_u16().await.unwrap();
+    // VLESS: 1 - IPv4, 2 - домен, 3 - IPv6
+    let host = match stream.read_u8().await.unwrap() {
+        1 => {
+            let mut octets = [0u8; 4];
+            stream.read_exact(&mut octets).await.unwrap();
+            IpAddr::from(octets).to_string()
+        }
+        2 => {
+            let mut domain = vec![0u8; stream.read_u8().await.unwrap() as usize];
+            stream.read_exact(&mut domain).await.unwrap();
+            String::from_utf8(domain).unwrap()
+        }
+        3 => {
+            let mut octets = [0u8; 16];
+            stream.read_exact(&mut octets).await.unwrap();
+            IpAddr::from(octets).to_string()
+        }
+        atyp => panic!("unexpected address type {}", atyp),
+    };
+    // Эхо-серверы слушают только на 127.0.0.1
+    let target = match host.as_str() {
+        "localhost" => SocketAddr::from(([127, 0, 0, 1], port)),
+        ip => SocketAddr::new(ip.parse().unwrap(), port),
+    };
+    let _ = requests.send(Request { command, host, port });
+    stream.write_all(&[version, 0]).await.unwrap();
+
+    match command {
+        1 => {
+            let mut upstream = TcpStream::connect(target).await.unwrap();
+            let _ = tokio::io::copy_bidirectional(&mut stream, &mut upstream).await;
+        }
+        2 => {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            loop {
+                let Ok(len) = stream.read_u16().await else { return };
+                let mut payload = vec![0u8; len as usize];
+                stream.read_exact(&mut payload).await.unwrap();
+
+                socket.send_to(&payload, target).await.unwrap();
+                let mut reply = vec![0u8; 65535];
+                let (reply_len, _) = socket.recv_from(&mut reply).await.unwrap();
+                let mut packet = (reply_len as u16).to_be_bytes().to_vec();
+                packet.extend_from_slice(&reply[..reply_len]);
+                if payload.starts_with(b"slow") {
+                    // Ответ по байту, чтобы клиент успел отправить что-то посреди пакета
+                    for byte in packet {
+                        stream.write_all(&[byte]).await.unwrap();
+                        stream.flush().await.unwrap();
+                        tokio::time::sleep(Duration::from_millis(10)).await;
+                    }
+                } else {
+                    stream.write_all(&packet).await.unwrap();
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn vless_server(addr: SocketAddr, flow: Option<&str>, security: &str, sni: Option<&str>) -> ProxyServerV2 {
+    server(
+        "vless",
+        ProxyProtocol::VLESS,
+        ProxyConfig::Vless(VlessConfig {
+            uuid: UUID.to_string(),
+            flow: flow.map(str::to_string),
+            encryption: "none".to_string(),
+            network: "tcp".to_string(),
+            security: security.to_string(),
+            sni: sni.map(str::to_string),
+            alpn: None,
+            fp: None,
+            pbk: None,
+            sid: None,
+            spx: None,
+            ws: None,
+            grpc: None,
+        }),
+        addr,
+    )
+}
+
+#[tokio::test]
+async fn tcp_handshake_round_trip() {
+    let echo = start_echo_server().await;
+    let (vless, mut requests) = start_vless_server(0).await;
+    let config = vless_server(vless, None, "none", None);
+    let proxy = start_engine(vec![port_rule(echo.port(), "vless")], vec![config]).await;
+
+    let stream = socks5_connect(proxy, echo).await;
+    let payload = test_payload(64 * 1024);
+    assert_eq!(echo_round_trip(stream, &payload).await, payload);
+
+    let request = requests.recv().await.unwrap();
+    assert_eq!(request.command, 1);
+    assert_eq!(request.host, "127.0.0.1");
+    assert_eq!(request.port, echo.port());
+}
+
+#[tokio::test]
+async fn domain_target_is_sent_as_domain() {
+    let echo = start_echo_server().await;
+    let (vless, mut requests) = start_vless_server(0).await;
+    let config = vless_server(vless, None, "none", None);
+    let proxy = start_engine(vec![port_rule(echo.port(), "vless")], vec![config]).await;
+
+    let (stream, reply) = socks5_request(proxy, "localhost", echo.port()).await;
+    assert_eq!(reply, 0);
+    assert_eq!(echo_round_trip(stream, b"hello domain").await, b"hello domain");
+    assert_eq!(requests.recv().await.unwrap().host, "localhost");
+}
+
+#[tokio::test]
+async fn unsupported_flow_is_rejected_before_handshake() {
+    let echo = start_echo_server().await;
+    let (vless, mut requests) = start_vless_server(0).await;
+    let config = vless_server(vless, Some("xtls-rprx-vision"), "none", None);
+    let proxy = start_engine(vec![port_rule(echo.port(), "vless")], vec![config]).await;
+
+    let (_stream, reply) = socks5_request(proxy, "localhost", echo.port()).await;
+    assert_ne!(reply, 0, "server with an unsupported flow must not be used");
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(requests.try_recv().is_err(), "no VLESS header must reach the server");
+}
+
+#[tokio::test]
+async fn unexpected_response_version_closes_tunnel() {
+    let echo = start_echo_server().await;
+    let (vless, _requests) = start_vless_server(1).await;
+    let config = vless_server(vless, None, "none", None);
+    let proxy = start_engine(vec![port_rule(echo.port(), "vless")], vec![config]).await;
+
+    // Ответ сервера читается вместе с первыми данными
+    let mut stream = socks5_connect(proxy, echo).await;
+    stream.write_all(b"hello").await.unwrap();
+    let mut buf = [0u8; 16];
+    let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await.unwrap();
+    assert!(matches!(read, Ok(0) | Err(_)), "tunnel must close, got {:?}", read);
+}
+
+#[tokio::test]
+async fn tls_security_starts_tls_with_sni() {
+    let echo = start_echo_server().await;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let vless = listener.local_addr().unwrap();
+    let config = vless_server(vless, None, "tls", Some("vless.example.com"));
+    let proxy = start_engine(vec![port_rule(echo.port(), "vless")], vec![config]).await;
+
+    let client = tokio::spawn(socks5_request(proxy, "localhost", echo.port()));
+    let (mut stream, _) = listener.accept().await.unwrap();
+    let mut hello = vec![0u8; 4096];
+    let n = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut hello)).await.unwrap().unwrap();
+    drop(stream);
+
+    // Первая запись - TLS Handshake с ClientHello, SNI в нём открытым текстом
+    assert_eq!(hello[0], 0x16);
+    assert_eq!(hello[5], 0x01);
+    assert!(hello[..n].windows(17).any(|w| w == b"vless.example.com"));
+    let (_, reply) = client.await.unwrap();
+    assert_ne!(reply, 0);
+}
+
+#[tokio::test]
+async fn udp_reply_survives_sends_in_the_middle_of_a_packet() {
+    let echo = start_udp_echo_server().await;
+    let (vless, mut requests) = start_vless_server(0).await;
+    let config = vless_server(vless, None, "none", None);
+    let proxy = start_engine(vec![port_rule(echo.port(), "vless")], vec![config]).await;
+
+    let (_control, relay) = socks5_udp_associate(proxy).await;
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    client.send_to(&udp_datagram(echo, b"slow"), relay).await.unwrap();
+    // Пока ответ на первый пакет приходит по байту, отправляем ещё
+    let messages: [&[u8]; 3] = [b"a", b"b", b"c"];
+    for message in messages {
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        client.send_to(&udp_datagram(echo, message), relay).await.unwrap();
+    }
+
+    for expected in [&b"slow"[..], b"a", b"b", b"c"] {
+        let mut buf = vec![0u8; 1024];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+        let (_, addr_len) = parse_address(&buf[3..len]);
+        assert_eq!(&buf[3 + addr_len..len], [&b"echo:"[..], expected].concat());
+    }
+    assert_eq!(requests.recv().await.unwrap().command, 2);
+}
@@ -0,0 +1,153 @@
+mod common;
+
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::TlsAcceptor;
+use stealthcat_backend::models::{ProxyConfig, ProxyProtocol, ProxyServerV2, VmessConfig};
+use common::vmess::{handle_vmess_client, Request, UUID};
+use common::*;
+
+fn tls_acceptor() -> TlsAcceptor {
+    let path = |name: &str| format!("{}/tests/certs/{}", env!("CARGO_MANIFEST_DIR"), name);
+    let certs = rustls_pemfile::certs(&mut BufReader::new(std::fs::File::open(path("server.pem")).unwrap()))
+        .unwrap()
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    let key = rustls_pemfile::pkcs8_private_keys(&mut BufReader::new(std::fs::File::open(path("server.key")).unwrap()))
+        .unwrap()
+        .remove(0);
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, PrivateKey(key))
+        .unwrap();
+    TlsAcceptor::from(Arc::new(config))
+}
+
+async fn start_vmess_server(tls: bool) -> (SocketAddr, mpsc::UnboundedReceiver<Request>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let acceptor = tls.then(tls_acceptor);
+    let (requests, received) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let acceptor = acceptor.clone();
+            let requests = requests.clone();
+            tokio::spawn(async move {
+                match acceptor {
+                    Some(acceptor) => {
+                        if let Ok(stream) = acceptor.accept(stream).await {
+                            handle_vmess_client(stream, requests).await;
+                        }
+                    }
+                    None => handle_vmess_client(stream, requests).await,
+                }
+            });
+        }
+    });
+    (addr, received)
+}
+
+fn vmess_server(addr: SocketAddr, uuid: &str, security: &str, alter_id: u16, tls: bool) -> ProxyServerV2 {
+    server(
+        "vmess",
+        ProxyProtocol::VMess,
+        ProxyConfig::Vmess(VmessConfig {
+            uuid: uuid.to_string(),
+            alter_id,
+            security: security.to_string(),
+            network: "tcp".to_string(),
+            tls,
+            sni: tls.then(|| "localhost".to_string()),
+            alpn: None,
+            skip_cert_verify: tls,
+            ws: None,
+            grpc: None,
+        }),
+        addr,
+    )
+}
+
+async fn round_trip(security: &str, expected_code: u8, tls: bool) {
+    let echo = start_echo_server().await;
+    let (vmess, mut requests) = start_vmess_server(tls).await;
+    let proxy = start_engine(vec![port_rule(echo.port(), "vmess")], vec![vmess_server(vmess, UUID, security, 0, tls)]).await;
+
+    let stream = socks5_connect(proxy, echo).await;
+    // Несколько чанков в обе стороны и завершающий пустой чанк
+    let payload = test_payload(256 * 1024);
+    assert_eq!(echo_round_trip(stream, &payload).await, payload);
+
+    let request = requests.recv().await.unwrap();
+    assert_eq!(request.security, expected_code);
+    assert_eq!(request.target, echo.to_string());
+}
+
+#[tokio::test]
+async fn aes_128_gcm_round_trip() {
+    round_trip("aes-128-gcm", 3, false).await;
+}
+
+#[tokio::test]
+async fn auto_security_uses_aes_128_gcm() {
+    round_trip("auto", 3, false).await;
+}
+
+#[tokio::test]
+async fn chacha20_poly1305_round_trip() {
+    round_trip("chacha20-poly1305", 4, false).await;
+}
+
+#[tokio::test]
+async fn none_security_round_trip() {
+    round_trip("none", 5, false).await;
+}
+
+#[tokio::test]
+async fn round_trip_over_tls() {
+    round_trip("aes-128-gcm", 3, true).await;
+}
+
+async fn connect_fails(config: ProxyServerV2, echo: SocketAddr) {
+    let proxy = start_engine(vec![port_rule(echo.port(), "vmess")], vec![config]).await;
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    stream.write_all(&[5, 1, 0]).await.unwrap();
+    let mut method = [0u8; 2];
+    stream.read_exact(&mut method).await.unwrap();
+    let mut request = vec![5, 1, 0];
+    push_address(&mut request, echo);
+    stream.write_all(&request).await.unwrap();
+    let mut reply = [0u8; 10];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_ne!(reply[1], 0, "CONNECT must fail");
+}
+
+#[tokio::test]
+async fn legacy_alter_id_is_rejected() {
+    let echo = start_echo_server().await;
+    let (vmess, _requests) = start_vmess_server(false).await;
+    connect_fails(vmess_server(vmess, UUID, "aes-128-gcm", 64, false), echo).await;
+}
+
+#[tokio::test]
+async fn wrong_uuid_gets_no_data() {
+    let echo = start_echo_server().await;
+    let (vmess, mut requests) = start_vmess_server(false).await;
+    let config = vmess_server(vmess, "00000000-0000-4000-8000-000000000000", "aes-128-gcm", 0, false);
+    let proxy = start_engine(vec![port_rule(echo.port(), "vmess")], vec![config]).await;
+
+    // Сервер не узнаёт AuthID и закрывает соединение, ответа нет
+    let mut stream = socks5_connect(proxy, echo).await;
+    stream.write_all(b"hello").await.unwrap();
+    let mut buf = Vec::new();
+    let _ = stream.read_to_end(&mut buf).await;
+    assert!(buf.is_empty());
+    assert!(requests.try_recv().is_err());
+}
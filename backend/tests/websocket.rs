@@ -0,0 +1,290 @@
+mod common;
+
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use base64::{Engine as _, engine::general_purpose};
+use futures_util::{SinkExt, StreamExt};
+use rustls::{Certificate, PrivateKey, ServerConfig};
+use sha2::{Digest, Sha224};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+use tokio_tungstenite::tungstenite::Message;
+use stealthcat_backend::models::{ProxyConfig, ProxyProtocol, ProxyServerV2, VlessConfig, WsConfig};
+use stealthcat_backend::subscription::SubscriptionParser;
+use common::*;
+
+const UUID: &str = "27848739-7e62-4138-9fd3-098a63964b6b";
+const PASSWORD: &str = "trojan-ws-password";
+
+// Что сервер увидел в запросе на upgrade
+#[derive(Debug)]
+struct Upgrade {
+    path: String,
+    host: Option<String>,
+    early_data: Option<Vec<u8>>,
+}
+
+#[derive(Clone, Copy)]
+enum Protocol {
+    Vless,
+    Trojan,
+}
+
+fn cert_path(name: &str) -> String {
+    format!("{}/tests/certs/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+fn tls_acceptor() -> TlsAcceptor {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(std::fs::File::open(cert_path("server.pem")).unwrap()))
+        .unwrap()
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    let key = rustls_pemfile::pkcs8_private_keys(&mut BufReader::new(std::fs::File::open(cert_path("server.key")).unwrap()))
+        .unwrap()
+        .remove(0);
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, PrivateKey(key))
+        .unwrap();
+    TlsAcceptor::from(Arc::new(config))
+}
+
+/// WebSocket сервер на tungstenite: сообщения склеиваются в поток, поверх
+/// которого работает минимальный VLESS или Trojan сервер.
+async fn start_ws_server(protocol: Protocol, tls: bool) -> (SocketAddr, mpsc::UnboundedReceiver<Upgrade>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let acceptor = tls.then(tls_acceptor);
+    let (upgrades, received) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let acceptor = acceptor.clone();
+            let upgrades = upgrades.clone();
+            tokio::spawn(async move {
+                match acceptor {
+                    Some(acceptor) => {
+                        if let Ok(stream) = acceptor.accept(stream).await {
+                            handle_ws_client(stream, protocol, upgrades).await;
+                        }
+                    }
+                    None => handle_ws_client(stream, protocol, upgrades).await,
+                }
+            });
+        }
+    });
+    (addr, received)
+}
+
+async fn handle_ws_client<S>(stream: S, protocol: Protocol, upgrades: mpsc::UnboundedSender<Upgrade>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut upgrade = None;
+    #[allow(clippy::result_large_err)] // тип ошибки задан tungstenite
+    let callback = |request: &Request, response: Response| {
+        let header = |name: &str| request.headers().get(name).map(|v| v.to_str().unwrap().to_string());
+        upgrade = Some(Upgrade {
+            path: request.uri().to_string(),
+            host: header("host"),
+            early_data: header("sec-websocket-protocol")
+                .map(|data| general_purpose::URL_SAFE_NO_PAD.decode(data).unwrap()),
+        });
+        Ok(response)
+    };
+    let Ok(ws) = tokio_tungstenite::accept_hdr_async(stream, callback).await else { return };
+    let upgrade = upgrade.unwrap();
+
+    let (app, bridge) = tokio::io::duplex(64 * 1024);
+    let (mut bridge_reader, mut bridge_writer) = tokio::io::split(bridge);
+    let (mut sink, mut source) = ws.split();
+    if let Some(early_data) = &upgrade.early_data {
+        bridge_writer.write_all(early_data).await.unwrap();
+    }
+    let _ = upgrades.send(upgrade);
+
+    tokio::spawn(async move {
+        while let Some(Ok(message)) = source.next().await {
+            if message.is_close() {
+                break;
+            }
+            if message.is_binary() && bridge_writer.write_all(&message.into_data()).await.is_err() {
+                break;
+            }
+        }
+        let _ = bridge_writer.shutdown().await;
+    });
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 16 * 1024];
+        loop {
+            let n = bridge_reader.read(&mut buf).await.unwrap_or(0);
+            if n == 0 || sink.send(Message::Binary(buf[..n].to_vec())).await.is_err() {
+                break;
+            }
+        }
+        let _ = sink.close().await;
+    });
+
+    match protocol {
+        Protocol::Vless => serve_vless(app).await,
+        Protocol::Trojan => serve_trojan(app).await,
+    }
+}
+
+async fn serve_vless(mut stream: DuplexStream) {
+    let mut header = [0u8; 18];
+    stream.read_exact(&mut header).await.unwrap();
+    assert_eq!(header[0], 0);
+    assert_eq!(&header[1..17], uuid::Uuid::parse_str(UUID).unwrap().as_bytes());
+    let mut addons = vec![0u8; header[17] as usize];
+    stream.read_exact(&mut addons).await.unwrap();
+    assert_eq!(stream.read_u8().await.unwrap(), 1, "only TCP is expected");
+    let port = stream.read_u16().await.unwrap();
+    // VLESS: 1 - IPv4, 3 - IPv6
+    let ip: std::net::IpAddr = match stream.read_u8().await.unwrap() {
+        1 => {
+            let mut octets = [0u8; 4];
+            stream.read_exact(&mut octets).await.unwrap();
+            octets.into()
+        }
+        atyp => panic!("unexpected address type {}", atyp),
+    };
+    stream.write_all(&[0, 0]).await.unwrap();
+    relay(stream, SocketAddr::new(ip, port)).await;
+}
+
+async fn serve_trojan(mut stream: DuplexStream) {
+    let mut hash = [0u8; 56];
+    stream.read_exact(&mut hash).await.unwrap();
+    let expected: String = Sha224::digest(PASSWORD).iter().map(|b| format!("{:02x}", b)).collect();
+    assert_eq!(&hash[..], expected.as_bytes());
+    let mut crlf = [0u8; 2];
+    stream.read_exact(&mut crlf).await.unwrap();
+    assert_eq!(stream.read_u8().await.unwrap(), 1, "only CONNECT is expected");
+    let mut address = [0u8; 7];
+    stream.read_exact(&mut address).await.unwrap();
+    stream.read_exact(&mut crlf).await.unwrap();
+    relay(stream, parse_address(&address).0).await;
+}
+
+async fn relay(mut stream: DuplexStream, target: SocketAddr) {
+    let mut upstream = TcpStream::connect(target).await.unwrap();
+    let _ = tokio::io::copy_bidirectional(&mut stream, &mut upstream).await;
+}
+
+fn ws_config(path: &str, host: Option<&str>, max_early_data: usize) -> WsConfig {
+    WsConfig {
+        path: path.to_string(),
+        host: host.map(str::to_string),
+        max_early_data,
+        early_data_header: None,
+    }
+}
+
+fn vless_server(addr: SocketAddr, ws: WsConfig) -> ProxyServerV2 {
+    server(
+        "ws",
+        ProxyProtocol::VLESS,
+        ProxyConfig::Vless(VlessConfig {
+            uuid: UUID.to_string(),
+            flow: None,
+            encryption: "none".to_string(),
+            network: "ws".to_string(),
+            security: "none".to_string(),
+            sni: None,
+            alpn: None,
+            fp: None,
+            pbk: None,
+            sid: None,
+            spx: None,
+            ws: Some(ws),
+            grpc: None,
+        }),
+        addr,
+    )
+}
+
+#[tokio::test]
+async fn vless_over_ws_sends_path_and_host() {
+    let echo = start_echo_server().await;
+    let (ws, mut upgrades) = start_ws_server(Protocol::Vless, false).await;
+    let config = vless_server(ws, ws_config("/ray?token=1", Some("cdn.example.com"), 0));
+    let proxy = start_engine(vec![port_rule(echo.port(), "ws")], vec![config]).await;
+
+    let stream = socks5_connect(proxy, echo).await;
+    let payload = test_payload(256 * 1024);
+    assert_eq!(echo_round_trip(stream, &payload).await, payload);
+
+    let upgrade = upgrades.recv().await.unwrap();
+    assert_eq!(upgrade.path, "/ray?token=1");
+    assert_eq!(upgrade.host.as_deref(), Some("cdn.example.com"));
+    assert!(upgrade.early_data.is_none());
+}
+
+#[tokio::test]
+async fn vless_over_ws_puts_header_into_early_data() {
+    let echo = start_echo_server().await;
+    let (ws, mut upgrades) = start_ws_server(Protocol::Vless, false).await;
+    let config = vless_server(ws, ws_config("/ray", None, 2048));
+    let proxy = start_engine(vec![port_rule(echo.port(), "ws")], vec![config]).await;
+
+    let stream = socks5_connect(proxy, echo).await;
+    assert_eq!(echo_round_trip(stream, b"hello early data").await, b"hello early data");
+
+    let upgrade = upgrades.recv().await.unwrap();
+    assert_eq!(upgrade.host.as_deref(), Some("127.0.0.1"));
+    let early_data = upgrade.early_data.expect("VLESS header must travel in the upgrade request");
+    assert_eq!(early_data[0], 0);
+    assert_eq!(&early_data[1..17], uuid::Uuid::parse_str(UUID).unwrap().as_bytes());
+}
+
+#[tokio::test]
+async fn trojan_over_wss_round_trip() {
+    let echo = start_echo_server().await;
+    let (wss, mut upgrades) = start_ws_server(Protocol::Trojan, true).await;
+    let config = server(
+        "ws",
+        ProxyProtocol::Trojan,
+        ProxyConfig::Trojan {
+            password: PASSWORD.to_string(),
+            sni: None,
+            alpn: None,
+            skip_cert_verify: false,
+            ca: Some(cert_path("ca.pem")),
+            network: Some("ws".to_string()),
+            // Host задаёт и SNI, сертификат выписан на localhost
+            ws: Some(ws_config("/trojan", Some("localhost"), 0)),
+            grpc: None,
+        },
+        wss,
+    );
+    let proxy = start_engine(vec![port_rule(echo.port(), "ws")], vec![config]).await;
+
+    let stream = socks5_connect(proxy, echo).await;
+    let payload = test_payload(64 * 1024);
+    assert_eq!(echo_round_trip(stream, &payload).await, payload);
+
+    let upgrade = upgrades.recv().await.unwrap();
+    assert_eq!(upgrade.path, "/trojan");
+    assert_eq!(upgrade.host.as_deref(), Some("localhost"));
+}
+
+#[test]
+fn vless_link_keeps_ws_parameters() {
+    let link = format!(
+        "vless://{}@example.com:443?type=ws&security=tls&path=%2Fray%3Fed%3D2048&host=cdn.example.com#node",
+        UUID
+    );
+    let server = SubscriptionParser::new().parse_vless_url(&link).unwrap();
+    let ProxyConfig::Vless(config) = server.config else { panic!("expected VLESS config") };
+    let ws = config.ws.expect("ws parameters must be kept");
+    assert_eq!(ws.path, "/ray");
+    assert_eq!(ws.host.as_deref(), Some("cdn.example.com"));
+    assert_eq!(ws.max_early_data, 2048);
+}
@@ -344,7 +344,8 @@ const ServerModal = ({ isOpen, onClose, server, onSave }) => {
     pbk: '',
     sid: '',
     spx: '',
-    // Trojan
+    // Trojan, HTTP, SOCKS5
+    username: '',
     password: '',
     // Shadowsocks
     method: 'aes-256-gcm'
@@ -401,6 +402,17 @@ const ServerModal = ({ isOpen, onClose, server, onSave }) => {
             method: server.config.Shadowsocks.method || 'aes-256-gcm',
             password: server.config.Shadowsocks.password || ''
           });
+        } else {
+          // HTTP, HTTPS и SOCKS5: учётные данные вышестоящего прокси
+          const upstream = server.config.Http || server.config.Https || server.config.Socks5;
+          if (upstream) {
+            setConfigData({
+              ...configData,
+              username: upstream.username || '',
+              password: upstream.password || '',
+              sni: upstream.sni || ''
+            });
+          }
         }
       }
     } else {
@@ -428,6 +440,7 @@ const ServerModal = ({ isOpen, onClose, server, onSave }) => {
         pbk: '',
         sid: '',
         spx: '',
+        username: '',
         password: '',
         method: 'aes-256-gcm'
       });
@@ -478,13 +491,29 @@ const ServerModal = ({ isOpen, onClose, server, onSave }) => {
           }
         };
       case 'HTTP':
-        return 'Http';
+        return {
+          Http: {
+            username: configData.username || null,
+            password: configData.password || null
+          }
+        };
       case 'HTTPS':
-        return 'Https';
+        return {
+          Https: {
+            username: configData.username || null,
+            password: configData.password || null,
+            sni: configData.sni || null
+          }
+        };
       case 'SOCKS5':
-        return 'Socks5';
+        return {
+          Socks5: {
+            username: configData.username || null,
+            password: configData.password || null
+          }
+        };
       default:
-        return 'Http';
+        return { Http: {} };
     }
   };
 